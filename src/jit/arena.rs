@@ -0,0 +1,84 @@
+use super::{JitMemory, PAGE_SIZE};
+use std::io;
+
+/// Alignment of every function in the arena
+pub const FUNCTION_ALIGN: usize = 16;
+
+/// Compiled functions packed back to back into a single executable region, so calls between
+/// them can use rel32 displacements. Scripts are only ever run through the entry stub of the
+/// `CompiledProgram`, which sets up the `RuntimeContext` they end the run through
+pub struct CodeArena {
+    mem: JitMemory,
+    /// Offset of each function from the start of the region
+    offsets: Vec<usize>,
+}
+
+impl CodeArena {
+    /// Offset of each function when packed, followed by the total size
+    pub fn layout(sizes: &[usize]) -> (Vec<usize>, usize) {
+        let mut offsets = Vec::with_capacity(sizes.len());
+        let mut end = 0;
+        for size in sizes {
+            offsets.push(end);
+            end += (size + FUNCTION_ALIGN - 1) & !(FUNCTION_ALIGN - 1);
+        }
+        (offsets, end)
+    }
+
+    /// Copy `functions` into a new writable region, using the layout from `layout`. With
+    /// `dual_mapped` the region is never writable and executable at the same time, see
+    /// `JitMemory::new_dual_mapped`
+    pub fn new(functions: &[Vec<u8>], dual_mapped: bool) -> io::Result<CodeArena> {
+        let sizes = functions.iter().map(Vec::len).collect::<Vec<_>>();
+        let (offsets, size) = CodeArena::layout(&sizes);
+        let num_pages = size.div_ceil(PAGE_SIZE);
+        let mut mem = if dual_mapped {
+            JitMemory::new_dual_mapped(num_pages)?
+        } else {
+            JitMemory::new(num_pages)?
+        };
+        unsafe {
+            let contents = mem.as_slice();
+            for (function, &offset) in functions.iter().zip(offsets.iter()) {
+                contents[offset..offset + function.len()].copy_from_slice(function);
+            }
+        }
+        Ok(CodeArena { mem, offsets })
+    }
+
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
+    /// Address the function at `index` runs from
+    pub fn address(&self, index: usize) -> *const u8 {
+        unsafe { self.mem.exec.add(self.offsets[index]) }
+    }
+
+    /// Index of the function the code at `address` belongs to and the offset into it
+    pub fn locate(&self, address: u64) -> Option<(usize, usize)> {
+        let offset = (address as usize).checked_sub(self.mem.exec as usize)?;
+        let index = match self.offsets.binary_search(&offset) {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+        Some((index, offset - self.offsets[index]))
+    }
+
+    pub fn lock(&mut self) -> io::Result<()> {
+        unsafe { self.mem.lock() }
+    }
+
+    pub fn unlock(&mut self) -> io::Result<()> {
+        unsafe { self.mem.unlock() }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.mem.locked
+    }
+}
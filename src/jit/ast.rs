@@ -1,4 +1,5 @@
 use msc::{Command, Script, Cmd};
+use std::collections::{HashMap, HashSet};
 
 pub trait AsAst {
     fn as_ast(&self) -> ScriptAst;
@@ -83,7 +84,7 @@ fn cmd_to_assignop(c: Cmd) -> AssignOp {
         Cmd::OrVarBy { .. } => AssignOp::Or,
         Cmd::XorVarBy { .. } => AssignOp::Xor,
         Cmd::IncF { .. } => AssignOp::Add(Type::Float),
-        Cmd::DecF { .. } => AssignOp::Sub(Type::Int),
+        Cmd::DecF { .. } => AssignOp::Sub(Type::Float),
         Cmd::VarSetF { .. } => AssignOp::Set(Type::Float),
         Cmd::AddVarByF { .. } => AssignOp::Add(Type::Float),
         Cmd::SubVarByF { .. } => AssignOp::Sub(Type::Float),
@@ -111,7 +112,39 @@ fn unaryop_cmd_type(c: Cmd) -> Type {
     }
 }
 
-fn take_node<'a, I, T>(commands: &mut I, type_suspect: T) -> Option<Node>
+/// A value an earlier operand left for a later one, `offset` counts the operands in between
+struct PendingCast {
+    offset: usize,
+    op: UnaryOp,
+}
+
+/// Take the next of several operands of the same command, applying the casts earlier operands
+/// left for it (IntToFloat/FloatToInt with a non-zero stack_pos)
+fn take_operand<I, T>(commands: &mut I, type_suspect: T, casts: &mut Vec<PendingCast>)
+    -> Option<Node>
+where
+    I: Iterator<Item = InterForm>,
+    T: Into<Option<Type>>,
+{
+    let apply = casts.iter()
+        .filter(|cast| cast.offset == 0)
+        .map(|cast| cast.op.clone())
+        .collect::<Vec<_>>();
+    casts.retain(|cast| cast.offset != 0);
+    for cast in casts.iter_mut() {
+        cast.offset -= 1;
+    }
+    let mut new_casts = vec![];
+    let mut node = take_node(commands, type_suspect, &mut new_casts)?;
+    for op in apply {
+        node = Node::UnaryOp { op, left: Box::new(node) };
+    }
+    casts.extend(new_casts.into_iter().map(|cast| PendingCast { offset: cast.offset - 1, ..cast }));
+    Some(node)
+}
+
+fn take_node<I, T>(commands: &mut I, type_suspect: T, casts: &mut Vec<PendingCast>)
+    -> Option<Node>
 where
     I: Iterator<Item = InterForm>,
     T: Into<Option<Type>>,
@@ -122,61 +155,57 @@ where
         match next_command {
             InterForm::Cmd{ cmd: c } => {
                 match c.cmd {
-                    Cmd::PushInt { val } => {
-                        if c.push_bit {
-                            return Some(
-                                match type_suspect.unwrap_or(Type::Int) {
-                                    Type::Int => Node::Const{ val: Const::U32(val) },
-                                    Type::Float => Node::Const{
-                                        val: Const::F32(
-                                            unsafe { std::mem::transmute(val) }
-                                        )
-                                    },
-                                })
-                        }
+                    Cmd::PushInt { val } if c.push_bit => {
+                        return Some(
+                            match type_suspect.unwrap_or(Type::Int) {
+                                Type::Int => Node::Const{ val: Const::U32(val) },
+                                Type::Float => Node::Const{
+                                    val: Const::F32(f32::from_bits(val))
+                                },
+                            })
                     }
-                    Cmd::PushShort { val } => {
-                        if c.push_bit {
-                            return Some(Node::Const{ val: Const::U32(val as u32) });
-                        }
+                    Cmd::PushShort { val } if c.push_bit => {
+                        return Some(Node::Const{ val: Const::U32(u32::from(val)) });
                     }
-                    Cmd::PushVar { var_type, var_num } => {
-                        if c.push_bit {
-                            return Some(Node::Var {
-                                is_global: var_type == 1,
-                                var_num
-                            })
-                        }
+                    Cmd::PushVar { var_type, var_num } if c.push_bit => {
+                        return Some(Node::Var {
+                            is_global: var_type != 0,
+                            var_num
+                        })
                     }
                     Cmd::AddI | Cmd::SubI | Cmd::MultI | Cmd::DivI | Cmd::ModI |
                     Cmd::AndI | Cmd::XorI | Cmd::ShiftL| Cmd::OrI  | Cmd::ShiftR |
                     Cmd::AddF | Cmd::SubF | Cmd::MultF | Cmd::DivF | Cmd::Equals |
                     Cmd::NotEquals | Cmd::LessThan | Cmd::LessOrEqual | Cmd::Greater |
                     Cmd::GreaterOrEqual | Cmd::EqualsF | Cmd::NotEqualsF | Cmd::LessThanF |
-                    Cmd::LessOrEqualF | Cmd::GreaterF | Cmd::GreaterOrEqualF => {
-                        if c.push_bit {
-                            return Some(Node::BinOp {
-                                op:    cmd_to_binop(c.cmd),
-                                right: Box::new(take_node(commands, binop_cmd_type(c.cmd))?),
-                                left:  Box::new(take_node(commands, binop_cmd_type(c.cmd))?),
-                            })
-                        }
+                    Cmd::LessOrEqualF | Cmd::GreaterF | Cmd::GreaterOrEqualF if c.push_bit => {
+                        let mut operand_casts = vec![];
+                        return Some(Node::BinOp {
+                            op:    cmd_to_binop(c.cmd),
+                            right: Box::new(take_operand(commands, binop_cmd_type(c.cmd), &mut operand_casts)?),
+                            left:  Box::new(take_operand(commands, binop_cmd_type(c.cmd), &mut operand_casts)?),
+                            position: c.position,
+                        })
                     }
                     Cmd::Return6 | Cmd::Return8 => {
                         return Some(Node::Return {
-                            val: Some(Box::new(take_node(commands, None)?))
+                            val: Some(Box::new(take_node(commands, None, &mut vec![])?))
                         })
                     }
                     Cmd::Return7 | Cmd::Return9 | Cmd::End => {
                         return Some(Node::Return { val: None });
                     }
+                    Cmd::Exit => {
+                        return Some(Node::Exit);
+                    }
                     Cmd::IncI { var_type, var_num } | Cmd::DecI { var_type, var_num } |
                     Cmd::IncF { var_type, var_num } | Cmd::DecF { var_type, var_num } => {
                         return Some(Node::Assign {
                             op: cmd_to_assignop(c.cmd),
-                            is_global: var_type == 1,
+                            is_global: var_type != 0,
                             var_num,
-                            right: Box::new(Node::const_from_type(1, var_cmd_type(c.cmd)))
+                            right: Box::new(Node::const_from_type(1, var_cmd_type(c.cmd))),
+                            position: c.position,
                         })
                     }
                     Cmd::SetVar { var_type, var_num } | Cmd::AddVarBy { var_type, var_num } |
@@ -189,54 +218,57 @@ where
                     Cmd::DivVarByF { var_type, var_num } => {
                         return Some(Node::Assign {
                             op: cmd_to_assignop(c.cmd),
-                            is_global: var_type == 1,
+                            is_global: var_type != 0,
                             var_num,
-                            right: Box::new(take_node(commands, None)?)
+                            right: Box::new(take_node(commands, var_cmd_type(c.cmd), &mut vec![])?),
+                            position: c.position,
                         })
                     }
-                    Cmd::Not | Cmd::NotI | Cmd::NegI | Cmd::NegF => {
-                        if c.push_bit {
-                            return Some(Node::UnaryOp {
-                                op: cmd_to_unaryop(c.cmd),
-                                left:  Box::new(take_node(commands, unaryop_cmd_type(c.cmd))?),
-                            });
-                        }
+                    Cmd::Not | Cmd::NotI | Cmd::NegI | Cmd::NegF if c.push_bit => {
+                        return Some(Node::UnaryOp {
+                            op: cmd_to_unaryop(c.cmd),
+                            left:  Box::new(take_node(commands, unaryop_cmd_type(c.cmd), &mut vec![])?),
+                        });
                     }
                     Cmd::IntToFloat { stack_pos } | Cmd::FloatToInt { stack_pos } => {
-                        if stack_pos != 0 {
-                            panic!("Variable stack_pos of int/float casting not supported");
+                        let (op, operand_type) = match c.cmd {
+                            Cmd::IntToFloat { .. } => (UnaryOp::ToFloat, Type::Int),
+                            Cmd::FloatToInt { .. } => (UnaryOp::ToInt, Type::Float),
+                            _ => unreachable!()
+                        };
+                        if stack_pos == 0 {
+                            return Some(Node::UnaryOp {
+                                op,
+                                left: Box::new(take_node(commands, operand_type, casts)?)
+                            })
                         }
-                        return Some(Node::UnaryOp {
-                            op: match c.cmd {
-                                Cmd::IntToFloat { stack_pos: _ } => UnaryOp::ToFloat,
-                                Cmd::FloatToInt { stack_pos: _ } => UnaryOp::ToInt,
-                                _ => unreachable!()
-                            },
-                            left: Box::new(take_node(commands,
-                                     match c.cmd {
-                                        Cmd::IntToFloat { stack_pos: _ } => Type::Int,
-                                        Cmd::FloatToInt { stack_pos: _ } => Type::Float,
-                                        _ => unreachable!()
-                                     }
-                                  )?)
-                        })
+                        // Converts a value deeper in the stack, which a later operand takes
+                        let node = take_node(commands, type_suspect, casts)?;
+                        casts.push(PendingCast { offset: stack_pos as usize, op });
+                        return Some(node);
                     }
                     Cmd::PrintF { arg_count } => {
+                        // Rejected by validate_script, only decompiling gets here
+                        if arg_count == 0 {
+                            continue;
+                        }
+                        let mut operand_casts = vec![];
                         let mut args = vec![];
                         for _ in 0..arg_count-1 {
-                            args.push(take_node(commands, Type::Float)?);
+                            args.push(take_operand(commands, None, &mut operand_casts)?);
                         }
                         args.reverse();
-                        let str_num = Box::new(take_node(commands, Type::Int)?);
+                        let str_num = Box::new(take_operand(commands, Type::Int, &mut operand_casts)?);
                         return Some(Node::Printf {
                             str_num,
                             args
                         });
                     }
                     Cmd::Sys { arg_count, sys_num } => {
+                        let mut operand_casts = vec![];
                         let mut args = vec![];
                         for _ in 0..arg_count {
-                            args.push(take_node(commands, Type::Float)?);
+                            args.push(take_operand(commands, None, &mut operand_casts)?);
                         }
                         args.reverse();
                         return Some(Node::SysCall {
@@ -244,53 +276,325 @@ where
                             args
                         });
                     }
-                    Cmd::Jump { loc: _ } | Cmd::Jump5 { loc: _ } | Cmd::Else { loc: _ } => panic!("Jump unsupported"),
+                    Cmd::CallFunc { arg_count } | Cmd::CallFunc2 { arg_count } |
+                    Cmd::CallFunc3 { arg_count } => {
+                        // The return value is pushed at the Try location, which is right after
+                        // the call when it's used
+                        let mut operand_casts = vec![];
+                        let func = Box::new(take_operand(commands, Type::Int, &mut operand_casts)?);
+                        let mut args = vec![];
+                        for _ in 0..arg_count {
+                            args.push(take_operand(commands, None, &mut operand_casts)?);
+                        }
+                        args.reverse();
+                        return Some(Node::FuncCall {
+                            func,
+                            args
+                        });
+                    }
+                    // Discarded expression
+                    Cmd::Pop if !c.push_bit => {
+                        return take_node(commands, None, casts);
+                    }
+                    Cmd::Jump { .. } | Cmd::Jump5 { .. } | Cmd::Else { .. } |
+                    Cmd::If { .. } | Cmd::IfNot { .. } => {
+                        unreachable!("{:?} is turned into a structure by group_structures", c.cmd)
+                    }
                     Cmd::Nop | Cmd::Begin { var_count: _, arg_count: _ } | Cmd::Unk1 |
-                    Cmd::ErrorC | Cmd::Error4C | Cmd::Exit | Cmd::Push | Cmd::Pop => {}
+                    Cmd::ErrorC | Cmd::Error4C | Cmd::Push => {}
                     _ => {}
                 }
             }
-            _ => {}
+            InterForm::IfElseBlock { negated, if_block, else_block } => {
+                let cond = take_node(commands, Type::Int, &mut vec![])?;
+                return Some(Node::If {
+                    cond: Box::new(if negated { cond.negate() } else { cond }),
+                    if_block: build_nodes(&if_block),
+                    else_block: else_block.map_or(vec![], |block| build_nodes(&block)),
+                });
+            }
+            InterForm::Loop { cond: None, body } => {
+                return Some(Node::While {
+                    cond: Box::new(Node::Const { val: Const::U32(1) }),
+                    body: build_nodes(&body),
+                });
+            }
+            InterForm::Loop { cond: Some((negated, cond)), body } => {
+                let mut cond_nodes = build_nodes(&cond);
+                let cond = cond_nodes.pop()?;
+                let cond = if negated { cond.negate() } else { cond };
+                let mut body = build_nodes(&body);
+                if cond_nodes.is_empty() {
+                    return Some(Node::While { cond: Box::new(cond), body });
+                }
+                // Statements before the condition, check it inside an endless loop instead
+                cond_nodes.push(Node::If {
+                    cond: Box::new(cond.negate()),
+                    if_block: vec![Node::Break],
+                    else_block: vec![],
+                });
+                cond_nodes.append(&mut body);
+                return Some(Node::While {
+                    cond: Box::new(Node::Const { val: Const::U32(1) }),
+                    body: cond_nodes,
+                });
+            }
+            InterForm::DoWhile { negated, body } => {
+                // The condition is the last value computed in the body
+                let mut body_commands = body.into_iter().rev();
+                let mut body_casts = vec![];
+                let cond = take_operand(&mut body_commands, Type::Int, &mut body_casts)?;
+                let mut body = vec![];
+                while let Some(node) = take_operand(&mut body_commands, None, &mut body_casts) {
+                    body.push(node);
+                }
+                body.reverse();
+                return Some(Node::DoWhile {
+                    body,
+                    cond: Box::new(if negated { cond.negate() } else { cond }),
+                });
+            }
+            InterForm::Break => return Some(Node::Break),
+            InterForm::Continue => return Some(Node::Continue),
+            InterForm::Goto { loc } => return Some(Node::Goto { loc }),
+            InterForm::Label { loc } => return Some(Node::Label { loc }),
+            InterForm::Node { node } => return Some(node),
         }
     }
 }
 
-fn group_structures<'a, I>(commands: &mut I) -> Vec<InterForm>
-where
-    I: Iterator<Item = &'a Command>,
-{
-    let mut out: Vec<InterForm> = vec![];
-    while let Some(c) = commands.next() {
-        match c.cmd {
+/// Build the nodes of a block of commands/structures
+fn build_nodes(forms: &[InterForm]) -> Vec<Node> {
+    let mut commands = forms.iter().cloned().rev();
+    let mut casts = vec![];
+    let mut nodes = vec![];
+    while let Some(node) = take_operand(&mut commands, None, &mut casts) {
+        nodes.push(node);
+    }
+    nodes.reverse();
+    nodes
+}
+
+/// Targets of the jumps out of the loop being structured
+#[derive(Debug, Clone, Copy)]
+struct LoopTargets {
+    /// First command of the loop, no new loop is started there
+    header: usize,
+    continue_index: Option<usize>,
+    break_index: usize,
+}
+
+/// Recovers if/else, loops, break and continue from the jumps of a script. Anything else
+/// becomes a goto with a matching label
+struct Structurer<'a> {
+    commands: &'a [Command],
+    /// Absolute location to command index, the end of the script maps to `commands.len()`
+    indices: HashMap<u32, usize>,
+    /// Command indices a label has to be emitted before
+    labels: HashSet<usize>,
+    /// Command indices targeted by gotos
+    goto_targets: HashSet<usize>,
+}
+
+/// Absolute location a jump or branch goes to
+pub fn branch_target(cmd: Cmd) -> Option<u32> {
+    match cmd {
+        Cmd::Jump { loc } | Cmd::Jump5 { loc } | Cmd::Else { loc } |
+        Cmd::If { loc } | Cmd::IfNot { loc } => Some(loc),
+        _ => None,
+    }
+}
+
+fn is_unconditional_jump(cmd: Cmd) -> bool {
+    matches!(cmd, Cmd::Jump { .. } | Cmd::Jump5 { .. } | Cmd::Else { .. })
+}
+
+impl<'a> Structurer<'a> {
+    fn target_index(&self, cmd: Cmd) -> Option<usize> {
+        branch_target(cmd).and_then(|loc| self.indices.get(&loc).cloned())
+    }
+
+    fn loc_of(&self, index: usize) -> u32 {
+        self.indices.iter()
+            .find(|(_, &i)| i == index)
+            .map(|(&loc, _)| loc)
+            .unwrap_or(0)
+    }
+
+    /// A jump leaving the current structure
+    fn jump_out(&mut self, target: Option<usize>, loc: u32, loop_targets: Option<LoopTargets>)
+        -> InterForm
+    {
+        if let (Some(target), Some(loop_targets)) = (target, loop_targets) {
+            if target == loop_targets.break_index {
+                return InterForm::Break;
+            }
+            if Some(target) == loop_targets.continue_index {
+                return InterForm::Continue;
+            }
+        }
+        if let Some(target) = target {
+            self.goto_targets.insert(target);
+        }
+        InterForm::Goto { loc }
+    }
+
+    /// The last command in [start, end) jumping back to `start`
+    fn find_back_edge(&self, start: usize, end: usize) -> Option<usize> {
+        (start..end).rev()
+            .find(|&i| self.target_index(self.commands[i].cmd) == Some(start))
+    }
+
+    fn structure(&mut self, start: usize, end: usize, loop_targets: Option<LoopTargets>)
+        -> Vec<InterForm>
+    {
+        let commands = self.commands;
+        let mut out = vec![];
+        let mut i = start;
+        while i < end {
+            if self.labels.contains(&i) {
+                out.push(InterForm::Label { loc: self.loc_of(i) });
+            }
+            let is_header = loop_targets.is_some_and(|targets| targets.header == i);
+            if !is_header {
+                if let Some(back_edge) = self.find_back_edge(i, end) {
+                    out.push(self.structure_loop(i, back_edge));
+                    i = back_edge + 1;
+                    continue;
+                }
+            }
+
+            let c = &commands[i];
+            let target = self.target_index(c.cmd);
+            match c.cmd {
+                Cmd::If { loc } | Cmd::IfNot { loc } => {
+                    let is_if_not = matches!(c.cmd, Cmd::IfNot { .. });
+                    match target {
+                        Some(target) if target > i && target <= end => {
+                            // if (cond) { ... } else { ... }, with the else block skipped by a
+                            // jump at the end of the if block
+                            let else_end = if target > i + 1 && is_unconditional_jump(commands[target - 1].cmd) {
+                                self.target_index(commands[target - 1].cmd)
+                                    .filter(|&else_end| else_end > target && else_end <= end)
+                            } else {
+                                None
+                            };
+                            let if_end = if else_end.is_some() { target - 1 } else { target };
+                            let if_block = self.structure(i + 1, if_end, loop_targets);
+                            let else_block = else_end.map(|else_end| {
+                                self.structure(target, else_end, loop_targets)
+                            });
+                            out.push(InterForm::IfElseBlock {
+                                negated: is_if_not,
+                                if_block,
+                                else_block,
+                            });
+                            i = else_end.unwrap_or(target);
+                            continue;
+                        }
+                        _ => {
+                            // Conditional break/continue/goto, If jumps when the condition is 0
+                            let jump = self.jump_out(target, loc, loop_targets);
+                            out.push(InterForm::IfElseBlock {
+                                negated: !is_if_not,
+                                if_block: vec![jump],
+                                else_block: None,
+                            });
+                        }
+                    }
+                }
+                Cmd::Jump { loc } | Cmd::Jump5 { loc } | Cmd::Else { loc } => {
+                    if target != Some(i + 1) {
+                        let jump = self.jump_out(target, loc, loop_targets);
+                        out.push(jump);
+                    }
+                }
+                _ => {
+                    out.push(InterForm::Cmd { cmd: c.clone() });
+                }
+            }
+            i += 1;
+        }
+        if end == self.commands.len() && self.labels.contains(&end) {
+            out.push(InterForm::Label { loc: self.loc_of(end) });
+        }
+        out
+    }
+
+    /// Loop from `header` to the jump back to it at `back_edge`
+    fn structure_loop(&mut self, header: usize, back_edge: usize) -> InterForm {
+        let break_index = back_edge + 1;
+        match self.commands[back_edge].cmd {
+            Cmd::If { .. } | Cmd::IfNot { .. } => {
+                // do { ... } while (cond), If jumps back when the condition is 0
+                let negated = matches!(self.commands[back_edge].cmd, Cmd::If { .. });
+                let loop_targets = LoopTargets { header, continue_index: None, break_index };
+                InterForm::DoWhile {
+                    negated,
+                    body: self.structure(header, back_edge, Some(loop_targets)),
+                }
+            }
             _ => {
-                out.push(InterForm::Cmd { cmd: c.clone() });
+                let loop_targets = LoopTargets {
+                    header, continue_index: Some(header), break_index
+                };
+                // while (cond) { ... } if the first branch leaves the loop
+                let first_branch = (header..back_edge)
+                    .find(|&i| branch_target(self.commands[i].cmd).is_some());
+                if let Some(cond_end) = first_branch {
+                    let cmd = self.commands[cond_end].cmd;
+                    let is_cond = matches!(cmd, Cmd::If { .. } | Cmd::IfNot { .. });
+                    if is_cond && self.target_index(cmd) == Some(break_index) {
+                        let cond = self.commands[header..cond_end].iter()
+                            .map(|cmd| InterForm::Cmd { cmd: cmd.clone() })
+                            .collect();
+                        let negated = matches!(cmd, Cmd::IfNot { .. });
+                        return InterForm::Loop {
+                            cond: Some((negated, cond)),
+                            body: self.structure(cond_end + 1, back_edge, Some(loop_targets)),
+                        };
+                    }
+                }
+                InterForm::Loop {
+                    cond: None,
+                    body: self.structure(header, back_edge, Some(loop_targets)),
+                }
             }
         }
     }
-    out
+}
+
+fn group_structures(script: &Script, commands: &[Command]) -> Vec<InterForm> {
+    let mut indices = commands.iter()
+        .enumerate()
+        .map(|(i, c)| (script.bounds.0 + c.position, i))
+        .collect::<HashMap<u32, usize>>();
+    indices.insert(script.bounds.1, commands.len());
+    let mut structurer = Structurer {
+        commands,
+        indices,
+        labels: HashSet::new(),
+        goto_targets: HashSet::new(),
+    };
+    let out = structurer.structure(0, commands.len(), None);
+    if structurer.goto_targets.is_empty() {
+        return out;
+    }
+    // Again, with labels for the gotos
+    structurer.labels = std::mem::take(&mut structurer.goto_targets);
+    structurer.structure(0, commands.len(), None)
 }
 
 impl AsAst for Script {
     fn as_ast(&self) -> ScriptAst {
-        let mut i = self.commands.iter();
-        let first_command = i.next().unwrap();
-        let (var_count, arg_count) = 
-            if let Cmd::Begin { var_count, arg_count } = first_command.cmd {
-                (var_count, arg_count)
-            }
-            else {
-                panic!("Script does not begin with Begin, begins with {:?}", first_command);
-            };
-        let temp = group_structures(&mut i);
-        let mut commands = temp.iter().cloned().rev();
-        
-        let mut nodes = vec![];
-        while let Some(node) = take_node(&mut commands, None) {
-            nodes.push(node);
-        }
-        nodes.reverse();
+        let (var_count, arg_count, body) = match self.commands.first().map(|c| c.cmd) {
+            Some(Cmd::Begin { var_count, arg_count }) => (var_count, arg_count, &self.commands[1..]),
+            // Nothing to set up, every command is part of the body
+            _ => (0, 0, &self.commands[..]),
+        };
+        let forms = group_structures(self, body);
         ScriptAst {
-            nodes,
+            nodes: build_nodes(&forms),
             var_count,
             arg_count,
         }
@@ -306,10 +610,31 @@ pub struct ScriptAst {
 
 #[derive(Debug, Clone)]
 pub enum InterForm {
+    /// Preceded by the condition, `negated` when the if block runs if it's 0
     IfElseBlock {
+        negated: bool,
         if_block: Vec<InterForm>,
         else_block: Option<Vec<InterForm>>
     },
+    /// Endless loop when there is no condition, the condition is computed by the commands
+    /// before the exit check
+    Loop {
+        cond: Option<(bool, Vec<InterForm>)>,
+        body: Vec<InterForm>
+    },
+    /// The condition is the last value computed by the body
+    DoWhile {
+        negated: bool,
+        body: Vec<InterForm>
+    },
+    Break,
+    Continue,
+    Goto {
+        loc: u32
+    },
+    Label {
+        loc: u32
+    },
     Node {
         node: Node
     },
@@ -318,6 +643,8 @@ pub enum InterForm {
     },
 }
 
+/// `position` is the command a node that can fault was built from, relative to the start of
+/// the script
 #[derive(Debug, Clone)]
 pub enum Node {
     Assign {
@@ -325,6 +652,7 @@ pub enum Node {
         is_global: bool,
         var_num: u16,
         right: Box<Node>,
+        position: u32,
     },
     Const {
         val: Const
@@ -333,6 +661,7 @@ pub enum Node {
         op: BinOp,
         left: Box<Node>,
         right: Box<Node>,
+        position: u32,
     },
     UnaryOp {
         op: UnaryOp,
@@ -343,11 +672,29 @@ pub enum Node {
         if_block: Vec<Node>,
         else_block: Vec<Node>,
     },
+    While {
+        cond: Box<Node>,
+        body: Vec<Node>,
+    },
+    DoWhile {
+        body: Vec<Node>,
+        cond: Box<Node>,
+    },
+    Break,
+    Continue,
+    /// Jump that isn't part of an if/else or loop, `loc` is the absolute offset of the target
+    Goto {
+        loc: u32
+    },
+    Label {
+        loc: u32
+    },
     Return {
         val: Option<Box<Node>>
     },
+    Exit,
     FuncCall {
-        func_offset: u32,
+        func: Box<Node>,
         args: Vec<Node>,
     },
     SysCall {
@@ -367,18 +714,22 @@ pub enum Node {
 impl Node {
     pub fn const_from_type(val: i32, t: Type) -> Node {
         match t {
-            Type::Int => Node::Const { val: Const::U32( unsafe { std::mem::transmute(val) } ) },
+            Type::Int => Node::Const { val: Const::U32(val as u32) },
             Type::Float => Node::Const { val: Const::F32(val as f32) } 
         }
     }
 
+    /// `!self`, removing a Not instead of stacking another one
+    pub fn negate(self) -> Node {
+        match self {
+            Node::UnaryOp { op: UnaryOp::Not, left } => *left,
+            node => Node::UnaryOp { op: UnaryOp::Not, left: Box::new(node) },
+        }
+    }
+
     pub fn as_u32(&self) -> Option<u64> {
-        if let Node::Const { val } = self {
-            if let Const::U32(val) = val {
-                Some(*val as u64)
-            } else {
-                None
-            }
+        if let Node::Const { val: Const::U32(val) } = self {
+            Some(*val as u64)
         } else {
             None
         }
@@ -435,7 +786,7 @@ pub enum Fix {
     Post
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Type {
     Int,
     Float
@@ -0,0 +1,418 @@
+//! Pretty-printer rendering the AST of scripts as C-like pseudocode
+use msc::MscsbFile;
+use super::ast::{AsAst, AssignOp, BinOp, Const, Node, ScriptAst, Type, UnaryOp};
+use std::collections::HashMap;
+
+const INDENT: &str = "    ";
+
+/// Decompile every script of the file
+pub fn decompile(file: &MscsbFile) -> String {
+    let mut out = String::new();
+    for script_index in 0..file.scripts.len() {
+        if script_index != 0 {
+            out.push('\n');
+        }
+        out.push_str(&decompile_script(file, script_index));
+    }
+    out
+}
+
+/// Decompile a single script into a function named `script_N`
+pub fn decompile_script(file: &MscsbFile, script_index: usize) -> String {
+    let ast = file.scripts[script_index].as_ast();
+    let mut decompiler = Decompiler {
+        file,
+        out: String::new(),
+        indent: 0,
+        var_types: HashMap::new(),
+    };
+    decompiler.infer_var_types(&ast.nodes);
+    decompiler.script(script_index, &ast);
+    decompiler.out
+}
+
+/// Type of the value a script returns, None if it never returns one. Floats are only known
+/// from the commands computing them, anything else is taken as an int
+pub fn return_type(file: &MscsbFile, script_index: usize) -> Option<Type> {
+    let ast = file.scripts[script_index].as_ast();
+    let mut decompiler = Decompiler {
+        file,
+        out: String::new(),
+        indent: 0,
+        var_types: HashMap::new(),
+    };
+    decompiler.infer_var_types(&ast.nodes);
+    decompiler.return_type(&ast.nodes)
+}
+
+fn type_name(t: Type) -> &'static str {
+    match t {
+        Type::Int => "int",
+        Type::Float => "float",
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::new();
+    for c in s.chars() {
+        match c {
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn var_name(is_global: bool, var_num: u16) -> String {
+    if is_global {
+        format!("global_{}", var_num)
+    } else {
+        format!("local_{}", var_num)
+    }
+}
+
+/// C operator and precedence (higher binds tighter)
+fn binop_info(op: &BinOp) -> (&'static str, u8) {
+    match op {
+        BinOp::Mult(_) => ("*", 10),
+        BinOp::Div(_) => ("/", 10),
+        BinOp::Mod => ("%", 10),
+        BinOp::Add(_) => ("+", 9),
+        BinOp::Sub(_) => ("-", 9),
+        BinOp::ShiftL => ("<<", 8),
+        BinOp::ShiftR => (">>", 8),
+        BinOp::LessThan(_) => ("<", 7),
+        BinOp::LessThanOrEqual(_) => ("<=", 7),
+        BinOp::GreaterThan(_) => (">", 7),
+        BinOp::GreaterThanOrEqual(_) => (">=", 7),
+        BinOp::Equal(_) => ("==", 6),
+        BinOp::NotEqual(_) => ("!=", 6),
+        BinOp::BitAnd => ("&", 5),
+        BinOp::BitXor => ("^", 4),
+        BinOp::BitOr => ("|", 3),
+        BinOp::And => ("&&", 2),
+        BinOp::Or => ("||", 1),
+    }
+}
+
+const UNARY_PRECEDENCE: u8 = 11;
+const ATOM_PRECEDENCE: u8 = 12;
+
+fn assignop_str(op: &AssignOp) -> &'static str {
+    match op {
+        AssignOp::Set(_) => "=",
+        AssignOp::Add(_) => "+=",
+        AssignOp::Sub(_) => "-=",
+        AssignOp::Mult(_) => "*=",
+        AssignOp::Div(_) => "/=",
+        AssignOp::Mod => "%=",
+        AssignOp::And => "&=",
+        AssignOp::Or => "|=",
+        AssignOp::Xor => "^=",
+    }
+}
+
+fn assignop_type(op: &AssignOp) -> Type {
+    match op {
+        AssignOp::Set(t) | AssignOp::Add(t) | AssignOp::Sub(t) | AssignOp::Mult(t) |
+        AssignOp::Div(t) => *t,
+        AssignOp::Mod | AssignOp::And | AssignOp::Or | AssignOp::Xor => Type::Int,
+    }
+}
+
+fn is_one(node: &Node) -> bool {
+    match node {
+        Node::Const { val: Const::U32(1) } => true,
+        Node::Const { val: Const::F32(val) } => *val == 1.0,
+        _ => false,
+    }
+}
+
+struct Decompiler<'a> {
+    file: &'a MscsbFile,
+    out: String,
+    indent: usize,
+    /// Type of every variable assigned a float somewhere, (is_global, var_num)
+    var_types: HashMap<(bool, u16), Type>,
+}
+
+impl<'a> Decompiler<'a> {
+    fn infer_var_types(&mut self, nodes: &[Node]) {
+        for node in nodes {
+            match node {
+                Node::Assign { op, is_global, var_num, right, .. } => {
+                    if let Type::Float = assignop_type(op) {
+                        self.var_types.insert((*is_global, *var_num), Type::Float);
+                    }
+                    self.infer_var_types(std::slice::from_ref(&**right));
+                }
+                Node::If { cond, if_block, else_block } => {
+                    self.infer_var_types(std::slice::from_ref(&**cond));
+                    self.infer_var_types(if_block);
+                    self.infer_var_types(else_block);
+                }
+                Node::While { cond, body } | Node::DoWhile { body, cond } => {
+                    self.infer_var_types(std::slice::from_ref(&**cond));
+                    self.infer_var_types(body);
+                }
+                Node::Return { val: Some(val) } => {
+                    self.infer_var_types(std::slice::from_ref(&**val));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn var_type(&self, is_global: bool, var_num: u16) -> Type {
+        self.var_types.get(&(is_global, var_num)).cloned().unwrap_or(Type::Int)
+    }
+
+    fn expr_type(&self, node: &Node) -> Type {
+        match node {
+            Node::Const { val: Const::F32(_) } => Type::Float,
+            Node::BinOp {
+                op: BinOp::Add(t) | BinOp::Sub(t) | BinOp::Mult(t) | BinOp::Div(t), ..
+            } => *t,
+            Node::UnaryOp { op, .. } => match op {
+                UnaryOp::ToFloat => Type::Float,
+                UnaryOp::Negate(t) => *t,
+                UnaryOp::Not | UnaryOp::BitNot | UnaryOp::ToInt => Type::Int,
+            },
+            Node::Var { is_global, var_num } => self.var_type(*is_global, *var_num),
+            Node::Assign { op, .. } => assignop_type(op),
+            _ => Type::Int,
+        }
+    }
+
+    fn return_type(&self, nodes: &[Node]) -> Option<Type> {
+        for node in nodes {
+            let found = match node {
+                Node::Return { val: Some(val) } => Some(self.expr_type(val)),
+                Node::If { if_block, else_block, .. } => {
+                    self.return_type(if_block).or_else(|| self.return_type(else_block))
+                }
+                Node::While { body, .. } | Node::DoWhile { body, .. } => self.return_type(body),
+                _ => None,
+            };
+            if found.is_some() {
+                return found;
+            }
+        }
+        None
+    }
+
+    fn line(&mut self, line: &str) {
+        for _ in 0..if line.is_empty() { 0 } else { self.indent } {
+            self.out.push_str(INDENT);
+        }
+        self.out.push_str(line);
+        self.out.push('\n');
+    }
+
+    fn script(&mut self, script_index: usize, ast: &ScriptAst) {
+        let is_entrypoint = self.file.get_script_from_loc(self.file.entrypoint) == Some(script_index);
+        if is_entrypoint {
+            self.line("// entrypoint");
+        }
+        let return_type = self.return_type(&ast.nodes).map_or("void", type_name);
+        let args = (0..ast.arg_count)
+            .map(|i| format!("{} {}", type_name(self.var_type(false, i)), var_name(false, i)))
+            .collect::<Vec<_>>()
+            .join(", ");
+        self.line(&format!("{} script_{}({}) {{", return_type, script_index, args));
+        self.indent += 1;
+        for i in ast.arg_count..ast.var_count {
+            let decl = format!("{} {};", type_name(self.var_type(false, i)), var_name(false, i));
+            self.line(&decl);
+        }
+        if ast.var_count > ast.arg_count && !ast.nodes.is_empty() {
+            self.line("");
+        }
+        self.block(&ast.nodes);
+        self.indent -= 1;
+        self.line("}");
+    }
+
+    fn block(&mut self, nodes: &[Node]) {
+        for node in nodes {
+            self.statement(node);
+        }
+    }
+
+    fn statement(&mut self, node: &Node) {
+        match node {
+            Node::If { cond, if_block, else_block } => {
+                let line = format!("if ({}) {{", self.expr(cond));
+                self.line(&line);
+                self.if_rest(if_block, else_block);
+            }
+            Node::While { cond, body } => {
+                let line = format!("while ({}) {{", self.expr(cond));
+                self.line(&line);
+                self.indent += 1;
+                self.block(body);
+                self.indent -= 1;
+                self.line("}");
+            }
+            Node::DoWhile { body, cond } => {
+                self.line("do {");
+                self.indent += 1;
+                self.block(body);
+                self.indent -= 1;
+                let line = format!("}} while ({});", self.expr(cond));
+                self.line(&line);
+            }
+            Node::Break => self.line("break;"),
+            Node::Continue => self.line("continue;"),
+            Node::Goto { loc } => self.line(&format!("goto label_{:X};", loc)),
+            Node::Label { loc } => {
+                // Labels are outdented like in most C code
+                let indent = self.indent;
+                self.indent = indent.saturating_sub(1);
+                self.line(&format!("label_{:X}:", loc));
+                self.indent = indent;
+            }
+            Node::Return { val: Some(val) } => {
+                let line = format!("return {};", self.expr(val));
+                self.line(&line);
+            }
+            Node::Return { val: None } => self.line("return;"),
+            Node::Exit => self.line("exit();"),
+            node => {
+                let line = format!("{};", self.assign_or_expr(node));
+                self.line(&line);
+            }
+        }
+    }
+
+    /// The blocks of an if statement whose header was already written, chaining `else if`
+    fn if_rest(&mut self, if_block: &[Node], else_block: &[Node]) {
+        self.indent += 1;
+        self.block(if_block);
+        self.indent -= 1;
+        match else_block {
+            [] => self.line("}"),
+            [Node::If { cond, if_block, else_block }] => {
+                let line = format!("}} else if ({}) {{", self.expr(cond));
+                self.line(&line);
+                self.if_rest(if_block, else_block);
+            }
+            _ => {
+                self.line("} else {");
+                self.indent += 1;
+                self.block(else_block);
+                self.indent -= 1;
+                self.line("}");
+            }
+        }
+    }
+
+    /// Assignments without the parentheses they need inside expressions
+    fn assign_or_expr(&self, node: &Node) -> String {
+        match node {
+            Node::Assign { op, is_global, var_num, right, .. } => {
+                let name = var_name(*is_global, *var_num);
+                match op {
+                    AssignOp::Add(_) if is_one(right) => format!("{}++", name),
+                    AssignOp::Sub(_) if is_one(right) => format!("{}--", name),
+                    _ => format!("{} {} {}", name, assignop_str(op), self.expr(right)),
+                }
+            }
+            node => self.expr(node),
+        }
+    }
+
+    fn expr(&self, node: &Node) -> String {
+        self.expr_prec(node, 0)
+    }
+
+    /// Render `node`, parenthesized if it binds looser than `min_prec`
+    fn expr_prec(&self, node: &Node, min_prec: u8) -> String {
+        let (s, prec) = match node {
+            Node::Const { val } => (self.constant(val), ATOM_PRECEDENCE),
+            Node::Var { is_global, var_num } => (var_name(*is_global, *var_num), ATOM_PRECEDENCE),
+            Node::BinOp { op, left, right, .. } => {
+                let (op_str, prec) = binop_info(op);
+                // Left associative, so a right operand of the same precedence needs parentheses
+                let s = format!("{} {} {}", self.expr_prec(left, prec), op_str,
+                                self.expr_prec(right, prec + 1));
+                (s, prec)
+            }
+            Node::UnaryOp { op, left } => {
+                let prefix = match op {
+                    UnaryOp::Not => "!",
+                    UnaryOp::BitNot => "~",
+                    UnaryOp::Negate(_) => "-",
+                    UnaryOp::ToFloat => "(float)",
+                    UnaryOp::ToInt => "(int)",
+                };
+                (format!("{}{}", prefix, self.expr_prec(left, UNARY_PRECEDENCE)), UNARY_PRECEDENCE)
+            }
+            Node::Printf { str_num, args } => {
+                let fmt = match str_num.as_u32().and_then(|i| self.file.strings.get(i as usize)) {
+                    Some(string) => format!("\"{}\"", escape(string)),
+                    None => format!("strings[{}]", self.expr(str_num)),
+                };
+                let mut call_args = vec![fmt];
+                call_args.extend(args.iter().map(|arg| self.expr(arg)));
+                (format!("printf({})", call_args.join(", ")), ATOM_PRECEDENCE)
+            }
+            Node::SysCall { sys_num, args } => {
+                (format!("sys_{}({})", sys_num, self.args(args)), ATOM_PRECEDENCE)
+            }
+            Node::FuncCall { func, args } => {
+                (format!("{}({})", self.func_name(func), self.args(args)), ATOM_PRECEDENCE)
+            }
+            // Value computed by both branches of an if
+            Node::If { cond, if_block, else_block } if if_block.len() == 1 && else_block.len() == 1 => {
+                let s = format!("{} ? {} : {}", self.expr_prec(cond, 1),
+                                self.expr_prec(&if_block[0], 1), self.expr_prec(&else_block[0], 1));
+                (s, 0)
+            }
+            Node::Assign { .. } => (self.assign_or_expr(node), 0),
+            node => (format!("/* {:?} */", node), ATOM_PRECEDENCE),
+        };
+        if prec < min_prec {
+            format!("({})", s)
+        } else {
+            s
+        }
+    }
+
+    fn args(&self, args: &[Node]) -> String {
+        args.iter().map(|arg| self.expr(arg)).collect::<Vec<_>>().join(", ")
+    }
+
+    fn constant(&self, val: &Const) -> String {
+        match val {
+            Const::U32(val) => {
+                let signed = *val as i32;
+                if signed > -0x1000 && signed < 0x10000 {
+                    signed.to_string()
+                } else {
+                    format!("0x{:X}", val)
+                }
+            }
+            Const::F32(val) => format!("{:?}f", val),
+            Const::Str(s) => format!("\"{}\"", escape(s)),
+        }
+    }
+
+    /// `script_N` for calls to the start of a script, a call through a pointer otherwise
+    fn func_name(&self, func: &Node) -> String {
+        if let Some(offset) = func.as_u32() {
+            let offset = offset as u32;
+            if let Some(script_index) = self.file.get_script_from_loc(offset) {
+                if self.file.scripts[script_index].bounds.0 == offset {
+                    return format!("script_{}", script_index);
+                }
+            }
+            return format!("(*0x{:X})", offset);
+        }
+        format!("(*{})", self.expr_prec(func, UNARY_PRECEDENCE))
+    }
+}
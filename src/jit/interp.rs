@@ -0,0 +1,590 @@
+//! Reference interpreter for MSC bytecode, used as a semantic oracle for the x86 JIT
+use msc::{MscsbFile, Cmd, Script};
+use std::collections::{HashSet, HashMap};
+use std::rc::Rc;
+use super::x86::{CompileError, ExitStatus, FaultKind, RunError, Signature, Value, validate_script};
+use super::x86::globals::global_count;
+use super::x86::runtime::error_value;
+use super::x86::printf::{Printer, StringTable};
+use super::x86::output::OutputSink;
+use super::x86::syscalls::{SyscallRegistry, SyscallArgs};
+
+pub struct Interpreter<'a> {
+    file: &'a MscsbFile,
+    printer: Printer,
+    /// Per script map from command position (relative to the script) to command index
+    command_indices: Vec<HashMap<u32, usize>>,
+    pub entrypoint_index: usize,
+    pub global_vars: Vec<u32>,
+    signatures: Vec<Signature>,
+    syscalls: Rc<SyscallRegistry>,
+    /// Fuel left for the run, used like the JIT compiled with `CompileOptions::fuel` does
+    fuel: u64,
+    /// Deepest the script calls of a run can nest, like `CompileOptions::max_call_depth`
+    pub max_call_depth: Option<u32>,
+    /// Calls the run can still nest
+    depth_left: u64,
+}
+
+/// Locals, operand stack, pending return value locations and Try handlers of a single script
+/// call
+struct Frame {
+    script_index: usize,
+    /// Command being run, for the errors of the frame
+    position: u32,
+    locals: Vec<u32>,
+    stack: Vec<u32>,
+    ret_val_locations: HashSet<u32>,
+    /// (Try target, stack depth at the Try), innermost last
+    handlers: Vec<(u32, usize)>,
+}
+
+impl Frame {
+    fn underflow(&self) -> Unwind {
+        Unwind::Error(RunError::StackUnderflow {
+            script_index: self.script_index, position: self.position
+        })
+    }
+
+    fn pop(&mut self) -> Result<u32, Unwind> {
+        self.stack.pop().ok_or_else(|| self.underflow())
+    }
+
+    fn pop_f(&mut self) -> Result<f32, Unwind> {
+        self.pop().map(f32::from_bits)
+    }
+
+    fn push_f(&mut self, val: f32) {
+        self.stack.push(val.to_bits())
+    }
+
+    /// Pop the top `count` stack entries, the deepest first
+    fn pop_n(&mut self, count: usize) -> Result<Vec<u32>, Unwind> {
+        let start = self.stack.len().checked_sub(count).ok_or_else(|| self.underflow())?;
+        Ok(self.stack.split_off(start))
+    }
+
+    /// Stack slot `stack_pos` entries below the top
+    fn stack_slot(&mut self, stack_pos: usize) -> Result<&mut u32, Unwind> {
+        let index = self.stack.len().checked_sub(1 + stack_pos).ok_or_else(|| self.underflow())?;
+        Ok(&mut self.stack[index])
+    }
+}
+
+/// Why a script call ended without returning
+enum Unwind {
+    /// Error no Try of the script caught, running out of fuel or stack, or a fault
+    Error(RunError),
+    Exit,
+}
+
+fn divide_fault(script_index: usize, position: u32) -> Unwind {
+    Unwind::Error(RunError::Fault {
+        script_index, position: Some(position), kind: FaultKind::DivideError
+    })
+}
+
+/// x86 `cvttss2si`/`fistp` semantics, out of range values become 0x80000000
+fn float_to_int(val: f32) -> u32 {
+    if (-2147483648.0..2147483648.0).contains(&val) {
+        val as i32 as u32
+    } else {
+        0x8000_0000
+    }
+}
+
+fn int_binop(cmd: Cmd, a: u32, b: u32) -> u32 {
+    let (sa, sb) = (a as i32, b as i32);
+    match cmd {
+        Cmd::AddI => a.wrapping_add(b),
+        Cmd::SubI => a.wrapping_sub(b),
+        Cmd::MultI => sa.wrapping_mul(sb) as u32,
+        Cmd::DivI => {
+            if sb == 0 {
+                panic!("Error: MSC integer division by zero");
+            }
+            sa.wrapping_div(sb) as u32
+        }
+        Cmd::ModI => {
+            if sb == 0 {
+                panic!("Error: MSC integer division by zero");
+            }
+            sa.wrapping_rem(sb) as u32
+        }
+        Cmd::AndI => a & b,
+        Cmd::OrI => a | b,
+        Cmd::XorI => a ^ b,
+        Cmd::ShiftL => a.wrapping_shl(b),
+        Cmd::ShiftR => a.wrapping_shr(b),
+        Cmd::Equals => (sa == sb) as u32,
+        Cmd::NotEquals => (sa != sb) as u32,
+        Cmd::LessThan => (sa < sb) as u32,
+        Cmd::LessOrEqual => (sa <= sb) as u32,
+        Cmd::Greater => (sa > sb) as u32,
+        Cmd::GreaterOrEqual => (sa >= sb) as u32,
+        _ => unreachable!()
+    }
+}
+
+fn float_binop(cmd: Cmd, a: f32, b: f32) -> u32 {
+    match cmd {
+        Cmd::AddF => (a + b).to_bits(),
+        Cmd::SubF => (a - b).to_bits(),
+        Cmd::MultF => (a * b).to_bits(),
+        Cmd::DivF => (a / b).to_bits(),
+        Cmd::EqualsF => (a == b) as u32,
+        Cmd::NotEqualsF => (a != b) as u32,
+        Cmd::LessThanF => (a < b) as u32,
+        Cmd::LessOrEqualF => (a <= b) as u32,
+        Cmd::GreaterF => (a > b) as u32,
+        Cmd::GreaterOrEqualF => (a >= b) as u32,
+        _ => unreachable!()
+    }
+}
+
+/// Whether the JIT faults on `a op b`, IDIV fails on a division by zero and on i32::MIN / -1
+fn divide_error(cmd: Cmd, a: u32, b: u32) -> bool {
+    match cmd {
+        Cmd::DivI | Cmd::ModI | Cmd::DivVarBy { .. } | Cmd::ModVarBy { .. } => {
+            b == 0 || (a as i32 == i32::MIN && b as i32 == -1)
+        }
+        _ => false,
+    }
+}
+
+/// `var op= val` for the integer var-op commands
+fn int_var_op(cmd: Cmd, var: u32, val: u32) -> u32 {
+    match cmd {
+        Cmd::IncI { .. } => var.wrapping_add(1),
+        Cmd::DecI { .. } => var.wrapping_sub(1),
+        Cmd::SetVar { .. } => val,
+        Cmd::AddVarBy { .. } => int_binop(Cmd::AddI, var, val),
+        Cmd::SubVarBy { .. } => int_binop(Cmd::SubI, var, val),
+        Cmd::MultVarBy { .. } => int_binop(Cmd::MultI, var, val),
+        Cmd::DivVarBy { .. } => int_binop(Cmd::DivI, var, val),
+        Cmd::ModVarBy { .. } => int_binop(Cmd::ModI, var, val),
+        Cmd::AndVarBy { .. } => int_binop(Cmd::AndI, var, val),
+        Cmd::OrVarBy { .. } => int_binop(Cmd::OrI, var, val),
+        Cmd::XorVarBy { .. } => int_binop(Cmd::XorI, var, val),
+        _ => unreachable!()
+    }
+}
+
+/// `var op= val` for the float var-op commands
+fn float_var_op(cmd: Cmd, var: f32, val: f32) -> f32 {
+    match cmd {
+        Cmd::IncF { .. } => var + 1.0,
+        Cmd::DecF { .. } => var - 1.0,
+        Cmd::VarSetF { .. } => val,
+        Cmd::AddVarByF { .. } => var + val,
+        Cmd::SubVarByF { .. } => var - val,
+        Cmd::MultVarByF { .. } => var * val,
+        Cmd::DivVarByF { .. } => var / val,
+        _ => unreachable!()
+    }
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new(file: &'a MscsbFile) -> Result<Interpreter<'a>, CompileError> {
+        Interpreter::with_syscalls(file, Rc::new(SyscallRegistry::default()))
+    }
+
+    pub fn with_syscalls(file: &'a MscsbFile, syscalls: Rc<SyscallRegistry>)
+        -> Result<Interpreter<'a>, CompileError>
+    {
+        let entrypoint_index = file.get_script_from_loc(file.entrypoint)
+            .ok_or(CompileError::InvalidEntrypoint { entrypoint: file.entrypoint })?;
+        for script_index in 0..file.scripts.len() {
+            validate_script(file, script_index, &syscalls)?;
+        }
+        let printer = Printer::new(StringTable::new(file));
+        let command_indices = file.scripts.iter()
+            .map(|script| {
+                script.iter()
+                    .enumerate()
+                    .map(|(i, cmd)| (cmd.position, i))
+                    .collect()
+            })
+            .collect();
+        Ok(Interpreter {
+            file,
+            printer,
+            command_indices,
+            entrypoint_index,
+            global_vars: vec![0; global_count(file)],
+            signatures: (0..file.scripts.len())
+                .map(|script_index| Signature::of(file, script_index))
+                .collect(),
+            syscalls,
+            fuel: u64::MAX,
+            max_call_depth: None,
+            depth_left: u64::MAX,
+        })
+    }
+
+    /// Same as `CompiledProgram::run`
+    pub fn run(&mut self) -> Result<ExitStatus, RunError> {
+        let entrypoint_index = self.entrypoint_index;
+        self.run_script_status(entrypoint_index, &[])
+    }
+
+    /// Same as `CompiledProgram::run_with_args`
+    pub fn run_with_args(&mut self, script_index: usize, args: &[u32]) -> Result<u64, RunError> {
+        let script_count = self.file.scripts.len();
+        if script_count <= script_index {
+            return Err(RunError::InvalidScript { script_index, script_count });
+        }
+        self.start_run(u64::MAX);
+        let ret = self.call(script_index, args);
+        self.printer.flush();
+        match ret {
+            Ok(ret) => Ok(u64::from(ret)),
+            Err(Unwind::Error(error)) => Err(error),
+            Err(Unwind::Exit) => Ok(0),
+        }
+    }
+
+    /// Same as `CompiledProgram::run_script`
+    pub fn run_script(&mut self, script_index: usize, args: &[Value]) -> Result<Value, RunError> {
+        match self.run_script_status(script_index, args)? {
+            ExitStatus::Returned(ret) => Ok(ret),
+            ExitStatus::Exited => Ok(Value::Int(0)),
+        }
+    }
+
+    /// Same as `CompiledProgram::run_script_status`
+    pub fn run_script_status(&mut self, script_index: usize, args: &[Value])
+        -> Result<ExitStatus, RunError>
+    {
+        self.run_script_with_fuel(script_index, args, u64::MAX)
+    }
+
+    /// Same as `CompiledProgram::run_script_with_fuel`
+    pub fn run_script_with_fuel(&mut self, script_index: usize, args: &[Value], fuel: u64)
+        -> Result<ExitStatus, RunError>
+    {
+        let signature = *self.signatures.get(script_index).ok_or(RunError::InvalidScript {
+            script_index, script_count: self.signatures.len()
+        })?;
+        signature.check_args(script_index, args)?;
+        let args = args.iter().map(|arg| arg.bits()).collect::<Vec<u32>>();
+        self.start_run(fuel);
+        let ret = self.call(script_index, &args);
+        self.printer.flush();
+        match ret {
+            Ok(ret) => Ok(ExitStatus::Returned(signature.return_value(ret))),
+            Err(Unwind::Error(error)) => Err(error),
+            Err(Unwind::Exit) => Ok(ExitStatus::Exited),
+        }
+    }
+
+    /// Send what scripts print to `sink` instead of stdout
+    pub fn set_output<S: OutputSink + 'static>(&mut self, sink: S) {
+        self.printer.set_sink(Box::new(sink));
+    }
+
+    fn start_run(&mut self, fuel: u64) {
+        self.fuel = fuel;
+        self.depth_left = self.max_call_depth.map_or(u64::MAX, u64::from);
+    }
+
+    /// Index of the command at `loc`, which the command of `frame` jumps to
+    fn jump_target(&self, frame: &Frame, script: &Script, loc: u32) -> Result<usize, Unwind> {
+        let script_index = frame.script_index;
+        loc.checked_sub(script.bounds.0)
+            .and_then(|target| self.command_indices[script_index].get(&target))
+            .cloned()
+            .ok_or(Unwind::Error(RunError::InvalidJump {
+                script_index, position: frame.position, loc
+            }))
+    }
+
+    fn get_var(&self, frame: &Frame, is_global: bool, var_num: u16) -> u32 {
+        if !is_global {
+            frame.locals[var_num as usize]
+        } else {
+            self.global_vars[var_num as usize]
+        }
+    }
+
+    fn set_var(&mut self, frame: &mut Frame, is_global: bool, var_num: u16, val: u32) {
+        if !is_global {
+            frame.locals[var_num as usize] = val;
+        } else {
+            self.global_vars[var_num as usize] = val;
+        }
+    }
+
+    /// Run a script, errors no Try of it caught and Exit are passed on to the caller
+    fn call(&mut self, script_index: usize, args: &[u32]) -> Result<u32, Unwind> {
+        let file = self.file;
+        let script = &file.scripts[script_index];
+        let (arg_count, var_count) = match script.iter().nth(0).map(|cmd| cmd.cmd) {
+            Some(Cmd::Begin { arg_count, var_count }) => (arg_count, var_count),
+            // No Begin, nothing to run
+            _ => return Ok(0),
+        };
+        if self.depth_left == 0 {
+            return Err(Unwind::Error(RunError::StackOverflow { script_index }));
+        }
+        self.depth_left -= 1;
+        let ret = self.run_frame(script_index, arg_count, var_count, args);
+        self.depth_left += 1;
+        ret
+    }
+
+    fn run_frame(&mut self, script_index: usize, arg_count: u16, var_count: u16, args: &[u32])
+        -> Result<u32, Unwind>
+    {
+        let file = self.file;
+        let script = &file.scripts[script_index];
+        self.use_fuel(script_index, script.commands[0].position)?;
+        let mut frame = Frame {
+            script_index,
+            position: script.commands[0].position,
+            locals: vec![0; std::cmp::max(var_count, arg_count) as usize],
+            stack: vec![],
+            ret_val_locations: HashSet::new(),
+            handlers: vec![],
+        };
+        for (local, arg) in frame.locals.iter_mut().zip(args.iter().take(arg_count as usize)) {
+            *local = *arg;
+        }
+
+        // Return value of the last CallFunc or value of the last caught error, pushed when
+        // reaching a Try location
+        let mut last_ret = 0u32;
+        let mut pc = 1;
+        while let Some(cmd) = script.commands.get(pc) {
+            pc += 1;
+            frame.position = cmd.position;
+            let loc = cmd.position + script.bounds.0;
+            if frame.handlers.last().map(|&(target, _)| target) == Some(loc) {
+                frame.handlers.pop();
+            }
+            if frame.ret_val_locations.contains(&loc) {
+                frame.stack.push(last_ret);
+            }
+            match cmd.cmd {
+                Cmd::Unk1 => {
+                    panic!("Unsupported command {:?}", cmd.cmd);
+                }
+                Cmd::ErrorC | Cmd::Error37 | Cmd::Error4C => {
+                    let error = Unwind::Error(RunError::Uncaught {
+                        script_index,
+                        position: cmd.position,
+                        value: error_value(cmd.cmd).unwrap(),
+                    });
+                    pc = self.unwind(script_index, &mut frame, error, &mut last_ret)?;
+                }
+                Cmd::Begin { .. } => {
+                    panic!("Begin not allowed after first command of script");
+                }
+                Cmd::Nop => {}
+                Cmd::Jump { loc } | Cmd::Jump5 { loc } | Cmd::Else { loc } => {
+                    if loc <= cmd.position + script.bounds.0 {
+                        self.use_fuel(script_index, cmd.position)?;
+                    }
+                    pc = self.jump_target(&frame, script, loc)?;
+                }
+                Cmd::If { loc } | Cmd::IfNot { loc } => {
+                    let cond = frame.pop()? != 0;
+                    let is_if = matches!(cmd.cmd, Cmd::If { .. });
+                    if cond != is_if {
+                        if loc <= cmd.position + script.bounds.0 {
+                            self.use_fuel(script_index, cmd.position)?;
+                        }
+                        pc = self.jump_target(&frame, script, loc)?;
+                    }
+                }
+                Cmd::PushInt { val } => {
+                    if cmd.push_bit {
+                        frame.stack.push(val);
+                    }
+                }
+                Cmd::PushShort { val } => {
+                    if cmd.push_bit {
+                        frame.stack.push(u32::from(val));
+                    }
+                }
+                Cmd::PushVar { var_type, var_num } => {
+                    let val = self.get_var(&frame, var_type != 0, var_num);
+                    frame.stack.push(val);
+                }
+                Cmd::Push => {
+                    if cmd.push_bit {
+                        let val = frame.pop()?;
+                        frame.stack.push(val);
+                        frame.stack.push(val);
+                    }
+                }
+                Cmd::Pop => {
+                    if !cmd.push_bit {
+                        frame.pop()?;
+                    }
+                }
+                Cmd::AddI | Cmd::SubI | Cmd::MultI | Cmd::DivI | Cmd::ModI | Cmd::AndI |
+                Cmd::OrI | Cmd::XorI | Cmd::ShiftL | Cmd::ShiftR | Cmd::Equals |
+                Cmd::NotEquals | Cmd::LessThan | Cmd::LessOrEqual | Cmd::Greater |
+                Cmd::GreaterOrEqual => {
+                    let b = frame.pop()?;
+                    let a = frame.pop()?;
+                    if cmd.push_bit {
+                        if divide_error(cmd.cmd, a, b) {
+                            return Err(divide_fault(script_index, cmd.position));
+                        }
+                        frame.stack.push(int_binop(cmd.cmd, a, b));
+                    }
+                }
+                Cmd::AddF | Cmd::SubF | Cmd::MultF | Cmd::DivF | Cmd::EqualsF |
+                Cmd::NotEqualsF | Cmd::LessThanF | Cmd::LessOrEqualF | Cmd::GreaterF |
+                Cmd::GreaterOrEqualF => {
+                    let b = frame.pop_f()?;
+                    let a = frame.pop_f()?;
+                    if cmd.push_bit {
+                        frame.stack.push(float_binop(cmd.cmd, a, b));
+                    }
+                }
+                Cmd::NegI | Cmd::NotI => {
+                    let val = frame.pop()?;
+                    if cmd.push_bit {
+                        frame.stack.push(match cmd.cmd {
+                            Cmd::NegI => (val as i32).wrapping_neg() as u32,
+                            _ => !val
+                        });
+                    }
+                }
+                Cmd::NegF => {
+                    let val = frame.pop_f()?;
+                    if cmd.push_bit {
+                        frame.push_f(-val);
+                    }
+                }
+                Cmd::Not => {
+                    let val = frame.pop()?;
+                    if cmd.push_bit {
+                        frame.stack.push((val == 0) as u32);
+                    }
+                }
+                Cmd::IntToFloat { stack_pos } => {
+                    let slot = frame.stack_slot(stack_pos as usize)?;
+                    *slot = (*slot as i32 as f32).to_bits();
+                }
+                Cmd::FloatToInt { stack_pos } => {
+                    let slot = frame.stack_slot(stack_pos as usize)?;
+                    *slot = float_to_int(f32::from_bits(*slot));
+                }
+                Cmd::IncI { var_type, var_num } | Cmd::DecI { var_type, var_num } => {
+                    let var = self.get_var(&frame, var_type != 0, var_num);
+                    self.set_var(&mut frame, var_type != 0, var_num, int_var_op(cmd.cmd, var, 0));
+                }
+                Cmd::SetVar { var_type, var_num } | Cmd::AddVarBy { var_type, var_num } |
+                Cmd::SubVarBy { var_type, var_num } | Cmd::MultVarBy { var_type, var_num } |
+                Cmd::DivVarBy { var_type, var_num } | Cmd::ModVarBy { var_type, var_num } |
+                Cmd::AndVarBy { var_type, var_num } | Cmd::OrVarBy { var_type, var_num } |
+                Cmd::XorVarBy { var_type, var_num } => {
+                    let val = frame.pop()?;
+                    let var = self.get_var(&frame, var_type != 0, var_num);
+                    if divide_error(cmd.cmd, var, val) {
+                        return Err(divide_fault(script_index, cmd.position));
+                    }
+                    self.set_var(&mut frame, var_type != 0, var_num, int_var_op(cmd.cmd, var, val));
+                }
+                Cmd::IncF { var_type, var_num } | Cmd::DecF { var_type, var_num } => {
+                    let var = f32::from_bits(self.get_var(&frame, var_type != 0, var_num));
+                    let new_val = float_var_op(cmd.cmd, var, 0.0).to_bits();
+                    self.set_var(&mut frame, var_type != 0, var_num, new_val);
+                }
+                Cmd::VarSetF { var_type, var_num } | Cmd::AddVarByF { var_type, var_num } |
+                Cmd::SubVarByF { var_type, var_num } | Cmd::MultVarByF { var_type, var_num } |
+                Cmd::DivVarByF { var_type, var_num } => {
+                    let val = frame.pop_f()?;
+                    let var = f32::from_bits(self.get_var(&frame, var_type != 0, var_num));
+                    let new_val = float_var_op(cmd.cmd, var, val).to_bits();
+                    self.set_var(&mut frame, var_type != 0, var_num, new_val);
+                }
+                Cmd::PrintF { arg_count } => {
+                    let args = frame.pop_n(arg_count as usize)?;
+                    if !self.printer.print(args[0], &args[1..]) {
+                        let error = RunError::InvalidString { script_index, str_num: args[0] };
+                        return Err(Unwind::Error(error));
+                    }
+                }
+                Cmd::Sys { sys_num, arg_count } => {
+                    // Passed to host functions the way the JIT does, top of the stack first
+                    let args = frame.pop_n(arg_count as usize)?
+                        .iter()
+                        .rev()
+                        .map(|&val| u64::from(val))
+                        .collect::<Vec<u64>>();
+                    let ret = self.syscalls.call(sys_num, &SyscallArgs::with_printer(&args, &self.printer))
+                        .map_err(|error| {
+                            Unwind::Error(RunError::Syscall { script_index, sys_num, error })
+                        })?;
+                    if cmd.push_bit {
+                        frame.stack.push(ret);
+                    }
+                }
+                Cmd::CallFunc { arg_count } | Cmd::CallFunc2 { arg_count } |
+                Cmd::CallFunc3 { arg_count } => {
+                    let func_offset = frame.pop()?;
+                    let call_args = frame.pop_n(arg_count as usize)?;
+                    let ret = match file.get_script_from_loc(func_offset) {
+                        Some(target) if file.scripts[target].bounds.0 == func_offset => {
+                            self.call(target, &call_args)
+                        }
+                        _ => Err(Unwind::Error(RunError::InvalidCall {
+                            script_index, offset: func_offset
+                        })),
+                    };
+                    match ret {
+                        Ok(ret) => last_ret = ret,
+                        Err(error) => pc = self.unwind(script_index, &mut frame, error, &mut last_ret)?,
+                    }
+                }
+                Cmd::Try { loc } => {
+                    if cmd.push_bit {
+                        frame.ret_val_locations.insert(loc);
+                    }
+                    frame.handlers.push((loc, frame.stack.len()));
+                }
+                Cmd::Return6 | Cmd::Return8 => {
+                    return frame.pop();
+                }
+                Cmd::Return7 | Cmd::Return9 | Cmd::End => {
+                    return Ok(0);
+                }
+                Cmd::Exit => {
+                    return Err(Unwind::Exit);
+                }
+            }
+        }
+        Ok(0)
+    }
+
+    /// Use a unit of fuel for the command at `position`, see `runtime::use_fuel`
+    fn use_fuel(&mut self, script_index: usize, position: u32) -> Result<(), Unwind> {
+        if self.fuel == 0 {
+            return Err(Unwind::Error(RunError::OutOfFuel { script_index, position }));
+        }
+        self.fuel -= 1;
+        Ok(())
+    }
+
+    /// Resume at the innermost handler of `frame` with the error value as the return value,
+    /// returns the index of the command to resume at. Without a handler, or for Exit, the
+    /// unwinding is passed on to the caller
+    fn unwind(&self, script_index: usize, frame: &mut Frame, error: Unwind, last_ret: &mut u32)
+        -> Result<usize, Unwind>
+    {
+        let (loc, depth) = match (frame.handlers.last(), &error) {
+            (Some(&handler), &Unwind::Error(RunError::Uncaught { value, .. })) => {
+                *last_ret = value;
+                handler
+            }
+            _ => return Err(error),
+        };
+        frame.stack.truncate(depth);
+        self.jump_target(frame, &self.file.scripts[script_index], loc)
+    }
+}
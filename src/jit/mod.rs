@@ -1,56 +1,143 @@
 #![allow(dead_code)]
-use std::mem;
+use std::ptr;
+use std::io;
 pub mod x86;
-//pub mod ast;
-
-extern {
-    fn memset(s: *mut libc::c_void, c: u32, n: libc::size_t) -> *mut libc::c_void;
-}
+pub mod arena;
+pub mod stack;
+pub mod ast;
+pub mod decompile;
+pub mod interp;
+#[cfg(test)]
+mod tests;
 
+/// Page aligned memory for generated code, writable until locked and executable afterwards.
+/// The pages are freed on drop
 pub struct JitMemory {
+    /// Where the code is written
     pub contents : *mut u8,
+    /// Where the code is run from, the same as `contents` unless dual mapped
+    pub exec: *mut u8,
     pub locked: bool,
     _contents: *mut libc::c_void,
     size: usize,
+    /// memfd backing both views when dual mapped
+    memfd: Option<libc::c_int>,
 }
 
 pub const PAGE_SIZE: usize = 4096;
 
-impl<'a> JitMemory {
-    pub fn new(num_pages: usize) -> JitMemory {
-        let contents : *mut u8;
+const MFD_CLOEXEC: libc::c_uint = 1;
+
+impl JitMemory {
+    pub fn new(num_pages: usize) -> io::Result<JitMemory> {
+        let size = num_pages.max(1) * PAGE_SIZE;
+        let mut _contents: *mut libc::c_void = ptr::null_mut();
         unsafe {
-            let size = num_pages * PAGE_SIZE;
-            let mut _contents : *mut libc::c_void = mem::uninitialized(); // avoid uninitalized warning
-            libc::posix_memalign(&mut _contents, PAGE_SIZE, size);
-            libc::mprotect(_contents, size, libc::PROT_READ | libc::PROT_WRITE);
+            let ret = libc::posix_memalign(&mut _contents, PAGE_SIZE, size);
+            if ret != 0 {
+                return Err(io::Error::from_raw_os_error(ret));
+            }
+            // From here on drop frees the allocation
+            let mut jit_mem = JitMemory {
+                contents: _contents as _, exec: _contents as _, _contents, size, locked: true,
+                memfd: None
+            };
+            jit_mem.unlock()?;
 
-            memset(_contents, 0xc3, size);  // for now, prepopulate with 'RET'
+            libc::memset(_contents, 0xc3, size);  // for now, prepopulate with 'RET'
 
-            contents = _contents as _;
-            JitMemory { contents, _contents, size, locked: false }
+            Ok(jit_mem)
         }
     }
 
-    pub unsafe fn lock(&mut self) -> i32 {
-        self.locked = true;
-        libc::mprotect(self._contents, self.size, libc::PROT_EXEC | libc::PROT_READ)
+    /// Map the same memfd twice, a read/write view at `contents` and a read/execute view at
+    /// `exec`, so the pages are never writable and executable at the same time. Locking and
+    /// unlocking only toggle whether running is allowed
+    pub fn new_dual_mapped(num_pages: usize) -> io::Result<JitMemory> {
+        let size = num_pages.max(1) * PAGE_SIZE;
+        unsafe {
+            let name = b"msc-jit\0";
+            let fd = libc::syscall(libc::SYS_memfd_create, name.as_ptr(), MFD_CLOEXEC) as libc::c_int;
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // From here on drop unmaps the views and closes the memfd
+            let mut jit_mem = JitMemory {
+                contents: ptr::null_mut(), exec: ptr::null_mut(), _contents: ptr::null_mut(),
+                size, locked: false, memfd: Some(fd)
+            };
+            if libc::ftruncate(fd, size as libc::off_t) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let rw = libc::mmap(ptr::null_mut(), size, libc::PROT_READ | libc::PROT_WRITE,
+                                libc::MAP_SHARED, fd, 0);
+            if rw == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            jit_mem._contents = rw;
+            jit_mem.contents = rw as _;
+            let rx = libc::mmap(ptr::null_mut(), size, libc::PROT_READ | libc::PROT_EXEC,
+                                libc::MAP_SHARED, fd, 0);
+            if rx == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            jit_mem.exec = rx as _;
+
+            libc::memset(rw, 0xc3, size);  // for now, prepopulate with 'RET'
+
+            Ok(jit_mem)
+        }
     }
 
-    pub unsafe fn unlock(&mut self) -> i32 {
-        self.locked = false;
-        libc::mprotect(self._contents, self.size, libc::PROT_WRITE | libc::PROT_READ)
+    pub fn is_dual_mapped(&self) -> bool {
+        self.memfd.is_some()
     }
 
-    pub unsafe fn run<T>(&self) -> T {
-        if !self.locked {
-            panic!("Cannot run unlocked JitMemory");
+    pub unsafe fn lock(&mut self) -> io::Result<()> {
+        if !self.is_dual_mapped() &&
+            libc::mprotect(self._contents, self.size, libc::PROT_EXEC | libc::PROT_READ) != 0
+        {
+            return Err(io::Error::last_os_error());
         }
-        let jit_func: (extern "C" fn() -> T) = mem::transmute(self._contents);
-        jit_func()
+        self.locked = true;
+        Ok(())
     }
 
-    pub unsafe fn as_slice(&mut self) -> &'a mut [u8] {
+    pub unsafe fn unlock(&mut self) -> io::Result<()> {
+        if !self.is_dual_mapped() &&
+            libc::mprotect(self._contents, self.size, libc::PROT_WRITE | libc::PROT_READ) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        self.locked = false;
+        Ok(())
+    }
+
+    /// The writable view, borrowed from `self` so it can't outlive the mapping
+    pub unsafe fn as_slice(&mut self) -> &mut [u8] {
         std::slice::from_raw_parts_mut(self.contents, self.size)
     }
 }
+
+impl Drop for JitMemory {
+    fn drop(&mut self) {
+        unsafe {
+            if let Some(fd) = self.memfd {
+                if !self.contents.is_null() {
+                    libc::munmap(self.contents as _, self.size);
+                }
+                if !self.exec.is_null() {
+                    libc::munmap(self.exec as _, self.size);
+                }
+                libc::close(fd);
+                return;
+            }
+            // free writes its bookkeeping into the block, it has to be writable again
+            if self.locked && self.unlock().is_err() {
+                // Leaking is better than crashing in free
+                return;
+            }
+            libc::free(self._contents);
+        }
+    }
+}
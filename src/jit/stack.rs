@@ -0,0 +1,61 @@
+//! Dedicated stack scripts can run on instead of the host's, with a guard page below it so
+//! running off the end faults instead of writing over other memory
+use std::io;
+use std::ptr;
+use super::PAGE_SIZE;
+
+/// Space the prologue of a script keeps free below its frame, for the operand stack and the
+/// host functions called by the script, which run on the same stack
+pub const STACK_HEADROOM: usize = 64 * 1024;
+
+pub struct ScriptStack {
+    /// Start of the mapping, the guard page
+    base: *mut libc::c_void,
+    /// Size of the mapping, guard page included
+    size: usize,
+}
+
+impl ScriptStack {
+    /// Map a stack of at least `size` bytes, rounded up to whole pages
+    pub fn new(size: usize) -> io::Result<ScriptStack> {
+        let size = ((size.max(1) + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)) + PAGE_SIZE;
+        unsafe {
+            let base = libc::mmap(
+                ptr::null_mut(), size, libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE, -1, 0
+            );
+            if base == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            // From here on drop unmaps the stack
+            let stack = ScriptStack { base, size };
+            if libc::mprotect(base, PAGE_SIZE, libc::PROT_NONE) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(stack)
+        }
+    }
+
+    /// Initial RSP, 16 byte aligned
+    pub fn top(&self) -> u64 {
+        self.base as u64 + self.size as u64
+    }
+
+    /// Lowest usable address, right above the guard page
+    pub fn bottom(&self) -> u64 {
+        self.base as u64 + PAGE_SIZE as u64
+    }
+
+    /// Lowest RSP a prologue accepts, `STACK_HEADROOM` above the bottom
+    pub fn limit(&self) -> u64 {
+        self.bottom() + STACK_HEADROOM as u64
+    }
+}
+
+impl Drop for ScriptStack {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.base, self.size);
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use msc::Cmd;
+use crate::jit::ast::{AsAst, Node, ScriptAst, UnaryOp};
+use super::{ScriptBuilder, build_file};
+
+fn ast(script: ScriptBuilder) -> ScriptAst {
+    build_file(vec![script], &[]).scripts[0].as_ast()
+}
+
+fn is_var(node: &Node, num: u16) -> bool {
+    if let Node::Var { is_global: false, var_num } = node { *var_num == num } else { false }
+}
+
+#[test]
+fn straight_line() {
+    let ast = ast(
+        ScriptBuilder::new(0, 1)
+            .push_int(5)
+            .set_local(0)
+            .push_local(0)
+            .ret()
+    );
+    assert_eq!(ast.var_count, 1);
+    assert_eq!(ast.nodes.len(), 2);
+    assert!(matches!(ast.nodes[0], Node::Assign { var_num: 0, .. }));
+    assert!(matches!(&ast.nodes[1], Node::Return { val: Some(val) } if is_var(val, 0)));
+}
+
+#[test]
+fn if_else() {
+    let ast = ast(
+        ScriptBuilder::new(1, 2)
+            .push_local(0)
+            .branch(|loc| Cmd::If { loc }, "else")
+            .push_int(10)
+            .set_local(1)
+            .branch(|loc| Cmd::Else { loc }, "end")
+            .label("else")
+            .push_int(20)
+            .set_local(1)
+            .label("end")
+            .push_local(1)
+            .ret()
+    );
+    assert_eq!(ast.nodes.len(), 2);
+    match &ast.nodes[0] {
+        Node::If { cond, if_block, else_block } => {
+            assert!(is_var(cond, 0));
+            assert!(matches!(if_block[..], [Node::Assign { .. }]));
+            assert!(matches!(else_block[..], [Node::Assign { .. }]));
+        }
+        node => panic!("Expected an if, got {:?}", node),
+    }
+}
+
+#[test]
+fn if_not_and_early_return() {
+    let ast = ast(
+        ScriptBuilder::new(1, 1)
+            .push_local(0)
+            .branch(|loc| Cmd::IfNot { loc }, "end")
+            .push_int(1)
+            .ret()
+            .label("end")
+            .push_int(2)
+            .ret()
+    );
+    assert_eq!(ast.nodes.len(), 2);
+    match &ast.nodes[0] {
+        Node::If { cond, if_block, else_block } => {
+            assert!(matches!(&**cond, Node::UnaryOp { op: UnaryOp::Not, left } if is_var(left, 0)));
+            assert!(matches!(if_block[..], [Node::Return { val: Some(_) }]));
+            assert!(else_block.is_empty());
+        }
+        node => panic!("Expected an if, got {:?}", node),
+    }
+}
+
+#[test]
+fn while_loop_with_break_and_continue() {
+    let ast = ast(
+        ScriptBuilder::new(0, 1)
+            .label("loop")
+            .push_local(0)
+            .push_int(10)
+            .cmd(Cmd::LessThan)
+            .branch(|loc| Cmd::If { loc }, "end")
+            .cmd(Cmd::IncI { var_type: 0, var_num: 0 })
+            .push_local(0)
+            .push_int(5)
+            .cmd(Cmd::Equals)
+            .branch(|loc| Cmd::IfNot { loc }, "end")
+            .push_local(0)
+            .push_int(2)
+            .cmd(Cmd::Equals)
+            .branch(|loc| Cmd::IfNot { loc }, "loop")
+            .branch(|loc| Cmd::Jump { loc }, "loop")
+            .label("end")
+            .push_local(0)
+            .ret()
+    );
+    assert_eq!(ast.nodes.len(), 2);
+    match &ast.nodes[0] {
+        Node::While { cond, body } => {
+            assert!(matches!(&**cond, Node::BinOp { .. }));
+            assert_eq!(body.len(), 3);
+            assert!(matches!(body[0], Node::Assign { .. }));
+            assert!(matches!(&body[1], Node::If { if_block, .. } if matches!(if_block[..], [Node::Break])));
+            assert!(matches!(&body[2], Node::If { if_block, .. } if matches!(if_block[..], [Node::Continue])));
+        }
+        node => panic!("Expected a loop, got {:?}", node),
+    }
+}
+
+#[test]
+fn do_while_loop() {
+    let ast = ast(
+        ScriptBuilder::new(0, 1)
+            .label("loop")
+            .cmd(Cmd::IncI { var_type: 0, var_num: 0 })
+            .push_local(0)
+            .push_int(10)
+            .cmd(Cmd::LessThan)
+            .branch(|loc| Cmd::IfNot { loc }, "loop")
+            .push_local(0)
+            .ret()
+    );
+    assert_eq!(ast.nodes.len(), 2);
+    match &ast.nodes[0] {
+        Node::DoWhile { body, cond } => {
+            assert!(matches!(body[..], [Node::Assign { .. }]));
+            assert!(matches!(&**cond, Node::BinOp { .. }));
+        }
+        node => panic!("Expected a do-while, got {:?}", node),
+    }
+}
+
+#[test]
+fn nested_if_in_loop() {
+    let ast = ast(
+        ScriptBuilder::new(0, 2)
+            .label("loop")
+            .push_local(0)
+            .push_int(10)
+            .cmd(Cmd::LessThan)
+            .branch(|loc| Cmd::If { loc }, "end")
+            .push_local(0)
+            .push_int(2)
+            .cmd(Cmd::ModI)
+            .branch(|loc| Cmd::If { loc }, "odd")
+            .cmd(Cmd::IncI { var_type: 0, var_num: 1 })
+            .branch(|loc| Cmd::Else { loc }, "next")
+            .label("odd")
+            .cmd(Cmd::DecI { var_type: 0, var_num: 1 })
+            .label("next")
+            .cmd(Cmd::IncI { var_type: 0, var_num: 0 })
+            .branch(|loc| Cmd::Jump { loc }, "loop")
+            .label("end")
+            .push_local(1)
+            .ret()
+    );
+    match &ast.nodes[0] {
+        Node::While { body, .. } => {
+            assert!(matches!(&body[0], Node::If { if_block, else_block, .. }
+                             if if_block.len() == 1 && else_block.len() == 1));
+            assert!(matches!(body[1], Node::Assign { .. }));
+        }
+        node => panic!("Expected a loop, got {:?}", node),
+    }
+}
+
+#[test]
+fn unstructured_jump_becomes_goto() {
+    // Jump into the middle of an if block
+    let ast = ast(
+        ScriptBuilder::new(0, 1)
+            .push_local(0)
+            .branch(|loc| Cmd::If { loc }, "end")
+            .branch(|loc| Cmd::Jump { loc }, "inner")
+            .label("inner")
+            .cmd(Cmd::Nop)
+            .label("end")
+            .push_int(0)
+            .branch(|loc| Cmd::If { loc }, "skip")
+            .branch(|loc| Cmd::Jump { loc }, "inner2")
+            .label("skip")
+            .cmd(Cmd::IncI { var_type: 0, var_num: 0 })
+            .label("inner2")
+            .push_local(0)
+            .ret()
+    );
+    assert!(ast.nodes.iter().any(|node| matches!(node, Node::If { .. })));
+}
+
+#[test]
+fn call_with_return_value() {
+    let file = build_file(vec![
+        ScriptBuilder::new(0, 0)
+            .branch(|loc| Cmd::Try { loc }, "ret")
+            .push_int(1)
+            .push_int(2)
+            .push_script(1)
+            .cmd(Cmd::CallFunc { arg_count: 2 })
+            .label("ret")
+            .ret(),
+        ScriptBuilder::new(2, 2).push_local(0).ret(),
+    ], &[]);
+    let ast = file.scripts[0].as_ast();
+    assert_eq!(ast.nodes.len(), 1);
+    assert!(matches!(&ast.nodes[0], Node::Return { val: Some(val) }
+                     if matches!(&**val, Node::FuncCall { args, .. } if args.len() == 2)));
+}
+
+#[test]
+fn cast_below_top() {
+    // 1 + 2.5 with the int converted in place
+    let ast = ast(
+        ScriptBuilder::new(0, 0)
+            .push_int(1)
+            .push_float(2.5)
+            .cmd(Cmd::IntToFloat { stack_pos: 1 })
+            .cmd(Cmd::AddF)
+            .ret()
+    );
+    match &ast.nodes[0] {
+        Node::Return { val: Some(val) } => match &**val {
+            Node::BinOp { left, right, .. } => {
+                assert!(matches!(&**left, Node::UnaryOp { op: UnaryOp::ToFloat, .. }));
+                assert!(matches!(&**right, Node::Const { .. }));
+            }
+            node => panic!("Expected a BinOp, got {:?}", node),
+        }
+        node => panic!("Expected a return, got {:?}", node),
+    }
+}
@@ -0,0 +1,257 @@
+use msc::{MscsbFile, Cmd};
+use crate::jit::x86::{Backend, Compilable, CompileOptions};
+use super::{ScriptBuilder, build_file, check, check_file};
+
+fn backends(file: &MscsbFile) -> Vec<Backend> {
+    let options = CompileOptions { backend: Backend::Ast, ..CompileOptions::default() };
+    file.compile_with(&options).expect("Failed to compile").backends
+}
+
+/// Push 1 to `count` and add them up, right to left so that every value stays live
+fn sum_ints(mut script: ScriptBuilder, count: u32) -> ScriptBuilder {
+    for i in 1..=count {
+        script = script.push_int(i);
+    }
+    for _ in 1..count {
+        script = script.cmd(Cmd::AddI);
+    }
+    script
+}
+
+#[test]
+fn expressions_use_ast() {
+    let file = build_file(vec![
+        ScriptBuilder::new(1, 2)
+            .push_local(0)
+            .push_int(3)
+            .cmd(Cmd::MultI)
+            .set_local(1)
+            .push_local(1)
+            .push_int(2)
+            .cmd(Cmd::ModI)
+            .ret()
+    ], &[]);
+    assert_eq!(backends(&file), vec![Backend::Ast]);
+    assert_eq!(check_file(&file, &[7]).ret, 1);
+}
+
+#[test]
+fn fallback_to_commands() {
+    // Duplicated value
+    let dup = build_file(vec![
+        ScriptBuilder::new(0, 0)
+            .push_int(6)
+            .cmd(Cmd::Push)
+            .cmd(Cmd::MultI)
+            .ret()
+    ], &[]);
+    assert_eq!(backends(&dup), vec![Backend::Commands]);
+    assert_eq!(check_file(&dup, &[]).ret, 36);
+
+    // Value live across a branch
+    let ternary = build_file(vec![
+        ScriptBuilder::new(0, 0)
+            .push_int(1)
+            .push_int(0)
+            .branch(|loc| Cmd::If { loc }, "else")
+            .push_int(10)
+            .branch(|loc| Cmd::Else { loc }, "end")
+            .label("else")
+            .push_int(20)
+            .label("end")
+            .cmd(Cmd::AddI)
+            .ret()
+    ], &[]);
+    assert_eq!(backends(&ternary), vec![Backend::Commands]);
+    assert_eq!(check_file(&ternary, &[]).ret, 21);
+}
+
+#[test]
+fn int_spills() {
+    // More live values than int registers
+    let file = build_file(vec![sum_ints(ScriptBuilder::new(0, 0), 12).ret()], &[]);
+    assert_eq!(backends(&file), vec![Backend::Ast]);
+    assert_eq!(check_file(&file, &[]).ret, 78);
+}
+
+#[test]
+fn float_spills() {
+    // More live values than float registers
+    let mut script = ScriptBuilder::new(0, 0);
+    for i in 1..=10 {
+        script = script.push_float(i as f32 * 0.5);
+    }
+    for _ in 1..10 {
+        script = script.cmd(Cmd::SubF);
+    }
+    let file = build_file(vec![script.ret()], &[]);
+    assert_eq!(backends(&file), vec![Backend::Ast]);
+    assert_eq!(check_file(&file, &[]).ret_float(), -2.5);
+}
+
+#[test]
+fn mixed_comparisons() {
+    let out = check(
+        ScriptBuilder::new(0, 1)
+            .push_float(1.5)
+            .push_int(2)
+            .cmd(Cmd::IntToFloat { stack_pos: 0 })
+            .cmd(Cmd::LessThanF)
+            .push_int(-3i32 as u32)
+            .push_int(4)
+            .cmd(Cmd::Greater)
+            .cmd(Cmd::AddI)
+            .push_float(7.9)
+            .cmd(Cmd::FloatToInt { stack_pos: 0 })
+            .cmd(Cmd::MultI)
+            .ret()
+    );
+    assert_eq!(out.ret, 7);
+}
+
+#[test]
+fn floats_live_across_call() {
+    // The left operand of the subtraction has to survive the call clobbering every XMM
+    // register
+    let callee = ScriptBuilder::new(1, 1)
+        .push_local(0)
+        .push_float(2.0)
+        .cmd(Cmd::MultF)
+        .ret();
+    let caller = ScriptBuilder::new(0, 0)
+        .push_float(10.0)
+        .branch(|loc| Cmd::Try { loc }, "ret")
+        .push_float(1.25)
+        .push_script(1)
+        .cmd(Cmd::CallFunc { arg_count: 1 })
+        .label("ret")
+        .cmd(Cmd::SubF)
+        .ret();
+    let file = build_file(vec![caller, callee], &[]);
+    assert_eq!(backends(&file), vec![Backend::Ast, Backend::Ast]);
+    assert_eq!(check_file(&file, &[]).ret_float(), 7.5);
+}
+
+#[test]
+fn nested_calls_with_stack_args() {
+    // callee(1, ..., 7, callee(1, ..., 8) % 1000), the inner call runs while the outer call's
+    // arguments are being stored
+    let mut callee = ScriptBuilder::new(8, 8).push_local(0);
+    for i in 1..8 {
+        callee = callee
+            .push_int(10)
+            .cmd(Cmd::MultI)
+            .push_local(i)
+            .cmd(Cmd::AddI);
+    }
+    let mut caller = ScriptBuilder::new(0, 0)
+        .branch(|loc| Cmd::Try { loc }, "ret");
+    for i in 1..=7 {
+        caller = caller.push_int(i);
+    }
+    caller = caller.branch(|loc| Cmd::Try { loc }, "inner");
+    for i in 1..=8 {
+        caller = caller.push_int(i);
+    }
+    let caller = caller
+        .push_script(1)
+        .cmd(Cmd::CallFunc { arg_count: 8 })
+        .label("inner")
+        .push_int(1000)
+        .cmd(Cmd::ModI)
+        .push_script(1)
+        .cmd(Cmd::CallFunc { arg_count: 8 })
+        .label("ret")
+        .ret();
+    let file = build_file(vec![caller, callee.ret()], &[]);
+    assert_eq!(backends(&file), vec![Backend::Ast, Backend::Ast]);
+    assert_eq!(check_file(&file, &[]).ret, 1_234_567 * 10 + 678);
+}
+
+#[test]
+fn dynamic_call_and_globals() {
+    let callee = ScriptBuilder::new(2, 2)
+        .push_local(0)
+        .push_local(1)
+        .cmd(Cmd::ShiftL)
+        .set_global(1)
+        .push_global(1)
+        .ret();
+    let caller = ScriptBuilder::new(0, 1)
+        .push_script(1)
+        .set_local(0)
+        .push_int(5)
+        .set_global(0)
+        .branch(|loc| Cmd::Try { loc }, "ret")
+        .push_global(0)
+        .push_int(3)
+        .push_local(0)
+        .cmd(Cmd::CallFunc { arg_count: 2 })
+        .label("ret")
+        .cmd(Cmd::AddVarBy { var_type: 1, var_num: 0 })
+        .push_global(0)
+        .ret();
+    let file = build_file(vec![caller, callee], &[]);
+    assert_eq!(backends(&file), vec![Backend::Ast, Backend::Ast]);
+    let out = check_file(&file, &[]);
+    assert_eq!(out.ret, 45);
+    assert_eq!(out.globals[1], 40);
+}
+
+#[test]
+fn loops_and_var_ops() {
+    // while (local_0 < 10) { local_0++; local_1 *= 2; if (local_0 == 5) { continue; } local_2 += 1.5 }
+    let script = ScriptBuilder::new(0, 3)
+        .push_int(0)
+        .set_local(0)
+        .push_int(1)
+        .set_local(1)
+        .push_int(0)
+        .set_local(2)
+        .label("loop")
+        .push_local(0)
+        .push_int(10)
+        .cmd(Cmd::LessThan)
+        .branch(|loc| Cmd::If { loc }, "end")
+        .cmd(Cmd::IncI { var_type: 0, var_num: 0 })
+        .push_int(2)
+        .cmd(Cmd::MultVarBy { var_type: 0, var_num: 1 })
+        .push_local(0)
+        .push_int(5)
+        .cmd(Cmd::Equals)
+        .branch(|loc| Cmd::IfNot { loc }, "loop")
+        .push_float(1.5)
+        .cmd(Cmd::AddVarByF { var_type: 0, var_num: 2 })
+        .branch(|loc| Cmd::Jump { loc }, "loop")
+        .label("end")
+        .push_local(1)
+        .push_local(2)
+        .cmd(Cmd::FloatToInt { stack_pos: 0 })
+        .cmd(Cmd::AddI)
+        .ret();
+    let file = build_file(vec![script], &[]);
+    assert_eq!(backends(&file), vec![Backend::Ast]);
+    assert_eq!(check_file(&file, &[]).ret, 1024 + 13);
+}
+
+#[test]
+fn printf_and_syscalls() {
+    let script = ScriptBuilder::new(0, 0)
+        .push_int(0)
+        .push_int(3)
+        .push_int(4)
+        .cmd(Cmd::MultI)
+        .push_float(0.5)
+        .push_float(0.25)
+        .cmd(Cmd::AddF)
+        .cmd(Cmd::PrintF { arg_count: 3 })
+        .push_int(0x41)
+        .discard(Cmd::Sys { sys_num: 1, arg_count: 1 })
+        .push_int(7)
+        .ret();
+    let file = build_file(vec![script], &["%d %.2f\n"]);
+    assert_eq!(backends(&file), vec![Backend::Ast]);
+    let out = check_file(&file, &[]);
+    assert_eq!(out.output, "12 0.75\n");
+    assert_eq!(out.ret, 7);
+}
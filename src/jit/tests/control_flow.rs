@@ -0,0 +1,164 @@
+use msc::Cmd;
+use super::{ScriptBuilder, build_file, check, check_file};
+
+#[test]
+fn if_else() {
+    for &cond in &[0u32, 1] {
+        let out = check(
+            ScriptBuilder::new(0, 0)
+                .push_int(cond)
+                .branch(|loc| Cmd::If { loc }, "else")
+                .push_int(10)
+                .branch(|loc| Cmd::Else { loc }, "end")
+                .label("else")
+                .push_int(20)
+                .label("end")
+                .ret()
+        );
+        assert_eq!(out.ret, if cond != 0 { 10 } else { 20 });
+    }
+}
+
+#[test]
+fn if_not() {
+    let out = check(
+        ScriptBuilder::new(0, 0)
+            .push_int(1)
+            .branch(|loc| Cmd::IfNot { loc }, "taken")
+            .push_int(10)
+            .ret()
+            .label("taken")
+            .push_int(20)
+            .ret()
+    );
+    assert_eq!(out.ret, 20);
+}
+
+#[test]
+fn loop_sum() {
+    // local_1 = sum of 1..=10
+    let out = check(
+        ScriptBuilder::new(0, 2)
+            .push_int(0)
+            .set_local(0)
+            .push_int(0)
+            .set_local(1)
+            .label("loop")
+            .push_local(0)
+            .push_int(10)
+            .cmd(Cmd::LessThan)
+            .branch(|loc| Cmd::If { loc }, "end")
+            .cmd(Cmd::IncI { var_type: 0, var_num: 0 })
+            .push_local(1)
+            .push_local(0)
+            .cmd(Cmd::AddI)
+            .set_local(1)
+            .branch(|loc| Cmd::Jump { loc }, "loop")
+            .label("end")
+            .push_local(1)
+            .ret()
+    );
+    assert_eq!(out.ret, 55);
+}
+
+fn callee_sub() -> ScriptBuilder {
+    // arg_0 - arg_1
+    ScriptBuilder::new(2, 2)
+        .push_local(0)
+        .push_local(1)
+        .cmd(Cmd::SubI)
+        .ret()
+}
+
+#[test]
+fn static_call() {
+    let caller = ScriptBuilder::new(0, 0)
+        .branch(|loc| Cmd::Try { loc }, "ret")
+        .push_int(10)
+        .push_int(3)
+        .push_script(1)
+        .cmd(Cmd::CallFunc { arg_count: 2 })
+        .label("ret")
+        .ret();
+    let out = check_file(&build_file(vec![caller, callee_sub()], &[]), &[]);
+    assert_eq!(out.ret, 7);
+}
+
+#[test]
+fn dynamic_call() {
+    let caller = ScriptBuilder::new(0, 1)
+        .push_script(1)
+        .set_local(0)
+        .branch(|loc| Cmd::Try { loc }, "ret")
+        .push_int(10)
+        .push_int(3)
+        .push_local(0)
+        .cmd(Cmd::CallFunc { arg_count: 2 })
+        .label("ret")
+        .ret();
+    let out = check_file(&build_file(vec![caller, callee_sub()], &[]), &[]);
+    assert_eq!(out.ret, 7);
+}
+
+#[test]
+fn many_args() {
+    // Arguments past the sixth are passed on the stack
+    let mut callee = ScriptBuilder::new(8, 8).push_local(0);
+    for i in 1..8 {
+        callee = callee
+            .push_int(10)
+            .cmd(Cmd::MultI)
+            .push_local(i)
+            .cmd(Cmd::AddI);
+    }
+    let mut caller = ScriptBuilder::new(0, 0)
+        .branch(|loc| Cmd::Try { loc }, "ret");
+    for i in 1..=8 {
+        caller = caller.push_int(i);
+    }
+    let caller = caller
+        .push_script(1)
+        .cmd(Cmd::CallFunc { arg_count: 8 })
+        .label("ret")
+        .ret();
+    let out = check_file(&build_file(vec![caller, callee.ret()], &[]), &[]);
+    assert_eq!(out.ret, 12_345_678);
+}
+
+#[test]
+fn entrypoint_args() {
+    let file = build_file(vec![callee_sub()], &[]);
+    let out = check_file(&file, &[20, 5]);
+    assert_eq!(out.ret, 15);
+}
+
+#[test]
+fn printf() {
+    let script = ScriptBuilder::new(0, 0)
+        .push_int(0)
+        .push_int(-5i32 as u32)
+        .push_int(0xAB)
+        .push_float(1.5)
+        .cmd(Cmd::PrintF { arg_count: 4 })
+        .push_int(1)
+        .cmd(Cmd::PrintF { arg_count: 1 })
+        .push_int(0)
+        .ret();
+    let file = build_file(vec![script], &["%d %x %.2f\n", "done"]);
+    let out = check_file(&file, &[]);
+    assert_eq!(out.output, "-5 ab 1.50\ndone");
+}
+
+#[test]
+fn syscall() {
+    let out = check(
+        ScriptBuilder::new(0, 0)
+            .push_int(0x41)
+            .cmd(Cmd::Sys { sys_num: 1, arg_count: 1 })
+            .push_int(1)
+            .push_int(2)
+            .discard(Cmd::Sys { sys_num: 1, arg_count: 2 })
+            .ret()
+    );
+    assert_ne!(out.ret, 0x41);
+}
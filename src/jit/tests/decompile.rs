@@ -0,0 +1,160 @@
+use msc::Cmd;
+use crate::jit::decompile::decompile_script;
+use super::{ScriptBuilder, build_file};
+
+fn decompile(scripts: Vec<ScriptBuilder>, strings: &[&str]) -> String {
+    decompile_script(&build_file(scripts, strings), 0)
+}
+
+#[test]
+fn expressions() {
+    let out = decompile(vec![
+        ScriptBuilder::new(2, 3)
+            .push_local(0)
+            .push_local(1)
+            .push_int(3)
+            .cmd(Cmd::AddI)
+            .cmd(Cmd::MultI)
+            .set_local(2)
+            .push_local(2)
+            .push_local(0)
+            .cmd(Cmd::SubI)
+            .push_local(1)
+            .cmd(Cmd::SubI)
+            .ret()
+    ], &[]);
+    assert_eq!(out, "\
+// entrypoint
+int script_0(int local_0, int local_1) {
+    int local_2;
+
+    local_2 = local_0 * (local_1 + 3);
+    return local_2 - local_0 - local_1;
+}
+");
+}
+
+#[test]
+fn floats_and_globals() {
+    let out = decompile(vec![
+        ScriptBuilder::new(0, 1)
+            .push_float(1.5)
+            .cmd(Cmd::VarSetF { var_type: 0, var_num: 0 })
+            .cmd(Cmd::IncF { var_type: 0, var_num: 0 })
+            .push_global(3)
+            .cmd(Cmd::IntToFloat { stack_pos: 0 })
+            .cmd(Cmd::AddVarByF { var_type: 0, var_num: 0 })
+            .push_local(0)
+            .cmd(Cmd::NegF)
+            .ret()
+    ], &[]);
+    assert_eq!(out, "\
+// entrypoint
+float script_0() {
+    float local_0;
+
+    local_0 = 1.5f;
+    local_0++;
+    local_0 += (float)global_3;
+    return -local_0;
+}
+");
+}
+
+#[test]
+fn control_flow() {
+    let out = decompile(vec![
+        ScriptBuilder::new(1, 1)
+            .label("loop")
+            .push_local(0)
+            .push_int(10)
+            .cmd(Cmd::LessThan)
+            .branch(|loc| Cmd::If { loc }, "end")
+            .push_local(0)
+            .push_int(5)
+            .cmd(Cmd::Equals)
+            .branch(|loc| Cmd::If { loc }, "else")
+            .push_int(0)
+            .push_local(0)
+            .cmd(Cmd::PrintF { arg_count: 2 })
+            .branch(|loc| Cmd::Else { loc }, "next")
+            .label("else")
+            .push_local(0)
+            .discard(Cmd::Sys { sys_num: 7, arg_count: 1 })
+            .label("next")
+            .cmd(Cmd::IncI { var_type: 0, var_num: 0 })
+            .branch(|loc| Cmd::Jump { loc }, "loop")
+            .label("end")
+            .cmd(Cmd::Return7)
+    ], &["five is %d\n"]);
+    assert_eq!(out, "\
+// entrypoint
+void script_0(int local_0) {
+    while (local_0 < 10) {
+        if (local_0 == 5) {
+            printf(\"five is %d\\n\", local_0);
+        } else {
+            sys_7(local_0);
+        }
+        local_0++;
+    }
+    return;
+}
+");
+}
+
+#[test]
+fn calls_by_name() {
+    let file = build_file(vec![
+        ScriptBuilder::new(0, 0)
+            .branch(|loc| Cmd::Try { loc }, "ret")
+            .push_int(1)
+            .push_int(2)
+            .push_script(1)
+            .cmd(Cmd::CallFunc { arg_count: 2 })
+            .label("ret")
+            .ret(),
+        ScriptBuilder::new(2, 2)
+            .push_local(0)
+            .push_local(1)
+            .cmd(Cmd::ShiftL)
+            .ret(),
+    ], &[]);
+    assert_eq!(decompile_script(&file, 0), "\
+// entrypoint
+int script_0() {
+    return script_1(1, 2);
+}
+");
+    assert_eq!(decompile_script(&file, 1), "\
+int script_1(int local_0, int local_1) {
+    return local_0 << local_1;
+}
+");
+}
+
+#[test]
+fn untyped_arguments() {
+    // Constants passed to printf and sys are ints unless the operand is a float expression
+    let out = decompile(vec![
+        ScriptBuilder::new(0, 0)
+            .push_int(0)
+            .push_int(7)
+            .push_float(1.5)
+            .push_float(2.0)
+            .cmd(Cmd::MultF)
+            .cmd(Cmd::PrintF { arg_count: 3 })
+            .push_int(9)
+            .discard(Cmd::Sys { sys_num: 1, arg_count: 1 })
+            .push_int(0)
+            .ret()
+    ], &["%d %f"]);
+    assert_eq!(out, "\
+// entrypoint
+int script_0() {
+    printf(\"%d %f\", 7, 1.5f * 2.0f);
+    sys_1(9);
+    return 0;
+}
+");
+}
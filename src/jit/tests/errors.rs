@@ -0,0 +1,230 @@
+use msc::{MscsbFile, Cmd};
+use crate::jit::interp::Interpreter;
+use crate::jit::x86::{Backend, Compilable, CompileError, CompileOptions, RunError, Value};
+use super::{ScriptBuilder, build_file, check, check_file};
+
+fn backends(file: &MscsbFile) -> Vec<Backend> {
+    let options = CompileOptions { backend: Backend::Ast, ..CompileOptions::default() };
+    file.compile_with(&options).expect("Failed to compile").backends
+}
+
+/// `run_script` of both backends and the interpreter, which have to agree
+fn run_script(file: &MscsbFile, script_index: usize, args: &[Value]) -> Result<Value, RunError> {
+    let interp = Interpreter::new(file).unwrap().run_script(script_index, args);
+    for &backend in [Backend::Commands, Backend::Ast].iter() {
+        let options = CompileOptions { backend, ..CompileOptions::default() };
+        let mut program = file.compile_with(&options).unwrap();
+        program.lock_all().unwrap();
+        let jit = program.run_script(script_index, args);
+        assert_eq!(jit, interp, "JIT (left, {:?} backend) and interpreter (right) disagree", backend);
+    }
+    interp
+}
+
+/// Raises `cmd` when its argument is nonzero, returns the argument + 1 otherwise
+fn raise_if(cmd: Cmd) -> ScriptBuilder {
+    ScriptBuilder::new(1, 1)
+        .push_local(0)
+        .branch(|loc| Cmd::If { loc }, "ok")
+        .cmd(cmd)
+        .label("ok")
+        .push_local(0)
+        .push_int(1)
+        .cmd(Cmd::AddI)
+        .ret()
+}
+
+#[test]
+fn caught_in_caller() {
+    // 100 * arg + callee(arg), the error value takes the place of the return value and the
+    // left operand survives the unwinding
+    let caller = ScriptBuilder::new(1, 1)
+        .push_local(0)
+        .push_int(100)
+        .cmd(Cmd::MultI)
+        .branch(|loc| Cmd::Try { loc }, "ret")
+        .push_local(0)
+        .push_script(1)
+        .cmd(Cmd::CallFunc { arg_count: 1 })
+        .label("ret")
+        .cmd(Cmd::AddI)
+        .ret();
+    let file = build_file(vec![caller, raise_if(Cmd::Error37)], &[]);
+    assert_eq!(backends(&file), vec![Backend::Ast, Backend::Commands]);
+    assert_eq!(check_file(&file, &[0]).ret, 1);
+    assert_eq!(check_file(&file, &[2]).ret, 200 + 0x37);
+}
+
+#[test]
+fn innermost_handler() {
+    // The error of the inner call is caught inside the outer one, which returns normally
+    let middle = ScriptBuilder::new(1, 1)
+        .branch(|loc| Cmd::Try { loc }, "ret")
+        .push_local(0)
+        .push_script(2)
+        .cmd(Cmd::CallFunc { arg_count: 1 })
+        .label("ret")
+        .push_int(0x1000)
+        .cmd(Cmd::OrI)
+        .ret();
+    let caller = ScriptBuilder::new(1, 1)
+        .branch(|loc| Cmd::Try { loc }, "ret")
+        .push_local(0)
+        .push_script(1)
+        .cmd(Cmd::CallFunc { arg_count: 1 })
+        .label("ret")
+        .ret();
+    let file = build_file(vec![caller, middle, raise_if(Cmd::ErrorC)], &[]);
+    assert_eq!(check_file(&file, &[0]).ret, 0x1001);
+    assert_eq!(check_file(&file, &[1]).ret, 0x100C);
+}
+
+#[test]
+fn same_script() {
+    // The values pushed after the Try are dropped
+    let out = check(
+        ScriptBuilder::new(0, 0)
+            .push_int(5)
+            .branch(|loc| Cmd::Try { loc }, "caught")
+            .push_int(6)
+            .push_int(7)
+            .cmd(Cmd::Error4C)
+            .label("caught")
+            .cmd(Cmd::SubI)
+            .ret()
+    );
+    assert_eq!(out.ret, 0x4C_u32.wrapping_neg().wrapping_add(5));
+}
+
+#[test]
+fn pending_handler_on_return() {
+    // The callee returns with its Try still pending, the error raised afterwards is caught
+    // by the caller and not by the callee's stale handler
+    let callee = ScriptBuilder::new(0, 0)
+        .branch(|loc| Cmd::Try { loc }, "never")
+        .push_int(3)
+        .ret()
+        .label("never")
+        .push_int(4)
+        .ret();
+    let caller = ScriptBuilder::new(0, 0)
+        .branch(|loc| Cmd::Try { loc }, "first")
+        .push_script(1)
+        .cmd(Cmd::CallFunc { arg_count: 0 })
+        .label("first")
+        .branch(|loc| Cmd::Try { loc }, "second")
+        .push_int(1)
+        .push_script(2)
+        .cmd(Cmd::CallFunc { arg_count: 1 })
+        .label("second")
+        .cmd(Cmd::AddI)
+        .ret();
+    let file = build_file(vec![caller, callee, raise_if(Cmd::Error37)], &[]);
+    assert_eq!(check_file(&file, &[]).ret, 3 + 0x37);
+}
+
+#[test]
+fn uncaught() {
+    let caller = ScriptBuilder::new(1, 1)
+        .branch(|loc| Cmd::Try { loc }, "ret")
+        .push_local(0)
+        .push_script(2)
+        .cmd(Cmd::CallFunc { arg_count: 1 })
+        .label("ret")
+        .ret();
+    let callee = ScriptBuilder::new(1, 1)
+        .push_local(0)
+        .set_global(0)
+        .cmd(Cmd::ErrorC)
+        .push_int(0)
+        .ret();
+    // The callee has no Try and the error isn't caught by the caller's handler
+    let file = build_file(vec![ScriptBuilder::new(1, 1)
+        .push_local(0)
+        .push_script(2)
+        .cmd(Cmd::CallFunc { arg_count: 1 })
+        .ret(), caller, callee], &[]);
+    match run_script(&file, 0, &[Value::Int(9)]) {
+        Err(RunError::Uncaught { script_index: 2, value: 0xC, .. }) => {}
+        other => panic!("Expected Uncaught, got {:?}", other),
+    }
+    assert_eq!(run_script(&file, 1, &[Value::Int(9)]).unwrap(), Value::Int(0xC));
+
+    // The program still runs after an error ended a run
+    let mut program = file.compile().unwrap();
+    program.lock_all().unwrap();
+    assert!(program.run_script(0, &[Value::Int(4)]).is_err());
+    assert_eq!(program.global_int(0), Some(4));
+    assert_eq!(program.run_script(1, &[Value::Int(5)]).unwrap(), Value::Int(0xC));
+    assert_eq!(program.global_int(0), Some(5));
+    match program.run_with_args(0, &[6]) {
+        Err(RunError::Uncaught { script_index: 2, value: 0xC, .. }) => {}
+        other => panic!("Expected Uncaught, got {:?}", other),
+    }
+}
+
+#[test]
+fn invalid_target() {
+    let file = build_file(vec![
+        ScriptBuilder::new(0, 0)
+            .label("back")
+            .push_int(1)
+            .branch(|loc| Cmd::Try { loc }, "back")
+            .ret()
+    ], &[]);
+    match file.compile() {
+        Err(CompileError::InvalidTryTarget { script_index: 0, cmd: Cmd::Try { .. }, .. }) => {}
+        other => panic!("Expected InvalidTryTarget, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn invalid_dynamic_call() {
+    // 0x11 is inside script 0, a Try doesn't catch the call
+    let caller = ScriptBuilder::new(0, 1)
+        .push_int(0x11)
+        .set_local(0)
+        .branch(|loc| Cmd::Try { loc }, "ret")
+        .push_local(0)
+        .cmd(Cmd::CallFunc { arg_count: 0 })
+        .label("ret")
+        .ret();
+    let file = build_file(vec![caller, raise_if(Cmd::ErrorC)], &[]);
+    assert_eq!(backends(&file)[0], Backend::Ast);
+    match run_script(&file, 0, &[]) {
+        Err(RunError::InvalidCall { script_index: 0, offset: 0x11 }) => {}
+        other => panic!("Expected InvalidCall, got {:?}", other),
+    }
+}
+
+#[test]
+fn stack_underflow() {
+    let underflowing = [
+        Cmd::AddI,
+        Cmd::IntToFloat { stack_pos: 1 },
+        Cmd::PrintF { arg_count: 2 },
+        Cmd::Sys { sys_num: 1, arg_count: 2 },
+        Cmd::CallFunc { arg_count: 1 },
+    ];
+    // One value on the stack, the format string or the script called
+    let file = build_file(underflowing.iter().map(|&cmd| {
+        let script = ScriptBuilder::new(0, 0);
+        let script = match cmd {
+            Cmd::CallFunc { .. } => script.push_script(0),
+            _ => script.push_int(0),
+        };
+        script.cmd(cmd).ret()
+    }).collect(), &["%d"]);
+    let mut interpreter = Interpreter::new(&file).unwrap();
+    for (script_index, &cmd) in underflowing.iter().enumerate() {
+        let position = file.scripts[script_index].iter()
+            .find(|command| command.cmd == cmd)
+            .unwrap()
+            .position;
+        assert_eq!(
+            interpreter.run_script(script_index, &[]),
+            Err(RunError::StackUnderflow { script_index, position }),
+            "{:?}", cmd
+        );
+    }
+}
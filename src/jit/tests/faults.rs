@@ -0,0 +1,112 @@
+use msc::{MscsbFile, Cmd};
+use crate::jit::interp::Interpreter;
+use crate::jit::x86::{Backend, Compilable, CompileOptions, FaultKind, RunError, Value};
+use super::{ScriptBuilder, build_file};
+
+/// `run_script` of both backends and the interpreter, which have to agree
+fn run_script(file: &MscsbFile, script_index: usize, args: &[Value]) -> Result<Value, RunError> {
+    let interp = Interpreter::new(file).unwrap().run_script(script_index, args);
+    for &backend in [Backend::Commands, Backend::Ast].iter() {
+        let options = CompileOptions { backend, ..CompileOptions::default() };
+        let mut program = file.compile_with(&options).unwrap();
+        program.lock_all().unwrap();
+        let jit = program.run_script(script_index, args);
+        assert_eq!(jit, interp, "JIT (left, {:?} backend) and interpreter (right) disagree", backend);
+    }
+    interp
+}
+
+/// Position of the first `cmd` in the script
+fn position_of(file: &MscsbFile, script_index: usize, cmd: Cmd) -> u32 {
+    file.scripts[script_index].iter().find(|command| command.cmd == cmd).unwrap().position
+}
+
+fn divide(cmd: Cmd) -> ScriptBuilder {
+    ScriptBuilder::new(2, 2)
+        .push_local(0)
+        .push_local(1)
+        .cmd(cmd)
+        .ret()
+}
+
+#[test]
+fn divide_error() {
+    let file = build_file(vec![divide(Cmd::DivI), divide(Cmd::ModI)], &[]);
+    let args = [Value::Int(7), Value::Int(2)];
+    assert_eq!(run_script(&file, 0, &args).unwrap(), Value::Int(3));
+    assert_eq!(run_script(&file, 1, &args).unwrap(), Value::Int(1));
+    for &(script_index, cmd) in [(0, Cmd::DivI), (1, Cmd::ModI)].iter() {
+        let position = position_of(&file, script_index, cmd);
+        for &(a, b) in [(7, 0), (i32::MIN as u32, -1i32 as u32)].iter() {
+            match run_script(&file, script_index, &[Value::Int(a), Value::Int(b)]) {
+                Err(RunError::Fault { script_index: s, position: Some(p), kind })
+                    if s == script_index && p == position && kind == FaultKind::DivideError => {}
+                other => panic!("Expected a divide error, got {:?}", other),
+            }
+        }
+    }
+}
+
+#[test]
+fn ast_backend() {
+    let file = build_file(vec![divide(Cmd::DivI), divide(Cmd::ModI)], &[]);
+    let options = CompileOptions { backend: Backend::Ast, ..CompileOptions::default() };
+    let mut program = file.compile_with(&options).unwrap();
+    program.lock_all().unwrap();
+    assert_eq!(program.backends, vec![Backend::Ast, Backend::Ast]);
+    for &(script_index, cmd) in [(0, Cmd::DivI), (1, Cmd::ModI)].iter() {
+        assert_eq!(program.run_script(script_index, &[Value::Int(7), Value::Int(0)]),
+                   Err(RunError::Fault {
+                       script_index,
+                       position: Some(position_of(&file, script_index, cmd)),
+                       kind: FaultKind::DivideError,
+                   }));
+    }
+}
+
+#[test]
+fn var_op() {
+    let file = build_file(vec![
+        ScriptBuilder::new(1, 1)
+            .push_int(100)
+            .set_global(0)
+            .push_local(0)
+            .cmd(Cmd::DivVarBy { var_type: 1, var_num: 0 })
+            .push_global(0)
+            .ret()
+    ], &[]);
+    assert_eq!(run_script(&file, 0, &[Value::Int(4)]).unwrap(), Value::Int(25));
+    match run_script(&file, 0, &[Value::Int(0)]) {
+        Err(RunError::Fault { script_index: 0, kind: FaultKind::DivideError, .. }) => {}
+        other => panic!("Expected a divide error, got {:?}", other),
+    }
+}
+
+#[test]
+fn ends_the_run() {
+    // A fault isn't an MSC error, the Try of the caller doesn't catch it
+    let caller = ScriptBuilder::new(2, 2)
+        .branch(|loc| Cmd::Try { loc }, "ret")
+        .push_local(0)
+        .push_local(1)
+        .push_script(1)
+        .cmd(Cmd::CallFunc { arg_count: 2 })
+        .label("ret")
+        .ret();
+    let file = build_file(vec![caller, divide(Cmd::DivI)], &[]);
+    let position = position_of(&file, 1, Cmd::DivI);
+    match run_script(&file, 0, &[Value::Int(1), Value::Int(0)]) {
+        Err(RunError::Fault { script_index: 1, position: Some(p), .. }) if p == position => {}
+        other => panic!("Expected a divide error, got {:?}", other),
+    }
+
+    // The program still runs afterwards
+    let mut program = file.compile().unwrap();
+    program.lock_all().unwrap();
+    let error = program.run_script(0, &[Value::Int(1), Value::Int(0)]).unwrap_err();
+    assert_eq!(error.to_string(),
+               format!("script_1 0x{:X}: integer division error", position));
+    assert_eq!(program.run_script(0, &[Value::Int(9), Value::Int(3)]).unwrap(), Value::Int(3));
+    assert_eq!(program.run_with_args(0, &[1, 0]).unwrap_err(), error);
+    assert_eq!(program.run_with_args(0, &[8, 2]), Ok(4));
+}
@@ -0,0 +1,156 @@
+use msc::Cmd;
+use super::{ScriptBuilder, build_file, check, check_file};
+
+fn binop(cmd: Cmd, a: f32, b: f32) -> u32 {
+    check(
+        ScriptBuilder::new(0, 0)
+            .push_float(a)
+            .push_float(b)
+            .cmd(cmd)
+            .ret()
+    ).ret
+}
+
+#[test]
+fn arithmetic() {
+    assert_eq!(f32::from_bits(binop(Cmd::AddF, 1.5, 2.25)), 3.75);
+    assert_eq!(f32::from_bits(binop(Cmd::SubF, 1.5, 2.25)), -0.75);
+    assert_eq!(f32::from_bits(binop(Cmd::MultF, 1.5, -2.0)), -3.0);
+    assert_eq!(f32::from_bits(binop(Cmd::DivF, 3.0, 4.0)), 0.75);
+}
+
+#[test]
+fn comparisons() {
+    let cases = [
+        (1.0f32, 2.0f32), (2.0, 1.0), (0.5, 0.5), (-1.0, 1.0), (-0.0, 0.0),
+        (f32::NAN, 1.0), (1.0, f32::NAN), (f32::NAN, f32::NAN),
+    ];
+    for &(a, b) in &cases {
+        binop(Cmd::EqualsF, a, b);
+        binop(Cmd::NotEqualsF, a, b);
+        binop(Cmd::LessThanF, a, b);
+        binop(Cmd::LessOrEqualF, a, b);
+        binop(Cmd::GreaterF, a, b);
+        binop(Cmd::GreaterOrEqualF, a, b);
+    }
+    assert_eq!(binop(Cmd::LessThanF, 1.0, 2.0), 1);
+    assert_eq!(binop(Cmd::EqualsF, f32::NAN, f32::NAN), 0);
+    assert_eq!(binop(Cmd::NotEqualsF, f32::NAN, 1.0), 1);
+    assert_eq!(binop(Cmd::LessOrEqualF, 1.0, f32::NAN), 0);
+}
+
+#[test]
+fn branch_on_nan() {
+    // Every ordered comparison with a NaN is false, so only the != branch adds its bit
+    let mut script = ScriptBuilder::new(0, 1)
+        .push_int(0)
+        .set_local(0);
+    let cmds = [
+        Cmd::EqualsF, Cmd::NotEqualsF, Cmd::LessThanF,
+        Cmd::LessOrEqualF, Cmd::GreaterF, Cmd::GreaterOrEqualF,
+    ];
+    let skips = ["skip0", "skip1", "skip2", "skip3", "skip4", "skip5"];
+    for (i, (cmd, skip)) in cmds.iter().zip(skips.iter()).enumerate() {
+        script = script
+            .push_float(f32::NAN)
+            .push_float(1.0)
+            .cmd(*cmd)
+            .branch(|loc| Cmd::If { loc }, skip)
+            .push_int(1 << i)
+            .cmd(Cmd::AddVarBy { var_type: 0, var_num: 0 })
+            .label(skip);
+    }
+    let out = check(script.push_local(0).ret());
+    assert_eq!(out.ret, 2);
+}
+
+#[test]
+fn negate() {
+    let out = check(
+        ScriptBuilder::new(0, 0)
+            .push_float(2.5)
+            .cmd(Cmd::NegF)
+            .ret()
+    );
+    assert_eq!(out.ret_float(), -2.5);
+}
+
+#[test]
+fn discarded_negate() {
+    let out = check(
+        ScriptBuilder::new(0, 0)
+            .push_int(42)
+            .push_float(2.5)
+            .discard(Cmd::NegF)
+            .ret()
+    );
+    assert_eq!(out.ret, 42);
+}
+
+#[test]
+fn comparison_after_args() {
+    // The 5th argument is passed in R8, which comparisons use for their false result
+    let file = build_file(vec![
+        ScriptBuilder::new(5, 5)
+            .push_float(2.0)
+            .push_float(1.0)
+            .cmd(Cmd::LessThanF)
+            .ret()
+    ], &[]);
+    assert_eq!(check_file(&file, &[1, 2, 3, 4, 5]).ret, 0);
+}
+
+#[test]
+fn int_to_float() {
+    let out = check(
+        ScriptBuilder::new(0, 0)
+            .push_int(-7i32 as u32)
+            .cmd(Cmd::IntToFloat { stack_pos: 0 })
+            .ret()
+    );
+    assert_eq!(out.ret_float(), -7.0);
+
+    // stack_pos counts from the top of the stack
+    let out = check(
+        ScriptBuilder::new(0, 0)
+            .push_int(3)
+            .push_float(0.5)
+            .cmd(Cmd::IntToFloat { stack_pos: 1 })
+            .cmd(Cmd::AddF)
+            .ret()
+    );
+    assert_eq!(out.ret_float(), 3.5);
+}
+
+#[test]
+fn float_to_int() {
+    for &val in &[2.75f32, -2.75, 0.5, 1e10, -1e10] {
+        check(
+            ScriptBuilder::new(0, 0)
+                .push_float(val)
+                .cmd(Cmd::FloatToInt { stack_pos: 0 })
+                .ret()
+        );
+    }
+    let out = check(
+        ScriptBuilder::new(0, 0)
+            .push_float(-2.75)
+            .cmd(Cmd::FloatToInt { stack_pos: 0 })
+            .ret()
+    );
+    assert_eq!(out.ret, -2i32 as u32);
+}
+
+#[test]
+fn float_to_int_keeps_rounding_mode() {
+    let out = check(
+        ScriptBuilder::new(0, 0)
+            .push_float(1.5)
+            .cmd(Cmd::FloatToInt { stack_pos: 0 })
+            .discard(Cmd::Pop)
+            .push_int(16_777_219)
+            .cmd(Cmd::IntToFloat { stack_pos: 0 })
+            .ret()
+    );
+    assert_eq!(out.ret_float(), 16_777_220.0);
+}
@@ -0,0 +1,108 @@
+use msc::{MscsbFile, Cmd};
+use crate::jit::interp::Interpreter;
+use crate::jit::x86::{Backend, Compilable, CompileOptions, ExitStatus, RunError, Value};
+use super::{ScriptBuilder, build_file};
+
+/// Run the entrypoint with a fuel budget through both backends and the interpreter, which have
+/// to agree on how the run ends and on the globals it leaves
+fn run_with_fuel(file: &MscsbFile, fuel: u64) -> (Result<ExitStatus, RunError>, Vec<u32>) {
+    let mut interpreter = Interpreter::new(file).unwrap();
+    let interp = interpreter.run_script_with_fuel(0, &[], fuel);
+    for &backend in [Backend::Commands, Backend::Ast].iter() {
+        let options = CompileOptions { backend, fuel: true, ..CompileOptions::default() };
+        let mut program = file.compile_with(&options).unwrap();
+        program.lock_all().unwrap();
+        let jit = program.run_script_with_fuel(0, &[], fuel);
+        assert_eq!(jit, interp, "JIT (left, {:?} backend) and interpreter (right) disagree", backend);
+        assert_eq!(program.snapshot_globals(), interpreter.global_vars, "{:?} backend", backend);
+    }
+    (interp, interpreter.global_vars)
+}
+
+#[test]
+fn infinite_loop() {
+    let file = build_file(vec![
+        ScriptBuilder::new(0, 0)
+            .label("loop")
+            .cmd(Cmd::IncI { var_type: 1, var_num: 0 })
+            .branch(|loc| Cmd::Jump { loc }, "loop")
+    ], &[]);
+    let options = CompileOptions { backend: Backend::Ast, fuel: true, ..CompileOptions::default() };
+    assert_eq!(file.compile_with(&options).unwrap().backends, vec![Backend::Commands]);
+
+    // The entry and 9 jumps
+    let (status, globals) = run_with_fuel(&file, 10);
+    match status {
+        Err(RunError::OutOfFuel { script_index: 0, .. }) => {}
+        other => panic!("Expected OutOfFuel, got {:?}", other),
+    }
+    assert_eq!(globals, vec![10]);
+}
+
+#[test]
+fn entries_and_backward_jumps() {
+    // Calls the callee 3 times in a loop, using 1 + 3 + 3 units
+    let caller = ScriptBuilder::new(0, 1)
+        .label("loop")
+        .push_local(0)
+        .push_int(3)
+        .cmd(Cmd::LessThan)
+        .branch(|loc| Cmd::If { loc }, "end")
+        .branch(|loc| Cmd::Try { loc }, "ret")
+        .push_local(0)
+        .push_script(1)
+        .cmd(Cmd::CallFunc { arg_count: 1 })
+        .label("ret")
+        .cmd(Cmd::AddVarBy { var_type: 1, var_num: 0 })
+        .cmd(Cmd::IncI { var_type: 0, var_num: 0 })
+        .branch(|loc| Cmd::Jump { loc }, "loop")
+        .label("end")
+        .push_global(0)
+        .ret();
+    let callee = ScriptBuilder::new(1, 1)
+        .push_local(0)
+        .push_int(10)
+        .cmd(Cmd::MultI)
+        .ret();
+    let file = build_file(vec![caller, callee], &[]);
+    let (status, _) = run_with_fuel(&file, 7);
+    assert_eq!(status.unwrap(), ExitStatus::Returned(Value::Int(30)));
+    let (status, globals) = run_with_fuel(&file, 6);
+    match status {
+        Err(RunError::OutOfFuel { script_index: 0, .. }) => {}
+        other => panic!("Expected OutOfFuel, got {:?}", other),
+    }
+    assert_eq!(globals, vec![30]);
+    // Out in the callee on its third call
+    let (status, _) = run_with_fuel(&file, 5);
+    match status {
+        Err(RunError::OutOfFuel { script_index: 1, position: 0 }) => {}
+        other => panic!("Expected OutOfFuel, got {:?}", other),
+    }
+}
+
+#[test]
+fn budget_per_run() {
+    let file = build_file(vec![
+        ScriptBuilder::new(0, 0)
+            .label("loop")
+            .cmd(Cmd::IncI { var_type: 1, var_num: 0 })
+            .branch(|loc| Cmd::Jump { loc }, "loop")
+    ], &[]);
+    let mut program = file.compile().unwrap();
+    program.lock_all().unwrap();
+    match program.run_script_with_fuel(0, &[], 100) {
+        Err(RunError::FuelNotEnabled) => {}
+        other => panic!("Expected FuelNotEnabled, got {:?}", other),
+    }
+
+    let options = CompileOptions { fuel: true, ..CompileOptions::default() };
+    let mut program = file.compile_with(&options).unwrap();
+    program.lock_all().unwrap();
+    assert!(program.run_script_with_fuel(0, &[], 100).is_err());
+    assert_eq!(program.global_int(0), Some(100));
+    assert!(program.run_script_with_fuel(0, &[], 0).is_err());
+    assert_eq!(program.global_int(0), Some(100));
+    assert!(program.run_script_with_fuel(0, &[], 5).is_err());
+    assert_eq!(program.global_int(0), Some(105));
+}
@@ -0,0 +1,115 @@
+use msc::Cmd;
+use crate::jit::x86::{Backend, Compilable, CompileOptions, RunError, Value};
+use crate::jit::x86::globals::global_count;
+use super::{ScriptBuilder, build_file, check_effects, check_file};
+
+#[test]
+fn sized_from_commands() {
+    let file = build_file(vec![
+        ScriptBuilder::new(0, 2)
+            .push_int(1)
+            .set_global(2)
+            .push_local(1)
+            .ret(),
+        ScriptBuilder::new(0, 0)
+            .cmd(Cmd::IncF { var_type: 1, var_num: 6 })
+            .cmd(Cmd::Return7),
+    ], &[]);
+    assert_eq!(global_count(&file), 7);
+    assert_eq!(file.compile().unwrap().snapshot_globals().len(), 7);
+
+    let file = build_file(vec![ScriptBuilder::new(0, 1).push_local(0).ret()], &[]);
+    assert_eq!(global_count(&file), 0);
+}
+
+#[test]
+fn high_var_num() {
+    // Past the 0x100 globals every program used to get
+    let out = check_effects(
+        ScriptBuilder::new(0, 0)
+            .push_int(7)
+            .set_global(0x180)
+            .push_global(0x180)
+            .push_int(3)
+            .cmd(Cmd::AddI)
+            .set_global(0x1FF)
+            .cmd(Cmd::End)
+    );
+    assert_eq!(out.globals.len(), 0x200);
+    assert_eq!((out.globals[0x180], out.globals[0x1FF]), (7, 10));
+}
+
+#[test]
+fn accessors() {
+    let file = build_file(vec![
+        ScriptBuilder::new(0, 0)
+            .push_global(0)
+            .push_int(2)
+            .cmd(Cmd::MultI)
+            .set_global(0)
+            .push_global(1)
+            .push_float(0.5)
+            .cmd(Cmd::AddF)
+            .ret()
+    ], &[]);
+    let mut program = file.compile().unwrap();
+    program.lock_all().unwrap();
+    program.set_global_int(0, 21).unwrap();
+    program.set_global_float(1, 1.25).unwrap();
+    assert_eq!(program.run_script(0, &[]).unwrap(), Value::Float(1.75));
+    assert_eq!(program.global_int(0), Some(42));
+    assert_eq!(program.global_float(1), Some(1.25));
+
+    assert_eq!(program.global_int(2), None);
+    match program.set_global_int(2, 0) {
+        Err(RunError::InvalidGlobal { var_num: 2, global_count: 2 }) => {}
+        other => panic!("Expected InvalidGlobal, got {:?}", other),
+    }
+}
+
+#[test]
+fn snapshot_restore() {
+    let file = build_file(vec![
+        ScriptBuilder::new(0, 0)
+            .cmd(Cmd::IncI { var_type: 1, var_num: 0 })
+            .push_global(0)
+            .ret()
+    ], &[]);
+    let mut program = file.compile().unwrap();
+    program.lock_all().unwrap();
+    program.run_script(0, &[]).unwrap();
+    let snapshot = program.snapshot_globals();
+    assert_eq!(snapshot, vec![1]);
+    program.run_script(0, &[]).unwrap();
+    assert_eq!(program.run_script(0, &[]).unwrap(), Value::Int(3));
+
+    program.restore_globals(&snapshot).unwrap();
+    assert_eq!(program.run_script(0, &[]).unwrap(), Value::Int(2));
+    match program.restore_globals(&[0, 0]) {
+        Err(RunError::GlobalCount { expected: 1, passed: 2 }) => {}
+        other => panic!("Expected GlobalCount, got {:?}", other),
+    }
+    assert_eq!(program.snapshot_globals(), vec![2]);
+}
+
+#[test]
+fn any_nonzero_var_type() {
+    // var_type 2 is a global like 1, for every backend
+    let file = build_file(vec![
+        ScriptBuilder::new(0, 2)
+            .push_int(5)
+            .set_local(1)
+            .push_int(3)
+            .cmd(Cmd::SetVar { var_type: 2, var_num: 1 })
+            .cmd(Cmd::IncI { var_type: 2, var_num: 1 })
+            .cmd(Cmd::PushVar { var_type: 2, var_num: 1 })
+            .push_local(1)
+            .cmd(Cmd::MultI)
+            .ret()
+    ], &[]);
+    let options = CompileOptions { backend: Backend::Ast, ..CompileOptions::default() };
+    assert_eq!(file.compile_with(&options).unwrap().backends, vec![Backend::Ast]);
+    let out = check_file(&file, &[]);
+    assert_eq!(out.ret, 20);
+    assert_eq!(out.globals, vec![0, 4]);
+}
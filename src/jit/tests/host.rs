@@ -0,0 +1,142 @@
+use std::rc::Rc;
+use msc::{MscsbFile, Cmd};
+use crate::jit::x86::host::{self, HostState};
+use crate::jit::x86::syscalls::{SyscallRegistry, SyscallArgs};
+use super::{Outcome, ScriptBuilder, build_file, check_file_with};
+
+fn sys(sys_num: u8, arg_count: u8) -> Cmd {
+    Cmd::Sys { sys_num, arg_count }
+}
+
+fn check_host(file: &MscsbFile) -> Outcome {
+    let (syscalls, _) = HostState::registry(file);
+    check_file_with(file, &Rc::new(syscalls), &[])
+}
+
+#[test]
+fn crc32_table() {
+    let registry = SyscallRegistry::default();
+    let entry = |byte: u64| registry.call(1, &SyscallArgs::new(&[byte])).unwrap();
+    assert_eq!(entry(0), 0);
+    assert_eq!(entry(1), 0x7707_3096);
+    assert_eq!(entry(0xFF), 0x2D02_EF8D);
+}
+
+#[test]
+fn extensions_leave_game_numbers_free() {
+    let file = build_file(vec![ScriptBuilder::new(0, 0)], &[]);
+    let (registry, _) = HostState::registry(&file);
+    assert!(registry.is_registered(0) && registry.is_registered(1));
+    for sys_num in 2..host::EXTENSIONS_START {
+        assert!(!registry.is_registered(sys_num), "sys 0x{:X} registered", sys_num);
+    }
+    assert!(registry.is_registered(host::SIN) && registry.is_registered(host::ARRAY_SET));
+}
+
+#[test]
+fn math() {
+    // floor(sqrt(16) * 0.6) + max(abs(-7), -2)
+    let file = build_file(vec![
+        ScriptBuilder::new(0, 0)
+            .push_float(16.0)
+            .cmd(sys(host::SQRT, 1))
+            .push_float(0.6)
+            .cmd(Cmd::MultF)
+            .cmd(sys(host::FLOOR, 1))
+            .cmd(Cmd::FloatToInt { stack_pos: 0 })
+            .push_int(-7i32 as u32)
+            .cmd(sys(host::ABS, 1))
+            .push_int(-2i32 as u32)
+            .cmd(sys(host::MAX, 2))
+            .cmd(Cmd::AddI)
+            .ret()
+    ], &[]);
+    assert_eq!(check_host(&file).ret, 9);
+
+    let file = build_file(vec![
+        ScriptBuilder::new(0, 0)
+            .push_float(1.0)
+            .push_float(-1.0)
+            .cmd(sys(host::ATAN2, 2))
+            .ret()
+    ], &[]);
+    assert_eq!(check_host(&file).ret_float(), 1.0f32.atan2(-1.0));
+}
+
+#[test]
+fn seeded_random() {
+    let file = build_file(vec![
+        ScriptBuilder::new(0, 0)
+            .push_int(42)
+            .discard(sys(host::RAND_SEED, 1))
+            .push_int(100)
+            .cmd(sys(host::RAND, 1))
+            .push_int(-10i32 as u32)
+            .push_int(10)
+            .cmd(sys(host::RAND_RANGE, 2))
+            .push_int(1000)
+            .cmd(Cmd::MultI)
+            .cmd(Cmd::AddI)
+            .ret()
+    ], &[]);
+    let mut state = HostState::new(&file);
+    state.seed(42);
+    let first = state.next_random() % 100;
+    let second = (state.next_random() % 20) as i32 - 10;
+    assert_eq!(check_host(&file).ret as i32, first as i32 + second * 1000);
+}
+
+#[test]
+fn text_formatting() {
+    let file = build_file(vec![
+        ScriptBuilder::new(0, 0)
+            .push_int(1)
+            .discard(sys(host::TEXT_STR, 1))
+            .push_int(-5i32 as u32)
+            .discard(sys(host::TEXT_INT, 1))
+            .push_float(1.5)
+            .push_int(2)
+            .discard(sys(host::TEXT_FLOAT, 2))
+            .cmd(sys(host::TEXT_FLUSH, 0))
+            .push_int(0)
+            .push_int(1)
+            .cmd(sys(host::STR_CHAR, 2))
+            .cmd(Cmd::AddI)
+            .ret()
+    ], &["hello", "hp: "]);
+    let out = check_host(&file);
+    assert_eq!(out.output, "hp: -51.50");
+    assert_eq!(out.ret, 10 + u32::from(b'e'));
+}
+
+#[test]
+fn arrays() {
+    let file = build_file(vec![
+        ScriptBuilder::new(0, 2)
+            .push_int(4)
+            .cmd(sys(host::ARRAY_NEW, 1))
+            .set_local(0)
+            .push_local(0)
+            .push_int(2)
+            .push_int(7)
+            .discard(sys(host::ARRAY_SET, 3))
+            .push_local(0)
+            .push_int(2)
+            .cmd(sys(host::ARRAY_GET, 2))
+            .push_local(0)
+            .cmd(sys(host::ARRAY_LEN, 1))
+            .cmd(Cmd::MultI)
+            .set_local(1)
+            .push_local(0)
+            .discard(sys(host::ARRAY_FREE, 1))
+            .push_local(1)
+            .push_local(0)
+            .cmd(Cmd::AddI)
+            .ret()
+    ], &[]);
+    let (syscalls, state) = HostState::registry(&file);
+    let out = check_file_with(&file, &Rc::new(syscalls), &[]);
+    // Freed handles are reused, so every run gets handle 1
+    assert_eq!(out.ret, 28 + 1);
+    assert!(state.borrow().array(1).is_none());
+}
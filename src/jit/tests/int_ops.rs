@@ -0,0 +1,105 @@
+use msc::Cmd;
+use super::{ScriptBuilder, check};
+
+fn binop(cmd: Cmd, a: u32, b: u32) -> u32 {
+    check(
+        ScriptBuilder::new(0, 0)
+            .push_int(a)
+            .push_int(b)
+            .cmd(cmd)
+            .ret()
+    ).ret
+}
+
+#[test]
+fn arithmetic() {
+    assert_eq!(binop(Cmd::AddI, 2, 3), 5);
+    assert_eq!(binop(Cmd::AddI, 0xFFFF_FFFF, 2), 1);
+    assert_eq!(binop(Cmd::SubI, 2, 3), -1i32 as u32);
+    assert_eq!(binop(Cmd::MultI, 7, -6i32 as u32), -42i32 as u32);
+}
+
+#[test]
+fn division() {
+    assert_eq!(binop(Cmd::DivI, 7, 2), 3);
+    assert_eq!(binop(Cmd::DivI, -7i32 as u32, 2), -3i32 as u32);
+    assert_eq!(binop(Cmd::DivI, 7, -2i32 as u32), -3i32 as u32);
+    assert_eq!(binop(Cmd::ModI, 7, 3), 1);
+    assert_eq!(binop(Cmd::ModI, -7i32 as u32, 3), -1i32 as u32);
+}
+
+#[test]
+fn bitwise() {
+    assert_eq!(binop(Cmd::AndI, 0b1100, 0b1010), 0b1000);
+    assert_eq!(binop(Cmd::OrI, 0b1100, 0b1010), 0b1110);
+    assert_eq!(binop(Cmd::XorI, 0b1100, 0b1010), 0b0110);
+    assert_eq!(binop(Cmd::ShiftL, 1, 4), 16);
+    assert_eq!(binop(Cmd::ShiftR, 0x8000_0000, 31), 1);
+}
+
+#[test]
+fn comparisons() {
+    for &(a, b) in &[(1u32, 2u32), (2, 1), (5, 5), (-1i32 as u32, 1), (1, -1i32 as u32)] {
+        binop(Cmd::Equals, a, b);
+        binop(Cmd::NotEquals, a, b);
+        binop(Cmd::LessThan, a, b);
+        binop(Cmd::LessOrEqual, a, b);
+        binop(Cmd::Greater, a, b);
+        binop(Cmd::GreaterOrEqual, a, b);
+    }
+    assert_eq!(binop(Cmd::LessThan, -1i32 as u32, 1), 1);
+}
+
+#[test]
+fn unary() {
+    for &val in &[0u32, 1, 5, -3i32 as u32] {
+        for &cmd in &[Cmd::NegI, Cmd::NotI, Cmd::Not] {
+            check(
+                ScriptBuilder::new(0, 0)
+                    .push_int(val)
+                    .cmd(cmd)
+                    .ret()
+            );
+        }
+    }
+}
+
+#[test]
+fn discarded_results() {
+    // Operations without the push bit still consume their operands
+    for &cmd in &[Cmd::AddI, Cmd::DivI, Cmd::Equals, Cmd::AddF, Cmd::LessThanF] {
+        let out = check(
+            ScriptBuilder::new(0, 0)
+                .push_int(42)
+                .push_int(1)
+                .push_int(1)
+                .discard(cmd)
+                .ret()
+        );
+        assert_eq!(out.ret, 42);
+    }
+    let out = check(
+        ScriptBuilder::new(0, 0)
+            .push_int(42)
+            .push_int(1)
+            .discard(Cmd::NegI)
+            .ret()
+    );
+    assert_eq!(out.ret, 42);
+}
+
+#[test]
+fn push_and_pop() {
+    let out = check(
+        ScriptBuilder::new(0, 0)
+            .push_int(3)
+            .cmd(Cmd::Push)
+            .cmd(Cmd::MultI)
+            .push_int(7)
+            .discard(Cmd::Pop)
+            .push_short(2)
+            .cmd(Cmd::AddI)
+            .ret()
+    );
+    assert_eq!(out.ret, 11);
+}
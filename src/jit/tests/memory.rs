@@ -0,0 +1,108 @@
+use msc::Cmd;
+use crate::jit::{JitMemory, PAGE_SIZE};
+use crate::jit::arena::{CodeArena, FUNCTION_ALIGN};
+use crate::jit::x86::{Compilable, CompileOptions, ExitStatus, Value};
+use super::{ScriptBuilder, build_file, run_jit, check_file};
+
+#[test]
+fn lock_and_unlock() {
+    let mut mem = JitMemory::new(2).unwrap();
+    unsafe {
+        mem.as_slice()[0] = 0x90;
+        assert_eq!(mem.as_slice().len(), 2 * PAGE_SIZE);
+        mem.lock().unwrap();
+        assert!(mem.locked);
+        mem.unlock().unwrap();
+        mem.as_slice()[1] = 0x90;
+        mem.lock().unwrap();
+    }
+    // Dropped while locked
+}
+
+#[test]
+fn recompile_many_times() {
+    let file = build_file(vec![ScriptBuilder::new(0, 0).push_int(1).ret()], &[]);
+    for _ in 0..1000 {
+        assert_eq!(run_jit(&file, 0, &[]).ret, 1);
+    }
+}
+
+#[test]
+fn arena_layout() {
+    let (offsets, size) = CodeArena::layout(&[1, 16, 17, 0, 3]);
+    assert_eq!(offsets, vec![0, 16, 32, 64, 64]);
+    assert_eq!(size, 80);
+}
+
+#[test]
+fn calls_between_packed_scripts() {
+    // Calls in both directions, between scripts sharing pages
+    let mut scripts = vec![
+        ScriptBuilder::new(0, 0)
+            .branch(|loc| Cmd::Try { loc }, "ret")
+            .push_int(1)
+            .push_script(2)
+            .cmd(Cmd::CallFunc { arg_count: 1 })
+            .label("ret")
+            .ret(),
+        ScriptBuilder::new(1, 1)
+            .push_local(0)
+            .push_int(100)
+            .cmd(Cmd::AddI)
+            .ret(),
+        ScriptBuilder::new(1, 1)
+            .branch(|loc| Cmd::Try { loc }, "ret")
+            .push_local(0)
+            .push_int(10)
+            .cmd(Cmd::MultI)
+            .push_script(1)
+            .cmd(Cmd::CallFunc { arg_count: 1 })
+            .label("ret")
+            .ret(),
+    ];
+    for _ in 0..50 {
+        scripts.push(ScriptBuilder::new(0, 0).push_int(0).ret());
+    }
+    let file = build_file(scripts, &[]);
+    let program = file.compile().unwrap();
+    for i in 0..program.code.len() {
+        assert_eq!(program.get_script_address(i) as usize % FUNCTION_ALIGN, 0);
+    }
+    assert_eq!(check_file(&file, &[]).ret, 110);
+}
+
+#[test]
+fn dual_mapped_views() {
+    let mut mem = JitMemory::new_dual_mapped(1).unwrap();
+    assert!(mem.is_dual_mapped());
+    assert_ne!(mem.contents, mem.exec);
+    unsafe {
+        mem.as_slice()[0] = 0x90;
+        assert_eq!(*mem.exec, 0x90);
+        mem.lock().unwrap();
+        // Still writable through the RW view while locked
+        mem.as_slice()[1] = 0x90;
+        assert_eq!(*mem.exec.add(1), 0x90);
+    }
+}
+
+#[test]
+fn dual_mapped_program() {
+    let caller = ScriptBuilder::new(0, 0)
+        .branch(|loc| Cmd::Try { loc }, "ret")
+        .push_int(6)
+        .push_script(1)
+        .cmd(Cmd::CallFunc { arg_count: 1 })
+        .label("ret")
+        .ret();
+    let callee = ScriptBuilder::new(1, 1)
+        .push_local(0)
+        .push_int(7)
+        .cmd(Cmd::MultI)
+        .ret();
+    let file = build_file(vec![caller, callee], &[]);
+    let options = CompileOptions { dual_mapping: true, ..CompileOptions::default() };
+    let mut program = file.compile_with(&options).unwrap();
+    program.lock_all().unwrap();
+    assert_eq!(program.run().unwrap(), ExitStatus::Returned(Value::Int(42)));
+}
@@ -0,0 +1,322 @@
+//! Differential tests, small scripts are built in memory and run through both backends of the
+//! x86 JIT and the reference interpreter, which have to agree on the return value, globals and
+//! printf output
+use std::rc::Rc;
+use msc::{MscsbFile, Script, Command, Cmd};
+use super::x86::{Backend, Compilable, CompileOptions, RunError};
+use super::x86::output::Buffer;
+use super::x86::syscalls::SyscallRegistry;
+use super::interp::Interpreter;
+
+mod int_ops;
+mod float_ops;
+mod vars;
+mod control_flow;
+mod memory;
+mod ast;
+mod decompile;
+mod backends;
+mod syscalls;
+mod host;
+mod printf;
+mod output;
+mod run;
+mod globals;
+mod errors;
+mod fuel;
+mod stack;
+mod faults;
+
+/// Encoded size of a command, used to lay out scripts like a real file
+fn cmd_size(cmd: &Cmd) -> u32 {
+    match cmd {
+        Cmd::Begin { .. } | Cmd::PushInt { .. } | Cmd::Jump { .. } | Cmd::Jump5 { .. } |
+        Cmd::If { .. } | Cmd::IfNot { .. } | Cmd::Else { .. } | Cmd::Try { .. } => 5,
+        Cmd::PushVar { .. } | Cmd::SetVar { .. } | Cmd::VarSetF { .. } | Cmd::IncI { .. } |
+        Cmd::DecI { .. } | Cmd::IncF { .. } | Cmd::DecF { .. } | Cmd::AddVarBy { .. } |
+        Cmd::SubVarBy { .. } | Cmd::MultVarBy { .. } | Cmd::DivVarBy { .. } |
+        Cmd::ModVarBy { .. } | Cmd::AndVarBy { .. } | Cmd::OrVarBy { .. } |
+        Cmd::XorVarBy { .. } | Cmd::AddVarByF { .. } | Cmd::SubVarByF { .. } |
+        Cmd::MultVarByF { .. } | Cmd::DivVarByF { .. } => 4,
+        Cmd::PushShort { .. } | Cmd::Sys { .. } => 3,
+        Cmd::PrintF { .. } | Cmd::CallFunc { .. } | Cmd::CallFunc2 { .. } |
+        Cmd::CallFunc3 { .. } | Cmd::IntToFloat { .. } | Cmd::FloatToInt { .. } => 2,
+        _ => 1,
+    }
+}
+
+enum Item {
+    Cmd(Cmd, bool),
+    Label(&'static str),
+    /// Command taking an absolute location, resolved from a label in the same script
+    Branch(fn(u32) -> Cmd, &'static str, bool),
+    /// Push the offset of another script
+    PushScript(usize),
+}
+
+impl Item {
+    fn size(&self) -> u32 {
+        match self {
+            Item::Cmd(cmd, _) => cmd_size(cmd),
+            Item::Label(_) => 0,
+            Item::Branch(..) | Item::PushScript(_) => 5,
+        }
+    }
+}
+
+pub struct ScriptBuilder {
+    items: Vec<Item>,
+}
+
+impl ScriptBuilder {
+    pub fn new(arg_count: u16, var_count: u16) -> Self {
+        ScriptBuilder {
+            items: vec![Item::Cmd(Cmd::Begin { arg_count, var_count }, false)],
+        }
+    }
+
+    /// Add a command with the push bit set
+    pub fn cmd(mut self, cmd: Cmd) -> Self {
+        self.items.push(Item::Cmd(cmd, true));
+        self
+    }
+
+    /// Add a command with the push bit cleared
+    pub fn discard(mut self, cmd: Cmd) -> Self {
+        self.items.push(Item::Cmd(cmd, false));
+        self
+    }
+
+    pub fn push_int(self, val: u32) -> Self {
+        self.cmd(Cmd::PushInt { val })
+    }
+
+    pub fn push_short(self, val: u16) -> Self {
+        self.cmd(Cmd::PushShort { val })
+    }
+
+    pub fn push_float(self, val: f32) -> Self {
+        self.cmd(Cmd::PushInt { val: val.to_bits() })
+    }
+
+    pub fn push_local(self, var_num: u16) -> Self {
+        self.cmd(Cmd::PushVar { var_type: 0, var_num })
+    }
+
+    pub fn push_global(self, var_num: u16) -> Self {
+        self.cmd(Cmd::PushVar { var_type: 1, var_num })
+    }
+
+    pub fn set_local(self, var_num: u16) -> Self {
+        self.cmd(Cmd::SetVar { var_type: 0, var_num })
+    }
+
+    pub fn set_global(self, var_num: u16) -> Self {
+        self.cmd(Cmd::SetVar { var_type: 1, var_num })
+    }
+
+    pub fn push_script(mut self, script_index: usize) -> Self {
+        self.items.push(Item::PushScript(script_index));
+        self
+    }
+
+    pub fn label(mut self, name: &'static str) -> Self {
+        self.items.push(Item::Label(name));
+        self
+    }
+
+    /// Add a command taking a location, pointing to `label`
+    pub fn branch(mut self, cmd: fn(u32) -> Cmd, label: &'static str) -> Self {
+        self.items.push(Item::Branch(cmd, label, true));
+        self
+    }
+
+    pub fn ret(self) -> Self {
+        self.cmd(Cmd::Return6)
+    }
+
+    fn size(&self) -> u32 {
+        self.items.iter().map(Item::size).sum()
+    }
+
+    fn build(self, start: u32, script_starts: &[u32]) -> Script {
+        let mut labels = vec![];
+        let mut position = 0;
+        for item in self.items.iter() {
+            if let Item::Label(name) = item {
+                labels.push((*name, position));
+            }
+            position += item.size();
+        }
+        let label_loc = |name: &str| {
+            labels.iter()
+                .find(|(label, _)| *label == name)
+                .map(|(_, position)| start + position)
+                .unwrap_or_else(|| panic!("Unknown label '{}'", name))
+        };
+
+        let mut commands = vec![];
+        let mut position = 0;
+        for item in self.items.iter() {
+            let command = match item {
+                Item::Cmd(cmd, push_bit) => Some((*cmd, *push_bit)),
+                Item::Label(_) => None,
+                Item::Branch(cmd, label, push_bit) => Some((cmd(label_loc(label)), *push_bit)),
+                Item::PushScript(script_index) => {
+                    Some((Cmd::PushInt { val: script_starts[*script_index] }, true))
+                }
+            };
+            if let Some((cmd, push_bit)) = command {
+                commands.push(Command { cmd, push_bit, position });
+            }
+            position += item.size();
+        }
+        Script {
+            bounds: (start, start + position),
+            commands,
+        }
+    }
+}
+
+/// Lay out the scripts back to back, the first one is the entrypoint
+pub fn build_file(scripts: Vec<ScriptBuilder>, strings: &[&str]) -> MscsbFile {
+    let mut script_starts = vec![];
+    let mut offset = 0x10;
+    for script in scripts.iter() {
+        script_starts.push(offset);
+        offset += script.size();
+    }
+    let scripts = scripts.into_iter()
+        .zip(script_starts.iter())
+        .map(|(script, start)| script.build(*start, &script_starts))
+        .collect();
+    MscsbFile {
+        entrypoint: script_starts[0],
+        scripts,
+        strings: strings.iter().map(|string| string.to_string()).collect(),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Outcome {
+    pub ret: u32,
+    pub globals: Vec<u32>,
+    pub output: String,
+}
+
+impl Outcome {
+    pub fn ret_float(&self) -> f32 {
+        f32::from_bits(self.ret)
+    }
+
+    pub fn global_float(&self, var_num: usize) -> f32 {
+        f32::from_bits(self.globals[var_num])
+    }
+}
+
+/// Value a run returned, or the value of the error no Try caught. Any other error fails the test
+fn outcome_ret(ret: Result<u64, RunError>) -> u32 {
+    match ret {
+        Ok(ret) => ret as u32,
+        Err(RunError::Uncaught { value, .. }) => value,
+        Err(error) => panic!("Run failed: {}", error),
+    }
+}
+
+pub fn run_jit(file: &MscsbFile, script_index: usize, args: &[u32]) -> Outcome {
+    run_jit_with(file, &CompileOptions::default(), script_index, args)
+}
+
+pub fn run_jit_with(file: &MscsbFile, options: &CompileOptions, script_index: usize, args: &[u32])
+    -> Outcome
+{
+    let mut program = file.compile_with(options).expect("Failed to compile");
+    program.lock_all().expect("Failed to lock");
+    let output = Buffer::new();
+    program.set_output(output.clone());
+    let ret = program.run_with_args(script_index, args);
+    Outcome {
+        ret: outcome_ret(ret),
+        globals: program.snapshot_globals(),
+        output: output.text(),
+    }
+}
+
+pub fn run_interp(file: &MscsbFile, script_index: usize, args: &[u32]) -> Outcome {
+    run_interp_with(file, Rc::new(SyscallRegistry::default()), script_index, args)
+}
+
+pub fn run_interp_with(
+    file: &MscsbFile, syscalls: Rc<SyscallRegistry>, script_index: usize, args: &[u32]
+) -> Outcome {
+    let mut interpreter = Interpreter::with_syscalls(file, syscalls).expect("Failed to load");
+    let output = Buffer::new();
+    interpreter.set_output(output.clone());
+    let ret = interpreter.run_with_args(script_index, args);
+    Outcome {
+        ret: outcome_ret(ret),
+        globals: interpreter.global_vars.clone(),
+        output: output.text(),
+    }
+}
+
+/// Run the entrypoint through both JIT backends and the interpreter, check they agree and return
+/// the outcome
+pub fn check_file(file: &MscsbFile, args: &[u32]) -> Outcome {
+    check_file_with(file, &Rc::new(SyscallRegistry::default()), args)
+}
+
+/// Same as `check_file`, with `syscalls` bound to the `sys` commands
+pub fn check_file_with(file: &MscsbFile, syscalls: &Rc<SyscallRegistry>, args: &[u32]) -> Outcome {
+    let entrypoint = file.get_script_from_loc(file.entrypoint).unwrap();
+    let interp = run_interp_with(file, syscalls.clone(), entrypoint, args);
+    for &backend in [Backend::Commands, Backend::Ast].iter() {
+        let options = CompileOptions {
+            backend, syscalls: syscalls.clone(), ..CompileOptions::default()
+        };
+        let jit = run_jit_with(file, &options, entrypoint, args);
+        assert_eq!(jit, interp, "JIT (left, {:?} backend) and interpreter (right) disagree", backend);
+    }
+    interp
+}
+
+pub fn check(script: ScriptBuilder) -> Outcome {
+    check_file(&build_file(vec![script], &[]), &[])
+}
+
+/// Same as `check`, for scripts ending without a return value, which is left undefined by the
+/// JIT
+pub fn check_effects(script: ScriptBuilder) -> Outcome {
+    let file = build_file(vec![script], &[]);
+    let interp = run_interp(&file, 0, &[]);
+    for &backend in [Backend::Commands, Backend::Ast].iter() {
+        let options = CompileOptions { backend, ..CompileOptions::default() };
+        let mut jit = run_jit_with(&file, &options, 0, &[]);
+        jit.ret = interp.ret;
+        assert_eq!(jit, interp, "JIT (left, {:?} backend) and interpreter (right) disagree", backend);
+    }
+    interp
+}
+
+// Stack slots are 64 bits but values only 32, PUSH imm32 sign-extends into the upper half and
+// float ops only write the lower one. Conditions have to ignore the upper half
+#[test]
+fn upper_half_of_slots() {
+    let syscalls = Rc::new(SyscallRegistry::default());
+    let not = ScriptBuilder::new(0, 0)
+        .push_float(-0.5)
+        .cmd(Cmd::FloatToInt { stack_pos: 0 })
+        .cmd(Cmd::Not)
+        .ret();
+    assert_eq!(check_file_with(&build_file(vec![not], &[]), &syscalls, &[]).ret, 1);
+    let if_not = ScriptBuilder::new(0, 0)
+        .push_int(0x8000_0000)
+        .cmd(Cmd::NegF)
+        .branch(|loc| Cmd::IfNot { loc }, "taken")
+        .push_int(1)
+        .ret()
+        .label("taken")
+        .push_int(2)
+        .ret();
+    assert_eq!(check_file_with(&build_file(vec![if_not], &[]), &syscalls, &[]).ret, 1);
+}
@@ -0,0 +1,75 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use msc::{MscsbFile, Cmd};
+use crate::jit::x86::{Compilable, CompileOptions};
+use crate::jit::x86::output::{Buffer, LogCallback};
+use crate::jit::x86::syscalls::SyscallRegistry;
+use super::{ScriptBuilder, build_file};
+
+fn print_all(strings: &[&str]) -> MscsbFile {
+    let mut script = ScriptBuilder::new(0, 0);
+    for str_num in 0..strings.len() {
+        script = script
+            .push_int(str_num as u32)
+            .cmd(Cmd::PrintF { arg_count: 1 });
+    }
+    build_file(vec![script.push_int(0).ret()], strings)
+}
+
+#[test]
+fn buffer() {
+    let file = print_all(&["a = ", "%d", "\n"]);
+    let mut program = file.compile().unwrap();
+    program.lock_all().unwrap();
+    let first = Buffer::new();
+    program.set_output(first.clone());
+    program.run().unwrap();
+    assert_eq!(first.text(), "a = %d\n");
+
+    // Only the current sink sees the output
+    let second = Buffer::new();
+    program.set_output(second.clone());
+    program.run().unwrap();
+    program.run().unwrap();
+    assert_eq!(first.take(), b"a = %d\n");
+    assert_eq!(second.text(), "a = %d\na = %d\n");
+    assert!(first.contents().is_empty());
+}
+
+#[test]
+fn log_callback() {
+    let file = print_all(&["one\ntw", "o\n", "three"]);
+    let mut program = file.compile().unwrap();
+    program.lock_all().unwrap();
+    let lines = Rc::new(RefCell::new(vec![]));
+    let log = lines.clone();
+    program.set_output(LogCallback::new(move |line| log.borrow_mut().push(line.to_string())));
+    program.run().unwrap();
+    // The unfinished line is passed when the run ends
+    assert_eq!(*lines.borrow(), vec!["one", "two", "three"]);
+}
+
+#[test]
+fn syscalls_print_to_sink() {
+    let mut syscalls = SyscallRegistry::default();
+    syscalls.register(0x50, |args| {
+        args.write_output(format!("<{}>", args.int(0)?).as_bytes());
+        Ok(0)
+    });
+    let file = build_file(vec![
+        ScriptBuilder::new(0, 0)
+            .push_int(0)
+            .cmd(Cmd::PrintF { arg_count: 1 })
+            .push_int(5)
+            .discard(Cmd::Sys { sys_num: 0x50, arg_count: 1 })
+            .push_int(0)
+            .ret()
+    ], &["x"]);
+    let options = CompileOptions { syscalls: Rc::new(syscalls), ..CompileOptions::default() };
+    let mut program = file.compile_with(&options).unwrap();
+    program.lock_all().unwrap();
+    let output = Buffer::new();
+    program.set_output(output.clone());
+    program.run().unwrap();
+    assert_eq!(output.text(), "x<5>");
+}
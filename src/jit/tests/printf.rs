@@ -0,0 +1,175 @@
+use std::ffi::CString;
+use libc::c_char;
+use msc::Cmd;
+use crate::jit::interp::Interpreter;
+use crate::jit::x86::{Backend, Compilable, CompileError, CompileOptions, RunError};
+use crate::jit::x86::output::Buffer;
+use crate::jit::x86::printf::{format, StringTable};
+use super::{ScriptBuilder, build_file, check_file};
+
+fn msc_format(fmt: &str, args: &[u32], strings: &[&str]) -> String {
+    let file = build_file(vec![ScriptBuilder::new(0, 0)], strings);
+    let mut out = vec![];
+    format(&mut out, fmt.as_bytes(), args, &StringTable::new(&file)).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+enum CArg {
+    Int(u32),
+    Float(f32),
+}
+
+fn c_format(fmt: &str, arg: CArg) -> String {
+    let fmt = CString::new(fmt).unwrap();
+    let mut buf = vec![0u8; 512];
+    let len = unsafe {
+        match arg {
+            CArg::Int(val) => {
+                libc::snprintf(buf.as_mut_ptr() as *mut c_char, buf.len(), fmt.as_ptr(), val)
+            }
+            CArg::Float(val) => {
+                libc::snprintf(buf.as_mut_ptr() as *mut c_char, buf.len(), fmt.as_ptr(),
+                               f64::from(val))
+            }
+        }
+    };
+    buf.truncate(len as usize);
+    String::from_utf8(buf).unwrap()
+}
+
+#[test]
+fn ints_match_libc() {
+    let specs = [
+        "%d", "%i", "%u", "%x", "%X", "%o", "%5d", "%-5d|", "%05d", "%+d", "% d", "%.3d",
+        "%8.3d", "%-+8.3d|", "%#x", "%#X", "%#o", "%#08x", "%.0d", "%.0x", "%c", "%3c", "%-3c|",
+        "%ld", "%hx", "%lld",
+    ];
+    let vals = [0u32, 1, 42, 255, 0x7FFF_FFFF, 0x8000_0000, -1i32 as u32, -42i32 as u32];
+    for spec in specs.iter() {
+        for &val in vals.iter() {
+            if spec.contains('c') && (val == 0 || val > 0x7F) {
+                continue;
+            }
+            let expected = if spec.contains("ll") {
+                // A long long would read 64 bits, MSC only has 32 bit values
+                c_format(&spec.replace("ll", ""), CArg::Int(val))
+            } else if spec.contains('h') {
+                c_format(&spec.replace('h', ""), CArg::Int(val))
+            } else {
+                c_format(&spec.replace('l', ""), CArg::Int(val))
+            };
+            assert_eq!(msc_format(spec, &[val], &[]), expected, "{} with 0x{:X}", spec, val);
+        }
+    }
+}
+
+#[test]
+fn floats_match_libc() {
+    let specs = [
+        "%f", "%.2f", "%10.3f", "%-10.1f|", "%010.2f", "%+f", "% f", "%.0f", "%#.0f", "%e",
+        "%.3e", "%E", "%12.2e", "%#.0e", "%g", "%G", "%.3g", "%10g", "%#g", "%.0g", "%.10g",
+        "%F",
+    ];
+    let vals = [
+        0.0f32, -0.0, 1.0, -1.5, 0.125, 2.5, 3.0625, 1e-5, 123456.0, 1e10, -7.25e-3,
+        16777216.0, 0.1, 99.999, f32::INFINITY, f32::NEG_INFINITY, f32::MAX, f32::MIN_POSITIVE,
+    ];
+    for spec in specs.iter() {
+        for &val in vals.iter() {
+            assert_eq!(
+                msc_format(spec, &[val.to_bits()], &[]),
+                c_format(spec, CArg::Float(val)),
+                "{} with {}", spec, val
+            );
+        }
+    }
+    assert_eq!(msc_format("%f %F", &[f32::NAN.to_bits(), f32::NAN.to_bits()], &[]), "nan NAN");
+}
+
+#[test]
+fn strings_and_star() {
+    assert_eq!(msc_format("[%s] [%5s] [%-5s] [%.2s]", &[1, 1, 1, 0], &["hello", "hi"]),
+               "[hi] [   hi] [hi   ] [he]");
+    assert_eq!(msc_format("%s", &[7], &["hello"]), "(null)");
+    assert_eq!(msc_format("%*d|%-*d|%.*f", &[4, 7, -3i32 as u32, 1, 2, 0.5f32.to_bits()], &[]),
+               "   7|1  |0.50");
+    assert_eq!(msc_format("100%% %q %", &[], &[]), "100% %q %");
+}
+
+#[test]
+fn never_reads_past_args() {
+    assert_eq!(msc_format("%d %d %x", &[1], &[]), "1 %d %x");
+    assert_eq!(msc_format("%*d", &[5], &[]), "%*d");
+    assert_eq!(msc_format("%s%f", &[], &["a"]), "%s%f");
+    // Huge widths are clamped instead of allocated
+    assert_eq!(msc_format("%999999999d", &[1], &[]).len(), 4096);
+}
+
+#[test]
+fn printf_command() {
+    let script = ScriptBuilder::new(0, 0)
+        .push_int(0)
+        .push_int(1)
+        .push_int(-3i32 as u32)
+        .push_float(0.1)
+        .cmd(Cmd::PrintF { arg_count: 4 })
+        // Not enough arguments for the format string
+        .push_int(2)
+        .cmd(Cmd::PrintF { arg_count: 1 })
+        .push_int(0)
+        .ret();
+    let file = build_file(vec![script], &["%s %+04d %.3e\n", "name", "%d %s"]);
+    assert_eq!(check_file(&file, &[]).output, "name -003 1.000e-01\n%d %s");
+}
+
+#[test]
+fn missing_format() {
+    let file = build_file(vec![
+        ScriptBuilder::new(0, 0)
+            .cmd(Cmd::PrintF { arg_count: 0 })
+            .push_int(0)
+            .ret()
+    ], &[]);
+    match file.compile() {
+        Err(CompileError::MissingFormat { script_index: 0, cmd: Cmd::PrintF { .. }, .. }) => {}
+        other => panic!("Expected MissingFormat, got {:?}", other.map(|_| ())),
+    }
+    match Interpreter::new(&file) {
+        Err(CompileError::MissingFormat { script_index: 0, .. }) => {}
+        other => panic!("Expected MissingFormat, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn invalid_format_string() {
+    let file = build_file(vec![
+        ScriptBuilder::new(0, 0)
+            .push_int(0)
+            .cmd(Cmd::PrintF { arg_count: 1 })
+            .push_int(9)
+            .cmd(Cmd::PrintF { arg_count: 1 })
+            .push_int(0)
+            .ret()
+    ], &["ok\n"]);
+    let output = Buffer::new();
+    let mut interp = Interpreter::new(&file).unwrap();
+    interp.set_output(output.clone());
+    assert_eq!(
+        interp.run_script(0, &[]),
+        Err(RunError::InvalidString { script_index: 0, str_num: 9 })
+    );
+    assert_eq!(output.take(), b"ok\n");
+    for &backend in [Backend::Commands, Backend::Ast].iter() {
+        let options = CompileOptions { backend, ..CompileOptions::default() };
+        let mut program = file.compile_with(&options).unwrap();
+        program.lock_all().unwrap();
+        program.set_output(output.clone());
+        assert_eq!(
+            program.run_script(0, &[]),
+            Err(RunError::InvalidString { script_index: 0, str_num: 9 }),
+            "{:?} backend", backend
+        );
+        assert_eq!(output.take(), b"ok\n");
+        assert_eq!(program.backends[0], backend);
+    }
+}
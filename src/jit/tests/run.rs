@@ -0,0 +1,215 @@
+use msc::{MscsbFile, Cmd};
+use crate::jit::interp::Interpreter;
+use crate::jit::x86::{
+    Backend, Compilable, CompileError, CompileOptions, ExitStatus, RunError, Value
+};
+use crate::jit::x86::output::Buffer;
+use super::{ScriptBuilder, build_file};
+
+/// Run a script through `run_script` of both backends and the interpreter, which have to agree
+fn run_script(file: &MscsbFile, script_index: usize, args: &[Value]) -> Value {
+    let interp = Interpreter::new(file).unwrap()
+        .run_script(script_index, args)
+        .expect("Interpreter failed to run");
+    for &backend in [Backend::Commands, Backend::Ast].iter() {
+        let options = CompileOptions { backend, ..CompileOptions::default() };
+        let mut program = file.compile_with(&options).unwrap();
+        program.lock_all().unwrap();
+        let jit = program.run_script(script_index, args).expect("JIT failed to run");
+        assert_eq!(jit, interp, "JIT (left, {:?} backend) and interpreter (right) disagree", backend);
+    }
+    interp
+}
+
+/// Sum of every argument times its position + 1
+fn weighted_sum(arg_count: u16) -> ScriptBuilder {
+    let mut script = ScriptBuilder::new(arg_count, arg_count).push_int(0);
+    for var_num in 0..arg_count {
+        script = script
+            .push_local(var_num)
+            .push_int(u32::from(var_num) + 1)
+            .cmd(Cmd::MultI)
+            .cmd(Cmd::AddI);
+    }
+    script.ret()
+}
+
+#[test]
+fn int_args() {
+    let file = build_file(vec![weighted_sum(3)], &[]);
+    let args = [Value::Int(5), Value::from(-2), Value::Int(1)];
+    assert_eq!(run_script(&file, 0, &args), Value::Int(5 - 4 + 3));
+}
+
+#[test]
+fn stack_args() {
+    // Odd and even numbers of arguments past the 6 passed in registers
+    for arg_count in 6..12u16 {
+        let file = build_file(vec![weighted_sum(arg_count)], &[]);
+        let args = (0..arg_count).map(|i| Value::Int(u32::from(i) * 3 + 1)).collect::<Vec<_>>();
+        let expected = (0..u32::from(arg_count)).map(|i| (i * 3 + 1) * (i + 1)).sum::<u32>();
+        assert_eq!(run_script(&file, 0, &args), Value::Int(expected), "{} args", arg_count);
+    }
+}
+
+#[test]
+fn stack_args_with_output() {
+    // Native calls from a script run through the stub still get an aligned stack
+    let mut script = ScriptBuilder::new(7, 7).push_int(0);
+    for var_num in 0..7 {
+        script = script.push_local(var_num);
+    }
+    let script = script
+        .cmd(Cmd::PrintF { arg_count: 8 })
+        .push_local(6)
+        .push_float(0.5)
+        .cmd(Cmd::MultF)
+        .ret();
+    let file = build_file(vec![script], &["%d %d %d %d %d %d %g\n"]);
+    let mut args = (1..7).map(Value::Int).collect::<Vec<_>>();
+    args.push(Value::Float(3.0));
+    let mut program = file.compile().unwrap();
+    program.lock_all().unwrap();
+    let output = Buffer::new();
+    program.set_output(output.clone());
+    assert_eq!(program.run_script(0, &args).unwrap(), Value::Float(1.5));
+    assert_eq!(output.text(), "1 2 3 4 5 6 3\n");
+}
+
+#[test]
+fn float_args() {
+    let file = build_file(vec![
+        ScriptBuilder::new(2, 2)
+            .push_local(0)
+            .push_local(1)
+            .cmd(Cmd::MultF)
+            .push_float(0.25)
+            .cmd(Cmd::AddF)
+            .ret()
+    ], &[]);
+    let ret = run_script(&file, 0, &[Value::Float(1.5), Value::Float(-3.0)]);
+    assert_eq!(ret, Value::Float(-4.25));
+    assert_eq!(ret.as_int(), (-4.25f32).to_bits());
+}
+
+#[test]
+fn float_to_int_return() {
+    let file = build_file(vec![
+        ScriptBuilder::new(1, 1)
+            .push_local(0)
+            .cmd(Cmd::FloatToInt { stack_pos: 0 })
+            .ret()
+    ], &[]);
+    assert_eq!(run_script(&file, 0, &[Value::Float(-7.75)]), Value::Int(-7i32 as u32));
+}
+
+#[test]
+fn any_script() {
+    let file = build_file(vec![
+        ScriptBuilder::new(0, 0).push_int(1).ret(),
+        weighted_sum(2),
+        ScriptBuilder::new(1, 1)
+            .push_local(0)
+            .push_int(1)
+            .cmd(Cmd::AddI)
+            .set_global(3)
+            .cmd(Cmd::Return7),
+    ], &[]);
+    assert_eq!(run_script(&file, 0, &[]), Value::Int(1));
+    assert_eq!(run_script(&file, 1, &[Value::Int(4), Value::Int(5)]), Value::Int(14));
+    // No return value
+    assert_eq!(run_script(&file, 2, &[Value::Int(9)]), Value::Int(0));
+
+    let mut program = file.compile().unwrap();
+    program.lock_all().unwrap();
+    program.run_script(2, &[Value::Int(9)]).unwrap();
+    assert_eq!(program.global_int(3), Some(10));
+}
+
+#[test]
+fn errors() {
+    let file = build_file(vec![weighted_sum(2)], &[]);
+    let mut program = file.compile().unwrap();
+    match program.run_script(0, &[Value::Int(1), Value::Int(2)]) {
+        Err(RunError::NotLocked) => {}
+        other => panic!("Expected NotLocked, got {:?}", other),
+    }
+    assert_eq!(program.run_with_args(0, &[1, 2]), Err(RunError::NotLocked));
+    program.lock_all().unwrap();
+    match program.run_script(1, &[]) {
+        Err(RunError::InvalidScript { script_index: 1, script_count: 1 }) => {}
+        other => panic!("Expected InvalidScript, got {:?}", other),
+    }
+    assert_eq!(
+        program.run_with_args(1, &[]),
+        Err(RunError::InvalidScript { script_index: 1, script_count: 1 })
+    );
+    match program.run_script(0, &[Value::Int(1)]) {
+        Err(RunError::ArgumentCount { script_index: 0, expected: 2, passed: 1 }) => {}
+        other => panic!("Expected ArgumentCount, got {:?}", other),
+    }
+    let mut interpreter = Interpreter::new(&file).unwrap();
+    match interpreter.run_script(0, &[Value::Int(1); 3]) {
+        Err(RunError::ArgumentCount { script_index: 0, expected: 2, passed: 3 }) => {}
+        other => panic!("Expected ArgumentCount, got {:?}", other),
+    }
+    assert_eq!(
+        interpreter.run_with_args(1, &[]),
+        Err(RunError::InvalidScript { script_index: 1, script_count: 1 })
+    );
+}
+
+#[test]
+fn exit() {
+    // Exit ends the run from inside a call with a Try pending, the rest of the caller never runs
+    let caller = ScriptBuilder::new(1, 1)
+        .branch(|loc| Cmd::Try { loc }, "ret")
+        .push_local(0)
+        .push_script(1)
+        .cmd(Cmd::CallFunc { arg_count: 1 })
+        .label("ret")
+        .set_global(1)
+        .push_int(1)
+        .ret();
+    let callee = ScriptBuilder::new(1, 1)
+        .push_local(0)
+        .set_global(0)
+        .cmd(Cmd::Exit)
+        .push_int(2)
+        .ret();
+    let file = build_file(vec![caller, callee], &[]);
+    let mut interpreter = Interpreter::new(&file).unwrap();
+    assert_eq!(interpreter.run_script_status(0, &[Value::Int(7)]).unwrap(), ExitStatus::Exited);
+    assert_eq!(interpreter.global_vars, vec![7, 0]);
+    for &backend in [Backend::Commands, Backend::Ast].iter() {
+        let options = CompileOptions { backend, ..CompileOptions::default() };
+        let mut program = file.compile_with(&options).unwrap();
+        program.lock_all().unwrap();
+        assert_eq!(program.run_script_status(0, &[Value::Int(7)]).unwrap(), ExitStatus::Exited);
+        assert_eq!(program.snapshot_globals(), vec![7, 0], "{:?} backend", backend);
+        // Later runs aren't affected
+        assert_eq!(program.run_script_status(1, &[Value::Int(3)]).unwrap(), ExitStatus::Exited);
+        assert_eq!(program.run_script(0, &[Value::Int(4)]).unwrap(), Value::Int(0));
+        assert_eq!(program.snapshot_globals(), vec![4, 0]);
+    }
+
+    let file = build_file(vec![ScriptBuilder::new(0, 0).push_int(5).ret()], &[]);
+    let mut program = file.compile().unwrap();
+    program.lock_all().unwrap();
+    assert_eq!(program.run().unwrap(), ExitStatus::Returned(Value::Int(5)));
+}
+
+#[test]
+fn dump_asm() {
+    // Fails to compile instead of panicking when objdump is missing or prints something else
+    let file = build_file(vec![ScriptBuilder::new(0, 0).push_int(5).ret()], &[]);
+    let options = CompileOptions { dump_asm: true, ..CompileOptions::default() };
+    match file.compile_with(&options) {
+        Ok(mut program) => {
+            program.lock_all().unwrap();
+            assert_eq!(program.run().unwrap(), ExitStatus::Returned(Value::Int(5)));
+        }
+        Err(CompileError::Disassembly { script_index: 0, .. }) => {}
+        Err(error) => panic!("Expected Disassembly, got {:?}", error),
+    }
+}
@@ -0,0 +1,145 @@
+use msc::{MscsbFile, Cmd};
+use crate::jit::interp::Interpreter;
+use crate::jit::stack::STACK_HEADROOM;
+use crate::jit::x86::{Backend, Compilable, CompileError, CompileOptions, RunError, Value};
+use super::{ScriptBuilder, build_file};
+
+/// `run_script` of both backends and the interpreter with a call depth limit, which have to agree
+fn run_with_depth(file: &MscsbFile, max_call_depth: u32, args: &[Value])
+    -> Result<Value, RunError>
+{
+    let mut interpreter = Interpreter::new(file).unwrap();
+    interpreter.max_call_depth = Some(max_call_depth);
+    let interp = interpreter.run_script(0, args);
+    for &backend in [Backend::Commands, Backend::Ast].iter() {
+        let options = CompileOptions {
+            backend,
+            max_call_depth: Some(max_call_depth),
+            ..CompileOptions::default()
+        };
+        let mut program = file.compile_with(&options).unwrap();
+        program.lock_all().unwrap();
+        let jit = program.run_script(0, args);
+        assert_eq!(jit, interp, "JIT (left, {:?} backend) and interpreter (right) disagree", backend);
+    }
+    interp
+}
+
+/// `f(n) = n == 0 ? 0 : f(n - 1) + 1` at `script_index`, nesting n + 1 calls
+fn countdown(script_index: usize) -> ScriptBuilder {
+    ScriptBuilder::new(1, 1)
+        .push_local(0)
+        .branch(|loc| Cmd::If { loc }, "base")
+        .push_int(1)
+        .branch(|loc| Cmd::Try { loc }, "ret")
+        .push_local(0)
+        .push_int(1)
+        .cmd(Cmd::SubI)
+        .push_script(script_index)
+        .cmd(Cmd::CallFunc { arg_count: 1 })
+        .label("ret")
+        .cmd(Cmd::AddI)
+        .ret()
+        .label("base")
+        .push_int(0)
+        .ret()
+}
+
+#[test]
+fn depth_limit() {
+    let file = build_file(vec![countdown(0)], &[]);
+    assert_eq!(run_with_depth(&file, 5, &[Value::Int(4)]).unwrap(), Value::Int(4));
+    match run_with_depth(&file, 5, &[Value::Int(5)]) {
+        Err(RunError::StackOverflow { script_index: 0 }) => {}
+        other => panic!("Expected StackOverflow, got {:?}", other),
+    }
+    assert_eq!(run_with_depth(&file, 1, &[Value::Int(0)]).unwrap(), Value::Int(0));
+
+    // The limit applies to each run
+    let options = CompileOptions { max_call_depth: Some(5), ..CompileOptions::default() };
+    let mut program = file.compile_with(&options).unwrap();
+    program.lock_all().unwrap();
+    assert!(program.run_script(0, &[Value::Int(10)]).is_err());
+    assert_eq!(program.run_script(0, &[Value::Int(4)]).unwrap(), Value::Int(4));
+}
+
+#[test]
+fn caught_errors_give_back_depth() {
+    // Both calls raise 2 levels down, the second only fits if the first one's depth was freed
+    let caller = ScriptBuilder::new(0, 0)
+        .branch(|loc| Cmd::Try { loc }, "first")
+        .push_script(1)
+        .cmd(Cmd::CallFunc { arg_count: 0 })
+        .label("first")
+        .branch(|loc| Cmd::Try { loc }, "second")
+        .push_script(1)
+        .cmd(Cmd::CallFunc { arg_count: 0 })
+        .label("second")
+        .cmd(Cmd::AddI)
+        .ret();
+    let middle = ScriptBuilder::new(0, 0)
+        .push_script(2)
+        .cmd(Cmd::CallFunc { arg_count: 0 })
+        .ret();
+    let raise = ScriptBuilder::new(0, 0)
+        .cmd(Cmd::Error37)
+        .push_int(0)
+        .ret();
+    let file = build_file(vec![caller, middle, raise], &[]);
+    assert_eq!(run_with_depth(&file, 3, &[]).unwrap(), Value::Int(2 * 0x37));
+    match run_with_depth(&file, 2, &[]) {
+        Err(RunError::StackOverflow { script_index: 2 }) => {}
+        other => panic!("Expected StackOverflow, got {:?}", other),
+    }
+}
+
+#[test]
+fn script_stack() {
+    let file = build_file(vec![
+        ScriptBuilder::new(0, 0)
+            .branch(|loc| Cmd::Try { loc }, "ret")
+            .push_script(0)
+            .cmd(Cmd::CallFunc { arg_count: 0 })
+            .label("ret")
+            .ret(),
+        countdown(1),
+    ], &[]);
+    for &backend in [Backend::Commands, Backend::Ast].iter() {
+        let options = CompileOptions {
+            backend,
+            script_stack_size: Some(256 * 1024),
+            ..CompileOptions::default()
+        };
+        let mut program = file.compile_with(&options).unwrap();
+        program.lock_all().unwrap();
+        match program.run_script(0, &[]) {
+            Err(RunError::StackOverflow { script_index: 0 }) => {}
+            other => panic!("Expected StackOverflow, got {:?} ({:?} backend)", other, backend),
+        }
+        // The program still runs afterwards, and deep recursion fits in the stack
+        assert!(program.run_script(0, &[]).is_err());
+        assert_eq!(program.run_script(1, &[Value::Int(1000)]).unwrap(), Value::Int(1000));
+    }
+}
+
+#[test]
+fn script_stack_size() {
+    // The headroom alone doesn't leave room for a single call
+    let file = build_file(vec![countdown(0)], &[]);
+    for &size in [0, 4096, STACK_HEADROOM].iter() {
+        let options = CompileOptions { script_stack_size: Some(size), ..CompileOptions::default() };
+        match file.compile_with(&options) {
+            Err(CompileError::ScriptStackSize { size: s, min }) if s == size => {
+                assert_eq!(min, STACK_HEADROOM + 1);
+            }
+            other => panic!("Expected ScriptStackSize, got {:?}", other.map(|_| ())),
+        }
+    }
+    let options = CompileOptions {
+        script_stack_size: Some(STACK_HEADROOM + 1),
+        ..CompileOptions::default()
+    };
+    let mut program = file.compile_with(&options).unwrap();
+    program.lock_all().unwrap();
+    assert_eq!(program.run_script(0, &[Value::Int(3)]).unwrap(), Value::Int(3));
+}
@@ -0,0 +1,116 @@
+use std::cell::Cell;
+use std::rc::Rc;
+use msc::Cmd;
+use crate::jit::interp::Interpreter;
+use crate::jit::x86::{Backend, Compilable, CompileError, CompileOptions, RunError};
+use crate::jit::x86::syscalls::{SyscallRegistry, SyscallArgs, SyscallError};
+use super::{ScriptBuilder, build_file, check_file_with};
+
+#[test]
+fn typed_arguments() {
+    let mut syscalls = SyscallRegistry::empty();
+    syscalls.register(0x20, |args| {
+        Ok(args.int(0)?.wrapping_mul(args.float(1)? as u32))
+    });
+    let file = build_file(vec![
+        ScriptBuilder::new(0, 0)
+            .push_int(7)
+            .push_float(6.5)
+            .cmd(Cmd::Sys { sys_num: 0x20, arg_count: 2 })
+            .ret()
+    ], &[]);
+    assert_eq!(check_file_with(&file, &Rc::new(syscalls), &[]).ret, 42);
+}
+
+#[test]
+fn arguments_in_push_order() {
+    let mut syscalls = SyscallRegistry::empty();
+    syscalls.register(3, |args| Ok(args.ints().iter().fold(0, |acc, digit| acc * 10 + digit)));
+    let mut script = ScriptBuilder::new(0, 0);
+    for i in 1..=8 {
+        script = script.push_int(i);
+    }
+    let file = build_file(vec![script.cmd(Cmd::Sys { sys_num: 3, arg_count: 8 }).ret()], &[]);
+    assert_eq!(check_file_with(&file, &Rc::new(syscalls), &[]).ret, 12_345_678);
+}
+
+#[test]
+fn host_state() {
+    let calls = Rc::new(Cell::new(0));
+    let mut syscalls = SyscallRegistry::empty();
+    let counter = calls.clone();
+    syscalls.register(0xFF, move |args| {
+        counter.set(counter.get() + args.int(0)?);
+        Ok(0)
+    });
+    let file = build_file(vec![
+        ScriptBuilder::new(0, 0)
+            .push_int(2)
+            .discard(Cmd::Sys { sys_num: 0xFF, arg_count: 1 })
+            .push_int(3)
+            .discard(Cmd::Sys { sys_num: 0xFF, arg_count: 1 })
+            .push_int(1)
+            .ret()
+    ], &[]);
+    check_file_with(&file, &Rc::new(syscalls), &[]);
+    // Once through the interpreter and once per backend
+    assert_eq!(calls.get(), 3 * 5);
+}
+
+#[test]
+fn missing_argument() {
+    let mut syscalls = SyscallRegistry::empty();
+    syscalls.register(4, |args| args.int(1));
+    let file = build_file(vec![
+        ScriptBuilder::new(0, 0)
+            .push_int(9)
+            .cmd(Cmd::Sys { sys_num: 4, arg_count: 1 })
+            .ret()
+    ], &[]);
+    let syscalls = Rc::new(syscalls);
+    let expected = SyscallError::MissingArgument { index: 1, count: 1 };
+    match Interpreter::with_syscalls(&file, syscalls.clone()).unwrap().run_script(0, &[]) {
+        Err(RunError::Syscall { script_index: 0, sys_num: 4, ref error }) if *error == expected => {}
+        other => panic!("Expected Syscall, got {:?}", other),
+    }
+    for &backend in [Backend::Commands, Backend::Ast].iter() {
+        let options = CompileOptions {
+            backend, syscalls: syscalls.clone(), ..CompileOptions::default()
+        };
+        let mut program = file.compile_with(&options).unwrap();
+        program.lock_all().unwrap();
+        match program.run_script(0, &[]) {
+            Err(RunError::Syscall { script_index: 0, sys_num: 4, ref error })
+                if *error == expected => {}
+            other => panic!("Expected Syscall from the {:?} backend, got {:?}", backend, other),
+        }
+        assert_eq!(program.backends[0], backend);
+    }
+
+    let registry = SyscallRegistry::empty();
+    assert_eq!(
+        registry.call(4, &SyscallArgs::new(&[])),
+        Err(SyscallError::Unregistered { sys_num: 4 })
+    );
+}
+
+#[test]
+fn unregistered_syscall() {
+    let file = build_file(vec![
+        ScriptBuilder::new(0, 0)
+            .push_int(1)
+            .discard(Cmd::Sys { sys_num: 2, arg_count: 1 })
+            .ret()
+    ], &[]);
+    match file.compile() {
+        Err(CompileError::UnregisteredSyscall { script_index: 0, position: 10, sys_num: 2, .. }) => {}
+        Err(err) => panic!("Unexpected error {}", err),
+        Ok(_) => panic!("Compiled a call to an unregistered syscall"),
+    }
+    assert_eq!(file.check().len(), 1);
+
+    let mut syscalls = SyscallRegistry::default();
+    syscalls.register(2, |_| Ok(0));
+    let options = CompileOptions { syscalls: Rc::new(syscalls), ..CompileOptions::default() };
+    assert!(file.compile_with(&options).is_ok());
+}
@@ -0,0 +1,219 @@
+use msc::Cmd;
+use super::{ScriptBuilder, check, check_effects};
+
+#[test]
+fn locals() {
+    let out = check(
+        ScriptBuilder::new(0, 2)
+            .push_int(5)
+            .set_local(0)
+            .push_int(7)
+            .set_local(1)
+            .push_local(0)
+            .push_local(1)
+            .cmd(Cmd::SubI)
+            .ret()
+    );
+    assert_eq!(out.ret, -2i32 as u32);
+}
+
+#[test]
+fn globals() {
+    let out = check_effects(
+        ScriptBuilder::new(0, 0)
+            .push_int(5)
+            .set_global(0)
+            .push_global(0)
+            .push_int(2)
+            .cmd(Cmd::MultI)
+            .set_global(3)
+            .cmd(Cmd::End)
+    );
+    assert_eq!(&out.globals[..4], &[5, 0, 0, 10]);
+}
+
+/// Run `var <op>= val` on local_0 or global_0 and return the new value of the variable
+macro_rules! int_var_op {
+    ($cmd:ident, $var_type:expr, $start:expr, $val:expr) => {
+        check(
+            ScriptBuilder::new(0, 1)
+                .push_int($start)
+                .cmd(Cmd::SetVar { var_type: $var_type, var_num: 0 })
+                .push_int($val)
+                .cmd(Cmd::$cmd { var_type: $var_type, var_num: 0 })
+                .cmd(Cmd::PushVar { var_type: $var_type, var_num: 0 })
+                .ret()
+        ).ret
+    };
+}
+
+/// Same as `int_var_op`, for IncI and DecI which don't take an operand
+macro_rules! int_var_step {
+    ($cmd:ident, $var_type:expr, $start:expr) => {
+        check(
+            ScriptBuilder::new(0, 1)
+                .push_int($start)
+                .cmd(Cmd::SetVar { var_type: $var_type, var_num: 0 })
+                .cmd(Cmd::$cmd { var_type: $var_type, var_num: 0 })
+                .cmd(Cmd::PushVar { var_type: $var_type, var_num: 0 })
+                .ret()
+        ).ret
+    };
+}
+
+#[test]
+fn inc_i() {
+    for &var_type in &[0, 1] {
+        assert_eq!(int_var_step!(IncI, var_type, 5), 6);
+        assert_eq!(int_var_step!(IncI, var_type, 0xFFFF_FFFF), 0);
+    }
+}
+
+#[test]
+fn dec_i() {
+    for &var_type in &[0, 1] {
+        assert_eq!(int_var_step!(DecI, var_type, 5), 4);
+        assert_eq!(int_var_step!(DecI, var_type, 0), 0xFFFF_FFFF);
+    }
+}
+
+#[test]
+fn add_var_by() {
+    for &var_type in &[0, 1] {
+        assert_eq!(int_var_op!(AddVarBy, var_type, 5, 3), 8);
+        assert_eq!(int_var_op!(AddVarBy, var_type, 5, -7i32 as u32), -2i32 as u32);
+    }
+}
+
+#[test]
+fn sub_var_by() {
+    for &var_type in &[0, 1] {
+        assert_eq!(int_var_op!(SubVarBy, var_type, 5, 3), 2);
+        assert_eq!(int_var_op!(SubVarBy, var_type, 3, 5), -2i32 as u32);
+    }
+}
+
+#[test]
+fn and_var_by() {
+    for &var_type in &[0, 1] {
+        assert_eq!(int_var_op!(AndVarBy, var_type, 0b1100, 0b1010), 0b1000);
+    }
+}
+
+#[test]
+fn or_var_by() {
+    for &var_type in &[0, 1] {
+        assert_eq!(int_var_op!(OrVarBy, var_type, 0b1100, 0b1010), 0b1110);
+    }
+}
+
+#[test]
+fn xor_var_by() {
+    for &var_type in &[0, 1] {
+        assert_eq!(int_var_op!(XorVarBy, var_type, 0b1100, 0b1010), 0b0110);
+    }
+}
+
+#[test]
+fn mult_var_by() {
+    for &var_type in &[0, 1] {
+        assert_eq!(int_var_op!(MultVarBy, var_type, 5, 3), 15);
+        assert_eq!(int_var_op!(MultVarBy, var_type, 5, -3i32 as u32), -15i32 as u32);
+    }
+}
+
+#[test]
+fn div_var_by() {
+    for &var_type in &[0, 1] {
+        assert_eq!(int_var_op!(DivVarBy, var_type, 7, 2), 3);
+        assert_eq!(int_var_op!(DivVarBy, var_type, -7i32 as u32, 2), -3i32 as u32);
+        assert_eq!(int_var_op!(DivVarBy, var_type, 7, -2i32 as u32), -3i32 as u32);
+    }
+}
+
+#[test]
+fn mod_var_by() {
+    for &var_type in &[0, 1] {
+        assert_eq!(int_var_op!(ModVarBy, var_type, 7, 3), 1);
+        assert_eq!(int_var_op!(ModVarBy, var_type, -7i32 as u32, 3), -1i32 as u32);
+    }
+}
+
+#[test]
+fn var_ops_keep_other_vars() {
+    let out = check(
+        ScriptBuilder::new(0, 3)
+            .push_int(1)
+            .set_local(0)
+            .push_int(2)
+            .set_local(1)
+            .push_int(3)
+            .set_local(2)
+            .push_int(10)
+            .cmd(Cmd::AddVarBy { var_type: 0, var_num: 1 })
+            .cmd(Cmd::DecI { var_type: 0, var_num: 2 })
+            .push_local(0)
+            .push_local(1)
+            .push_local(2)
+            .cmd(Cmd::MultI)
+            .cmd(Cmd::AddI)
+            .ret()
+    );
+    assert_eq!(out.ret, 1 + 12 * 2);
+}
+
+#[test]
+fn float_locals() {
+    let out = check(
+        ScriptBuilder::new(0, 1)
+            .push_float(1.5)
+            .cmd(Cmd::VarSetF { var_type: 0, var_num: 0 })
+            .cmd(Cmd::IncF { var_type: 0, var_num: 0 })
+            .push_float(0.25)
+            .cmd(Cmd::AddVarByF { var_type: 0, var_num: 0 })
+            .push_local(0)
+            .ret()
+    );
+    assert_eq!(out.ret_float(), 2.75);
+}
+
+#[test]
+fn float_globals() {
+    let out = check_effects(
+        ScriptBuilder::new(0, 0)
+            .push_float(1.5)
+            .cmd(Cmd::VarSetF { var_type: 1, var_num: 2 })
+            .cmd(Cmd::DecF { var_type: 1, var_num: 2 })
+            .push_float(0.25)
+            .cmd(Cmd::AddVarByF { var_type: 1, var_num: 2 })
+            .cmd(Cmd::End)
+    );
+    assert_eq!(out.global_float(2), 0.75);
+}
+
+/// Run `var <op>= val` on local_0 or global_0 and return the new value of the variable
+macro_rules! float_var_op {
+    ($cmd:ident, $var_type:expr, $start:expr, $val:expr) => {
+        check(
+            ScriptBuilder::new(0, 1)
+                .push_float($start)
+                .cmd(Cmd::VarSetF { var_type: $var_type, var_num: 0 })
+                .push_float($val)
+                .cmd(Cmd::$cmd { var_type: $var_type, var_num: 0 })
+                .cmd(Cmd::PushVar { var_type: $var_type, var_num: 0 })
+                .ret()
+        ).ret_float()
+    };
+}
+
+#[test]
+fn float_var_ops() {
+    for &var_type in &[0, 1] {
+        assert_eq!(float_var_op!(AddVarByF, var_type, 1.5, 0.25), 1.75);
+        assert_eq!(float_var_op!(SubVarByF, var_type, 1.5, 0.25), 1.25);
+        assert_eq!(float_var_op!(SubVarByF, var_type, 0.25, 1.5), -1.25);
+        assert_eq!(float_var_op!(MultVarByF, var_type, 1.5, -4.0), -6.0);
+        assert_eq!(float_var_op!(DivVarByF, var_type, 3.0, 4.0), 0.75);
+        assert_eq!(float_var_op!(DivVarByF, var_type, 4.0, 3.0), 4.0 / 3.0);
+    }
+}
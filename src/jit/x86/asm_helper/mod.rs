@@ -3,6 +3,7 @@ use x86asm::{OperandSize, InstructionEncodingError, InstructionWriter, Mnemonic,
 
 mod into_operand;
 pub use into_operand::*;
+use super::ARG_REGS_32;
 
 use Mnemonic::*;
 use Reg::*;
@@ -15,6 +16,7 @@ type IoResult<T> = std::result::Result<T, std::io::Error>;
 pub trait AsmWriterHelper {
     fn write_ret(&mut self, num_vars: u32) -> Result<()>;
     fn setup_stack_frame(&mut self, num_vars: u32) -> Result<()>;
+    fn load_args(&mut self, arg_count: u16, var_count: u16) -> Result<()>;
     fn save_nonvolatile_regs(&mut self) -> Result<()>;
     fn restore_nonvolatile_regs(&mut self) -> Result<()>;
     fn pop(&mut self, reg: Reg) -> Result<()>;
@@ -23,14 +25,9 @@ pub trait AsmWriterHelper {
     fn call<I: IntoOperand>(&mut self, op1: I) -> Result<()>;
     fn get_global(&mut self, globals: *const u32, reg: Reg, global_num: u16) -> Result<()>;
     fn set_global(&mut self, globals: *const u32, reg: Reg, global_num: u16) -> Result<()>;
-    fn copy_to_fpu(&mut self, count: usize) -> Result<()>;
-    fn copy_to_fpu_rev(&mut self, count: usize) -> Result<()>;
-    fn get_global_float(&mut self, globals: *const u32, global_num: u16) -> Result<()>;
-    fn set_global_float(&mut self, globals: *const u32, global_num: u16) -> Result<()>;
-    fn fstsw_ax(&mut self) -> IoResult<()>;
-    fn sahf(&mut self) -> IoResult<()>;
-    fn fcompp(&mut self) -> IoResult<()>;
-    fn mov_rax_0(&mut self) -> IoResult<()>;
+    fn get_global_float(&mut self, globals: *const u32, reg: Reg, global_num: u16) -> Result<()>;
+    fn set_global_float(&mut self, globals: *const u32, reg: Reg, global_num: u16) -> Result<()>;
+    fn call_rel32_0(&mut self) -> IoResult<()>;
 }
 
 static NONVOLATILE_REGS: &[Reg] = &[RBX, RBP, RDI, RSI, R12, R13, R14, R15];
@@ -81,6 +78,27 @@ impl<T: Write + Seek> AsmWriterHelper for InstructionWriter<T> {
         Ok(())
     }
 
+    /// Copy the arguments into the first locals, 6 from registers and the rest from the stack
+    fn load_args(&mut self, arg_count: u16, var_count: u16) -> Result<()> {
+        for i in 0..std::cmp::min(arg_count, 6) {
+            self.mov(
+                (RBP, u64::from(i) * 4, Dword),
+                ARG_REGS_32[i as usize]
+            )?;
+        }
+        for i in 0..arg_count as isize - 6 {
+            self.mov(
+                EAX,
+                (RSP, ((i as u64) * 8) + 16 + (var_count + ((4 - (var_count % 4)) % 4)) as u64 * 4, Dword),
+            )?;
+            self.mov(
+                (RBP, ((i as u64) + 6) * 4, Dword),
+                EAX
+            )?;
+        }
+        Ok(())
+    }
+
     fn save_nonvolatile_regs(&mut self) -> Result<()> {
         for reg in NONVOLATILE_REGS {
             self.write1(PUSH, Direct(*reg))?;
@@ -146,61 +164,28 @@ impl<T: Write + Seek> AsmWriterHelper for InstructionWriter<T> {
         Ok(())
     }
 
-    fn get_global_float(&mut self, globals: *const u32, global_num: u16) -> Result<()> {
+    fn get_global_float(&mut self, globals: *const u32, reg: Reg, global_num: u16) -> Result<()> {
         self.mov(RDX, globals as u64)?;
-        self.write1(
-            FLD,
+        self.write2(
+            MOVSS,
+            Direct(reg),
             (RDX, global_num as u64 * 4, Dword).into_op()
         )?;
         Ok(())
     }
 
-    fn set_global_float(&mut self, globals: *const u32, global_num: u16) -> Result<()> {
+    fn set_global_float(&mut self, globals: *const u32, reg: Reg, global_num: u16) -> Result<()> {
         self.mov(RDX, globals as u64)?;
-        self.write1(
-            FSTP,
-            (RDX, global_num as u64 * 4, Dword).into_op()
-        ).unwrap();
-        Ok(())
-    }
-
-    fn copy_to_fpu(&mut self, count: usize) -> Result<()> {
-        for i in 1..=count {
-            self.write1(
-                FLD,
-                (RSP, ((count - i) * 8) as u64, Dword).into_op()
-            )?;
-        }
-        Ok(())
-    }
-
-    fn copy_to_fpu_rev(&mut self, count: usize) -> Result<()> {
-        for i in (1..=count).rev() {
-            self.write1(
-                FLD,
-                (RSP, ((count - i) * 8) as u64, Dword).into_op()
-            )?;
-        }
-        Ok(())
-    }
-
-    fn fstsw_ax(&mut self) -> IoResult<()> {
-        self.write_bytes(b"\x9B\xDF\xE0")?;
-        Ok(())
-    }
-
-    fn sahf(&mut self) -> IoResult<()> {
-        self.write_bytes(b"\x9e")?;
-        Ok(())
-    }
-
-    fn fcompp(&mut self) -> IoResult<()> {
-        self.write_bytes(b"\xde\xd9")?;
+        self.write2(
+            MOVSS,
+            (RDX, global_num as u64 * 4, Dword).into_op(),
+            Direct(reg)
+        )?;
         Ok(())
     }
 
-    fn mov_rax_0(&mut self) -> IoResult<()> {
-        self.write_bytes(b"\x48\xb8\xf8\xff\xff\xff\xff\xff\xff\xf8")?;
+    fn call_rel32_0(&mut self) -> IoResult<()> {
+        self.write_bytes(b"\xe8\x00\x00\x00\x00")?;
         Ok(())
     }
 }
@@ -11,7 +11,7 @@
             asm_impl!(@asm
                       $writer,
                       $mnem 
-                      $(, crate::jit::x86::asm_helper::IntoOperand::into_op($op))*
+                      $(, $crate::jit::x86::asm_helper::IntoOperand::into_op($op))*
             );
         )*
     };
@@ -20,28 +20,28 @@
         @asm $writer:ident,
         $mnem:ident
     ) => {
-        $writer.write0($mnem).unwrap();
+        $writer.write0($mnem)?;
     };
 
     (
         @asm $writer:ident,
         $mnem:ident, $op:expr
     ) => {
-        $writer.write1($mnem, $op).unwrap();
+        $writer.write1($mnem, $op)?;
     };
 
     (
         @asm $writer:ident,
         $mnem:ident, $op:expr, $op2:expr
     ) => {
-        $writer.write2($mnem, $op, $op2).unwrap();
+        $writer.write2($mnem, $op, $op2)?;
     };
 
     (
         @asm $writer:ident,
         $mnem:ident, $op:expr, $op2:expr, $op3:expr
     ) => {
-        $writer.write3($mnem, $op, $op2, $op3).unwrap();
+        $writer.write3($mnem, $op, $op2, $op3)?;
     };
 }
 
@@ -0,0 +1,1303 @@
+//! Code generator working on the AST of a script instead of its commands. Expressions are
+//! evaluated into registers, general purpose ones for ints and XMM ones for floats, so memory
+//! is only touched for variables, call arguments and the occasional spill
+use std::io::prelude::*;
+use std::io::{self, SeekFrom};
+use std::collections::{HashMap, HashSet};
+use msc::{MscsbFile, Cmd, Command, Script};
+use x86asm::{OperandSize, InstructionEncodingError, Mnemonic, Operand, Reg};
+use crate::jit::ast::{AsAst, Node, BinOp, UnaryOp, AssignOp, Const, Type, branch_target};
+use super::asm_helper::*;
+use super::asm_macro::asm_impl;
+use super::{
+    CompileOptions, ProgramPointers, CodeWriter, CompileError, EncodingError, ARG_REGS_32,
+    get_var_info, patch_jump,
+};
+use super::syscalls::call_syscall;
+use super::printf::msc_printf;
+use super::dispatch::resolve_call;
+use super::trap::CommandMap;
+use super::runtime::{
+    push_handler, pop_handler, exit, use_fuel, enter_frame, leave_frame, check_call_target,
+    check_syscall,
+};
+
+use Reg::*;
+use OperandSize::*;
+use Mnemonic::*;
+
+macro_rules! asm {
+    (
+        $writer:ident,
+        $(
+            $mnem:ident $($op:expr),*;
+        )*
+    ) => {
+        asm_impl!($writer, {
+            $(
+                $mnem $($op),*
+            );*
+        })
+    };
+}
+
+/// Registers ints are evaluated in. They're callee-saved, so values survive calls, and saved
+/// by the prologue
+static INT_REGS: [Reg; 4] = [RBX, R12, R13, R14];
+/// Registers floats are evaluated in, XMM0 is left as scratch. Every XMM register is
+/// caller-saved, floats are spilled before calls
+static FLOAT_REGS: [Reg; 7] = [XMM1, XMM2, XMM3, XMM4, XMM5, XMM6, XMM7];
+/// Size of the saved INT_REGS, right below the locals. Spill slots come after them
+const SAVED_REGS_SIZE: i64 = 8 * 4;
+
+fn dword(reg: Reg) -> Reg {
+    match reg {
+        RBX => EBX,
+        R12 => R12D,
+        R13 => R13D,
+        R14 => R14D,
+        _ => unreachable!("{:?} is not in INT_REGS", reg),
+    }
+}
+
+fn spill_slot(slot: u32) -> Operand {
+    (RBP, -(SAVED_REGS_SIZE + 8 * (i64::from(slot) + 1)), Dword).into_op()
+}
+
+/// Register file a value lives in
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Class {
+    Int,
+    Float,
+}
+
+impl From<Type> for Class {
+    fn from(t: Type) -> Class {
+        match t {
+            Type::Int => Class::Int,
+            Type::Float => Class::Float,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Loc {
+    Reg(Reg),
+    Spill(u32),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Value {
+    class: Class,
+    loc: Loc,
+}
+
+enum AstError {
+    Encoding(EncodingError),
+    /// The AST can't be compiled as is, the script is left to the per-command backend
+    Unsupported,
+}
+
+impl From<EncodingError> for AstError {
+    fn from(err: EncodingError) -> Self {
+        AstError::Encoding(err)
+    }
+}
+
+impl From<InstructionEncodingError> for AstError {
+    fn from(err: InstructionEncodingError) -> Self {
+        AstError::Encoding(err.into())
+    }
+}
+
+impl From<io::Error> for AstError {
+    fn from(err: io::Error) -> Self {
+        AstError::Encoding(err.into())
+    }
+}
+
+type Result<T> = std::result::Result<T, AstError>;
+
+fn is_binop(cmd: Cmd) -> bool {
+    matches!(
+        cmd,
+        Cmd::AddI | Cmd::SubI | Cmd::MultI | Cmd::DivI | Cmd::ModI | Cmd::AndI | Cmd::OrI |
+        Cmd::XorI | Cmd::ShiftL | Cmd::ShiftR | Cmd::AddF | Cmd::SubF | Cmd::MultF |
+        Cmd::DivF | Cmd::Equals | Cmd::NotEquals | Cmd::LessThan | Cmd::LessOrEqual |
+        Cmd::Greater | Cmd::GreaterOrEqual | Cmd::EqualsF | Cmd::NotEqualsF |
+        Cmd::LessThanF | Cmd::LessOrEqualF | Cmd::GreaterF | Cmd::GreaterOrEqualF
+    )
+}
+
+fn is_call(cmd: Cmd) -> bool {
+    matches!(cmd, Cmd::CallFunc { .. } | Cmd::CallFunc2 { .. } | Cmd::CallFunc3 { .. })
+}
+
+/// Whether the AST of `script` evaluates exactly like its commands. The AST only holds
+/// values inside expressions, so the stack has to be empty between statements and at every
+/// jump, and return values have to be pushed right after their call. The AST keeps no Try
+/// either, so every call has to have one Try targeting its end, which is where an error
+/// caught during the call resumes. With `fuel`, backward jumps have to use fuel exactly like
+/// the commands do, the loops of the AST don't keep them so they're left to the commands
+fn fits_ast(script: &Script, fuel: bool) -> bool {
+    let commands = match script.commands.first().map(|c| c.cmd) {
+        Some(Cmd::Begin { .. }) => &script.commands[1..],
+        _ => return false,
+    };
+    let loc = |i: usize| {
+        commands.get(i).map_or(script.bounds.1, |c| script.bounds.0 + c.position)
+    };
+    let jump_targets = commands.iter()
+        .filter_map(|c| branch_target(c.cmd))
+        .collect::<HashSet<u32>>();
+    let is_backward = |c: &Command| {
+        branch_target(c.cmd).is_some_and(|target| target <= script.bounds.0 + c.position)
+    };
+    if fuel && commands.iter().any(is_backward) {
+        return false;
+    }
+    let ret_val_locations = commands.iter()
+        .filter_map(|c| match c.cmd {
+            Cmd::Try { loc } if c.push_bit => Some(loc),
+            _ => None,
+        })
+        .collect::<HashSet<u32>>();
+    let try_locations = commands.iter()
+        .filter_map(|c| match c.cmd {
+            Cmd::Try { loc } => Some(loc),
+            _ => None,
+        })
+        .collect::<Vec<u32>>();
+    let call_ends = (0..commands.len())
+        .filter(|&i| is_call(commands[i].cmd))
+        .map(|i| loc(i + 1))
+        .collect::<HashSet<u32>>();
+    let is_call_value = |loc: &u32| call_ends.contains(loc) && !jump_targets.contains(loc);
+    if !ret_val_locations.iter().all(is_call_value) {
+        return false;
+    }
+    if !try_locations.is_empty() {
+        let call_count = commands.iter().filter(|c| is_call(c.cmd)).count();
+        let try_targets = try_locations.iter().copied().collect::<HashSet<u32>>();
+        if try_locations.len() != call_count || try_targets != call_ends ||
+            !try_targets.iter().all(is_call_value)
+        {
+            return false;
+        }
+    }
+
+    let mut depth = 0;
+    for (i, c) in commands.iter().enumerate() {
+        if jump_targets.contains(&loc(i)) && depth != 0 {
+            return false;
+        }
+        let pushed = usize::from(c.push_bit);
+        let (pops, pushes) = match c.cmd {
+            Cmd::Nop | Cmd::Try { .. } | Cmd::PrintF { arg_count: 0 } => continue,
+            Cmd::PushInt { .. } | Cmd::PushShort { .. } | Cmd::PushVar { .. } => {
+                if !c.push_bit {
+                    continue;
+                }
+                (0, 1)
+            }
+            // Duplicating a value
+            Cmd::Push if c.push_bit => return false,
+            Cmd::Push => continue,
+            Cmd::Pop if c.push_bit => continue,
+            Cmd::Pop => (1, 0),
+            _ if is_binop(c.cmd) => (2, pushed),
+            Cmd::Not | Cmd::NotI | Cmd::NegI | Cmd::NegF => (1, pushed),
+            Cmd::IntToFloat { stack_pos } | Cmd::FloatToInt { stack_pos } => {
+                // Conversions below the top are only kept for the operand of a binary op
+                let stack_pos = usize::from(stack_pos);
+                let before_binop = commands.get(i + 1)
+                    .is_some_and(|next| next.push_bit && is_binop(next.cmd));
+                if depth <= stack_pos || (stack_pos > 0 && !(stack_pos == 1 && before_binop)) {
+                    return false;
+                }
+                continue;
+            }
+            Cmd::IncI { .. } | Cmd::DecI { .. } | Cmd::IncF { .. } | Cmd::DecF { .. } => (0, 0),
+            Cmd::SetVar { .. } | Cmd::AddVarBy { .. } | Cmd::SubVarBy { .. } |
+            Cmd::MultVarBy { .. } | Cmd::DivVarBy { .. } | Cmd::ModVarBy { .. } |
+            Cmd::AndVarBy { .. } | Cmd::OrVarBy { .. } | Cmd::XorVarBy { .. } |
+            Cmd::VarSetF { .. } | Cmd::AddVarByF { .. } | Cmd::SubVarByF { .. } |
+            Cmd::MultVarByF { .. } | Cmd::DivVarByF { .. } => (1, 0),
+            Cmd::If { .. } | Cmd::IfNot { .. } => (1, 0),
+            Cmd::Jump { .. } | Cmd::Jump5 { .. } | Cmd::Else { .. } => (0, 0),
+            Cmd::Sys { arg_count, .. } => (usize::from(arg_count), pushed),
+            Cmd::PrintF { arg_count } => (usize::from(arg_count), 0),
+            Cmd::CallFunc { arg_count } | Cmd::CallFunc2 { arg_count } |
+            Cmd::CallFunc3 { arg_count } => {
+                (usize::from(arg_count) + 1, usize::from(ret_val_locations.contains(&loc(i + 1))))
+            }
+            Cmd::Return6 | Cmd::Return8 => (1, 0),
+            Cmd::Return7 | Cmd::Return9 | Cmd::End | Cmd::Exit => (0, 0),
+            _ => return false,
+        };
+        if depth < pops {
+            return false;
+        }
+        depth = depth - pops + pushes;
+        // Statements have to leave the stack empty
+        if pushes == 0 && depth != 0 {
+            return false;
+        }
+    }
+    true
+}
+
+/// Class of the value a node produces on its own
+fn natural_class(node: &Node) -> Class {
+    match node {
+        Node::Const { val: Const::F32(_) } |
+        Node::UnaryOp { op: UnaryOp::ToFloat, .. } |
+        Node::UnaryOp { op: UnaryOp::Negate(Type::Float), .. } |
+        Node::BinOp { op: BinOp::Add(Type::Float), .. } |
+        Node::BinOp { op: BinOp::Sub(Type::Float), .. } |
+        Node::BinOp { op: BinOp::Mult(Type::Float), .. } |
+        Node::BinOp { op: BinOp::Div(Type::Float), .. } => Class::Float,
+        _ => Class::Int,
+    }
+}
+
+/// Class of the operands of a binary op
+fn operand_class(op: &BinOp) -> Class {
+    match op {
+        BinOp::Add(t) | BinOp::Sub(t) | BinOp::Mult(t) | BinOp::Div(t) |
+        BinOp::LessThan(t) | BinOp::LessThanOrEqual(t) | BinOp::Equal(t) |
+        BinOp::NotEqual(t) | BinOp::GreaterThanOrEqual(t) | BinOp::GreaterThan(t) => {
+            Class::from(*t)
+        }
+        _ => Class::Int,
+    }
+}
+
+/// (cmov if true, jump if true, jump if false) after comparing the operands of a comparison
+/// with `compare`. Float equality also has to check PF, which is set for unordered operands
+fn condition(op: &BinOp) -> Option<(Mnemonic, Mnemonic, Mnemonic)> {
+    Some(match op {
+        BinOp::Equal(_) => (CMOVE, JE, JNE),
+        BinOp::NotEqual(_) => (CMOVNE, JNE, JE),
+        BinOp::LessThan(Type::Int) => (CMOVL, JL, JGE),
+        BinOp::LessThanOrEqual(Type::Int) => (CMOVLE, JLE, JG),
+        BinOp::GreaterThan(Type::Int) => (CMOVG, JG, JLE),
+        BinOp::GreaterThanOrEqual(Type::Int) => (CMOVGE, JGE, JL),
+        BinOp::LessThan(Type::Float) | BinOp::GreaterThan(Type::Float) => (CMOVA, JA, JBE),
+        BinOp::LessThanOrEqual(Type::Float) |
+        BinOp::GreaterThanOrEqual(Type::Float) => (CMOVAE, JAE, JB),
+        _ => return None,
+    })
+}
+
+fn is_float_equality(op: &BinOp) -> bool {
+    matches!(op, BinOp::Equal(Type::Float) | BinOp::NotEqual(Type::Float))
+}
+
+struct AstCompiler<'a> {
+    writer: CodeWriter,
+    file: &'a MscsbFile,
+    script_index: usize,
+    var_count: u16,
+    ptrs: ProgramPointers,
+    /// Values of the expressions being evaluated, operands are taken from the end
+    values: Vec<Value>,
+    free_slots: Vec<u32>,
+    slot_count: u32,
+    /// Position of each label in the code, once emitted
+    labels: Vec<Option<u64>>,
+    /// (position in the code, jump mnemonic, label)
+    jumps: Vec<(u64, Mnemonic, usize)>,
+    /// Label for each goto target
+    goto_labels: HashMap<u32, usize>,
+    /// (continue label, break label) of the loops around the current statement
+    loops: Vec<(usize, usize)>,
+    /// (script_index, position of the `call rel32` in the code, called script offset)
+    call_relocs: Vec<(usize, u64, u32)>,
+    /// Whether calls are made inside a handler, when the script has Try commands
+    catch_calls: bool,
+    /// Fuel and stack checks to make
+    options: &'a CompileOptions,
+    /// Code position of each node that can fault, by the position of its command
+    command_locations: HashMap<u32, u64>,
+}
+
+impl<'a> AstCompiler<'a> {
+    fn position(&self) -> u64 {
+        self.writer.get_inner_writer_ref().position()
+    }
+
+    /// Attribute the code from here on to the command at `position`, for locating faults
+    fn locate(&mut self, position: u32) {
+        let offset = self.position();
+        self.command_locations.insert(position, offset);
+    }
+
+    fn new_label(&mut self) -> usize {
+        self.labels.push(None);
+        self.labels.len() - 1
+    }
+
+    fn bind(&mut self, label: usize) -> Result<()> {
+        if self.labels[label].is_some() {
+            return Err(AstError::Unsupported);
+        }
+        self.labels[label] = Some(self.position());
+        Ok(())
+    }
+
+    fn goto_label(&mut self, loc: u32) -> usize {
+        if let Some(&label) = self.goto_labels.get(&loc) {
+            return label;
+        }
+        let label = self.new_label();
+        self.goto_labels.insert(loc, label);
+        label
+    }
+
+    fn jump(&mut self, mnem: Mnemonic, label: usize) -> Result<()> {
+        let position = self.position();
+        self.jumps.push((position, mnem, label));
+        let writer = &mut self.writer;
+        asm!(writer,
+            mnem 0u32;
+        );
+        Ok(())
+    }
+
+    /// A free register of `class`, spilling the oldest value in one if there are none. The
+    /// two newest values are never spilled, they're the operands being worked on
+    fn alloc(&mut self, class: Class) -> Result<Reg> {
+        let pool: &[Reg] = match class {
+            Class::Int => &INT_REGS,
+            Class::Float => &FLOAT_REGS,
+        };
+        let values = &self.values;
+        if let Some(&reg) = pool.iter().find(|&&reg| values.iter().all(|v| v.loc != Loc::Reg(reg))) {
+            return Ok(reg);
+        }
+        let victim = values.iter()
+            .take(values.len().saturating_sub(2))
+            .position(|v| v.class == class && matches!(v.loc, Loc::Reg(_)))
+            .expect("More operands than registers");
+        let reg = match values[victim].loc {
+            Loc::Reg(reg) => reg,
+            Loc::Spill(_) => unreachable!(),
+        };
+        self.spill(victim)?;
+        Ok(reg)
+    }
+
+    fn spill(&mut self, index: usize) -> Result<()> {
+        let Value { class, loc } = self.values[index];
+        let reg = match loc {
+            Loc::Reg(reg) => reg,
+            Loc::Spill(_) => return Ok(()),
+        };
+        let slot = match self.free_slots.pop() {
+            Some(slot) => slot,
+            None => {
+                self.slot_count += 1;
+                self.slot_count - 1
+            }
+        };
+        let writer = &mut self.writer;
+        match class {
+            Class::Int => {
+                asm!(writer,
+                    MOV spill_slot(slot), dword(reg);
+                );
+            }
+            Class::Float => {
+                asm!(writer,
+                    MOVSS spill_slot(slot), reg;
+                );
+            }
+        }
+        self.values[index].loc = Loc::Spill(slot);
+        Ok(())
+    }
+
+    /// Spill every float, no XMM register survives a call
+    fn spill_floats(&mut self) -> Result<()> {
+        for index in 0..self.values.len() {
+            if self.values[index].class == Class::Float {
+                self.spill(index)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Register holding the value at `index`, reloading it if it was spilled
+    fn reg_of(&mut self, index: usize) -> Result<Reg> {
+        let Value { class, loc } = self.values[index];
+        let slot = match loc {
+            Loc::Reg(reg) => return Ok(reg),
+            Loc::Spill(slot) => slot,
+        };
+        let reg = self.alloc(class)?;
+        let writer = &mut self.writer;
+        match class {
+            Class::Int => {
+                asm!(writer,
+                    MOV dword(reg), spill_slot(slot);
+                );
+            }
+            Class::Float => {
+                asm!(writer,
+                    MOVSS reg, spill_slot(slot);
+                );
+            }
+        }
+        self.free_slots.push(slot);
+        self.values[index].loc = Loc::Reg(reg);
+        Ok(reg)
+    }
+
+    fn top_reg(&mut self) -> Result<Reg> {
+        self.reg_of(self.values.len() - 1)
+    }
+
+    fn push_value(&mut self, class: Class) -> Result<Reg> {
+        let reg = self.alloc(class)?;
+        self.values.push(Value { class, loc: Loc::Reg(reg) });
+        Ok(reg)
+    }
+
+    fn pop_value(&mut self) {
+        if let Some(Value { loc: Loc::Spill(slot), .. }) = self.values.pop() {
+            self.free_slots.push(slot);
+        }
+    }
+
+    /// Replace the newest value with one in a register of `class`
+    fn replace_top(&mut self, class: Class) -> Result<Reg> {
+        let reg = self.alloc(class)?;
+        let top = self.values.len() - 1;
+        self.values[top] = Value { class, loc: Loc::Reg(reg) };
+        Ok(reg)
+    }
+
+    /// Move the bits of the newest value to a register of `class`
+    fn convert_top(&mut self, class: Class) -> Result<()> {
+        if self.values[self.values.len() - 1].class == class {
+            return Ok(());
+        }
+        let from = self.top_reg()?;
+        let to = self.replace_top(class)?;
+        let writer = &mut self.writer;
+        match class {
+            Class::Int => {
+                asm!(writer,
+                    MOVD dword(to), from;
+                );
+            }
+            Class::Float => {
+                asm!(writer,
+                    MOVD to, dword(from);
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Operand for a variable, globals are addressed through `base`
+    fn var_operand(&mut self, is_global: bool, var_num: u16, base: Reg) -> Result<Operand> {
+        if is_global {
+            self.writer.mov(base, self.ptrs.global_vars as u64)?;
+            Ok((base, u64::from(var_num) * 4, Dword).into_op())
+        } else {
+            Ok((RBP, u64::from(var_num) * 4, Dword).into_op())
+        }
+    }
+
+    /// Evaluate `node` into a new value of `class`, reinterpreting the bits if the node
+    /// produces the other class
+    fn eval(&mut self, node: &Node, class: Class) -> Result<()> {
+        match node {
+            Node::Const { val } => {
+                let bits = match val {
+                    Const::U32(val) => *val,
+                    Const::F32(val) => val.to_bits(),
+                    Const::Str(_) => return Err(AstError::Unsupported),
+                };
+                let reg = self.push_value(class)?;
+                let writer = &mut self.writer;
+                match class {
+                    Class::Int => {
+                        asm!(writer,
+                            MOV dword(reg), bits;
+                        );
+                    }
+                    Class::Float => {
+                        asm!(writer,
+                            MOV EAX, bits;
+                            MOVD reg, EAX;
+                        );
+                    }
+                }
+            }
+            Node::Var { is_global, var_num } => {
+                let reg = self.push_value(class)?;
+                let var = self.var_operand(*is_global, *var_num, RDX)?;
+                let writer = &mut self.writer;
+                match class {
+                    Class::Int => {
+                        asm!(writer,
+                            MOV dword(reg), var;
+                        );
+                    }
+                    Class::Float => {
+                        asm!(writer,
+                            MOVSS reg, var;
+                        );
+                    }
+                }
+            }
+            Node::BinOp { op, left, right, position } => {
+                self.binop(op, left, right, *position)?;
+                self.convert_top(class)?;
+            }
+            Node::UnaryOp { op, left } => {
+                self.unaryop(op, left)?;
+                self.convert_top(class)?;
+            }
+            Node::FuncCall { func, args } => {
+                self.call(func, args)?;
+                self.convert_top(class)?;
+            }
+            Node::SysCall { sys_num, args } => {
+                self.syscall(*sys_num, args)?;
+                self.convert_top(class)?;
+            }
+            // Statements, never part of an expression in scripts that pass fits_ast
+            _ => return Err(AstError::Unsupported),
+        }
+        Ok(())
+    }
+
+    /// Registers of the two newest values, which get popped
+    fn pop_operands(&mut self) -> Result<(Reg, Reg)> {
+        let top = self.values.len() - 1;
+        let left = self.reg_of(top - 1)?;
+        let right = self.reg_of(top)?;
+        self.pop_value();
+        self.pop_value();
+        Ok((left, right))
+    }
+
+    fn binop(&mut self, op: &BinOp, left: &Node, right: &Node, position: u32) -> Result<()> {
+        let class = operand_class(op);
+        self.eval(left, class)?;
+        self.eval(right, class)?;
+        self.locate(position);
+
+        if let Some((cmov, _, _)) = condition(op) {
+            let writer = &mut self.writer;
+            asm!(writer,
+                XOR EAX, EAX;
+                XOR ECX, ECX;
+                MOV EDX, 1u32;
+            );
+            self.compare(op)?;
+            let writer = &mut self.writer;
+            asm!(writer,
+                cmov EAX, EDX;
+            );
+            match op {
+                BinOp::Equal(Type::Float) => {
+                    asm!(writer,
+                        CMOVP EAX, ECX;
+                    );
+                }
+                BinOp::NotEqual(Type::Float) => {
+                    asm!(writer,
+                        CMOVP EAX, EDX;
+                    );
+                }
+                _ => {}
+            }
+            let reg = self.push_value(Class::Int)?;
+            let writer = &mut self.writer;
+            asm!(writer,
+                MOV dword(reg), EAX;
+            );
+            return Ok(());
+        }
+
+        // The result replaces the left operand
+        let top = self.values.len() - 1;
+        let left = self.reg_of(top - 1)?;
+        let right = self.reg_of(top)?;
+        self.pop_value();
+        let writer = &mut self.writer;
+        if class == Class::Float {
+            let op = match op {
+                BinOp::Add(_) => ADDSS,
+                BinOp::Sub(_) => SUBSS,
+                BinOp::Mult(_) => MULSS,
+                BinOp::Div(_) => DIVSS,
+                _ => unreachable!(),
+            };
+            asm!(writer,
+                op left, right;
+            );
+            return Ok(());
+        }
+        let (left, right) = (dword(left), dword(right));
+        match op {
+            BinOp::Add(_) | BinOp::Sub(_) | BinOp::BitAnd | BinOp::BitOr | BinOp::BitXor => {
+                let op = match op {
+                    BinOp::Add(_) => ADD,
+                    BinOp::Sub(_) => SUB,
+                    BinOp::BitAnd => AND,
+                    BinOp::BitOr => OR,
+                    _ => XOR,
+                };
+                asm!(writer,
+                    op left, right;
+                );
+            }
+            BinOp::Mult(_) => {
+                asm!(writer,
+                    IMUL left, right;
+                );
+            }
+            BinOp::Div(_) | BinOp::Mod => {
+                asm!(writer,
+                    MOV EAX, left;
+                    CDQ;
+                    IDIV right;
+                    MOV left, if let BinOp::Mod = op { EDX } else { EAX };
+                );
+            }
+            BinOp::ShiftL | BinOp::ShiftR => {
+                let shift = if let BinOp::ShiftL = op { SHL } else { SHR };
+                asm!(writer,
+                    MOV ECX, right;
+                    shift left, CL;
+                );
+            }
+            BinOp::And | BinOp::Or => {
+                let combine = if let BinOp::And = op { AND } else { OR };
+                asm!(writer,
+                    XOR EAX, EAX;
+                    XOR ECX, ECX;
+                    MOV EDX, 1u32;
+                    TEST left, left;
+                    CMOVNE EAX, EDX;
+                    TEST right, right;
+                    CMOVNE ECX, EDX;
+                    combine EAX, ECX;
+                    MOV left, EAX;
+                );
+            }
+            _ => unreachable!("{:?} is a comparison", op),
+        }
+        Ok(())
+    }
+
+    fn unaryop(&mut self, op: &UnaryOp, left: &Node) -> Result<()> {
+        match op {
+            UnaryOp::ToFloat => {
+                self.eval(left, Class::Int)?;
+                let from = self.top_reg()?;
+                let to = self.replace_top(Class::Float)?;
+                let writer = &mut self.writer;
+                asm!(writer,
+                    CVTSI2SS to, dword(from);
+                );
+            }
+            UnaryOp::ToInt => {
+                // Truncating, like the per-command backend
+                self.eval(left, Class::Float)?;
+                let from = self.top_reg()?;
+                let to = self.replace_top(Class::Int)?;
+                let writer = &mut self.writer;
+                asm!(writer,
+                    CVTTSS2SI dword(to), from;
+                );
+            }
+            UnaryOp::Negate(Type::Float) => {
+                self.eval(left, Class::Float)?;
+                let reg = self.top_reg()?;
+                let writer = &mut self.writer;
+                asm!(writer,
+                    MOVD EAX, reg;
+                    XOR EAX, 0x8000_0000u32;
+                    MOVD reg, EAX;
+                );
+            }
+            UnaryOp::Negate(Type::Int) | UnaryOp::BitNot => {
+                self.eval(left, Class::Int)?;
+                let reg = dword(self.top_reg()?);
+                let mnem = if let UnaryOp::BitNot = op { NOT } else { NEG };
+                let writer = &mut self.writer;
+                asm!(writer,
+                    mnem reg;
+                );
+            }
+            UnaryOp::Not => {
+                self.eval(left, Class::Int)?;
+                let reg = dword(self.top_reg()?);
+                let writer = &mut self.writer;
+                asm!(writer,
+                    XOR EAX, EAX;
+                    MOV EDX, 1u32;
+                    TEST reg, reg;
+                    CMOVE EAX, EDX;
+                    MOV reg, EAX;
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Reserve 8 bytes of native stack per node and evaluate the nodes into them, the first
+    /// one at the top of the stack, or the deepest with `reversed`
+    fn store_args(&mut self, nodes: &[&Node], reversed: bool) -> Result<()> {
+        let count = nodes.len() as u32;
+        if count > 0 {
+            let writer = &mut self.writer;
+            asm!(writer,
+                SUB RSP, (8 * count);
+            );
+        }
+        for (i, node) in nodes.iter().enumerate() {
+            self.eval(node, Class::Int)?;
+            let reg = self.top_reg()?;
+            self.pop_value();
+            let slot = if reversed { count as usize - (i + 1) } else { i };
+            let writer = &mut self.writer;
+            asm!(writer,
+                MOV (RSP, slot as u64 * 8, Qword), reg;
+            );
+        }
+        Ok(())
+    }
+
+    /// Call the native function in RAX with the stack aligned to 16 bytes
+    fn call_native(&mut self) -> Result<()> {
+        let writer = &mut self.writer;
+        asm!(writer,
+            PUSH R15;
+            MOV R15, RSP;
+            AND R15, 8u8;
+            SUB RSP, R15;
+            CALL RAX;
+            ADD RSP, R15;
+            POP R15;
+        );
+        Ok(())
+    }
+
+    fn call(&mut self, func: &Node, args: &[Node]) -> Result<()> {
+        self.spill_floats()?;
+        // The record saves the registers holding the values around the call, so an error
+        // resumes with them as they were
+        let handler = if self.catch_calls {
+            let position = push_handler(&mut self.writer, self.ptrs.runtime as u64)?;
+            let label = self.new_label();
+            self.jumps.push((position, JMP, label));
+            Some(label)
+        } else {
+            None
+        };
+        let arg_count = args.len() as u32;
+        self.store_args(&args.iter().collect::<Vec<_>>(), false)?;
+
+        // Calls to the start of a script are patched to a rel32 once everything is compiled
+        let file = self.file;
+        let static_target = match func {
+            Node::Const { val: Const::U32(loc) } => {
+                file.get_script_from_loc(*loc)
+                    .filter(|&target| file.scripts[target].bounds.0 == *loc)
+                    .map(|_| *loc)
+            }
+            _ => None,
+        };
+        if static_target.is_none() {
+            self.eval(func, Class::Int)?;
+            let reg = dword(self.top_reg()?);
+            self.pop_value();
+            let writer = &mut self.writer;
+            asm!(writer,
+                MOV EDX, reg;
+                MOV RDI, (self.ptrs.dispatch_table as u64);
+                MOV RSI, (self.ptrs.dispatch_len as u64);
+                MOV RCX, (self.ptrs.runtime as u64);
+                MOV RAX, (resolve_call as *const () as u64);
+            );
+            self.call_native()?;
+            check_call_target(&mut self.writer, self.ptrs.runtime as u64, self.script_index)?;
+            let writer = &mut self.writer;
+            asm!(writer,
+                MOV R11, RAX;
+            );
+        }
+
+        // The first 6 arguments go in registers, the rest stay on the stack
+        let reg_count = std::cmp::min(arg_count, 6);
+        let writer = &mut self.writer;
+        for i in 0..reg_count {
+            asm!(writer,
+                MOV ARG_REGS_32[i as usize], (RSP, u64::from(i) * 8, Dword);
+            );
+        }
+        if reg_count > 0 {
+            asm!(writer,
+                ADD RSP, (8 * reg_count);
+            );
+        }
+        if let Some(loc) = static_target {
+            let position = self.position();
+            self.call_relocs.push((self.script_index, position, loc));
+            self.writer.call_rel32_0()?;
+        } else {
+            asm!(writer,
+                CALL R11;
+            );
+        }
+        let writer = &mut self.writer;
+        if arg_count > 6 {
+            asm!(writer,
+                ADD RSP, (8 * (arg_count - 6));
+            );
+        }
+        if let Some(label) = handler {
+            // The return value or the error value is in EAX either way
+            let resume = pop_handler(&mut self.writer, self.ptrs.runtime as u64)?;
+            self.labels[label] = Some(resume);
+        }
+        let reg = self.push_value(Class::Int)?;
+        let writer = &mut self.writer;
+        asm!(writer,
+            MOV dword(reg), EAX;
+        );
+        Ok(())
+    }
+
+    fn syscall(&mut self, sys_num: u8, args: &[Node]) -> Result<()> {
+        self.spill_floats()?;
+        let arg_count = args.len() as u32;
+        // Same layout as the pushed arguments of the per-command backend
+        self.store_args(&args.iter().collect::<Vec<_>>(), true)?;
+        let writer = &mut self.writer;
+        asm!(writer,
+            MOV RDI, (self.ptrs.syscalls as u64);
+            MOV RSI, (self.ptrs.printer as u64);
+            MOV RDX, RSP;
+            MOV RCX, arg_count;
+            MOV R8D, (u32::from(sys_num));
+            MOV R9, (self.ptrs.runtime as u64);
+            MOV RAX, (call_syscall as *const () as u64);
+        );
+        self.call_native()?;
+        check_syscall(&mut self.writer, self.ptrs.runtime as u64, self.script_index)?;
+        if arg_count > 0 {
+            let writer = &mut self.writer;
+            asm!(writer,
+                ADD RSP, (8 * arg_count);
+            );
+        }
+        let reg = self.push_value(Class::Int)?;
+        let writer = &mut self.writer;
+        asm!(writer,
+            MOV dword(reg), EAX;
+        );
+        Ok(())
+    }
+
+    fn printf(&mut self, str_num: &Node, args: &[Node]) -> Result<()> {
+        self.spill_floats()?;
+        let mut nodes = vec![str_num];
+        nodes.extend(args.iter());
+        let arg_count = nodes.len() as u64;
+        self.store_args(&nodes, true)?;
+        let writer = &mut self.writer;
+        asm!(writer,
+            MOV RDI, (self.ptrs.printer as u64);
+            MOV RSI, (RSP, 8 * (arg_count - 1), Qword);
+            MOV RDX, RSP;
+            MOV RCX, (arg_count - 1);
+            MOV R8, (self.ptrs.runtime as u64);
+            MOV RAX, (msc_printf as *const () as u64);
+        );
+        self.call_native()?;
+        check_syscall(&mut self.writer, self.ptrs.runtime as u64, self.script_index)?;
+        let writer = &mut self.writer;
+        asm!(writer,
+            ADD RSP, (8 * arg_count as u32);
+        );
+        Ok(())
+    }
+
+    fn assign(
+        &mut self, op: &AssignOp, is_global: bool, var_num: u16, right: &Node, position: u32
+    ) -> Result<()> {
+        match op {
+            AssignOp::Set(_) => {
+                let class = natural_class(right);
+                self.eval(right, class)?;
+                self.locate(position);
+                let reg = self.top_reg()?;
+                self.pop_value();
+                let var = self.var_operand(is_global, var_num, RDX)?;
+                let writer = &mut self.writer;
+                match class {
+                    Class::Int => {
+                        asm!(writer,
+                            MOV var, dword(reg);
+                        );
+                    }
+                    Class::Float => {
+                        asm!(writer,
+                            MOVSS var, reg;
+                        );
+                    }
+                }
+            }
+            AssignOp::Add(Type::Int) | AssignOp::Sub(Type::Int) | AssignOp::And |
+            AssignOp::Or | AssignOp::Xor => {
+                let mnem = match op {
+                    AssignOp::Add(_) => ADD,
+                    AssignOp::Sub(_) => SUB,
+                    AssignOp::And => AND,
+                    AssignOp::Or => OR,
+                    _ => XOR,
+                };
+                if let Node::Const { val: Const::U32(val) } = right {
+                    self.locate(position);
+                    let var = self.var_operand(is_global, var_num, RDX)?;
+                    let writer = &mut self.writer;
+                    asm!(writer,
+                        mnem var, *val;
+                    );
+                } else {
+                    self.eval(right, Class::Int)?;
+                    self.locate(position);
+                    let reg = dword(self.top_reg()?);
+                    self.pop_value();
+                    let var = self.var_operand(is_global, var_num, RDX)?;
+                    let writer = &mut self.writer;
+                    asm!(writer,
+                        mnem var, reg;
+                    );
+                }
+            }
+            AssignOp::Mult(Type::Int) | AssignOp::Div(Type::Int) | AssignOp::Mod => {
+                self.eval(right, Class::Int)?;
+                self.locate(position);
+                let reg = dword(self.top_reg()?);
+                self.pop_value();
+                // RDX is taken by CDQ
+                let var = self.var_operand(is_global, var_num, RCX)?;
+                let writer = &mut self.writer;
+                asm!(writer,
+                    MOV EAX, var;
+                );
+                if let AssignOp::Mult(_) = op {
+                    asm!(writer,
+                        IMUL EAX, reg;
+                    );
+                } else {
+                    asm!(writer,
+                        CDQ;
+                        IDIV reg;
+                    );
+                }
+                asm!(writer,
+                    MOV var, if let AssignOp::Mod = op { EDX } else { EAX };
+                );
+            }
+            AssignOp::Add(Type::Float) | AssignOp::Sub(Type::Float) |
+            AssignOp::Mult(Type::Float) | AssignOp::Div(Type::Float) => {
+                let mnem = match op {
+                    AssignOp::Add(_) => ADDSS,
+                    AssignOp::Sub(_) => SUBSS,
+                    AssignOp::Mult(_) => MULSS,
+                    _ => DIVSS,
+                };
+                self.eval(right, Class::Float)?;
+                self.locate(position);
+                let reg = self.top_reg()?;
+                self.pop_value();
+                let var = self.var_operand(is_global, var_num, RDX)?;
+                let writer = &mut self.writer;
+                asm!(writer,
+                    MOVSS XMM0, var;
+                    mnem XMM0, reg;
+                    MOVSS var, XMM0;
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Pop the operands of a comparison and compare them. Float less than is compared as
+    /// greater than with the operands swapped, so that unordered operands (CF = ZF = PF = 1)
+    /// make it false
+    fn compare(&mut self, op: &BinOp) -> Result<()> {
+        let (left, right) = self.pop_operands()?;
+        let writer = &mut self.writer;
+        match op {
+            BinOp::LessThan(Type::Float) | BinOp::LessThanOrEqual(Type::Float) => {
+                asm!(writer,
+                    UCOMISS right, left;
+                );
+            }
+            _ if operand_class(op) == Class::Float => {
+                asm!(writer,
+                    UCOMISS left, right;
+                );
+            }
+            _ => {
+                asm!(writer,
+                    CMP dword(left), dword(right);
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Jump to `label` if `cond` is nonzero, or if it's zero when `!jump_if`
+    fn branch(&mut self, cond: &Node, jump_if: bool, label: usize) -> Result<()> {
+        match cond {
+            Node::UnaryOp { op: UnaryOp::Not, left } => self.branch(left, !jump_if, label),
+            Node::Const { val: Const::U32(val) } => {
+                if (*val != 0) == jump_if {
+                    self.jump(JMP, label)?;
+                }
+                Ok(())
+            }
+            Node::BinOp { op, left, right, .. } if condition(op).is_some() => {
+                let (_, if_true, if_false) = condition(op).unwrap();
+                let class = operand_class(op);
+                self.eval(left, class)?;
+                self.eval(right, class)?;
+                self.compare(op)?;
+                let mnem = if jump_if { if_true } else { if_false };
+                if !is_float_equality(op) {
+                    return self.jump(mnem, label);
+                }
+                // Unordered operands are never equal
+                if mnem == JNE {
+                    self.jump(JP, label)?;
+                    self.jump(JNE, label)
+                } else {
+                    let skip = self.new_label();
+                    self.jump(JP, skip)?;
+                    self.jump(JE, label)?;
+                    self.bind(skip)
+                }
+            }
+            _ => {
+                self.eval(cond, Class::Int)?;
+                let reg = dword(self.top_reg()?);
+                self.pop_value();
+                let writer = &mut self.writer;
+                asm!(writer,
+                    TEST reg, reg;
+                );
+                self.jump(if jump_if { JNE } else { JE }, label)
+            }
+        }
+    }
+
+    fn block(&mut self, nodes: &[Node]) -> Result<()> {
+        for node in nodes {
+            self.stmt(node)?;
+        }
+        Ok(())
+    }
+
+    fn loop_body(&mut self, body: &[Node], continue_label: usize, break_label: usize)
+        -> Result<()>
+    {
+        self.loops.push((continue_label, break_label));
+        self.block(body)?;
+        self.loops.pop();
+        Ok(())
+    }
+
+    fn stmt(&mut self, node: &Node) -> Result<()> {
+        match node {
+            Node::Assign { op, is_global, var_num, right, position } => {
+                self.assign(op, *is_global, *var_num, right, *position)?;
+            }
+            Node::Printf { str_num, args } => self.printf(str_num, args)?,
+            Node::If { cond, if_block, else_block } => {
+                let else_label = self.new_label();
+                self.branch(cond, false, else_label)?;
+                self.block(if_block)?;
+                if else_block.is_empty() {
+                    self.bind(else_label)?;
+                } else {
+                    let end = self.new_label();
+                    self.jump(JMP, end)?;
+                    self.bind(else_label)?;
+                    self.block(else_block)?;
+                    self.bind(end)?;
+                }
+            }
+            Node::While { cond, body } => {
+                let (start, end) = (self.new_label(), self.new_label());
+                self.bind(start)?;
+                self.branch(cond, false, end)?;
+                self.loop_body(body, start, end)?;
+                self.jump(JMP, start)?;
+                self.bind(end)?;
+            }
+            Node::DoWhile { body, cond } => {
+                let (start, next, end) = (self.new_label(), self.new_label(), self.new_label());
+                self.bind(start)?;
+                self.loop_body(body, next, end)?;
+                self.bind(next)?;
+                self.branch(cond, true, start)?;
+                self.bind(end)?;
+            }
+            Node::Break | Node::Continue => {
+                let &(continue_label, break_label) = self.loops.last()
+                    .ok_or(AstError::Unsupported)?;
+                let label = if let Node::Break = node { break_label } else { continue_label };
+                self.jump(JMP, label)?;
+            }
+            Node::Goto { loc } => {
+                let label = self.goto_label(*loc);
+                self.jump(JMP, label)?;
+            }
+            Node::Label { loc } => {
+                let label = self.goto_label(*loc);
+                self.bind(label)?;
+            }
+            Node::Return { val } => {
+                if let Some(val) = val {
+                    self.eval(val, Class::Int)?;
+                    let reg = dword(self.top_reg()?);
+                    self.pop_value();
+                    let writer = &mut self.writer;
+                    asm!(writer,
+                        MOV EAX, reg;
+                    );
+                }
+                self.epilogue()?;
+            }
+            Node::Exit => {
+                exit(&mut self.writer, self.ptrs.runtime as u64)?;
+            }
+            _ => {
+                // Only evaluated for its side effects
+                self.eval(node, natural_class(node))?;
+                self.pop_value();
+            }
+        }
+        Ok(())
+    }
+
+    fn epilogue(&mut self) -> Result<()> {
+        let writer = &mut self.writer;
+        asm!(writer,
+            MOV RSP, RBP;
+            SUB RSP, (SAVED_REGS_SIZE as u8);
+        );
+        for reg in INT_REGS.iter().rev() {
+            writer.pop(*reg)?;
+        }
+        if self.options.max_call_depth.is_some() {
+            leave_frame(writer, self.ptrs.runtime as u64)?;
+        }
+        writer.write_ret(u32::from(self.var_count))?;
+        Ok(())
+    }
+
+    fn compile(&mut self, arg_count: u16, nodes: &[Node]) -> Result<()> {
+        let ctx = self.ptrs.runtime as u64;
+        let (check_depth, check_stack) = self.options.stack_checks();
+        self.writer.setup_stack_frame(u32::from(self.var_count))?;
+        if check_depth || check_stack {
+            enter_frame(&mut self.writer, ctx, self.script_index, check_depth, check_stack)?;
+        }
+        self.writer.load_args(arg_count, self.var_count)?;
+        if self.options.fuel {
+            let begin = &self.file.scripts[self.script_index].commands[0];
+            use_fuel(&mut self.writer, ctx, self.script_index, begin.position)?;
+        }
+        for reg in INT_REGS.iter() {
+            self.writer.push(*reg)?;
+        }
+        // Room for the spill slots, patched once their number is known
+        let frame_position = self.position();
+        let writer = &mut self.writer;
+        asm!(writer,
+            SUB RSP, 0u32;
+        );
+
+        self.block(nodes)?;
+        // For scripts running off the end
+        self.epilogue()?;
+
+        for &(position, mnem, label) in self.jumps.iter() {
+            // Gotos to a label the AST doesn't have can't be compiled
+            let target = self.labels[label].ok_or(AstError::Unsupported)?;
+            patch_jump(&mut self.writer, position, mnem, target)?;
+        }
+        self.writer.seek(SeekFrom::Start(frame_position))?;
+        let writer = &mut self.writer;
+        asm!(writer,
+            SUB RSP, (8 * self.slot_count);
+        );
+        Ok(())
+    }
+}
+
+/// Compile a script from its AST, appending static calls to `call_relocs`. Returns the code and
+/// where the nodes that can fault are in it. `Ok(None)` if the AST doesn't evaluate exactly like
+/// the commands, the script is then left to the per-command backend
+pub fn compile_script(
+    file: &MscsbFile,
+    script_index: usize,
+    ptrs: ProgramPointers,
+    options: &CompileOptions,
+    call_relocs: &mut Vec<(usize, u64, u32)>,
+) -> std::result::Result<Option<(Vec<u8>, CommandMap)>, CompileError> {
+    let script = &file.scripts[script_index];
+    if !fits_ast(script, options.fuel) {
+        return Ok(None);
+    }
+    let (arg_count, var_count) = match get_var_info(script) {
+        Some(var_info) => var_info,
+        None => return Ok(None),
+    };
+    let ast = script.as_ast();
+    let mut compiler = AstCompiler {
+        writer: CodeWriter::new(io::Cursor::new(Vec::new()), x86asm::Mode::Long),
+        file,
+        script_index,
+        var_count,
+        ptrs,
+        values: vec![],
+        free_slots: vec![],
+        slot_count: 0,
+        labels: vec![],
+        jumps: vec![],
+        goto_labels: HashMap::new(),
+        loops: vec![],
+        call_relocs: vec![],
+        catch_calls: script.iter().any(|cmd| matches!(cmd.cmd, Cmd::Try { .. })),
+        options,
+        command_locations: HashMap::new(),
+    };
+    match compiler.compile(arg_count, &ast.nodes) {
+        Ok(()) => {}
+        Err(AstError::Unsupported) => return Ok(None),
+        Err(AstError::Encoding(error)) => {
+            let begin = &script.commands[0];
+            return Err(CompileError::Encoding {
+                script_index, position: begin.position, cmd: begin.cmd, error
+            });
+        }
+    }
+    call_relocs.append(&mut compiler.call_relocs);
+    let command_map = CommandMap::new(script.commands[0].position, &compiler.command_locations);
+    Ok(Some((compiler.writer.get_inner_writer_ref().get_ref().clone(), command_map)))
+}
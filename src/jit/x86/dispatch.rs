@@ -0,0 +1,25 @@
+use super::runtime::RuntimeContext;
+
+/// Entry of the table used to resolve calls to script offsets only known at runtime
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct DispatchEntry {
+    pub offset: u32,
+    pub script_index: usize,
+    pub addr: u64,
+}
+
+/// Find the compiled entry of the script starting at `offset`. 0 if no script starts there,
+/// the offset is then left in the context for `runtime::check_call_target`
+pub extern "C" fn resolve_call(
+    table: *const DispatchEntry, len: u64, offset: u32, runtime: *const RuntimeContext
+) -> u64 {
+    let table = unsafe { std::slice::from_raw_parts(table, len as usize) };
+    match table.binary_search_by_key(&offset, |entry| entry.offset) {
+        Ok(i) => table[i].addr,
+        Err(_) => {
+            unsafe { (*runtime).error_value.set(offset) };
+            0
+        }
+    }
+}
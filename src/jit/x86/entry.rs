@@ -0,0 +1,125 @@
+//! Native stub the host calls scripts through, so they can take any number of arguments. The
+//! first 6 go in the System V argument registers and the rest on the stack, where the prologue
+//! of a script looks for them
+//!
+//! The stub also sets up the `RuntimeContext` for the run. Unwinding out of the script, for an
+//! uncaught error, returns into the stub with every register but RSP clobbered, so it saves the
+//! callee-saved registers itself and finds its frame again through the context. That's also how
+//! it gets back to the host's stack from the script stack, when the program has one
+use std::io::{Cursor, SeekFrom};
+use std::io::prelude::*;
+use x86asm::{InstructionWriter, Mnemonic, Mode, Operand, OperandSize, Reg, RegScale};
+use super::asm_macro::asm_impl;
+use super::runtime::{RuntimeContext, HANDLER, ENTRY_RSP, ENTRY_RBP, STACK_TOP};
+use super::{CodeWriter, EncodingError, patch_jump};
+
+use Reg::*;
+use OperandSize::*;
+use Mnemonic::*;
+
+/// `entry(script_address, args, arg_count)`, `args` has to hold at least 6 values
+pub type EntryFn = unsafe extern "C" fn(u64, *const u64, u64) -> u64;
+
+/// Callee-saved registers the stub restores, pushed in this order
+const SAVED_REGS: [Reg; 6] = [RBP, RBX, R12, R13, R14, R15];
+
+/// Context fields saved for the duration of a run, so a script run from a host function
+/// called by another script doesn't clobber them
+const SAVED_FIELDS: [u64; 3] = [HANDLER, ENTRY_RSP, ENTRY_RBP];
+
+/// Emit a rel32 jump to be patched with `patch_jump`, returns its position
+fn jump(writer: &mut CodeWriter, mnem: Mnemonic) -> Result<u64, EncodingError> {
+    let pos = writer.get_inner_writer_ref().position();
+    writer.write1(mnem, Operand::Literal32(0))?;
+    Ok(pos)
+}
+
+/// `switch_stack` runs scripts on the stack at `RuntimeContext::stack_top`
+pub fn compile_entry(ctx: *const RuntimeContext, switch_stack: bool)
+    -> Result<Vec<u8>, EncodingError>
+{
+    let ctx = ctx as u64;
+    let mut writer = InstructionWriter::new(Cursor::new(Vec::new()), Mode::Long);
+    let writer = &mut writer;
+    for reg in SAVED_REGS.iter() {
+        asm_impl!(writer, {
+            PUSH *reg
+        });
+    }
+    asm_impl!(writer, {
+        MOV R10, ctx
+    });
+    for &field in SAVED_FIELDS.iter() {
+        asm_impl!(writer, {
+            PUSH (R10, field, Qword)
+        });
+    }
+    // The return address and 9 pushes leave the stack aligned to 16 bytes
+    asm_impl!(writer, {
+        MOV RBP, RSP;
+        MOV (R10, HANDLER, Qword), 0u32;
+        MOV (R10, ENTRY_RBP, Qword), RBP
+    });
+    if switch_stack {
+        asm_impl!(writer, {
+            MOV RSP, (R10, STACK_TOP, Qword)
+        });
+    }
+    asm_impl!(writer, {
+        MOV R11, RDI;
+        MOV RCX, RDX;
+        SUB RCX, 6u8
+    });
+    let no_stack_args = jump(writer, JLE)?;
+    // Push args[arg_count - 1] down to args[6], padded so the stack stays aligned
+    asm_impl!(writer, {
+        LEA RAX, (RSI, RDX, RegScale::Eight, Qword);
+        TEST ECX, 1u32
+    });
+    let is_even = jump(writer, JE)?;
+    asm_impl!(writer, {
+        SUB RSP, 8u8
+    });
+    let push_loop = writer.get_inner_writer_ref().position();
+    asm_impl!(writer, {
+        SUB RAX, 8u8;
+        PUSH (RAX, Qword);
+        SUB RCX, 1u8
+    });
+    let next_arg = jump(writer, JNE)?;
+    let load_regs = writer.get_inner_writer_ref().position();
+    asm_impl!(writer, {
+        // Where the CALL puts the return address
+        LEA RAX, (RSP, -8i64, Qword);
+        MOV (R10, ENTRY_RSP, Qword), RAX;
+        MOV RAX, RSI;
+        MOV RDI, (RAX, Qword);
+        MOV RSI, (RAX, 8u64, Qword);
+        MOV RDX, (RAX, 16u64, Qword);
+        MOV RCX, (RAX, 24u64, Qword);
+        MOV R8, (RAX, 32u64, Qword);
+        MOV R9, (RAX, 40u64, Qword);
+        CALL R11;
+        MOV R10, ctx;
+        MOV RSP, (R10, ENTRY_RBP, Qword)
+    });
+    for &field in SAVED_FIELDS.iter().rev() {
+        asm_impl!(writer, {
+            POP (R10, field, Qword)
+        });
+    }
+    for reg in SAVED_REGS.iter().rev() {
+        asm_impl!(writer, {
+            POP *reg
+        });
+    }
+    asm_impl!(writer, {
+        RET
+    });
+    let end = writer.get_inner_writer_ref().position();
+    patch_jump(writer, no_stack_args, JLE, load_regs)?;
+    patch_jump(writer, is_even, JE, push_loop)?;
+    patch_jump(writer, next_arg, JNE, push_loop)?;
+    writer.seek(SeekFrom::Start(end))?;
+    Ok(writer.get_inner_writer_ref().get_ref().clone())
+}
@@ -0,0 +1,256 @@
+use msc::Cmd;
+use x86asm::InstructionEncodingError;
+use std::fmt;
+use std::io;
+use super::syscalls::SyscallError;
+
+#[derive(Debug)]
+pub enum EncodingError {
+    Instruction(InstructionEncodingError),
+    Io(io::Error),
+}
+
+impl From<InstructionEncodingError> for EncodingError {
+    fn from(err: InstructionEncodingError) -> Self {
+        EncodingError::Instruction(err)
+    }
+}
+
+impl From<io::Error> for EncodingError {
+    fn from(err: io::Error) -> Self {
+        EncodingError::Io(err)
+    }
+}
+
+impl fmt::Display for EncodingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncodingError::Instruction(err) => write!(f, "{:?}", err),
+            EncodingError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// An error compiling a script, `position` is the offset of the failing command relative to
+/// the start of the script
+#[derive(Debug)]
+pub enum CompileError {
+    /// Command with no known semantics (Unk1)
+    UnsupportedCommand { script_index: usize, position: u32, cmd: Cmd },
+    /// Begin anywhere but the first command of a script
+    MisplacedBegin { script_index: usize, position: u32, cmd: Cmd },
+    /// CallFunc to an offset that isn't the start of a script
+    InvalidCallTarget { script_index: usize, position: u32, cmd: Cmd, loc: u32 },
+    /// PrintF with no arguments, not even the format string
+    MissingFormat { script_index: usize, position: u32, cmd: Cmd },
+    /// Sys with a number nothing is registered for in the `SyscallRegistry`
+    UnregisteredSyscall { script_index: usize, position: u32, cmd: Cmd, sys_num: u8 },
+    /// Jump, If, IfNot or Else to an offset that isn't a command in the same script
+    InvalidJumpTarget { script_index: usize, position: u32, cmd: Cmd, loc: u32 },
+    /// Try to an offset that isn't a later command in the same script
+    InvalidTryTarget { script_index: usize, position: u32, cmd: Cmd, loc: u32 },
+    /// x86asm failed to encode the code for a command
+    Encoding { script_index: usize, position: u32, cmd: Cmd, error: EncodingError },
+    /// The file's entrypoint isn't inside any script
+    InvalidEntrypoint { entrypoint: u32 },
+    /// x86asm failed to encode the stub scripts are run through
+    Entry { error: EncodingError },
+    /// Allocating or protecting the memory for the generated code failed
+    Allocation { error: io::Error },
+    /// Script stack too small for even a single call, see `CompileOptions::script_stack_size`
+    ScriptStackSize { size: usize, min: usize },
+    /// Running objdump for `CompileOptions::dump_asm` failed
+    Disassembly { script_index: usize, error: io::Error },
+}
+
+impl CompileError {
+    pub fn script_index(&self) -> Option<usize> {
+        match *self {
+            CompileError::UnsupportedCommand { script_index, .. } |
+            CompileError::MisplacedBegin { script_index, .. } |
+            CompileError::InvalidCallTarget { script_index, .. } |
+            CompileError::MissingFormat { script_index, .. } |
+            CompileError::UnregisteredSyscall { script_index, .. } |
+            CompileError::InvalidJumpTarget { script_index, .. } |
+            CompileError::InvalidTryTarget { script_index, .. } |
+            CompileError::Encoding { script_index, .. } |
+            CompileError::Disassembly { script_index, .. } => Some(script_index),
+            CompileError::InvalidEntrypoint { .. } |
+            CompileError::Entry { .. } |
+            CompileError::Allocation { .. } |
+            CompileError::ScriptStackSize { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompileError::UnsupportedCommand { script_index, position, cmd } => {
+                write!(f, "script_{} 0x{:X}: unsupported command {:?}", script_index, position, cmd)
+            }
+            CompileError::MisplacedBegin { script_index, position, cmd } => {
+                write!(f, "script_{} 0x{:X}: {:?} not allowed after first command of script",
+                       script_index, position, cmd)
+            }
+            CompileError::InvalidCallTarget { script_index, position, cmd, loc } => {
+                write!(f, "script_{} 0x{:X}: {:?} target 0x{:X} is not the start of a script",
+                       script_index, position, cmd, loc)
+            }
+            CompileError::MissingFormat { script_index, position, cmd } => {
+                write!(f, "script_{} 0x{:X}: {:?} has no format string", script_index, position, cmd)
+            }
+            CompileError::UnregisteredSyscall { script_index, position, cmd, sys_num } => {
+                write!(f, "script_{} 0x{:X}: {:?} no host function registered for sys 0x{:X}",
+                       script_index, position, cmd, sys_num)
+            }
+            CompileError::InvalidJumpTarget { script_index, position, cmd, loc } => {
+                write!(f, "script_{} 0x{:X}: {:?} target 0x{:X} is not a command in this script",
+                       script_index, position, cmd, loc)
+            }
+            CompileError::InvalidTryTarget { script_index, position, cmd, loc } => {
+                write!(f, "script_{} 0x{:X}: {:?} target 0x{:X} is not a later command in this script",
+                       script_index, position, cmd, loc)
+            }
+            CompileError::Encoding { script_index, position, cmd, error } => {
+                write!(f, "script_{} 0x{:X}: failed to encode {:?} ({})",
+                       script_index, position, cmd, error)
+            }
+            CompileError::InvalidEntrypoint { entrypoint } => {
+                write!(f, "entrypoint 0x{:X} is not inside any script", entrypoint)
+            }
+            CompileError::Entry { error } => {
+                write!(f, "failed to encode the entry stub ({})", error)
+            }
+            CompileError::Allocation { error } => {
+                write!(f, "failed to allocate code memory ({})", error)
+            }
+            CompileError::ScriptStackSize { size, min } => {
+                write!(f, "script stack of {} bytes is too small, at least {} needed", size, min)
+            }
+            CompileError::Disassembly { script_index, error } => {
+                write!(f, "script_{}: failed to disassemble the code ({})", script_index, error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+/// An error running a compiled script
+#[derive(Debug, PartialEq)]
+pub enum RunError {
+    /// No script at this index
+    InvalidScript { script_index: usize, script_count: usize },
+    /// The number of arguments passed doesn't match the Begin of the script
+    ArgumentCount { script_index: usize, expected: u16, passed: usize },
+    /// The code has to be locked with `lock_all` before running
+    NotLocked,
+    /// No global at this index, the program only has the globals its scripts use
+    InvalidGlobal { var_num: u16, global_count: usize },
+    /// Snapshot with a different number of globals than the program
+    GlobalCount { expected: usize, passed: usize },
+    /// An error command ran with no Try to catch it, `position` is the offset of the command
+    /// relative to the start of the script
+    Uncaught { script_index: usize, position: u32, value: u32 },
+    /// The fuel budget of the run ran out at the command at `position`, the Begin of a script
+    /// or a backward jump
+    OutOfFuel { script_index: usize, position: u32 },
+    /// Fuel budget given to a program compiled without fuel checks
+    FuelNotEnabled,
+    /// A CallFunc of the script called `offset` at runtime, which isn't the start of a script
+    InvalidCall { script_index: usize, offset: u32 },
+    /// The host function bound to `sys_num` failed when the script called it
+    Syscall { script_index: usize, sys_num: u8, error: SyscallError },
+    /// A PrintF of the script used `str_num` as its format string, which isn't in the string
+    /// table
+    InvalidString { script_index: usize, str_num: u32 },
+    /// Entering the script went over the call depth limit or ran out of script stack
+    StackOverflow { script_index: usize },
+    /// The code of a script faulted. `position` is the command it faulted in, `None` if no command
+    /// of the script has code there
+    Fault { script_index: usize, position: Option<u32>, kind: FaultKind },
+    /// The command at `position` popped more values than the operand stack had. Only the
+    /// interpreter checks for it
+    StackUnderflow { script_index: usize, position: u32 },
+    /// The command at `position` jumped to `loc`, which isn't a command of the script.
+    /// `validate_script` rejects these up front, the interpreter checks again when jumping
+    InvalidJump { script_index: usize, position: u32, loc: u32 },
+}
+
+/// What a script faulted on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FaultKind {
+    /// Integer division by zero, or of i32::MIN by -1 (SIGFPE)
+    DivideError,
+    /// Access to unmapped or protected memory at `address` (SIGSEGV or SIGBUS)
+    InvalidAccess { address: u64 },
+}
+
+impl fmt::Display for FaultKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FaultKind::DivideError => write!(f, "integer division error"),
+            FaultKind::InvalidAccess { address } => {
+                write!(f, "invalid memory access at 0x{:X}", address)
+            }
+        }
+    }
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RunError::InvalidScript { script_index, script_count } => {
+                write!(f, "script index {} out of bounds (< {})", script_index, script_count)
+            }
+            RunError::ArgumentCount { script_index, expected, passed } => {
+                write!(f, "script_{} takes {} arguments, {} passed", script_index, expected, passed)
+            }
+            RunError::NotLocked => write!(f, "code is not executable, lock_all wasn't called"),
+            RunError::InvalidGlobal { var_num, global_count } => {
+                write!(f, "global {} out of bounds (< {})", var_num, global_count)
+            }
+            RunError::GlobalCount { expected, passed } => {
+                write!(f, "snapshot of {} globals restored to a program with {}", passed, expected)
+            }
+            RunError::Uncaught { script_index, position, value } => {
+                write!(f, "script_{} 0x{:X}: uncaught error 0x{:X}", script_index, position, value)
+            }
+            RunError::OutOfFuel { script_index, position } => {
+                write!(f, "script_{} 0x{:X}: out of fuel", script_index, position)
+            }
+            RunError::FuelNotEnabled => {
+                write!(f, "program was compiled without fuel checks")
+            }
+            RunError::InvalidCall { script_index, offset } => {
+                write!(f, "script_{}: call to 0x{:X}, which is not the start of a script",
+                       script_index, offset)
+            }
+            RunError::Syscall { script_index, sys_num, error } => {
+                write!(f, "script_{}: sys 0x{:X} failed ({})", script_index, sys_num, error)
+            }
+            RunError::InvalidString { script_index, str_num } => {
+                write!(f, "script_{}: printf format string {} out of bounds", script_index, str_num)
+            }
+            RunError::StackOverflow { script_index } => {
+                write!(f, "script_{}: stack overflow", script_index)
+            }
+            RunError::Fault { script_index, position: Some(position), kind } => {
+                write!(f, "script_{} 0x{:X}: {}", script_index, position, kind)
+            }
+            RunError::Fault { script_index, position: None, kind } => {
+                write!(f, "script_{}: {}", script_index, kind)
+            }
+            RunError::StackUnderflow { script_index, position } => {
+                write!(f, "script_{} 0x{:X}: stack underflow", script_index, position)
+            }
+            RunError::InvalidJump { script_index, position, loc } => {
+                write!(f, "script_{} 0x{:X}: target 0x{:X} is not a command in this script",
+                       script_index, position, loc)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RunError {}
@@ -0,0 +1,32 @@
+//! Global variables of a program. Generated code addresses them with immediates, so their storage
+//! is sized once from the commands of the file and never moves afterwards
+use msc::{Cmd, MscsbFile};
+
+/// Variable a command reads or writes, `(var_type, var_num)`
+pub fn var_ref(cmd: &Cmd) -> Option<(u8, u16)> {
+    match *cmd {
+        Cmd::PushVar { var_type, var_num } | Cmd::SetVar { var_type, var_num } |
+        Cmd::IncI { var_type, var_num } | Cmd::DecI { var_type, var_num } |
+        Cmd::AddVarBy { var_type, var_num } | Cmd::SubVarBy { var_type, var_num } |
+        Cmd::MultVarBy { var_type, var_num } | Cmd::DivVarBy { var_type, var_num } |
+        Cmd::ModVarBy { var_type, var_num } | Cmd::AndVarBy { var_type, var_num } |
+        Cmd::OrVarBy { var_type, var_num } | Cmd::XorVarBy { var_type, var_num } |
+        Cmd::IncF { var_type, var_num } | Cmd::DecF { var_type, var_num } |
+        Cmd::VarSetF { var_type, var_num } | Cmd::AddVarByF { var_type, var_num } |
+        Cmd::SubVarByF { var_type, var_num } | Cmd::MultVarByF { var_type, var_num } |
+        Cmd::DivVarByF { var_type, var_num } => Some((var_type, var_num)),
+        _ => None,
+    }
+}
+
+/// Number of globals the scripts of `file` use, one past the highest global `var_num`. Any
+/// nonzero `var_type` is a global to the code generator, not just 1
+pub fn global_count(file: &MscsbFile) -> usize {
+    file.scripts.iter()
+        .flat_map(|script| script.iter())
+        .filter_map(|command| var_ref(&command.cmd))
+        .filter(|&(var_type, _)| var_type != 0)
+        .map(|(_, var_num)| usize::from(var_num) + 1)
+        .max()
+        .unwrap_or(0)
+}
@@ -0,0 +1,255 @@
+//! Host side of the MSC system calls, enough for scripts to run without the game. Everything a
+//! syscall keeps between calls lives in a `HostState` shared by the registered closures
+//!
+//! Numbers 0 and 1 are the `getc` and `crc32_for_byte` builtins of the original syscall table.
+//! The numbers and semantics the game itself gives its syscalls aren't implemented: there is no
+//! table of them we could check against, and guessing would make game scripts silently do the
+//! wrong thing instead of failing with `UnregisteredSyscall`.
+//!
+//! Everything else here is an extension of this crate, not a syscall of the game. Extensions
+//! start at `EXTENSIONS_START` so the numbers below stay free for the game's, grouped by area:
+//! math from 0xC0, random numbers from 0xD0, strings and text formatting from 0xE0 and arrays
+//! from 0xF0. Floats are passed and returned as their bit pattern, like everywhere else in MSC
+use std::cell::RefCell;
+use std::rc::Rc;
+use msc::MscsbFile;
+use super::syscalls::{SyscallRegistry, SyscallArgs, SyscallError};
+
+/// First number of the extensions, nothing below it but the builtins is registered here
+pub const EXTENSIONS_START: u8 = 0xC0;
+
+pub const SIN: u8 = 0xC0;
+pub const COS: u8 = 0xC1;
+pub const ATAN2: u8 = 0xC2;
+pub const SQRT: u8 = 0xC3;
+pub const POW: u8 = 0xC4;
+pub const FLOOR: u8 = 0xC5;
+pub const ABS: u8 = 0xC6;
+pub const ABS_F: u8 = 0xC7;
+pub const MIN: u8 = 0xC8;
+pub const MAX: u8 = 0xC9;
+
+pub const RAND_SEED: u8 = 0xD0;
+pub const RAND: u8 = 0xD1;
+pub const RAND_F: u8 = 0xD2;
+pub const RAND_RANGE: u8 = 0xD3;
+
+pub const STR_LEN: u8 = 0xE0;
+pub const STR_CHAR: u8 = 0xE1;
+pub const STR_EQ: u8 = 0xE2;
+pub const TEXT_STR: u8 = 0xE3;
+pub const TEXT_INT: u8 = 0xE4;
+pub const TEXT_FLOAT: u8 = 0xE5;
+pub const TEXT_FLUSH: u8 = 0xE6;
+
+pub const ARRAY_NEW: u8 = 0xF0;
+pub const ARRAY_FREE: u8 = 0xF1;
+pub const ARRAY_LEN: u8 = 0xF2;
+pub const ARRAY_GET: u8 = 0xF3;
+pub const ARRAY_SET: u8 = 0xF4;
+
+/// Seed used until a script calls RAND_SEED
+const DEFAULT_SEED: u32 = 0x2545_F491;
+
+/// Largest array a script can allocate, in entries
+const MAX_ARRAY_LEN: u32 = 0x10_0000;
+
+pub struct HostState {
+    strings: Vec<String>,
+    rng: u32,
+    /// Text built up by the TEXT_* syscalls, printed by TEXT_FLUSH
+    text: String,
+    /// Arrays by handle - 1, freed ones are None
+    arrays: Vec<Option<Vec<u32>>>,
+}
+
+impl HostState {
+    /// State for running the scripts of `file`, string syscalls index its string table
+    pub fn new(file: &MscsbFile) -> HostState {
+        HostState {
+            strings: file.strings.clone(),
+            rng: DEFAULT_SEED,
+            text: String::new(),
+            arrays: vec![],
+        }
+    }
+
+    /// Shared state with every extension of this module registered on top of the builtins
+    pub fn registry(file: &MscsbFile) -> (SyscallRegistry, Rc<RefCell<HostState>>) {
+        let state = Rc::new(RefCell::new(HostState::new(file)));
+        let mut registry = SyscallRegistry::default();
+        register(&mut registry, &state);
+        (registry, state)
+    }
+
+    pub fn seed(&mut self, seed: u32) {
+        // xorshift never leaves 0
+        self.rng = if seed == 0 { DEFAULT_SEED } else { seed };
+    }
+
+    /// xorshift32
+    pub fn next_random(&mut self) -> u32 {
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rng = x;
+        x
+    }
+
+    /// Text formatted but not flushed yet
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn array(&self, handle: u32) -> Option<&[u32]> {
+        self.arrays.get((handle as usize).wrapping_sub(1))
+            .and_then(Option::as_deref)
+    }
+
+    fn string(&self, str_num: u32) -> Result<&str, SyscallError> {
+        self.strings.get(str_num as usize)
+            .map(String::as_str)
+            .ok_or_else(|| SyscallError::Host(format!("no string {}", str_num)))
+    }
+
+    fn array_mut(&mut self, handle: u32) -> Result<&mut Vec<u32>, SyscallError> {
+        self.arrays.get_mut((handle as usize).wrapping_sub(1))
+            .and_then(Option::as_mut)
+            .ok_or_else(|| SyscallError::Host(format!("invalid array handle {}", handle)))
+    }
+
+    fn new_array(&mut self, len: u32) -> Result<u32, SyscallError> {
+        if len > MAX_ARRAY_LEN {
+            return Err(SyscallError::Host(format!("array of {} entries is too large", len)));
+        }
+        let array = vec![0; len as usize];
+        let index = match self.arrays.iter().position(Option::is_none) {
+            Some(index) => {
+                self.arrays[index] = Some(array);
+                index
+            }
+            None => {
+                self.arrays.push(Some(array));
+                self.arrays.len() - 1
+            }
+        };
+        Ok(index as u32 + 1)
+    }
+}
+
+fn index_error(index: u32, len: usize) -> SyscallError {
+    SyscallError::Host(format!("index {} out of bounds (< {})", index, len))
+}
+
+fn float(val: f32) -> Result<u32, SyscallError> {
+    Ok(val.to_bits())
+}
+
+/// Register every extension of this module, backed by `state`
+pub fn register(registry: &mut SyscallRegistry, state: &Rc<RefCell<HostState>>) {
+    registry.register(SIN, |args| float(args.float(0)?.sin()));
+    registry.register(COS, |args| float(args.float(0)?.cos()));
+    registry.register(ATAN2, |args| float(args.float(0)?.atan2(args.float(1)?)));
+    registry.register(SQRT, |args| float(args.float(0)?.sqrt()));
+    registry.register(POW, |args| float(args.float(0)?.powf(args.float(1)?)));
+    registry.register(FLOOR, |args| float(args.float(0)?.floor()));
+    registry.register(ABS, |args| Ok((args.int(0)? as i32).wrapping_abs() as u32));
+    registry.register(ABS_F, |args| float(args.float(0)?.abs()));
+    registry.register(MIN, |args| Ok((args.int(0)? as i32).min(args.int(1)? as i32) as u32));
+    registry.register(MAX, |args| Ok((args.int(0)? as i32).max(args.int(1)? as i32) as u32));
+
+    let with_state = |registry: &mut SyscallRegistry, sys_num: u8,
+                      f: fn(&mut HostState, &SyscallArgs) -> Result<u32, SyscallError>| {
+        let state = state.clone();
+        registry.register(sys_num, move |args| f(&mut state.borrow_mut(), args));
+    };
+
+    with_state(registry, RAND_SEED, |state, args| {
+        state.seed(args.int(0)?);
+        Ok(0)
+    });
+    // Integer in [0, max), max = 0 gives the raw 32 bits
+    with_state(registry, RAND, |state, args| {
+        let max = args.int(0)?;
+        let val = state.next_random();
+        Ok(if max == 0 { val } else { val % max })
+    });
+    // Float in [0, 1), from the top 24 bits so every value is exact
+    with_state(registry, RAND_F, |state, _| {
+        float((state.next_random() >> 8) as f32 / (1u32 << 24) as f32)
+    });
+    // Signed integer in [min, max)
+    with_state(registry, RAND_RANGE, |state, args| {
+        let (min, max) = (args.int(0)? as i32, args.int(1)? as i32);
+        if max <= min {
+            return Ok(min as u32);
+        }
+        let span = (i64::from(max) - i64::from(min)) as u64;
+        Ok((i64::from(min) + (u64::from(state.next_random()) % span) as i64) as u32)
+    });
+
+    with_state(registry, STR_LEN, |state, args| {
+        Ok(state.string(args.int(0)?)?.len() as u32)
+    });
+    with_state(registry, STR_CHAR, |state, args| {
+        let (string, index) = (state.string(args.int(0)?)?, args.int(1)?);
+        string.as_bytes().get(index as usize)
+            .map(|&byte| u32::from(byte))
+            .ok_or_else(|| index_error(index, string.len()))
+    });
+    with_state(registry, STR_EQ, |state, args| {
+        Ok((state.string(args.int(0)?)? == state.string(args.int(1)?)?) as u32)
+    });
+    with_state(registry, TEXT_STR, |state, args| {
+        let string = state.string(args.int(0)?)?.to_owned();
+        state.text.push_str(&string);
+        Ok(state.text.len() as u32)
+    });
+    with_state(registry, TEXT_INT, |state, args| {
+        let val = args.int(0)? as i32;
+        state.text.push_str(&val.to_string());
+        Ok(state.text.len() as u32)
+    });
+    // Float with a number of decimals, 6 if not passed
+    with_state(registry, TEXT_FLOAT, |state, args| {
+        let val = args.float(0)?;
+        let precision = if args.len() > 1 { args.int(1)?.min(32) } else { 6 };
+        state.text.push_str(&format!("{:.*}", precision as usize, val));
+        Ok(state.text.len() as u32)
+    });
+    // Print the text like printf would and clear it, returns how many bytes were printed
+    with_state(registry, TEXT_FLUSH, |state, args| {
+        let text = std::mem::take(&mut state.text);
+        args.write_output(text.as_bytes());
+        Ok(text.len() as u32)
+    });
+
+    // Handle of a zeroed array, handles start at 1
+    with_state(registry, ARRAY_NEW, |state, args| state.new_array(args.int(0)?));
+    with_state(registry, ARRAY_FREE, |state, args| {
+        let handle = args.int(0)?;
+        state.array_mut(handle)?;
+        state.arrays[handle as usize - 1] = None;
+        Ok(0)
+    });
+    with_state(registry, ARRAY_LEN, |state, args| {
+        Ok(state.array_mut(args.int(0)?)?.len() as u32)
+    });
+    with_state(registry, ARRAY_GET, |state, args| {
+        let index = args.int(1)?;
+        let array = state.array_mut(args.int(0)?)?;
+        array.get(index as usize)
+            .copied()
+            .ok_or_else(|| index_error(index, array.len()))
+    });
+    // Returns the value stored
+    with_state(registry, ARRAY_SET, |state, args| {
+        let (index, val) = (args.int(1)?, args.int(2)?);
+        let array = state.array_mut(args.int(0)?)?;
+        let len = array.len();
+        let slot = array.get_mut(index as usize).ok_or_else(|| index_error(index, len))?;
+        *slot = val;
+        Ok(val)
+    });
+}
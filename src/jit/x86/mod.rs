@@ -1,17 +1,43 @@
 use std::io::prelude::*;
-use super::{JitMemory, PAGE_SIZE};
-use msc::{MscsbFile, Cmd, Script};
-use std::io::{Cursor, SeekFrom};
-use x86asm::{OperandSize, RegScale, InstructionWriter, Mnemonic, Mode, Operand, Reg};
-use libc::c_void;
-use std::process::{Command};
+use super::arena::CodeArena;
+use msc::{MscsbFile, Cmd, Command, Script};
+use std::io::{self, Cursor, SeekFrom};
+use x86asm::{OperandSize, InstructionWriter, Mnemonic, Mode, Operand, Reg};
+use std::process;
 use std::collections::{HashSet, HashMap};
+use std::rc::Rc;
+use std::cell::Cell;
+use std::mem;
 
 mod asm_helper;
 use asm_helper::*;
-mod printf;
-use printf::msc_printf;
-mod syscalls;
+pub mod printf;
+use printf::{msc_printf, Printer, StringTable};
+pub mod output;
+use output::OutputSink;
+pub mod syscalls;
+use syscalls::{SyscallRegistry, call_syscall};
+pub mod host;
+mod dispatch;
+pub use dispatch::DispatchEntry;
+use dispatch::resolve_call;
+mod error;
+pub use error::{CompileError, EncodingError, FaultKind, RunError};
+mod ast_backend;
+mod entry;
+use entry::{compile_entry, EntryFn};
+pub mod runtime;
+use runtime::{
+    RuntimeContext, error_value, push_handler, pop_handler, raise, unlink_frame_handlers, exit,
+    use_fuel, enter_frame, leave_frame, check_call_target, check_syscall
+};
+use super::stack::{ScriptStack, STACK_HEADROOM};
+pub mod trap;
+use trap::{CommandMap, TrapRegion};
+pub mod value;
+pub use value::{ExitStatus, Signature, Value};
+pub mod globals;
+use globals::global_count;
 
 use Reg::*;
 use Operand::*;
@@ -19,19 +45,92 @@ use OperandSize::*;
 use Mnemonic::*;
 
 pub struct CompiledProgram {
-    pub mem: Vec<JitMemory>,
-    pub string_section: Vec<u8>,
-    pub string_offsets: Vec<*const c_void>,
+    /// Registration of the script code with the fault handler, dropped before the code
+    trap_region: TrapRegion,
+    /// Every script, followed by the entry stub they are run through
+    pub code: CodeArena,
+    /// Commands of the code of each script, for locating faults
+    command_maps: Vec<CommandMap>,
+    /// Boxed so the address baked into the PrintF and Sys code stays valid
+    pub printer: Box<Printer>,
     pub entrypoint_index: usize,
-    pub global_vars: Vec<u32>,
+    /// Sized by `global_count`, its address is baked into the code so it's only reachable
+    /// through the accessors. Cells, as the code writes them through a shared pointer
+    global_vars: Box<[Cell<u32>]>,
+    pub dispatch_table: Vec<DispatchEntry>,
+    /// Code generator each script was compiled with
+    pub backends: Vec<Backend>,
+    pub signatures: Vec<Signature>,
+    /// Host functions called by `sys` commands
+    pub syscalls: Rc<SyscallRegistry>,
+    /// Boxed so the address baked into the code stays valid
+    runtime: Box<RuntimeContext>,
+    /// Whether the code checks fuel, see `CompileOptions::fuel`
+    fuel: bool,
+    max_call_depth: Option<u32>,
+    /// Stack the scripts run on, see `CompileOptions::script_stack_size`
+    stack: Option<ScriptStack>,
+}
+
+/// Code generator used for the scripts of a program
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Backend {
+    /// Translate every command on its own, with every value going through the native stack
+    #[default]
+    Commands,
+    /// Compile the AST of the script, evaluating expressions in registers. Scripts whose AST
+    /// doesn't evaluate exactly like their commands are compiled with `Commands` instead
+    Ast,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CompileOptions {
+    /// Print the objdump disassembly of every compiled script
+    pub dump_asm: bool,
+    /// Write the code through a separate mapping from the one it runs from, instead of
+    /// switching the protection of a single mapping with mprotect
+    pub dual_mapping: bool,
+    /// Code generator to compile scripts with
+    pub backend: Backend,
+    /// Host functions `sys` commands are bound to, using a `sys` number missing from it is a
+    /// compile error
+    pub syscalls: Rc<SyscallRegistry>,
+    /// Use fuel at every script entry and backward jump, so runs can be given a budget with
+    /// `run_script_with_fuel`
+    pub fuel: bool,
+    /// Script calls a run can nest, the first script included. Going deeper ends the run with
+    /// `RunError::StackOverflow`
+    pub max_call_depth: Option<u32>,
+    /// Run scripts on a stack of their own of this many bytes, with a guard page below it.
+    /// Running out of it ends the run with `RunError::StackOverflow`. It has to be larger than
+    /// the `STACK_HEADROOM` every prologue keeps free
+    pub script_stack_size: Option<usize>,
+}
+
+impl CompileOptions {
+    /// Whether prologues check (the call depth, the space left on the script stack)
+    fn stack_checks(&self) -> (bool, bool) {
+        (self.max_call_depth.is_some(), self.script_stack_size.is_some())
+    }
 }
 
 pub trait Compilable {
-    fn compile(&self) -> Option<CompiledProgram>;
+    fn compile_with(&self, options: &CompileOptions) -> Result<CompiledProgram, CompileError>;
+
+    fn compile(&self) -> Result<CompiledProgram, CompileError> {
+        self.compile_with(&CompileOptions::default())
+    }
+
+    /// Compile every script without stopping at the first failure, returning all errors found
+    fn check_with(&self, options: &CompileOptions) -> Vec<CompileError>;
+
+    fn check(&self) -> Vec<CompileError> {
+        self.check_with(&CompileOptions::default())
+    }
 }
 
 fn get_var_info(script: &Script) -> Option<(u16, u16)> {
-    if let Some(cmd) = script.iter().nth(0) {
+    if let Some(cmd) = script.iter().next() {
         if let Cmd::Begin { arg_count, var_count } = cmd.cmd {
             Some((arg_count, var_count))
         } else {
@@ -52,687 +151,1168 @@ static ARG_REGS_32: [Reg; 6] = [
 mod asm_macro;
 use asm_macro::asm_impl;
 
-impl Compilable for MscsbFile {
-    fn compile(&self) -> Option<CompiledProgram> {
-        let global_vars = vec![0; 0x100];
-        
-        let mut string_writer = Cursor::new(Vec::new());
-        let mut string_offsets: Vec<usize> = vec![];
-        for string in self.strings.iter() {
-            string_offsets.push(string_writer.get_ref().len());
-            string_writer.write_all(string.as_bytes()).unwrap();
-            string_writer.write_all(&[0u8]).unwrap();
-        }
-        let string_section = string_writer.into_inner();
-        let string_offsets = string_offsets.iter().map(
-            |offset| unsafe {
-                string_section.as_ptr().add(*offset) as *const c_void
-            }
-        ).collect::<Vec<*const c_void>>();
-
-        let mut mem = vec![];
-        let mut call_relocs = vec![];
-        for script_index in 0..self.scripts.len() {
-            let mut last_cmd_pushint: Option<u32> = None;
-            let mut ret_val_locations = HashSet::new();
-            let mut jump_relocations = vec![];
-            let mut command_locations = HashMap::new();
-            // Setup stack frame and whatnot
-            let buffer = Cursor::new(Vec::new());
-            let mut writer = InstructionWriter::new(buffer, Mode::Long);
-
-
-            macro_rules! asm {
-                (
-                    $(
-                        $mnem:ident $($op:expr),*;
-                    )*
-                ) => {
-                    asm_impl!(writer, {
-                        $(
-                            $mnem $($op),*
-                        );*
-                    })
-                };
-            }
+/// The constant a PushInt/PushShort puts on the stack, used to make calls to constant offsets
+/// static
+fn pushed_int(cmd: &Command) -> Option<u32> {
+    match cmd.cmd {
+        Cmd::PushInt { val } if cmd.push_bit => Some(val),
+        Cmd::PushShort { val } if cmd.push_bit => Some(u32::from(val)),
+        _ => None,
+    }
+}
 
-            if let Some((arg_count, var_count)) = get_var_info(&self.scripts[script_index]) {
-                writer.setup_stack_frame(u32::from(var_count)).unwrap();
-                for i in 0..std::cmp::min(arg_count, 6) {
-                    writer.mov(
-                        (RBP, u64::from(i) * 4, Dword),
-                        ARG_REGS_32[i as usize]
-                    ).unwrap();
+/// Reject commands the code generator can't handle before emitting anything. The interpreter
+/// rejects the same ones
+pub fn validate_script(file: &MscsbFile, script_index: usize, syscalls: &SyscallRegistry)
+    -> Result<(), CompileError>
+{
+    let script = &file.scripts[script_index];
+    let positions = script.iter().map(|cmd| cmd.position).collect::<HashSet<u32>>();
+    let mut last_cmd_pushint: Option<u32> = None;
+    for cmd in script.iter().skip(1) {
+        let position = cmd.position;
+        match cmd.cmd {
+            Cmd::Unk1 => {
+                return Err(CompileError::UnsupportedCommand { script_index, position, cmd: cmd.cmd });
+            }
+            Cmd::Begin { .. } => {
+                return Err(CompileError::MisplacedBegin { script_index, position, cmd: cmd.cmd });
+            }
+            Cmd::Jump { loc } | Cmd::Jump5 { loc } | Cmd::Else { loc } |
+            Cmd::If { loc } | Cmd::IfNot { loc } => {
+                let is_valid = loc.checked_sub(script.bounds.0)
+                    .is_some_and(|target| positions.contains(&target));
+                if !is_valid {
+                    return Err(CompileError::InvalidJumpTarget {
+                        script_index, position, cmd: cmd.cmd, loc
+                    });
                 }
-                for i in 0..arg_count as isize - 6 {
-                    writer.mov(
-                        EAX,
-                        (RSP, ((i as u64) * 8) + 16 + (var_count + ((4 - (var_count % 4)) % 4)) as u64 * 4, Dword),
-                    ).unwrap();
-                    writer.mov(
-                        (RBP, ((i as u64) + 6) * 4, Dword),
-                        EAX
-                    ).unwrap();
+            }
+            // The handler is unlinked when reaching the target, which has to come after the Try
+            Cmd::Try { loc } => {
+                let is_valid = loc.checked_sub(script.bounds.0)
+                    .is_some_and(|target| target > position && positions.contains(&target));
+                if !is_valid {
+                    return Err(CompileError::InvalidTryTarget {
+                        script_index, position, cmd: cmd.cmd, loc
+                    });
                 }
-                for cmd in self.scripts[script_index].iter().skip(1) {
-                    if ret_val_locations.contains(&(cmd.position + self.scripts[script_index].bounds.0)) {
-                        writer.push(RAX).unwrap();
+            }
+            // The format string is the first argument
+            Cmd::PrintF { arg_count: 0 } => {
+                return Err(CompileError::MissingFormat { script_index, position, cmd: cmd.cmd });
+            }
+            Cmd::Sys { sys_num, .. } if !syscalls.is_registered(sys_num) => {
+                return Err(CompileError::UnregisteredSyscall {
+                    script_index, position, cmd: cmd.cmd, sys_num
+                });
+            }
+            Cmd::CallFunc { .. } | Cmd::CallFunc2 { .. } | Cmd::CallFunc3 { .. } => {
+                // Dynamic calls are checked at runtime by resolve_call
+                if let Some(loc) = last_cmd_pushint {
+                    let is_script_start = file.get_script_from_loc(loc)
+                        .is_some_and(|target| file.scripts[target].bounds.0 == loc);
+                    if !is_script_start {
+                        return Err(CompileError::InvalidCallTarget {
+                            script_index, position, cmd: cmd.cmd, loc
+                        });
                     }
-                    let command_asm_pos = writer.get_inner_writer_ref().position();
-                    command_locations.insert(&cmd.position, command_asm_pos);
-                    match cmd.cmd {
-                        Cmd::Unk1 | Cmd::ErrorC | Cmd::Error37 | Cmd::Error4C => {
-                            panic!("Unsupported command {:?}", cmd.cmd);
-                        }
-                        Cmd::Begin { .. } => {
-                            panic!("Begin not allowed after first command of script");
-                        }
-                        Cmd::Jump { loc } | Cmd::Jump5 { loc } | Cmd::Else { loc } => {
-                            asm!(
-                                JMP 0u32;
-                            );
-                            jump_relocations.push((command_asm_pos, JMP, loc - self.scripts[script_index].bounds.0));
-                        }
-                        Cmd::Sys { sys_num, arg_count } => {
-                            asm!(
-                                MOV RDI, RSP;
-                                MOV RSI, (u32::from(arg_count));
-                                MOV RCX, (syscalls::SYSCALL_TABLE[sys_num as usize] as u64);
-                                PUSH R15;
-                                MOV R15, RSP;
-                                AND R15, 8u8;
-                                SUB RSP, R15;
-                                CALL RCX;
-                                ADD RSP, R15;
-                                POP R15;
-                                ADD RSP, (8 * arg_count);
-                            );
-                            if cmd.push_bit {
-                                asm!(
-                                    PUSH RAX;
-                                );
-                            }
-                        }
-                        Cmd::Push => {
-                            if cmd.push_bit {
-                                asm!(
-                                    POP RAX;
-                                    PUSH RAX;
-                                    PUSH RAX;
-                                );
-                            }
-                        }
-                        Cmd::Pop => {
-                            if !cmd.push_bit {
-                                asm!(
-                                    ADD RSP, 8u8;
-                                );
-                            }
-                        }
-                        Cmd::If { loc } | Cmd::IfNot { loc } => {
-                            asm!(
-                                POP RAX;
-                                CMP RAX, 0u8;
-                            );
-                            let command_asm_pos = writer.get_inner_writer_ref().position();
-                            let mnem = if let Cmd::If { .. } = cmd.cmd { JE } else { JNE };
-                            asm!(
-                                mnem 0u32;
-                            );
-                            jump_relocations.push((
-                                command_asm_pos,
-                                mnem,
-                                loc - self.scripts[script_index].bounds.0
-                            ));
-                        }
-                        Cmd::CallFunc { arg_count } | Cmd::CallFunc2 { arg_count } |
-                        Cmd::CallFunc3 { arg_count } => {
-                            if let Some(i) = last_cmd_pushint {
-                                writer.seek(SeekFrom::Current(-5)).unwrap();
-                                if arg_count > 6 {
-                                    asm!(
-                                        ADD RSP, ((arg_count - 6) * 8);
-                                    );
-                                }
-                                let arg_reg_count = std::cmp::min(arg_count, 6);
-                                for i in 0..arg_reg_count {
-                                    asm!(
-                                        POP ARG_REGS[(arg_reg_count - (i + 1)) as usize];
-                                    );
-                                }
-                                if arg_count > 6 {
-                                    asm!(
-                                        SUB RSP, ((arg_count - 6) * 8);
-                                    );
-                                    for i in 0..(arg_count - arg_reg_count) {
-                                        asm!(
-                                            MOV RAX, (RSP, (u64::from(i) * 8) + (-0x30i64 as u64), Qword);
-                                            MOV (RSP, (((arg_count - arg_reg_count) as u64 - (u64::from(i) + 1)) * 8), Qword), RAX;
-                                        );
-                                    }
-                                }
-                                let command_asm_pos = writer.get_inner_writer_ref().position();
-                                command_locations.insert(&cmd.position, command_asm_pos);
-                                call_relocs.push((script_index, command_asm_pos, i));
-                                writer.mov_rax_0().unwrap();
-                                asm!(
-                                    CALL RAX;
-                                );
-                                if arg_count > 6 {
-                                    asm!(
-                                        ADD RSP, ((arg_count - 6) * 8);
-                                    );
-                                }
-                            } else {
-                                // Dynamically find function pointer
-                                // (and cry at the performance impact)
-                                panic!("Dynamic function calls not supported");
-                            }
-                        }
-                        Cmd::PushShort { val } => {
-                            if cmd.push_bit {
-                                asm!(
-                                    PUSH (u32::from(val));
-                                );
-                            }
-                        }
-                        Cmd::PushInt { val } => {
-                            if cmd.push_bit {
-                                asm!(
-                                    PUSH val;
-                                );
-                            }
-                        }
-                        Cmd::IntToFloat { stack_pos } => {
-                            asm!(
-                                FILD (RSP, u64::from(stack_pos) * 8, Dword);
-                                FSTP (RSP, u64::from(stack_pos) * 8, Dword);
-                            );
-                        }
-                        Cmd::FloatToInt { stack_pos } => {
-                            asm!(
-                                FSTCW (RSP, -2i64 as u64, Word);
-                                OR (RSP, -2i64 as u64, Word), 0xc00u16;
-                                FLDCW (RSP, -2i64 as u64, Word);
-                                FLD (RSP, u64::from(stack_pos) * 8, Dword);
-                                FISTP (RSP, u64::from(stack_pos) * 8, Dword);
-                            );
-                        }
-                        Cmd::PushVar { var_type, var_num } => {
-                            if var_type == 0 {
-                                // Local variable
-                                asm!(
-                                    MOV EAX, (RBP, u64::from(var_num) * 4, Dword);
-                                    PUSH RAX;
-                                );
-                            } else {
-                                // Global variable
-                                writer.get_global(global_vars.as_ptr(), EAX, var_num).unwrap();
-                                asm!(
-                                    PUSH RAX;
-                                );
-                            }
-                        }
-                        Cmd::SetVar { var_type, var_num } | Cmd::VarSetF { var_type, var_num } => {
-                            if var_type == 0 {
-                                // Local var
-                                asm!(
-                                    POP RAX;
-                                    MOV (RBP, u64::from(var_num) * 4, Dword), EAX;
-                                );
-                            } else {
-                                // Global var
-                                asm!(
-                                    POP RCX;
-                                );
-                                writer.set_global(global_vars.as_ptr(), ECX, var_num).unwrap();
-                            }
-                        }
-                        Cmd::IncI { var_type, var_num } | Cmd::DecI { var_type, var_num } => {
-                            if var_type == 0 {
-                                // Local var
-                                asm!(
-                                    INC (RBP, u64::from(var_num) * 4, Dword);
-                                );
-                            } else {
-                                // Global var
-                                writer.get_global(global_vars.as_ptr(), ECX, var_num).unwrap();
-                                asm!(
-                                    INC RCX;
-                                );
-                                writer.set_global(global_vars.as_ptr(), ECX, var_num).unwrap();
-                            }
-                        }
-                        Cmd::IncF { var_type, var_num } | Cmd::DecF { var_type, var_num } => {
-                            asm!(
-                                MOV (RSP, -4i64 as u64, Dword),
-                                        if let Cmd::IncF { .. } = cmd.cmd {
-                                            1u32
-                                        } else {
-                                            -1i32 as u32
-                                        };
-                            );
-                            if var_type == 0 {
-                                // Local var
-                                asm!(
-                                    FLD (RBP, u64::from(var_num) * 4, Dword);
-                                    FIADD (RSP, -4i64, Dword);
-                                    FSTP (RBP, u64::from(var_num) * 4, Dword);
-                                );
-                            } else {
-                                // Global var
-                                writer.get_global_float(global_vars.as_ptr(), var_num).unwrap();
-                                asm!(
-                                    FIADD (RSP, -4i64 as u64, Dword);
-                                );
-                                writer.set_global_float(global_vars.as_ptr(), var_num).unwrap();
-                            }
-                        }
-                        Cmd::AddVarByF { var_type, var_num } | Cmd::SubVarByF { var_type, var_num } |
-                        Cmd::DivVarByF { var_type, var_num } | Cmd::MultVarByF { var_type, var_num }
-                        => {
-                            if var_type == 0 {
-                                // Local var
-                                asm!(
-                                    FLD (RBP, u64::from(var_num) * 4, Dword);
-                                    FADD (RSP, Dword);
-                                    FSTP (RBP, u64::from(var_num) * 4, Dword);
-                                );
-                            } else {
-                                // Global var
-                                writer.get_global_float(global_vars.as_ptr(), var_num).unwrap();
-                                asm!(
-                                    FADD (RSP, Dword);
-                                );
-                                writer.set_global_float(global_vars.as_ptr(), var_num).unwrap();
-                            }
-                            asm!(
-                                ADD RSP, 8u8;
-                            );
-                        }
-                        Cmd::AddVarBy { var_type, var_num } | Cmd::SubVarBy { var_type, var_num } |
-                        Cmd::AndVarBy { var_type, var_num } | Cmd::OrVarBy {var_type, var_num} |
-                        Cmd::XorVarBy { var_type, var_num } => {
-                            asm!(
-                                POP RCX;
-                            );
-                            let operation = match cmd.cmd {
-                                Cmd::AddVarBy { .. } => ADD,
-                                Cmd::SubVarBy { .. } => SUB,
-                                Cmd::AndVarBy { .. } => AND,
-                                Cmd::OrVarBy { .. } => OR,
-                                Cmd::XorVarBy { .. } => XOR,
-                                _ => { unreachable!() }
-                            };
-                            if var_type == 0 {
-                                asm!(
-                                    MOV ECX, (RBP, u64::from(var_num) * 4, Dword);
-                                    operation ECX, EAX;
-                                    MOV (RBP, u64::from(var_num) * 4, Dword), ECX;
-                                );
-                            } else {
-                                writer.get_global(global_vars.as_ptr(), EAX, var_num).unwrap();
-                                asm!(
-                                    operation EAX, ECX;
-                                );
-                                writer.set_global(global_vars.as_ptr(), EAX, var_num).unwrap();
-                            }
-                        }
-                        Cmd::MultVarBy { var_type, var_num } | Cmd::DivVarBy { var_type, var_num } |
-                        Cmd::ModVarBy { var_type, var_num } => {
-                            asm!(
-                                POP RCX;
-                            );
-                            let operation = match cmd.cmd {
-                                Cmd::MultVarBy { .. } => IMUL,
-                                Cmd::DivVarBy { .. } | Cmd::ModVarBy { .. } => IDIV,
-                                _ => { unreachable!() }
+                }
+            }
+            _ => {}
+        }
+        last_cmd_pushint = pushed_int(cmd);
+    }
+    Ok(())
+}
+
+type CodeWriter = InstructionWriter<Cursor<Vec<u8>>>;
+
+/// Addresses of program data baked into the generated code
+#[derive(Clone, Copy)]
+struct ProgramPointers {
+    global_vars: *mut u32,
+    printer: *const Printer,
+    dispatch_table: *const DispatchEntry,
+    dispatch_len: usize,
+    syscalls: *const SyscallRegistry,
+    runtime: *const RuntimeContext,
+}
+
+/// Codegen state for the script currently being compiled
+struct ScriptState<'a> {
+    script: &'a Script,
+    script_index: usize,
+    var_count: u16,
+    ptrs: ProgramPointers,
+    last_cmd_pushint: Option<u32>,
+    ret_val_locations: HashSet<u32>,
+    /// Targets of the Try commands emitted so far, where their handler is unlinked
+    try_locations: HashSet<u32>,
+    /// Whether the script has a Try, its returns then unlink the handlers left in its frame
+    has_try: bool,
+    /// (position of the JMP to the code resuming after an error, Try target, Try command)
+    handler_relocations: Vec<(u64, u32, &'a Command)>,
+    /// Position errors resume at for each Try target
+    resume_locations: HashMap<u32, u64>,
+    /// Whether backward jumps use fuel
+    fuel: bool,
+    /// Whether returns give back the call depth taken by the prologue
+    check_depth: bool,
+    /// (position in the code, jump mnemonic, jump target relative to the script, jump command)
+    jump_relocations: Vec<(u64, Mnemonic, u32, &'a Command)>,
+    command_locations: HashMap<u32, u64>,
+    /// (script_index, position of the `call rel32` in the code, called script offset)
+    call_relocs: Vec<(usize, u64, u32)>,
+}
+
+/// Emit the code for a single script, static calls to other scripts are appended to
+/// `call_relocs`. Returns the backend that was actually used and where the commands are in
+/// the code, only known for the commands backend
+fn compile_script(
+    file: &MscsbFile,
+    script_index: usize,
+    ptrs: ProgramPointers,
+    options: &CompileOptions,
+    call_relocs: &mut Vec<(usize, u64, u32)>,
+) -> Result<(Vec<u8>, Backend, CommandMap), CompileError> {
+    // The registry outlives the compiled program, see ProgramPointers
+    validate_script(file, script_index, unsafe { &*ptrs.syscalls })?;
+
+    if options.backend == Backend::Ast {
+        let code = ast_backend::compile_script(file, script_index, ptrs, options, call_relocs)?;
+        if let Some((code, command_map)) = code {
+            return Ok((code, Backend::Ast, command_map));
+        }
+    }
+
+    let script = &file.scripts[script_index];
+    let (arg_count, var_count) = match get_var_info(script) {
+        Some(var_info) => var_info,
+        // No Begin, nothing to run
+        None => return Ok((vec![0xc3], Backend::Commands, CommandMap::default())),
+    };
+    let begin = &script.commands[0];
+    let mut state = ScriptState {
+        script,
+        script_index,
+        var_count,
+        ptrs,
+        last_cmd_pushint: None,
+        ret_val_locations: HashSet::new(),
+        try_locations: HashSet::new(),
+        has_try: script.iter().any(|cmd| matches!(cmd.cmd, Cmd::Try { .. })),
+        handler_relocations: vec![],
+        resume_locations: HashMap::new(),
+        fuel: options.fuel,
+        check_depth: options.max_call_depth.is_some(),
+        jump_relocations: vec![],
+        command_locations: HashMap::new(),
+        call_relocs: vec![],
+    };
+    let mut writer = InstructionWriter::new(Cursor::new(Vec::new()), Mode::Long);
+
+    // Setup stack frame and whatnot
+    let (check_depth, check_stack) = options.stack_checks();
+    writer.setup_stack_frame(u32::from(var_count))
+        .map_err(EncodingError::from)
+        .and_then(|_| if check_depth || check_stack {
+            enter_frame(&mut writer, ptrs.runtime as u64, script_index, check_depth, check_stack)
+        } else {
+            Ok(())
+        })
+        .and_then(|_| writer.load_args(arg_count, var_count).map_err(EncodingError::from))
+        .and_then(|_| if options.fuel {
+            use_fuel(&mut writer, ptrs.runtime as u64, script_index, begin.position)
+        } else {
+            Ok(())
+        })
+        .map_err(|error| CompileError::Encoding {
+            script_index, position: begin.position, cmd: begin.cmd, error
+        })?;
+
+    for cmd in script.iter().skip(1) {
+        emit_cmd(&mut writer, &mut state, cmd)
+            .map_err(|error| CompileError::Encoding {
+                script_index, position: cmd.position, cmd: cmd.cmd, error
+            })?;
+        state.last_cmd_pushint = pushed_int(cmd);
+    }
+    //writer.write_ret(u32::from(var_count)).unwrap();
+    for &(asm_pos, mnem, target, cmd) in state.jump_relocations.iter() {
+        // Jump targets are checked by validate_script
+        patch_jump(&mut writer, asm_pos, mnem, state.command_locations[&target])
+            .map_err(|error| CompileError::Encoding {
+                script_index, position: cmd.position, cmd: cmd.cmd, error
+            })?;
+    }
+    for &(asm_pos, loc, cmd) in state.handler_relocations.iter() {
+        // Try targets are checked by validate_script, and emitted after the Try
+        patch_jump(&mut writer, asm_pos, JMP, state.resume_locations[&loc])
+            .map_err(|error| CompileError::Encoding {
+                script_index, position: cmd.position, cmd: cmd.cmd, error
+            })?;
+    }
+    call_relocs.append(&mut state.call_relocs);
+    let command_map = CommandMap::new(begin.position, &state.command_locations);
+    Ok((writer.get_inner_writer_ref().get_ref().clone(), Backend::Commands, command_map))
+}
+
+fn patch_jump(writer: &mut CodeWriter, asm_pos: u64, mnem: Mnemonic, target_pos: u64)
+    -> Result<(), EncodingError>
+{
+    writer.seek(SeekFrom::Start(asm_pos))?;
+    writer.write1(
+        mnem,
+        Literal32(
+            (target_pos as i64
+             - asm_pos as i64
+             - match mnem {
+                JMP => 5,
+                // Jcc rel32
+                _ => 6,
+             })
+            as u32
+        )
+    )?;
+    Ok(())
+}
+
+fn emit_cmd<'a>(writer: &mut CodeWriter, state: &mut ScriptState<'a>, cmd: &'a Command)
+    -> Result<(), EncodingError>
+{
+    let ScriptState {
+        script, script_index, var_count, ptrs, last_cmd_pushint, has_try, fuel, check_depth,
+        ref mut ret_val_locations, ref mut try_locations, ref mut handler_relocations,
+        ref mut resume_locations, ref mut jump_relocations, ref mut command_locations,
+        ref mut call_relocs
+    } = *state;
+    let ProgramPointers {
+        global_vars, printer, dispatch_table, dispatch_len, syscalls, runtime
+    } = ptrs;
+    let ctx = runtime as u64;
+
+    macro_rules! asm {
+        (
+            $(
+                $mnem:ident $($op:expr),*;
+            )*
+        ) => {
+            asm_impl!(writer, {
+                $(
+                    $mnem $($op),*
+                );*
+            })
+        };
+    }
+
+    let loc = cmd.position + script.bounds.0;
+    if try_locations.contains(&loc) {
+        // Reached after the call or by an error, the return value or error value is in EAX
+        resume_locations.insert(loc, pop_handler(writer, ctx)?);
+    }
+    if ret_val_locations.contains(&loc) {
+        writer.push(RAX)?;
+    }
+    let command_asm_pos = writer.get_inner_writer_ref().position();
+    command_locations.insert(cmd.position, command_asm_pos);
+    match cmd.cmd {
+        Cmd::Unk1 | Cmd::Begin { .. } => {
+            unreachable!("{:?} is rejected by validate_script", cmd.cmd);
+        }
+        Cmd::ErrorC | Cmd::Error37 | Cmd::Error4C => {
+            raise(writer, ctx, error_value(cmd.cmd).unwrap(), script_index, cmd.position)?;
+        }
+        Cmd::Jump { loc } | Cmd::Jump5 { loc } | Cmd::Else { loc } => {
+            let target = loc - script.bounds.0;
+            if fuel && target <= cmd.position {
+                use_fuel(writer, ctx, script_index, cmd.position)?;
+            }
+            let jump_asm_pos = writer.get_inner_writer_ref().position();
+            asm!(
+                JMP 0u32;
+            );
+            jump_relocations.push((jump_asm_pos, JMP, target, cmd));
+        }
+        Cmd::Sys { sys_num, arg_count } => {
+            asm!(
+                MOV RDI, (syscalls as u64);
+                MOV RSI, (printer as u64);
+                MOV RDX, RSP;
+                MOV RCX, (u32::from(arg_count));
+                MOV R8D, (u32::from(sys_num));
+                MOV R9, ctx;
+                MOV RAX, (call_syscall as *const () as u64);
+                PUSH R15;
+                MOV R15, RSP;
+                AND R15, 8u8;
+                SUB RSP, R15;
+                CALL RAX;
+                ADD RSP, R15;
+                POP R15;
+            );
+            check_syscall(writer, ctx, script_index)?;
+            asm!(
+                ADD RSP, (8 * arg_count);
+            );
+            if cmd.push_bit {
+                asm!(
+                    PUSH RAX;
+                );
+            }
+        }
+        Cmd::Push => {
+            if cmd.push_bit {
+                asm!(
+                    POP RAX;
+                    PUSH RAX;
+                    PUSH RAX;
+                );
+            }
+        }
+        Cmd::Pop => {
+            if !cmd.push_bit {
+                asm!(
+                    ADD RSP, 8u8;
+                );
+            }
+        }
+        Cmd::If { loc } | Cmd::IfNot { loc } => {
+            asm!(
+                POP RAX;
+                CMP EAX, 0u8;
+            );
+            let command_asm_pos = writer.get_inner_writer_ref().position();
+            let mnem = if let Cmd::If { .. } = cmd.cmd { JE } else { JNE };
+            let target = loc - script.bounds.0;
+            if fuel && target <= cmd.position {
+                // Only a taken jump uses fuel
+                let not_taken = if mnem == JE { JNE } else { JE };
+                asm!(
+                    not_taken 0u32;
+                );
+                use_fuel(writer, ctx, script_index, cmd.position)?;
+                let jump_asm_pos = writer.get_inner_writer_ref().position();
+                asm!(
+                    JMP 0u32;
+                );
+                jump_relocations.push((jump_asm_pos, JMP, target, cmd));
+                let end = writer.get_inner_writer_ref().position();
+                patch_jump(writer, command_asm_pos, not_taken, end)?;
+                writer.seek(SeekFrom::Start(end))?;
+                return Ok(());
+            }
+            asm!(
+                mnem 0u32;
+            );
+            jump_relocations.push((
+                command_asm_pos,
+                mnem,
+                target,
+                cmd
+            ));
+        }
+        Cmd::CallFunc { arg_count } | Cmd::CallFunc2 { arg_count } |
+        Cmd::CallFunc3 { arg_count } => {
+            if last_cmd_pushint.is_some() {
+                // Static call, overwrite the push of the function offset
+                writer.seek(SeekFrom::Current(-5))?;
+            } else {
+                // Dynamically find function pointer
+                // (and cry at the performance impact)
+                asm!(
+                    POP RDX;
+                    MOV RDI, (dispatch_table as u64);
+                    MOV RSI, (dispatch_len as u64);
+                    MOV RCX, ctx;
+                    MOV RAX, (resolve_call as *const () as u64);
+                    PUSH R15;
+                    MOV R15, RSP;
+                    AND R15, 8u8;
+                    SUB RSP, R15;
+                    CALL RAX;
+                    ADD RSP, R15;
+                    POP R15;
+                );
+                check_call_target(writer, ctx, script_index)?;
+                asm!(
+                    MOV R11, RAX;
+                );
+            }
+            if arg_count > 6 {
+                asm!(
+                    ADD RSP, ((arg_count - 6) * 8);
+                );
+            }
+            let arg_reg_count = std::cmp::min(arg_count, 6);
+            for i in 0..arg_reg_count {
+                asm!(
+                    POP ARG_REGS[(arg_reg_count - (i + 1)) as usize];
+                );
+            }
+            if arg_count > 6 {
+                asm!(
+                    SUB RSP, ((arg_count - 6) * 8);
+                );
+                for i in 0..(arg_count - arg_reg_count) {
+                    asm!(
+                        MOV RAX, (RSP, (u64::from(i) * 8) + (-0x30i64 as u64), Qword);
+                        MOV (RSP, (((arg_count - arg_reg_count) as u64 - (u64::from(i) + 1)) * 8), Qword), RAX;
+                    );
+                }
+            }
+            if let Some(func_offset) = last_cmd_pushint {
+                let command_asm_pos = writer.get_inner_writer_ref().position();
+                command_locations.insert(cmd.position, command_asm_pos);
+                call_relocs.push((script_index, command_asm_pos, func_offset));
+                writer.call_rel32_0()?;
+            } else {
+                asm!(
+                    CALL R11;
+                );
+            }
+            if arg_count > 6 {
+                asm!(
+                    ADD RSP, ((arg_count - 6) * 8);
+                );
+            }
+        }
+        Cmd::PushShort { val } => {
+            if cmd.push_bit {
+                asm!(
+                    PUSH (u32::from(val));
+                );
+            }
+        }
+        Cmd::PushInt { val } => {
+            if cmd.push_bit {
+                asm!(
+                    PUSH val;
+                );
+            }
+        }
+        Cmd::IntToFloat { stack_pos } => {
+            asm!(
+                CVTSI2SS XMM0, (RSP, u64::from(stack_pos) * 8, Dword);
+                MOVSS (RSP, u64::from(stack_pos) * 8, Dword), XMM0;
+            );
+        }
+        Cmd::FloatToInt { stack_pos } => {
+            // Always truncates, whatever the rounding mode in MXCSR
+            asm!(
+                CVTTSS2SI EAX, (RSP, u64::from(stack_pos) * 8, Dword);
+                MOV (RSP, u64::from(stack_pos) * 8, Dword), EAX;
+            );
+        }
+        Cmd::PushVar { var_type, var_num } => {
+            if var_type == 0 {
+                // Local variable
+                asm!(
+                    MOV EAX, (RBP, u64::from(var_num) * 4, Dword);
+                    PUSH RAX;
+                );
+            } else {
+                // Global variable
+                writer.get_global(global_vars, EAX, var_num)?;
+                asm!(
+                    PUSH RAX;
+                );
+            }
+        }
+        Cmd::SetVar { var_type, var_num } | Cmd::VarSetF { var_type, var_num } => {
+            if var_type == 0 {
+                // Local var
+                asm!(
+                    POP RAX;
+                    MOV (RBP, u64::from(var_num) * 4, Dword), EAX;
+                );
+            } else {
+                // Global var
+                asm!(
+                    POP RCX;
+                );
+                writer.set_global(global_vars, ECX, var_num)?;
+            }
+        }
+        Cmd::IncI { var_type, var_num } | Cmd::DecI { var_type, var_num } => {
+            let operation = if let Cmd::IncI { .. } = cmd.cmd { INC } else { DEC };
+            if var_type == 0 {
+                // Local var
+                asm!(
+                    operation (RBP, u64::from(var_num) * 4, Dword);
+                );
+            } else {
+                // Global var
+                writer.get_global(global_vars, ECX, var_num)?;
+                asm!(
+                    operation ECX;
+                );
+                writer.set_global(global_vars, ECX, var_num)?;
+            }
+        }
+        Cmd::IncF { var_type, var_num } | Cmd::DecF { var_type, var_num } => {
+            let step: f32 = if let Cmd::IncF { .. } = cmd.cmd { 1.0 } else { -1.0 };
+            asm!(
+                MOV EAX, (step.to_bits());
+                MOVD XMM1, EAX;
+            );
+            if var_type == 0 {
+                // Local var
+                asm!(
+                    MOVSS XMM0, (RBP, u64::from(var_num) * 4, Dword);
+                    ADDSS XMM0, XMM1;
+                    MOVSS (RBP, u64::from(var_num) * 4, Dword), XMM0;
+                );
+            } else {
+                // Global var
+                writer.get_global_float(global_vars, XMM0, var_num)?;
+                asm!(
+                    ADDSS XMM0, XMM1;
+                );
+                writer.set_global_float(global_vars, XMM0, var_num)?;
+            }
+        }
+        Cmd::AddVarByF { var_type, var_num } | Cmd::SubVarByF { var_type, var_num } |
+        Cmd::DivVarByF { var_type, var_num } | Cmd::MultVarByF { var_type, var_num }
+        => {
+            // XMM0 is the variable, the operand is the popped value
+            let operation = match cmd.cmd {
+                Cmd::AddVarByF { .. } => ADDSS,
+                Cmd::SubVarByF { .. } => SUBSS,
+                Cmd::MultVarByF { .. } => MULSS,
+                Cmd::DivVarByF { .. } => DIVSS,
+                _ => { unreachable!() }
+            };
+            if var_type == 0 {
+                // Local var
+                asm!(
+                    MOVSS XMM0, (RBP, u64::from(var_num) * 4, Dword);
+                    operation XMM0, (RSP, Dword);
+                    MOVSS (RBP, u64::from(var_num) * 4, Dword), XMM0;
+                );
+            } else {
+                // Global var
+                writer.get_global_float(global_vars, XMM0, var_num)?;
+                asm!(
+                    operation XMM0, (RSP, Dword);
+                );
+                writer.set_global_float(global_vars, XMM0, var_num)?;
+            }
+            asm!(
+                ADD RSP, 8u8;
+            );
+        }
+        Cmd::AddVarBy { var_type, var_num } | Cmd::SubVarBy { var_type, var_num } |
+        Cmd::AndVarBy { var_type, var_num } | Cmd::OrVarBy {var_type, var_num} |
+        Cmd::XorVarBy { var_type, var_num } => {
+            asm!(
+                POP RCX;
+            );
+            let operation = match cmd.cmd {
+                Cmd::AddVarBy { .. } => ADD,
+                Cmd::SubVarBy { .. } => SUB,
+                Cmd::AndVarBy { .. } => AND,
+                Cmd::OrVarBy { .. } => OR,
+                Cmd::XorVarBy { .. } => XOR,
+                _ => { unreachable!() }
+            };
+            if var_type == 0 {
+                asm!(
+                    operation (RBP, u64::from(var_num) * 4, Dword), ECX;
+                );
+            } else {
+                writer.get_global(global_vars, EAX, var_num)?;
+                asm!(
+                    operation EAX, ECX;
+                );
+                writer.set_global(global_vars, EAX, var_num)?;
+            }
+        }
+        Cmd::MultVarBy { var_type, var_num } | Cmd::DivVarBy { var_type, var_num } |
+        Cmd::ModVarBy { var_type, var_num } => {
+            asm!(
+                POP RCX;
+            );
+            let operation = match cmd.cmd {
+                Cmd::MultVarBy { .. } => IMUL,
+                Cmd::DivVarBy { .. } | Cmd::ModVarBy { .. } => IDIV,
+                _ => { unreachable!() }
+            };
+            if var_type == 0 {
+                asm!(
+                    MOV EAX, (RBP, u64::from(var_num) * 4, Dword);
+                );
+            } else {
+                writer.get_global(global_vars, EAX, var_num)?;
+            }
+            if let IDIV = operation {
+                asm!(
+                    CDQ;
+                );
+            }
+            asm!(
+                operation ECX;
+            );
+            if let Cmd::ModVarBy { .. } = cmd.cmd {
+                asm!(
+                    MOV EAX, EDX;
+                );
+            }
+            if var_type == 0 {
+                asm!(
+                    MOV (RBP, u64::from(var_num) * 4, Dword), EAX;
+                );
+            } else {
+                writer.set_global(global_vars, EAX, var_num)?;
+            }
+        }
+        Cmd::MultI | Cmd::DivI | Cmd::ModI => {
+            asm!(
+                POP RCX;
+                POP RAX;
+            );
+            if cmd.push_bit {
+                let op = match cmd.cmd {
+                    Cmd::MultI => IMUL,
+                    Cmd::DivI | Cmd::ModI => {
+                        asm!(
+                            CDQ;
+                        );
+                        IDIV
+                    },
+                    _ => { unreachable!() }
+                };
+                asm!(
+                    op ECX;
+                    PUSH if let Cmd::ModI = cmd.cmd { RDX } else { RAX };
+                );
+            }
+        }
+        Cmd::AddI | Cmd::SubI | Cmd::ShiftL | Cmd::ShiftR | Cmd::AndI | Cmd::OrI |
+        Cmd::XorI => {
+            asm!(
+                POP RCX;
+                POP RAX;
+            );
+            if cmd.push_bit {
+                let op = match cmd.cmd {
+                            Cmd::AddI => ADD,
+                            Cmd::SubI => SUB,
+                            Cmd::ShiftR => SHR,
+                            Cmd::ShiftL => SHL,
+                            Cmd::AndI => AND,
+                            Cmd::OrI => OR,
+                            Cmd::XorI => XOR,
+                            _ => { unreachable!() }
+                        };
+                asm!(
+                    op EAX, match cmd.cmd {
+                                Cmd::ShiftR | Cmd::ShiftL => { CL }
+                                _ => { ECX }
                             };
-                            if var_type == 0 {
-                                asm!(
-                                    MOV EAX, (RBP, u64::from(var_num) * 4, Dword);
-                                    operation ECX;
-                                    MOV 
-                                        (RBP, u64::from(var_num) * 4, Dword),
-                                        match cmd.cmd {
-                                            Cmd::ModVarBy { .. } => EDX,
-                                            _ => EAX
-                                        };
-                                );
-                            } else {
-                                writer.get_global(global_vars.as_ptr(), EAX, var_num).unwrap();
-                                if let IDIV = operation {
-                                    asm!(
-                                        MOV EDX, 0u32;
-                                    );
-                                }
-                                asm!(
-                                    operation ECX;
-                                );
-                                if let Cmd::ModVarBy { .. } = cmd.cmd {
-                                    asm!(
-                                        MOV EAX, EDX;
-                                    );
-                                }
-                                writer.set_global(global_vars.as_ptr(), EAX, var_num).unwrap();
-                            }
-                        }
-                        Cmd::MultI | Cmd::DivI | Cmd::ModI => {
-                            asm!(
-                                POP RCX;
-                                POP RAX;
-                            );
-                            if cmd.push_bit {
-                                let op = match cmd.cmd {
-                                    Cmd::MultI => IMUL,
-                                    Cmd::DivI | Cmd::ModI => {
-                                        asm!(
-                                            MOV EDX, 0u32;
-                                        );
-                                        IDIV
-                                    },
-                                    _ => { unreachable!() }
-                                };
-                                asm!(
-                                    op ECX;
-                                    PUSH if let Cmd::ModI = cmd.cmd { RDX } else { RAX };
-                                );
-                            }
-                        }
-                        Cmd::AddI | Cmd::SubI | Cmd::ShiftL | Cmd::ShiftR | Cmd::AndI | Cmd::OrI |
-                        Cmd::XorI => {
-                            asm!(
-                                POP RCX;
-                                POP RAX;
-                            );
-                            if cmd.push_bit {
-                                let op = match cmd.cmd {
-                                            Cmd::AddI => ADD,
-                                            Cmd::SubI => SUB,
-                                            Cmd::ShiftR => SHR,
-                                            Cmd::ShiftL => SHL,
-                                            Cmd::AndI => AND,
-                                            Cmd::OrI => OR,
-                                            Cmd::XorI => XOR,
-                                            _ => { unreachable!() }
-                                        };
-                                asm!(
-                                    op EAX, match cmd.cmd {
-                                                Cmd::ShiftR | Cmd::ShiftL => { CL }
-                                                _ => { ECX }
-                                            };
-                                    PUSH RAX;
-                                );
-                            }
-                        }
-                        Cmd::Equals | Cmd::NotEquals | Cmd::LessThan | Cmd::LessOrEqual |
-                        Cmd::Greater | Cmd::GreaterOrEqual => {
-                            asm!(
-                                POP RAX;
-                                POP RCX;
-                            );
-                            if cmd.push_bit {
-                                let (op, op_inverse) = match cmd.cmd {
-                                    Cmd::Equals => (CMOVE, CMOVNE),
-                                    Cmd::NotEquals => (CMOVNE, CMOVE),
-                                    Cmd::LessThan => (CMOVL, CMOVGE),
-                                    Cmd::LessOrEqual => (CMOVLE, CMOVG),
-                                    Cmd::Greater => (CMOVG, CMOVLE),
-                                    Cmd::GreaterOrEqual => (CMOVGE, CMOVL),
-                                    _ => { unreachable!() }
-                                };
-                                asm!(
-                                    XOR R8, R8;
-                                    MOV EDX, 1u32;
-                                    CMP ECX, EAX;
-                                    op EAX, EDX;
-                                    op_inverse EAX, R8D;
-                                    PUSH RAX;
-                                );
-                            }
-                        }
-                        Cmd::EqualsF | Cmd::NotEqualsF | Cmd::LessThanF | Cmd::LessOrEqualF |
-                        Cmd::GreaterF | Cmd::GreaterOrEqualF => {
-                            if cmd.push_bit {
-                                writer.copy_to_fpu_rev(2).unwrap();
-                                asm!(
-                                    MOV EDX, 1u32;
-                                );
-                                writer.fcompp().unwrap();
-                                writer.fstsw_ax().unwrap();
-                                asm!(
-                                    FWAIT;
-                                );
-                                writer.sahf().unwrap();
-                                let (op, op_inverse) = match cmd.cmd {
-                                    Cmd::EqualsF => (CMOVE, CMOVNE),
-                                    Cmd::NotEqualsF => (CMOVNE, CMOVE),
-                                    Cmd::LessThanF => (CMOVB, CMOVAE),
-                                    Cmd::LessOrEqualF => (CMOVBE, CMOVA),
-                                    Cmd::GreaterF => (CMOVA, CMOVBE),
-                                    Cmd::GreaterOrEqualF => (CMOVAE, CMOVB),
-                                    _ => { unreachable!() }
-                                };
-                                asm!(
-                                    op EAX, EDX;
-                                    op_inverse EAX, R8D;
-                                    ADD RSP, 16u8;
-                                    PUSH RAX;
-                                );
-                            }
-                        }
-                        Cmd::NegI | Cmd::NotI => {
-                            if cmd.push_bit {
-                                let op = match cmd.cmd {
-                                            Cmd::NegI => NEG,
-                                            Cmd::NotI => NOT,
-                                            _ => { unreachable!() }
-                                        };
-                                asm!(
-                                    op (RSP, Dword);
-                                );
-                            } else {
-                                asm!(
-                                    POP RAX;
-                                );
-                            }
-                        }
-                        Cmd::NegF => {
-                            writer.copy_to_fpu(1).unwrap();
-                            asm!(
-                                FCHS;
-                                FSTP (RSP, Dword);
-                            );
-                        }
-                        Cmd::Not => {
-                            asm!(
-                                POP RAX;
-                            );
-                            if cmd.push_bit {
-                                asm!(
-                                    XOR R8, R8;
-                                    MOV EDX, 1u32;
-                                    TEST RAX, RAX;
-                                    CMOVE RAX, RDX;
-                                    CMOVNZ RAX, R8;
-                                );
-                            }
-                        }
-                        Cmd::AddF | Cmd::SubF | Cmd::MultF | Cmd::DivF => {
-                            if cmd.push_bit {
-                                writer.copy_to_fpu(2).unwrap();
-                                let op = match cmd.cmd {
-                                            Cmd::AddF => FADD,
-                                            Cmd::SubF => FSUB,
-                                            Cmd::MultF => FMUL,
-                                            Cmd::DivF => FDIV,
-                                            _ => { unreachable!() }
-                                        };
-                                asm!(
-                                    op ST, ST1;
-                                    ADD RSP, 8u8;
-                                    FSTP (RSP, Dword);
-                                    FSTP ST0;
-                                );
-                            } else {
-                                asm!(
-                                    ADD RSP, 0x10u8;
-                                );
-                            }
-                        }
-                        Cmd::PrintF { arg_count } => {
-                            if arg_count == 0 {
-                                println!("WARNING: printf arg_count cannot be 0");
-                                continue;
-                            }
-                            asm!(
-                                MOV RSI, RSP;
-                                MOV RAX, (RSP, 8 * (u64::from(arg_count) - 1), Qword);
-                                MOV RDX, (u64::from(arg_count) - 1);
-                                MOV RDI, (string_offsets.as_ptr() as u64);
-                                MOV RDI, (RDI, RAX, RegScale::Eight, Qword);
-                                MOV RCX, (msc_printf as u64);
-                                PUSH R15;
-                                MOV R15, RSP;
-                                AND R15, 8u8;
-                                SUB RSP, R15;
-                                CALL RCX;
-                                ADD RSP, R15;
-                                POP R15;
-                                ADD RSP, (8 * arg_count);
-                            );
-                        }
-                        Cmd::Try { loc } => {
-                            if cmd.push_bit {
-                                ret_val_locations.insert(loc);
-                            }
-                        }
-                        Cmd::Return6 | Cmd::Return8 => {
-                            asm!(
-                                POP RAX;
-                            );
-                            writer.write_ret(u32::from(var_count)).unwrap();
-                        }
-                        Cmd::Return7 | Cmd::Return9 | Cmd::End => {
-                            writer.write_ret(u32::from(var_count)).unwrap();
-                        }
-                        Cmd::Exit => {
-                            asm!(
-                                MOV EAX, 60u32;
-                                XOR EDI, EDI;
-                                SYSCALL;
-                            );
-                        }
-                        Cmd::Nop => {}
+                    PUSH RAX;
+                );
+            }
+        }
+        Cmd::Equals | Cmd::NotEquals | Cmd::LessThan | Cmd::LessOrEqual |
+        Cmd::Greater | Cmd::GreaterOrEqual => {
+            asm!(
+                POP RAX;
+                POP RCX;
+            );
+            if cmd.push_bit {
+                let (op, op_inverse) = match cmd.cmd {
+                    Cmd::Equals => (CMOVE, CMOVNE),
+                    Cmd::NotEquals => (CMOVNE, CMOVE),
+                    Cmd::LessThan => (CMOVL, CMOVGE),
+                    Cmd::LessOrEqual => (CMOVLE, CMOVG),
+                    Cmd::Greater => (CMOVG, CMOVLE),
+                    Cmd::GreaterOrEqual => (CMOVGE, CMOVL),
+                    _ => { unreachable!() }
+                };
+                asm!(
+                    XOR R8, R8;
+                    MOV EDX, 1u32;
+                    CMP ECX, EAX;
+                    op EAX, EDX;
+                    op_inverse EAX, R8D;
+                    PUSH RAX;
+                );
+            }
+        }
+        Cmd::EqualsF | Cmd::NotEqualsF | Cmd::LessThanF | Cmd::LessOrEqualF |
+        Cmd::GreaterF | Cmd::GreaterOrEqualF => {
+            if cmd.push_bit {
+                // Less than is compared as greater than with the operands swapped, so that an
+                // unordered result (CF = ZF = PF = 1) makes every comparison but != false
+                let (first, second) = match cmd.cmd {
+                    Cmd::LessThanF | Cmd::LessOrEqualF => (0u64, 8u64),
+                    _ => (8, 0),
+                };
+                let (op, op_inverse) = match cmd.cmd {
+                    Cmd::EqualsF => (CMOVE, CMOVNE),
+                    Cmd::NotEqualsF => (CMOVNE, CMOVE),
+                    Cmd::LessThanF | Cmd::GreaterF => (CMOVA, CMOVBE),
+                    Cmd::LessOrEqualF | Cmd::GreaterOrEqualF => (CMOVAE, CMOVB),
+                    _ => { unreachable!() }
+                };
+                asm!(
+                    XOR R8, R8;
+                    MOV EDX, 1u32;
+                    MOVSS XMM0, (RSP, first, Dword);
+                    UCOMISS XMM0, (RSP, second, Dword);
+                    op EAX, EDX;
+                    op_inverse EAX, R8D;
+                );
+                match cmd.cmd {
+                    Cmd::EqualsF => {
+                        asm!(
+                            CMOVP EAX, R8D;
+                        );
                     }
-                    last_cmd_pushint = match cmd.cmd {
-                        Cmd::PushInt { val } => {
-                            Some(val)
-                        }
-                        Cmd::PushShort { val } => {
-                            Some(u32::from(val))
-                        }
-                        _ => {
-                            None
-                        }
-                    };
-                }
-                //writer.write_ret(u32::from(var_count)).unwrap();
-                for relocation in jump_relocations {
-                    writer.seek(SeekFrom::Start(relocation.0)).unwrap();
-                    writer.write1(
-                        relocation.1,
-                        Literal32(
-                            (*command_locations.get(&relocation.2).unwrap() as i64
-                             - relocation.0 as i64
-                             - match relocation.1 {
-                                JMP => 5,
-                                JE => 6,
-                                JNE => 6,
-                                _ => { unreachable!() }
-                             })
-                            as u32
-                        )
-                    ).unwrap();
+                    Cmd::NotEqualsF => {
+                        asm!(
+                            CMOVP EAX, EDX;
+                        );
+                    }
+                    _ => {}
                 }
+                asm!(
+                    ADD RSP, 16u8;
+                    PUSH RAX;
+                );
+            } else {
+                asm!(
+                    ADD RSP, 16u8;
+                );
+            }
+        }
+        Cmd::NegI | Cmd::NotI => {
+            if cmd.push_bit {
+                let op = match cmd.cmd {
+                            Cmd::NegI => NEG,
+                            Cmd::NotI => NOT,
+                            _ => { unreachable!() }
+                        };
+                asm!(
+                    op (RSP, Dword);
+                );
             } else {
                 asm!(
-                    RET;
+                    POP RAX;
                 );
             }
-            let buffer = writer.get_inner_writer_ref().get_ref();
-            println!("\n\nEmitted asm:");
-            objdump(&buffer);
-            let mut code = JitMemory::new((buffer.len() + (PAGE_SIZE - 1)) / PAGE_SIZE);
-            unsafe {
-                code.as_slice()[..buffer.len()].copy_from_slice(&buffer[..]);
+        }
+        Cmd::NegF => {
+            if cmd.push_bit {
+                // Flip the sign bit
+                asm!(
+                    XOR (RSP, Dword), 0x8000_0000u32;
+                );
+            } else {
+                asm!(
+                    ADD RSP, 8u8;
+                );
+            }
+        }
+        Cmd::Not => {
+            asm!(
+                POP RAX;
+            );
+            if cmd.push_bit {
+                asm!(
+                    XOR R8, R8;
+                    MOV EDX, 1u32;
+                    TEST EAX, EAX;
+                    CMOVE RAX, RDX;
+                    CMOVNZ RAX, R8;
+                    PUSH RAX;
+                );
             }
-            mem.push(code);
         }
+        Cmd::AddF | Cmd::SubF | Cmd::MultF | Cmd::DivF => {
+            if cmd.push_bit {
+                // XMM0 is the left operand, the right one stays on the stack
+                let op = match cmd.cmd {
+                            Cmd::AddF => ADDSS,
+                            Cmd::SubF => SUBSS,
+                            Cmd::MultF => MULSS,
+                            Cmd::DivF => DIVSS,
+                            _ => { unreachable!() }
+                        };
+                asm!(
+                    MOVSS XMM0, (RSP, 8u64, Dword);
+                    op XMM0, (RSP, Dword);
+                    ADD RSP, 8u8;
+                    MOVSS (RSP, Dword), XMM0;
+                );
+            } else {
+                asm!(
+                    ADD RSP, 0x10u8;
+                );
+            }
+        }
+        Cmd::PrintF { arg_count } => {
+            // The format string index is pushed first, below the format arguments
+            asm!(
+                MOV RDI, (printer as u64);
+                MOV RSI, (RSP, 8 * (u64::from(arg_count) - 1), Qword);
+                MOV RDX, RSP;
+                MOV RCX, (u64::from(arg_count) - 1);
+                MOV R8, ctx;
+                MOV RAX, (msc_printf as *const () as u64);
+                PUSH R15;
+                MOV R15, RSP;
+                AND R15, 8u8;
+                SUB RSP, R15;
+                CALL RAX;
+                ADD RSP, R15;
+                POP R15;
+            );
+            check_syscall(writer, ctx, script_index)?;
+            asm!(
+                ADD RSP, (8 * arg_count);
+            );
+        }
+        Cmd::Try { loc } => {
+            if cmd.push_bit {
+                ret_val_locations.insert(loc);
+            }
+            try_locations.insert(loc);
+            handler_relocations.push((push_handler(writer, ctx)?, loc, cmd));
+        }
+        Cmd::Return6 | Cmd::Return8 => {
+            asm!(
+                POP RAX;
+            );
+            if has_try {
+                unlink_frame_handlers(writer, ctx)?;
+            }
+            if check_depth {
+                leave_frame(writer, ctx)?;
+            }
+            writer.write_ret(u32::from(var_count))?;
+        }
+        Cmd::Return7 | Cmd::Return9 | Cmd::End => {
+            if has_try {
+                unlink_frame_handlers(writer, ctx)?;
+            }
+            if check_depth {
+                leave_frame(writer, ctx)?;
+            }
+            writer.write_ret(u32::from(var_count))?;
+        }
+        Cmd::Exit => {
+            exit(writer, ctx)?;
+        }
+        Cmd::Nop => {}
+    }
+    Ok(())
+}
 
-        for (script_index, pos, script_offset) in call_relocs {
-            let call_addr = mem[self.get_script_from_loc(script_offset).unwrap()].contents as u64;
-            #[allow(clippy::cast_ptr_alignment)]
-            unsafe {
-                *(mem[script_index].contents.offset(pos as isize + 2) as *mut u64) = call_addr;
+/// Dispatch table sorted by script offset, entry addresses are filled in once every script is
+/// compiled
+fn build_dispatch_table(file: &MscsbFile) -> Vec<DispatchEntry> {
+    let mut dispatch_table = file.scripts.iter().enumerate()
+        .map(|(script_index, script)| DispatchEntry {
+            offset: script.bounds.0,
+            script_index,
+            addr: 0,
+        })
+        .collect::<Vec<DispatchEntry>>();
+    dispatch_table.sort_by_key(|entry| entry.offset);
+    dispatch_table
+}
+
+impl Compilable for MscsbFile {
+    fn compile_with(&self, options: &CompileOptions) -> Result<CompiledProgram, CompileError> {
+        match options.script_stack_size {
+            Some(size) if size <= STACK_HEADROOM => {
+                return Err(CompileError::ScriptStackSize { size, min: STACK_HEADROOM + 1 });
             }
+            _ => {}
         }
-        
-        //println!("\n\nEmitted asm:");
-        
-        /*for i in 0..mem.len() {
-            unsafe {
-                objdump(std::slice::from_raw_parts(mem[i].contents, mem[i].size));
+        let global_vars = vec![Cell::new(0); global_count(self)].into_boxed_slice();
+        let printer = Box::new(Printer::new(StringTable::new(self)));
+        let runtime = Box::new(RuntimeContext::default());
+        let mut dispatch_table = build_dispatch_table(self);
+        let ptrs = ProgramPointers {
+            // Writing through it is fine, the Cells are UnsafeCells
+            global_vars: global_vars.as_ptr() as *mut u32,
+            printer: &*printer,
+            dispatch_table: dispatch_table.as_ptr(),
+            dispatch_len: dispatch_table.len(),
+            syscalls: Rc::as_ptr(&options.syscalls),
+            runtime: &*runtime,
+        };
+
+        let mut buffers = vec![];
+        let mut backends = vec![];
+        let mut command_maps = vec![];
+        let mut call_relocs = vec![];
+        for script_index in 0..self.scripts.len() {
+            let (buffer, backend, command_map) = compile_script(
+                self, script_index, ptrs, options, &mut call_relocs
+            )?;
+            if options.dump_asm {
+                let asm = objdump(&buffer)
+                    .map_err(|error| CompileError::Disassembly { script_index, error })?;
+                println!("\n\nEmitted asm (script {}, {:?} backend):", script_index, backend);
+                println!("{}\n", asm);
             }
-        }*/
+            buffers.push(buffer);
+            backends.push(backend);
+            command_maps.push(command_map);
+        }
+
+        let stack = options.script_stack_size
+            .map(ScriptStack::new)
+            .transpose()
+            .map_err(|error| CompileError::Allocation { error })?;
+        if let Some(stack) = &stack {
+            runtime.stack_top.set(stack.top());
+            runtime.stack_limit.set(stack.limit());
+        }
+        let entry = compile_entry(&*runtime, stack.is_some())
+            .map_err(|error| CompileError::Entry { error })?;
+        // All scripts end up in one region, so calls are relative to the packed layout
+        let sizes = buffers.iter().map(Vec::len).collect::<Vec<_>>();
+        let (offsets, _) = CodeArena::layout(&sizes);
+        for (script_index, pos, script_offset) in call_relocs {
+            // Call targets are checked by validate_script
+            let target = offsets[self.get_script_from_loc(script_offset).unwrap()];
+            let next_instruction = offsets[script_index] + pos as usize + 5;
+            let rel = (target as i64 - next_instruction as i64) as i32;
+            let pos = pos as usize;
+            buffers[script_index][pos + 1..pos + 5].copy_from_slice(&rel.to_le_bytes());
+        }
+
+        buffers.push(entry);
+        let code = CodeArena::new(&buffers, options.dual_mapping)
+            .map_err(|error| CompileError::Allocation { error })?;
+        for entry in dispatch_table.iter_mut() {
+            entry.addr = code.address(entry.script_index) as u64;
+        }
+        // Up to the entry stub
+        let script_code = code.address(0) as u64..code.address(self.scripts.len()) as u64;
+        let trap_region = TrapRegion::new(script_code, &*runtime)
+            .map_err(|error| CompileError::Allocation { error })?;
+
+        let entrypoint_index = self.get_script_from_loc(self.entrypoint)
+            .ok_or(CompileError::InvalidEntrypoint { entrypoint: self.entrypoint })?;
 
-        let entrypoint_index = self.get_script_from_loc(self.entrypoint)?;
+        let signatures = (0..self.scripts.len())
+            .map(|script_index| Signature::of(self, script_index))
+            .collect();
 
-        //println!("{}", buffer.iter().map(|b| format!("{:02X}", b)).collect::<Vec<String>>().join(" "));
-        println!("\n\n\n");
-        Some(CompiledProgram {
-            mem, entrypoint_index,
-            string_section, string_offsets, global_vars
+        Ok(CompiledProgram {
+            trap_region, code, command_maps, entrypoint_index,
+            printer, global_vars,
+            dispatch_table, backends, signatures, syscalls: options.syscalls.clone(), runtime,
+            fuel: options.fuel, max_call_depth: options.max_call_depth, stack
         })
     }
+
+    fn check_with(&self, options: &CompileOptions) -> Vec<CompileError> {
+        let mut global_vars = vec![0u32; global_count(self)];
+        let printer = Printer::new(StringTable::new(self));
+        let runtime = RuntimeContext::default();
+        let dispatch_table = build_dispatch_table(self);
+        let ptrs = ProgramPointers {
+            global_vars: global_vars.as_mut_ptr(),
+            printer: &printer,
+            dispatch_table: dispatch_table.as_ptr(),
+            dispatch_len: dispatch_table.len(),
+            syscalls: Rc::as_ptr(&options.syscalls),
+            runtime: &runtime,
+        };
+        let options = CompileOptions { backend: Backend::Commands, ..options.clone() };
+        let mut call_relocs = vec![];
+        let mut errors = (0..self.scripts.len())
+            .filter_map(|script_index| {
+                compile_script(self, script_index, ptrs, &options, &mut call_relocs).err()
+            })
+            .collect::<Vec<_>>();
+        if self.get_script_from_loc(self.entrypoint).is_none() {
+            errors.push(CompileError::InvalidEntrypoint { entrypoint: self.entrypoint });
+        }
+        errors
+    }
 }
 
-fn objdump(buffer: &[u8]) {
-    std::fs::File::create("/tmp/msc-jit-temp.bin").unwrap()
-        .write_all(buffer).unwrap();
-    // objdump -D -b binary -mi386 -Maddr16,data16,x86-64,intel /dev/stdin
-    let output = Command::new("objdump")
-        .args(&["-D", "-b", "binary", "-mi386", "-Maddr16,data16,x86-64,intel", "/tmp/msc-jit-temp.bin"])
-        .output()
-        .unwrap();
-    std::fs::remove_file("/tmp/msc-jit-temp.bin").ok();
-    println!("{}\n", &String::from_utf8_lossy(&output.stdout).trim()[97..]);
+/// Disassembly of `buffer` by objdump, without the header
+fn objdump(buffer: &[u8]) -> io::Result<String> {
+    let path = std::env::temp_dir().join(format!("msc-jit-{}.bin", process::id()));
+    std::fs::write(&path, buffer)?;
+    let output = process::Command::new("objdump")
+        .args(["-D", "-b", "binary", "-mi386", "-Maddr16,data16,x86-64,intel"])
+        .arg(&path)
+        .output();
+    std::fs::remove_file(&path).ok();
+    let output = output?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "objdump failed: {}", String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    let text = String::from_utf8_lossy(&output.stdout);
+    // The instructions start on the line after the label of the section
+    let start = text.find("<.data>:\n")
+        .map(|label| label + "<.data>:\n".len())
+        .ok_or_else(|| io::Error::other("no disassembly in the objdump output"))?;
+    Ok(text[start..].trim_end().to_owned())
 }
 
 impl CompiledProgram {
-    pub fn lock_all(&mut self) {
-        for jit_mem in self.mem.iter_mut() {
-            let ret = unsafe { jit_mem.lock() };
-            if ret != 0 {
-                panic!("Error: lock_all lock returned {}", ret);
-            }
+    /// Make the code of every script executable, must be called before running
+    pub fn lock_all(&mut self) -> io::Result<()> {
+        self.code.lock()
+    }
+
+    /// Run the entrypoint, which has to take no arguments
+    pub fn run(&self) -> Result<ExitStatus, RunError> {
+        self.run_script_status(self.entrypoint_index, &[])
+    }
+
+    /// Run the script at `script_index` with raw arguments, extra ones are ignored and missing
+    /// ones are 0. A run ending with Exit returns 0, errors are the same as `run_script_status`
+    pub fn run_with_args(&self, script_index: usize, args: &[u32]) -> Result<u64, RunError> {
+        if self.script_count() <= script_index {
+            return Err(RunError::InvalidScript { script_index, script_count: self.script_count() });
+        }
+        if !self.code.is_locked() {
+            return Err(RunError::NotLocked);
+        }
+        self.start_run(u64::MAX);
+        let ret = unsafe { self.call_entry(script_index, args) };
+        self.printer.flush();
+        let exited = self.runtime.take_status(|address| self.locate(address))?;
+        Ok(if exited { 0 } else { ret })
+    }
+
+    /// Run the script at `script_index`, checking the arguments against its Begin. Scripts that
+    /// never return a value or that exit give `Value::Int(0)`, errors no Try caught give
+    /// `RunError::Uncaught`
+    pub fn run_script(&self, script_index: usize, args: &[Value]) -> Result<Value, RunError> {
+        match self.run_script_status(script_index, args)? {
+            ExitStatus::Returned(ret) => Ok(ret),
+            ExitStatus::Exited => Ok(Value::Int(0)),
+        }
+    }
+
+    /// Same as `run_script`, telling apart runs ending with Exit
+    pub fn run_script_status(&self, script_index: usize, args: &[Value])
+        -> Result<ExitStatus, RunError>
+    {
+        self.execute(script_index, args, u64::MAX)
+    }
+
+    /// Same as `run_script_status`, ending the run with `RunError::OutOfFuel` once `fuel`
+    /// script entries and backward jumps have been made. The program has to be compiled with
+    /// `CompileOptions::fuel`
+    pub fn run_script_with_fuel(&self, script_index: usize, args: &[Value], fuel: u64)
+        -> Result<ExitStatus, RunError>
+    {
+        if !self.fuel {
+            return Err(RunError::FuelNotEnabled);
+        }
+        self.execute(script_index, args, fuel)
+    }
+
+    fn execute(&self, script_index: usize, args: &[Value], fuel: u64)
+        -> Result<ExitStatus, RunError>
+    {
+        let signature = self.signatures.get(script_index)
+            .ok_or(RunError::InvalidScript { script_index, script_count: self.script_count() })?;
+        signature.check_args(script_index, args)?;
+        if !self.code.is_locked() {
+            return Err(RunError::NotLocked);
+        }
+        let args = args.iter().map(|arg| arg.bits()).collect::<Vec<u32>>();
+        self.start_run(fuel);
+        let ret = unsafe { self.call_entry(script_index, &args) };
+        self.printer.flush();
+        if self.runtime.take_status(|address| self.locate(address))? {
+            return Ok(ExitStatus::Exited);
         }
+        Ok(ExitStatus::Returned(signature.return_value(ret as u32)))
+    }
+
+    /// Script and command position of the code at `address`, which is in the code of a script
+    fn locate(&self, address: u64) -> (usize, Option<u32>) {
+        let (script_index, offset) = self.code.locate(address)
+            .expect("Fault outside of the program's code");
+        (script_index, self.command_maps[script_index].position(offset as u32))
+    }
+
+    /// Reset the fuel and the call depth for a new run
+    fn start_run(&self, fuel: u64) {
+        self.runtime.fuel.set(fuel);
+        self.runtime.depth_left.set(self.max_call_depth.map_or(u64::MAX, u64::from));
     }
 
-    pub fn run(&self) {
-        if self.mem.len() <= self.entrypoint_index {
-            panic!("Error: entrypoint_index '{}' out of bounds (< {})",
-                   self.entrypoint_index, self.mem.len());
+    /// Call a script through the entry stub, the code has to be locked
+    unsafe fn call_entry(&self, script_index: usize, args: &[u32]) -> u64 {
+        let mut arg_slots = args.iter().map(|&arg| u64::from(arg)).collect::<Vec<u64>>();
+        // The stub always loads every argument register
+        let arg_count = arg_slots.len();
+        arg_slots.resize(arg_count.max(ARG_REGS.len()), 0);
+        let entry: EntryFn = mem::transmute(self.code.address(self.script_count()));
+        entry(self.get_script_address(script_index), arg_slots.as_ptr(), arg_count as u64)
+    }
+
+    pub fn script_count(&self) -> usize {
+        self.signatures.len()
+    }
+
+    pub fn global_int(&self, var_num: u16) -> Option<u32> {
+        self.global_vars.get(usize::from(var_num)).map(Cell::get)
+    }
+
+    pub fn global_float(&self, var_num: u16) -> Option<f32> {
+        self.global_int(var_num).map(f32::from_bits)
+    }
+
+    pub fn set_global_int(&mut self, var_num: u16, val: u32) -> Result<(), RunError> {
+        let global_count = self.global_vars.len();
+        let global = self.global_vars.get(usize::from(var_num))
+            .ok_or(RunError::InvalidGlobal { var_num, global_count })?;
+        global.set(val);
+        Ok(())
+    }
+
+    pub fn set_global_float(&mut self, var_num: u16, val: f32) -> Result<(), RunError> {
+        self.set_global_int(var_num, val.to_bits())
+    }
+
+    /// Copy of every global by `var_num`, to be put back with `restore_globals`
+    pub fn snapshot_globals(&self) -> Vec<u32> {
+        self.global_vars.iter().map(Cell::get).collect()
+    }
+
+    /// Set every global from a snapshot of this program
+    pub fn restore_globals(&mut self, snapshot: &[u32]) -> Result<(), RunError> {
+        if snapshot.len() != self.global_vars.len() {
+            return Err(RunError::GlobalCount {
+                expected: self.global_vars.len(), passed: snapshot.len()
+            });
         }
-        unsafe {
-            let ret = self.mem[self.entrypoint_index].run::<u64>();
-            // Flush printf buffer
-            libc::printf("\n\0".as_ptr() as _);
-            println!("Return value - 0x{:X}", ret);
+        for (global, &val) in self.global_vars.iter().zip(snapshot) {
+            global.set(val);
         }
+        Ok(())
+    }
+
+    /// Send what scripts print to `sink` instead of stdout
+    pub fn set_output<S: OutputSink + 'static>(&mut self, sink: S) {
+        self.printer.set_sink(Box::new(sink));
     }
 
     pub fn get_entrypoint_address(&self) -> u64 {
-        self.mem[self.entrypoint_index].contents as u64
+        self.get_script_address(self.entrypoint_index)
+    }
+
+    pub fn get_script_address(&self, script_index: usize) -> u64 {
+        self.code.address(script_index) as u64
     }
 }
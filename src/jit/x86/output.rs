@@ -0,0 +1,93 @@
+//! Where the text printed by scripts goes. A program writes everything its PrintF commands and
+//! syscalls print to one `OutputSink`, stdout unless the host picks another
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+pub trait OutputSink {
+    fn write(&mut self, bytes: &[u8]);
+
+    /// Called when a run ends
+    fn flush(&mut self) {}
+}
+
+/// The process stdout
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Stdout;
+
+impl OutputSink for Stdout {
+    fn write(&mut self, bytes: &[u8]) {
+        // Nowhere to report a closed stdout to
+        io::stdout().write_all(bytes).ok();
+    }
+
+    fn flush(&mut self) {
+        io::stdout().flush().ok();
+    }
+}
+
+/// In-memory buffer. Clones share the contents, so one can be given to a program and another
+/// kept to read what it printed
+#[derive(Debug, Default, Clone)]
+pub struct Buffer {
+    bytes: Rc<RefCell<Vec<u8>>>,
+}
+
+impl Buffer {
+    pub fn new() -> Buffer {
+        Buffer::default()
+    }
+
+    pub fn contents(&self) -> Vec<u8> {
+        self.bytes.borrow().clone()
+    }
+
+    /// Contents as text, invalid UTF-8 replaced
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.bytes.borrow()).into_owned()
+    }
+
+    /// Contents, leaving the buffer empty
+    pub fn take(&self) -> Vec<u8> {
+        self.bytes.replace(vec![])
+    }
+}
+
+impl OutputSink for Buffer {
+    fn write(&mut self, bytes: &[u8]) {
+        self.bytes.borrow_mut().extend_from_slice(bytes);
+    }
+}
+
+/// Passes every printed line to a callback, without its newline. What is left of an unfinished
+/// line is passed on flush
+pub struct LogCallback<F: FnMut(&str)> {
+    callback: F,
+    line: Vec<u8>,
+}
+
+impl<F: FnMut(&str)> LogCallback<F> {
+    pub fn new(callback: F) -> LogCallback<F> {
+        LogCallback { callback, line: vec![] }
+    }
+}
+
+impl<F: FnMut(&str)> OutputSink for LogCallback<F> {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            if byte == b'\n' {
+                (self.callback)(&String::from_utf8_lossy(&self.line));
+                self.line.clear();
+            } else {
+                self.line.push(byte);
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        if !self.line.is_empty() {
+            (self.callback)(&String::from_utf8_lossy(&self.line));
+            self.line.clear();
+        }
+    }
+}
@@ -1,68 +1,373 @@
-use libc::{c_char};
-use std::ffi::CString;
-
-/// Extremely unsafe, sneeze and you'll have code execution
-pub unsafe extern "C" fn msc_printf(fmt: *const c_char, args_ptr: *const u64, argsc: u64) {
-    let args = std::slice::from_raw_parts(args_ptr, argsc as usize);
-    let len = libc::strlen(fmt);
-    let mut fmt = fmt as *const u8;
-    let mut arg_i = 0;
-    let end = fmt.add(len);
-    while fmt < end {
-        match *fmt as char {
-            '%' => {
-                let specifier_start = fmt;
-                fmt = fmt.offset(1);
-                if *fmt as char == '%' {
-                    libc::putchar('%' as i32);
-                }
-                // Evaluate format specifier
-                while let 0x30..=0x39 = *fmt {
-                    // While in pad length of format specifier
-                    fmt = fmt.offset(1);
+//! MSC `printf`, formatted in Rust so a script can never make the host read past its arguments
+//! or string table. Specifiers follow C: `%[flags][width][.precision][length]conversion`, with
+//! floats being the f32 bit pattern of the argument and `%s` taking an index into the string
+//! table
+use std::cell::RefCell;
+use std::io::{self, Write};
+use msc::MscsbFile;
+use super::output::{OutputSink, Stdout};
+use super::runtime::{RuntimeContext, STATUS_INVALID_STRING};
+
+/// Widths and precisions are clamped to this, a script can't make the host allocate gigabytes
+/// of padding
+const MAX_WIDTH: usize = 4096;
+
+/// The strings of a file, indexed by PrintF for its format string and by `%s`
+#[derive(Debug, Clone, Default)]
+pub struct StringTable {
+    strings: Vec<Vec<u8>>,
+}
+
+impl StringTable {
+    pub fn new(file: &MscsbFile) -> StringTable {
+        StringTable {
+            strings: file.strings.iter().map(|string| string.as_bytes().to_vec()).collect(),
+        }
+    }
+
+    pub fn get(&self, index: u32) -> Option<&[u8]> {
+        self.strings.get(index as usize).map(Vec::as_slice)
+    }
+
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
+    }
+}
+
+/// A parsed conversion specification
+#[derive(Debug, Default, Clone, Copy)]
+struct Spec {
+    left: bool,
+    plus: bool,
+    space: bool,
+    zero: bool,
+    alt: bool,
+    width: usize,
+    precision: Option<usize>,
+    conversion: u8,
+}
+
+impl Spec {
+    /// Sign character for a signed conversion
+    fn sign(&self, is_negative: bool) -> &'static str {
+        if is_negative {
+            "-"
+        } else if self.plus {
+            "+"
+        } else if self.space {
+            " "
+        } else {
+            ""
+        }
+    }
+
+    fn is_upper(&self) -> bool {
+        self.conversion.is_ascii_uppercase()
+    }
+}
+
+fn parse_num(fmt: &[u8], i: &mut usize) -> usize {
+    let mut num = 0usize;
+    while let Some(digit @ b'0'..=b'9') = fmt.get(*i) {
+        num = num.saturating_mul(10).saturating_add(usize::from(digit - b'0'));
+        *i += 1;
+    }
+    num.min(MAX_WIDTH)
+}
+
+/// Write `body` with its sign or prefix, padded to the width of `spec`. Zero padding goes
+/// between the prefix and the body
+fn pad<W: Write + ?Sized>(out: &mut W, spec: &Spec, prefix: &str, body: &[u8], zero_pad: bool)
+    -> io::Result<()>
+{
+    let fill = spec.width.saturating_sub(prefix.len() + body.len());
+    if spec.left {
+        out.write_all(prefix.as_bytes())?;
+        out.write_all(body)?;
+        out.write_all(&vec![b' '; fill])
+    } else if zero_pad {
+        out.write_all(prefix.as_bytes())?;
+        out.write_all(&vec![b'0'; fill])?;
+        out.write_all(body)
+    } else {
+        out.write_all(&vec![b' '; fill])?;
+        out.write_all(prefix.as_bytes())?;
+        out.write_all(body)
+    }
+}
+
+fn write_int<W: Write + ?Sized>(out: &mut W, spec: &Spec, val: u32) -> io::Result<()> {
+    let (sign, mut digits) = match spec.conversion {
+        b'd' | b'i' => {
+            let val = val as i32;
+            (spec.sign(val < 0), i64::from(val).abs().to_string())
+        }
+        b'u' => ("", val.to_string()),
+        b'o' => ("", format!("{:o}", val)),
+        b'x' | b'p' => ("", format!("{:x}", val)),
+        _ => ("", format!("{:X}", val)),
+    };
+    if let Some(precision) = spec.precision {
+        if precision == 0 && val == 0 {
+            digits.clear();
+        } else if digits.len() < precision {
+            digits.insert_str(0, &"0".repeat(precision - digits.len()));
+        }
+    }
+    let prefix = match spec.conversion {
+        b'p' => "0x",
+        b'x' if spec.alt && val != 0 => "0x",
+        b'X' if spec.alt && val != 0 => "0X",
+        b'o' if spec.alt && !digits.starts_with('0') => {
+            digits.insert(0, '0');
+            ""
+        }
+        _ => sign,
+    };
+    pad(out, spec, prefix, digits.as_bytes(), spec.zero && spec.precision.is_none())
+}
+
+/// `%e` body of a non-negative finite value
+fn format_exp(val: f64, precision: usize, spec: &Spec) -> String {
+    let formatted = format!("{:.*e}", precision, val);
+    let (mantissa, exponent) = formatted.split_at(formatted.find('e').unwrap());
+    let exponent = exponent[1..].parse::<i32>().unwrap();
+    format!(
+        "{}{}{}{}{:02}",
+        mantissa,
+        if spec.alt && precision == 0 { "." } else { "" },
+        if spec.is_upper() { 'E' } else { 'e' },
+        if exponent < 0 { '-' } else { '+' },
+        exponent.abs()
+    )
+}
+
+/// `%g` body of a non-negative finite value, `%e` or `%f` depending on the exponent
+fn format_general(val: f64, spec: &Spec) -> String {
+    let precision = match spec.precision {
+        None => 6,
+        Some(0) => 1,
+        Some(precision) => precision,
+    };
+    let exponent = if val == 0.0 {
+        0
+    } else {
+        // The exponent after rounding to the precision
+        let formatted = format!("{:.*e}", precision - 1, val);
+        formatted[formatted.find('e').unwrap() + 1..].parse::<i32>().unwrap()
+    };
+    let mut formatted = if exponent < -4 || exponent >= precision as i32 {
+        format_exp(val, precision - 1, spec)
+    } else {
+        format!("{:.*}", (precision as i32 - 1 - exponent) as usize, val)
+    };
+    if !spec.alt {
+        let mantissa_end = formatted.find(['e', 'E']).unwrap_or(formatted.len());
+        if formatted[..mantissa_end].contains('.') {
+            let trimmed = formatted[..mantissa_end].trim_end_matches('0').trim_end_matches('.');
+            formatted = format!("{}{}", trimmed, &formatted[mantissa_end..]);
+        }
+    } else if !formatted.contains('.') {
+        let mantissa_end = formatted.find(['e', 'E']).unwrap_or(formatted.len());
+        formatted.insert(mantissa_end, '.');
+    }
+    formatted
+}
+
+fn write_float<W: Write + ?Sized>(out: &mut W, spec: &Spec, val: f32) -> io::Result<()> {
+    let sign = spec.sign(val.is_sign_negative());
+    let val = f64::from(val.abs());
+    if !val.is_finite() {
+        let body = match (val.is_nan(), spec.is_upper()) {
+            (true, false) => "nan",
+            (true, true) => "NAN",
+            (false, false) => "inf",
+            (false, true) => "INF",
+        };
+        return pad(out, spec, sign, body.as_bytes(), false);
+    }
+    let precision = spec.precision.unwrap_or(6);
+    let body = match spec.conversion {
+        b'f' | b'F' => {
+            let mut body = format!("{:.*}", precision, val);
+            if spec.alt && precision == 0 {
+                body.push('.');
+            }
+            body
+        }
+        b'e' | b'E' => format_exp(val, precision, spec),
+        _ => format_general(val, spec),
+    };
+    pad(out, spec, sign, body.as_bytes(), spec.zero)
+}
+
+/// Write `fmt` formatted with `args`, given in the order they were pushed. Specifiers with
+/// no argument left and unknown ones are written as they are
+pub fn format<W: Write + ?Sized>(out: &mut W, fmt: &[u8], args: &[u32], strings: &StringTable)
+    -> io::Result<()>
+{
+    let mut args = args.iter().copied();
+    let mut i = 0;
+    while i < fmt.len() {
+        if fmt[i] != b'%' {
+            let end = fmt[i..].iter().position(|&byte| byte == b'%').map_or(fmt.len(), |pos| i + pos);
+            out.write_all(&fmt[i..end])?;
+            i = end;
+            continue;
+        }
+        let start = i;
+        i += 1;
+        let mut spec = Spec::default();
+        let mut is_missing = false;
+        while let Some(&flag) = fmt.get(i) {
+            match flag {
+                b'-' => spec.left = true,
+                b'+' => spec.plus = true,
+                b' ' => spec.space = true,
+                b'0' => spec.zero = true,
+                b'#' => spec.alt = true,
+                _ => break,
+            }
+            i += 1;
+        }
+        if fmt.get(i) == Some(&b'*') {
+            i += 1;
+            match args.next() {
+                Some(width) => {
+                    let width = width as i32;
+                    spec.left |= width < 0;
+                    spec.width = (width.unsigned_abs() as usize).min(MAX_WIDTH);
                 }
-                // Parse decimal count
-                if *fmt as char == '.' {
-                    fmt = fmt.offset(1);
-                    while let 0x30..=0x39 = *fmt {
-                        fmt = fmt.offset(1);
+                None => is_missing = true,
+            }
+        } else {
+            spec.width = parse_num(fmt, &mut i);
+        }
+        if fmt.get(i) == Some(&b'.') {
+            i += 1;
+            if fmt.get(i) == Some(&b'*') {
+                i += 1;
+                match args.next() {
+                    // A negative precision is taken as none
+                    Some(precision) => {
+                        spec.precision = Some(precision as i32)
+                            .filter(|&precision| precision >= 0)
+                            .map(|precision| (precision as usize).min(MAX_WIDTH));
                     }
+                    None => is_missing = true,
                 }
-                match *fmt as char {
-                    'c' | 'd' | 'i' | 'l' | 'o' | 'x' | 'p' | 'u' | 'X' => {
-                        fmt = fmt.offset(1);
-                        let s = std::slice::from_raw_parts(
-                            specifier_start,
-                            fmt as usize - specifier_start as usize
-                        );
-                        if let Ok(cstr) = CString::new(s) {
-                            libc::printf(cstr.as_ptr(), args[argsc as usize - (arg_i as usize + 1)]);
-                        }
-                        arg_i += 1;
+            } else {
+                spec.precision = Some(parse_num(fmt, &mut i));
+            }
+        }
+        // Every argument is 32 bits, length modifiers change nothing
+        while let Some(b'h' | b'l' | b'L' | b'q' | b'j' | b'z' | b't') = fmt.get(i) {
+            i += 1;
+        }
+        spec.conversion = match fmt.get(i) {
+            Some(&conversion) => conversion,
+            None => {
+                out.write_all(&fmt[start..])?;
+                break;
+            }
+        };
+        i += 1;
+        spec.zero &= !spec.left;
+        match spec.conversion {
+            b'%' => out.write_all(b"%")?,
+            b'd' | b'i' | b'u' | b'o' | b'x' | b'X' | b'p' | b'c' | b's' |
+            b'f' | b'F' | b'e' | b'E' | b'g' | b'G' => {
+                let val = match args.next() {
+                    Some(val) if !is_missing => val,
+                    _ => {
+                        out.write_all(&fmt[start..i])?;
+                        continue;
                     }
-                    'f' | 'e' | 'g' => {
-                        fmt = fmt.offset(1);
-                        let s = std::slice::from_raw_parts(
-                            specifier_start,
-                            fmt as usize - specifier_start as usize
-                        );
-                        if let Ok(cstr) = CString::new(s) {
-                            let val = *(&args[argsc as usize - (arg_i as usize + 1)] as *const u64 as *const f32);
-                            libc::printf(cstr.as_ptr(), f64::from(val));
-                        }
-                        arg_i += 1;
+                };
+                match spec.conversion {
+                    b'c' => pad(out, &spec, "", &[val as u8], false)?,
+                    b's' => {
+                        let string = strings.get(val).unwrap_or(b"(null)");
+                        let len = spec.precision.map_or(string.len(), |precision| {
+                            precision.min(string.len())
+                        });
+                        pad(out, &spec, "", &string[..len], false)?;
                     }
-                    _ => {
-                        println!("oof");
-                        fmt = fmt.offset(1);
+                    b'f' | b'F' | b'e' | b'E' | b'g' | b'G' => {
+                        write_float(out, &spec, f32::from_bits(val))?;
                     }
+                    _ => write_int(out, &spec, val)?,
                 }
-                continue;
-            }
-            _ => {
-                libc::putchar(i32::from(*fmt));
             }
+            _ => out.write_all(&fmt[start..i])?,
         }
-        fmt = fmt.offset(1);
+    }
+    Ok(())
+}
+
+/// Output of a program, the strings PrintF formats and the sink everything printed goes to
+pub struct Printer {
+    strings: StringTable,
+    sink: RefCell<Box<dyn OutputSink>>,
+}
+
+impl Printer {
+    /// Printer writing to stdout
+    pub fn new(strings: StringTable) -> Printer {
+        Printer { strings, sink: RefCell::new(Box::new(Stdout)) }
+    }
+
+    pub fn strings(&self) -> &StringTable {
+        &self.strings
+    }
+
+    /// Send the output to `sink` from now on, returning the previous sink
+    pub fn set_sink(&self, sink: Box<dyn OutputSink>) -> Box<dyn OutputSink> {
+        self.sink.replace(sink)
+    }
+
+    pub fn write(&self, bytes: &[u8]) {
+        self.sink.borrow_mut().write(bytes);
+    }
+
+    pub fn flush(&self) {
+        self.sink.borrow_mut().flush();
+    }
+
+    /// Format string `str_num` with `args` and write it to the sink. False if the file has no
+    /// string `str_num`, nothing is written then
+    pub fn print(&self, str_num: u32, args: &[u32]) -> bool {
+        let fmt = match self.strings.get(str_num) {
+            Some(fmt) => fmt,
+            None => return false,
+        };
+        let mut buffer = vec![];
+        // Writing to a Vec can't fail
+        format(&mut buffer, fmt, args, &self.strings).unwrap();
+        self.write(&buffer);
+        true
+    }
+}
+
+/// Called by the generated code for PrintF. `args_ptr` points to the `argsc` format
+/// arguments, top of the stack first. A format string out of bounds is left in the context,
+/// the code after the call ends the run with it, see `runtime::check_syscall`
+pub unsafe extern "C" fn msc_printf(
+    printer: *const Printer, str_num: u64, args_ptr: *const u64, argsc: u64,
+    runtime: *const RuntimeContext
+) {
+    let args = std::slice::from_raw_parts(args_ptr, argsc as usize)
+        .iter()
+        .rev()
+        .map(|&arg| arg as u32)
+        .collect::<Vec<u32>>();
+    if !(*printer).print(str_num as u32, &args) {
+        let runtime = &*runtime;
+        runtime.status.set(STATUS_INVALID_STRING);
+        runtime.error_value.set(str_num as u32);
     }
 }
@@ -0,0 +1,411 @@
+//! State shared by the generated code of a program while it runs, and the code for MSC errors,
+//! Exit, fuel checks and stack checks
+//!
+//! `Try { loc }` pushes a handler record on the native stack and links it into the
+//! `RuntimeContext`. It is unlinked again when execution reaches `loc`, normally right after
+//! the call the Try is for. ErrorC, Error37 and Error4C raise an error, which unwinds to the
+//! innermost handler: the stack is cut back to the record, the registers saved in it are
+//! restored and execution resumes at its `loc` with the error value instead of a return value.
+//! Without a handler the run ends, returning to the entry stub, which leaves the error in the
+//! context for the host
+//!
+//! Exit ends the run the same way, whatever handlers there are, and so do running out of fuel,
+//! a dynamic call to an offset that isn't the start of a script and a failing host function.
+//! Programs compiled with fuel checks use one unit of fuel at each script entry and each
+//! backward jump taken. Overflowing the call depth limit or the script stack ends the run too,
+//! both are checked in the prologue of every script. So does a fault in the code, see `trap`
+//!
+//! A handler record, from its address up, 80 bytes so the stack keeps its alignment:
+//! `[resume address][previous handler][depth left][padding][RBP][RBX][R12][R13][R14][R15]`
+use std::cell::{Cell, RefCell};
+use msc::Cmd;
+use std::io::SeekFrom;
+use std::io::prelude::*;
+use x86asm::{Mnemonic, OperandSize, Reg};
+use super::asm_macro::asm_impl;
+use super::{CodeWriter, EncodingError, FaultKind, RunError, patch_jump};
+use super::syscalls::SyscallError;
+
+use Reg::*;
+use OperandSize::*;
+use Mnemonic::*;
+
+/// Registers a handler record restores, pushed in this order
+const HANDLER_REGS: [Reg; 6] = [R15, R14, R13, R12, RBX, RBP];
+
+pub const HANDLER: u64 = 0;
+pub const ENTRY_RSP: u64 = 8;
+pub const ENTRY_RBP: u64 = 16;
+pub const STATUS: u64 = 24;
+pub const ERROR_VALUE: u64 = 28;
+pub const ERROR_SCRIPT: u64 = 32;
+pub const ERROR_POSITION: u64 = 36;
+pub const FUEL: u64 = 40;
+pub const DEPTH_LEFT: u64 = 48;
+pub const STACK_LIMIT: u64 = 56;
+pub const STACK_TOP: u64 = 64;
+
+/// How the last run ended, see `RuntimeContext::status`
+pub const STATUS_RETURNED: u32 = 0;
+pub const STATUS_ERROR: u32 = 1;
+pub const STATUS_EXITED: u32 = 2;
+pub const STATUS_OUT_OF_FUEL: u32 = 3;
+pub const STATUS_STACK_OVERFLOW: u32 = 4;
+pub const STATUS_FAULT: u32 = 5;
+pub const STATUS_INVALID_CALL: u32 = 6;
+pub const STATUS_SYSCALL_ERROR: u32 = 7;
+pub const STATUS_INVALID_STRING: u32 = 8;
+
+/// Boxed by the program, the generated code addresses the fields by their offsets above
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct RuntimeContext {
+    /// Innermost handler record, 0 if there is none
+    pub handler: Cell<u64>,
+    /// Where the return address of the script called by the entry stub is, unwinding to it
+    /// ends the run
+    pub entry_rsp: Cell<u64>,
+    /// Frame of the entry stub
+    pub entry_rbp: Cell<u64>,
+    pub status: Cell<u32>,
+    pub error_value: Cell<u32>,
+    pub error_script: Cell<u32>,
+    pub error_position: Cell<u32>,
+    /// Fuel left for the run
+    pub fuel: Cell<u64>,
+    /// Script calls that can still be nested
+    pub depth_left: Cell<u64>,
+    /// Lowest RSP a prologue accepts, with room left below it for the operand stack and the
+    /// host functions called
+    pub stack_limit: Cell<u64>,
+    /// Where the entry stub switches RSP to, for programs with a script stack
+    pub stack_top: Cell<u64>,
+    /// Signal of the last fault, only set by the signal handler
+    pub fault_signal: Cell<i32>,
+    /// Address of the faulting instruction
+    pub fault_address: Cell<u64>,
+    /// Address of the memory access that faulted
+    pub fault_access: Cell<u64>,
+    /// Failure of the host function that ended the run, only set by `call_syscall`
+    pub syscall_error: RefCell<Option<SyscallError>>,
+}
+
+impl RuntimeContext {
+    /// Whether the last run ended with Exit, or the error it ended with. Clears the status for
+    /// the next run. `locate` maps the address of a fault to the script and the command
+    pub fn take_status<F>(&self, locate: F) -> Result<bool, RunError>
+        where F: FnOnce(u64) -> (usize, Option<u32>)
+    {
+        match self.status.replace(STATUS_RETURNED) {
+            STATUS_ERROR => Err(RunError::Uncaught {
+                script_index: self.error_script.get() as usize,
+                position: self.error_position.get(),
+                value: self.error_value.get(),
+            }),
+            STATUS_OUT_OF_FUEL => Err(RunError::OutOfFuel {
+                script_index: self.error_script.get() as usize,
+                position: self.error_position.get(),
+            }),
+            STATUS_STACK_OVERFLOW => Err(RunError::StackOverflow {
+                script_index: self.error_script.get() as usize,
+            }),
+            STATUS_INVALID_CALL => Err(RunError::InvalidCall {
+                script_index: self.error_script.get() as usize,
+                offset: self.error_value.get(),
+            }),
+            STATUS_SYSCALL_ERROR => Err(RunError::Syscall {
+                script_index: self.error_script.get() as usize,
+                sys_num: self.error_value.get() as u8,
+                error: self.syscall_error.borrow_mut().take()
+                    .expect("syscall error status without an error"),
+            }),
+            STATUS_INVALID_STRING => Err(RunError::InvalidString {
+                script_index: self.error_script.get() as usize,
+                str_num: self.error_value.get(),
+            }),
+            STATUS_FAULT => {
+                let (script_index, position) = locate(self.fault_address.get());
+                let kind = match self.fault_signal.get() {
+                    libc::SIGFPE => FaultKind::DivideError,
+                    _ => FaultKind::InvalidAccess { address: self.fault_access.get() },
+                };
+                Err(RunError::Fault { script_index, position, kind })
+            }
+            status => Ok(status == STATUS_EXITED),
+        }
+    }
+}
+
+/// Value raised by an error command, its opcode
+pub fn error_value(cmd: Cmd) -> Option<u32> {
+    match cmd {
+        Cmd::ErrorC => Some(0xC),
+        Cmd::Error37 => Some(0x37),
+        Cmd::Error4C => Some(0x4C),
+        _ => None,
+    }
+}
+
+/// Field of the context at `offset`, the code keeps the address of the context in RDX
+fn field(offset: u64, size: OperandSize) -> (Reg, u64, OperandSize) {
+    (RDX, offset, size)
+}
+
+/// Push a handler record and link it. Returns the position of the `JMP rel32` to the code
+/// resuming after an error, which has to be patched to the code `pop_handler` emits
+pub fn push_handler(writer: &mut CodeWriter, ctx: u64) -> Result<u64, EncodingError> {
+    for reg in HANDLER_REGS.iter() {
+        asm_impl!(writer, {
+            PUSH *reg
+        });
+    }
+    asm_impl!(writer, {
+        MOV RDX, ctx;
+        SUB RSP, 8u8;
+        PUSH field(DEPTH_LEFT, Qword);
+        PUSH field(HANDLER, Qword)
+    });
+    // Call over the JMP, pushing its address as the resume address
+    writer.write_bytes(b"\xe8\x05\x00\x00\x00")?;
+    let resume_jump = writer.get_inner_writer_ref().position();
+    asm_impl!(writer, {
+        JMP 0u32;
+        MOV field(HANDLER, Qword), RSP
+    });
+    Ok(resume_jump)
+}
+
+/// Unlink the handler record on top of the stack and drop it. Returns the position errors
+/// resume at, with the resume address already popped and the error value in EAX. The call
+/// depth is put back for the frames an error skipped the epilogue of
+pub fn pop_handler(writer: &mut CodeWriter, ctx: u64) -> Result<u64, EncodingError> {
+    asm_impl!(writer, {
+        ADD RSP, 8u8
+    });
+    let resume = writer.get_inner_writer_ref().position();
+    asm_impl!(writer, {
+        MOV RDX, ctx;
+        POP RCX;
+        MOV field(HANDLER, Qword), RCX;
+        POP field(DEPTH_LEFT, Qword);
+        ADD RSP, 8u8
+    });
+    for reg in HANDLER_REGS.iter().rev() {
+        asm_impl!(writer, {
+            POP *reg
+        });
+    }
+    Ok(resume)
+}
+
+/// Raise an error, unwinding to the innermost handler or ending the run
+pub fn raise(writer: &mut CodeWriter, ctx: u64, value: u32, script_index: usize, position: u32)
+    -> Result<(), EncodingError>
+{
+    let script_index = script_index as u32;
+    asm_impl!(writer, {
+        MOV RDX, ctx;
+        MOV EAX, value;
+        MOV RCX, field(HANDLER, Qword);
+        TEST RCX, RCX
+    });
+    let uncaught = writer.get_inner_writer_ref().position();
+    asm_impl!(writer, {
+        JE 0u32;
+        // Returns to the resume address of the record
+        MOV RSP, RCX;
+        RET
+    });
+    let uncaught_target = writer.get_inner_writer_ref().position();
+    asm_impl!(writer, {
+        MOV field(STATUS, Dword), STATUS_ERROR;
+        MOV field(ERROR_VALUE, Dword), value;
+        MOV field(ERROR_SCRIPT, Dword), script_index;
+        MOV field(ERROR_POSITION, Dword), position
+    });
+    end_run(writer)?;
+    let end = writer.get_inner_writer_ref().position();
+    patch_jump(writer, uncaught, JE, uncaught_target)?;
+    writer.seek(SeekFrom::Start(end))?;
+    Ok(())
+}
+
+/// Exit, returning from every script call to the entry stub. The run returns 0
+pub fn exit(writer: &mut CodeWriter, ctx: u64) -> Result<(), EncodingError> {
+    asm_impl!(writer, {
+        MOV RDX, ctx;
+        MOV field(STATUS, Dword), STATUS_EXITED;
+        XOR EAX, EAX
+    });
+    end_run(writer)
+}
+
+/// Use a unit of fuel, ending the run if there is none left. `position` is the command using it
+pub fn use_fuel(writer: &mut CodeWriter, ctx: u64, script_index: usize, position: u32)
+    -> Result<(), EncodingError>
+{
+    let script_index = script_index as u32;
+    asm_impl!(writer, {
+        MOV RDX, ctx;
+        SUB field(FUEL, Qword), 1u8
+    });
+    // Borrows when the fuel was already 0
+    let has_fuel = writer.get_inner_writer_ref().position();
+    asm_impl!(writer, {
+        JAE 0u32;
+        MOV field(STATUS, Dword), STATUS_OUT_OF_FUEL;
+        MOV field(ERROR_SCRIPT, Dword), script_index;
+        MOV field(ERROR_POSITION, Dword), position
+    });
+    end_run(writer)?;
+    let end = writer.get_inner_writer_ref().position();
+    patch_jump(writer, has_fuel, JAE, end)?;
+    writer.seek(SeekFrom::Start(end))?;
+    Ok(())
+}
+
+/// After `resolve_call`, end the run if it found no script to call. Leaves everything but the
+/// flags alone when it did
+pub fn check_call_target(writer: &mut CodeWriter, ctx: u64, script_index: usize)
+    -> Result<(), EncodingError>
+{
+    let script_index = script_index as u32;
+    asm_impl!(writer, {
+        TEST RAX, RAX
+    });
+    let found = writer.get_inner_writer_ref().position();
+    asm_impl!(writer, {
+        JNE 0u32;
+        MOV RDX, ctx;
+        MOV field(STATUS, Dword), STATUS_INVALID_CALL;
+        MOV field(ERROR_SCRIPT, Dword), script_index
+    });
+    end_run(writer)?;
+    let end = writer.get_inner_writer_ref().position();
+    patch_jump(writer, found, JNE, end)?;
+    writer.seek(SeekFrom::Start(end))?;
+    Ok(())
+}
+
+/// After `call_syscall` or `msc_printf`, end the run if the call failed. Leaves the return
+/// value in EAX alone when it didn't
+pub fn check_syscall(writer: &mut CodeWriter, ctx: u64, script_index: usize)
+    -> Result<(), EncodingError>
+{
+    let script_index = script_index as u32;
+    asm_impl!(writer, {
+        MOV RDX, ctx;
+        CMP field(STATUS, Dword), STATUS_RETURNED
+    });
+    let returned = writer.get_inner_writer_ref().position();
+    asm_impl!(writer, {
+        JE 0u32;
+        MOV field(ERROR_SCRIPT, Dword), script_index
+    });
+    end_run(writer)?;
+    let end = writer.get_inner_writer_ref().position();
+    patch_jump(writer, returned, JE, end)?;
+    writer.seek(SeekFrom::Start(end))?;
+    Ok(())
+}
+
+/// Prologue check of the call depth and of the space left on the script stack, after the frame
+/// is set up. Only uses R11, the arguments are still in their registers
+pub fn enter_frame(
+    writer: &mut CodeWriter, ctx: u64, script_index: usize, check_depth: bool, check_stack: bool
+) -> Result<(), EncodingError> {
+    let script_index = script_index as u32;
+    let mut overflows = vec![];
+    asm_impl!(writer, {
+        MOV R11, ctx
+    });
+    if check_depth {
+        // Borrows when no call was left
+        asm_impl!(writer, {
+            SUB (R11, DEPTH_LEFT, Qword), 1u8
+        });
+        overflows.push((writer.get_inner_writer_ref().position(), JB));
+        asm_impl!(writer, {
+            JB 0u32
+        });
+    }
+    if check_stack {
+        asm_impl!(writer, {
+            CMP RSP, (R11, STACK_LIMIT, Qword)
+        });
+        overflows.push((writer.get_inner_writer_ref().position(), JB));
+        asm_impl!(writer, {
+            JB 0u32
+        });
+    }
+    let no_overflow = writer.get_inner_writer_ref().position();
+    asm_impl!(writer, {
+        JMP 0u32
+    });
+    let overflow = writer.get_inner_writer_ref().position();
+    asm_impl!(writer, {
+        MOV RDX, ctx;
+        MOV field(STATUS, Dword), STATUS_STACK_OVERFLOW;
+        MOV field(ERROR_SCRIPT, Dword), script_index
+    });
+    end_run(writer)?;
+    let end = writer.get_inner_writer_ref().position();
+    for &(position, mnem) in overflows.iter() {
+        patch_jump(writer, position, mnem, overflow)?;
+    }
+    patch_jump(writer, no_overflow, JMP, end)?;
+    writer.seek(SeekFrom::Start(end))?;
+    Ok(())
+}
+
+/// Epilogue counterpart of `enter_frame` with the call depth checked, leaves RAX alone
+pub fn leave_frame(writer: &mut CodeWriter, ctx: u64) -> Result<(), EncodingError> {
+    asm_impl!(writer, {
+        MOV RDX, ctx;
+        ADD field(DEPTH_LEFT, Qword), 1u8
+    });
+    Ok(())
+}
+
+/// Return to the entry stub from any depth, dropping every frame in between
+fn end_run(writer: &mut CodeWriter) -> Result<(), EncodingError> {
+    asm_impl!(writer, {
+        MOV RSP, field(ENTRY_RSP, Qword);
+        RET
+    });
+    Ok(())
+}
+
+/// Unlink the handlers of a returning frame, the ones below its RBP. Only needed by scripts
+/// that can return with a Try still pending
+pub fn unlink_frame_handlers(writer: &mut CodeWriter, ctx: u64) -> Result<(), EncodingError> {
+    asm_impl!(writer, {
+        MOV RDX, ctx
+    });
+    let check = writer.get_inner_writer_ref().position();
+    asm_impl!(writer, {
+        MOV RCX, field(HANDLER, Qword);
+        TEST RCX, RCX
+    });
+    let none_left = writer.get_inner_writer_ref().position();
+    asm_impl!(writer, {
+        JE 0u32;
+        CMP RCX, RBP
+    });
+    let outside = writer.get_inner_writer_ref().position();
+    asm_impl!(writer, {
+        JAE 0u32;
+        MOV RCX, (RCX, 8u64, Qword);
+        MOV field(HANDLER, Qword), RCX
+    });
+    let next = writer.get_inner_writer_ref().position();
+    asm_impl!(writer, {
+        JMP 0u32
+    });
+    let end = writer.get_inner_writer_ref().position();
+    patch_jump(writer, none_left, JE, end)?;
+    patch_jump(writer, outside, JAE, end)?;
+    patch_jump(writer, next, JMP, check)?;
+    writer.seek(SeekFrom::Start(end))?;
+    Ok(())
+}
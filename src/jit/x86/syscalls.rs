@@ -1,278 +1,181 @@
-pub extern "C" fn nop(_: *const u64, _: u64) -> u32 {
-    0
+//! Host functions bound to `sys` numbers. Scripts compiled with a `SyscallRegistry` call into
+//! it at runtime, and `sys` numbers nothing is registered for are rejected at compile time
+use std::fmt;
+use super::output::{OutputSink, Stdout};
+use super::printf::Printer;
+use super::runtime::{RuntimeContext, STATUS_SYSCALL_ERROR};
+
+/// Host function bound to a `sys` number
+pub type SyscallFn = Box<dyn Fn(&SyscallArgs) -> Result<u32, SyscallError>>;
+
+/// Arguments of a syscall, as pushed by the script
+pub struct SyscallArgs<'a> {
+    /// Top of the stack first, the layout the generated code passes them in
+    raw: &'a [u64],
+    printer: Option<&'a Printer>,
+}
+
+impl<'a> SyscallArgs<'a> {
+    /// `raw` holds the arguments top of the stack first
+    pub fn new(raw: &'a [u64]) -> SyscallArgs<'a> {
+        SyscallArgs { raw, printer: None }
+    }
+
+    /// Arguments of a syscall made by a program printing through `printer`
+    pub fn with_printer(raw: &'a [u64], printer: &'a Printer) -> SyscallArgs<'a> {
+        SyscallArgs { raw, printer: Some(printer) }
+    }
+
+    /// Print to the output of the calling program, stdout if there is none
+    pub fn write_output(&self, bytes: &[u8]) {
+        match self.printer {
+            Some(printer) => printer.write(bytes),
+            None => Stdout.write(bytes),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.raw.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.raw.is_empty()
+    }
+
+    /// Argument `index`, counting in the order they were pushed
+    pub fn int(&self, index: usize) -> Result<u32, SyscallError> {
+        let count = self.raw.len();
+        if index < count {
+            Ok(self.raw[count - 1 - index] as u32)
+        } else {
+            Err(SyscallError::MissingArgument { index, count })
+        }
+    }
+
+    pub fn float(&self, index: usize) -> Result<f32, SyscallError> {
+        self.int(index).map(f32::from_bits)
+    }
+
+    /// Every argument as an int, in the order they were pushed
+    pub fn ints(&self) -> Vec<u32> {
+        self.raw.iter().rev().map(|&val| val as u32).collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyscallError {
+    /// Nothing is registered for the `sys` number
+    Unregistered { sys_num: u8 },
+    /// The script passed fewer arguments than the host function reads
+    MissingArgument { index: usize, count: usize },
+    /// Failure reported by the host function itself
+    Host(String),
+}
+
+impl fmt::Display for SyscallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyscallError::Unregistered { sys_num } => {
+                write!(f, "no host function registered for sys 0x{:X}", sys_num)
+            }
+            SyscallError::MissingArgument { index, count } => {
+                write!(f, "argument {} read but only {} passed", index, count)
+            }
+            SyscallError::Host(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for SyscallError {}
+
+/// Host functions by `sys` number
+pub struct SyscallRegistry {
+    handlers: Vec<Option<SyscallFn>>,
+}
+
+impl SyscallRegistry {
+    /// Registry with nothing registered, not even the builtins
+    pub fn empty() -> SyscallRegistry {
+        SyscallRegistry {
+            handlers: (0..=u8::MAX).map(|_| None).collect(),
+        }
+    }
+
+    /// Bind `handler` to `sys_num`, replacing whatever was registered before
+    pub fn register<F>(&mut self, sys_num: u8, handler: F)
+        where F: Fn(&SyscallArgs) -> Result<u32, SyscallError> + 'static
+    {
+        self.handlers[sys_num as usize] = Some(Box::new(handler));
+    }
+
+    pub fn unregister(&mut self, sys_num: u8) {
+        self.handlers[sys_num as usize] = None;
+    }
+
+    pub fn is_registered(&self, sys_num: u8) -> bool {
+        self.handlers[sys_num as usize].is_some()
+    }
+
+    pub fn call(&self, sys_num: u8, args: &SyscallArgs) -> Result<u32, SyscallError> {
+        match &self.handlers[sys_num as usize] {
+            Some(handler) => handler(args),
+            None => Err(SyscallError::Unregistered { sys_num }),
+        }
+    }
+}
+
+impl Default for SyscallRegistry {
+    /// `getc` at 0 and `crc32_for_byte` at 1
+    fn default() -> SyscallRegistry {
+        let mut registry = SyscallRegistry::empty();
+        registry.register(0, getc);
+        registry.register(1, crc32_for_byte);
+        registry
+    }
 }
 
-pub extern "C" fn crc32_for_byte(args_ptr: *const u64, argsc: u64) -> u32 {
-    if argsc > 0 {
-        let mut r = unsafe { *args_ptr } as u32;
-        for _ in 0..8 {
-            r = (if r & 1 != 0 { 0 } else { 0xEDB8_8320 }) ^ r >> 1;
+impl fmt::Debug for SyscallRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let registered = (0..=u8::MAX)
+            .filter(|&sys_num| self.is_registered(sys_num))
+            .collect::<Vec<u8>>();
+        f.debug_struct("SyscallRegistry")
+            .field("registered", &registered)
+            .finish()
+    }
+}
+
+/// Called by the generated code for every `sys` command. A failure is left in the context,
+/// the code after the call ends the run with it, see `runtime::check_syscall`
+pub unsafe extern "C" fn call_syscall(
+    registry: *const SyscallRegistry, printer: *const Printer, args_ptr: *const u64, argsc: u64,
+    sys_num: u64, runtime: *const RuntimeContext
+) -> u32 {
+    let raw = std::slice::from_raw_parts(args_ptr, argsc as usize);
+    match (*registry).call(sys_num as u8, &SyscallArgs::with_printer(raw, &*printer)) {
+        Ok(ret) => ret,
+        Err(error) => {
+            let runtime = &*runtime;
+            runtime.status.set(STATUS_SYSCALL_ERROR);
+            runtime.error_value.set(sys_num as u32);
+            runtime.syscall_error.replace(Some(error));
+            0
         }
-        r ^ 0xFF00_0000
-    } else {
-        -1i32 as u32
     }
 }
 
-pub extern "C" fn getc(_: *const u64, _: u64) -> u32 {
-    unsafe { libc::getchar() as u32 }
+/// Entry of the reflected CRC-32 table (polynomial 0xEDB88320) for the byte passed
+pub fn crc32_for_byte(args: &SyscallArgs) -> Result<u32, SyscallError> {
+    if args.is_empty() {
+        return Ok(-1i32 as u32);
+    }
+    let mut r = args.int(0)?;
+    for _ in 0..8 {
+        r = (if r & 1 != 0 { 0xEDB8_8320 } else { 0 }) ^ r >> 1;
+    }
+    Ok(r)
 }
 
-pub static SYSCALL_TABLE: [extern "C" fn(*const u64, u64) -> u32; 256] = [
-    getc,
-    crc32_for_byte,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop
-];
+pub fn getc(_: &SyscallArgs) -> Result<u32, SyscallError> {
+    Ok(unsafe { libc::getchar() as u32 })
+}
@@ -0,0 +1,216 @@
+//! Faults in the generated code, a division by zero or a bad pointer, end the run instead of
+//! the process. The first program compiled installs a handler for SIGSEGV, SIGBUS and SIGFPE,
+//! and every program registers the range of its script code with its `RuntimeContext`. For a
+//! fault in a registered range the handler records the fault in the context and resumes in the
+//! entry stub, like `end_run` does. Faults anywhere else, host functions included, are passed on
+//! to the handler that was installed before
+//!
+//! The program then maps the address of the fault back to the script and to the command with the
+//! `CommandMap` of the script. The AST backend only maps the nodes that can fault
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::ops::Range;
+use std::ptr;
+use std::sync::Once;
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+use super::runtime::{RuntimeContext, STATUS_FAULT};
+
+/// Code offset of each command of a script, relative to the start of its code
+#[derive(Debug, Default)]
+pub struct CommandMap {
+    /// (code offset, command position), sorted by code offset
+    commands: Vec<(u32, u32)>,
+}
+
+impl CommandMap {
+    /// Built from the code position of each command. The prologue is attributed to the Begin
+    /// at `begin_position`
+    pub fn new(begin_position: u32, command_locations: &HashMap<u32, u64>) -> CommandMap {
+        let mut commands = command_locations.iter()
+            .map(|(&position, &offset)| (offset as u32, position))
+            .collect::<Vec<_>>();
+        commands.push((0, begin_position));
+        commands.sort();
+        CommandMap { commands }
+    }
+
+    /// Position of the command the code at `offset` belongs to, `None` if the map is empty.
+    /// Commands without code share their offset with the next one, which gets it
+    pub fn position(&self, offset: u32) -> Option<u32> {
+        self.commands.iter()
+            .rev()
+            .find(|&&(start, _)| start <= offset)
+            .map(|&(_, position)| position)
+    }
+}
+
+/// Live programs the handler can end a run of, there is no allocating in a signal handler
+const MAX_REGIONS: usize = 256;
+
+/// Script code of a program, free while `start` is 0
+struct Region {
+    start: AtomicUsize,
+    end: AtomicUsize,
+    runtime: AtomicUsize,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const FREE_REGION: Region = Region {
+    start: AtomicUsize::new(0),
+    end: AtomicUsize::new(0),
+    runtime: AtomicUsize::new(0),
+};
+
+static REGIONS: [Region; MAX_REGIONS] = [FREE_REGION; MAX_REGIONS];
+
+const SIGNALS: [libc::c_int; 3] = [libc::SIGSEGV, libc::SIGBUS, libc::SIGFPE];
+
+/// `sa_sigaction` and `sa_flags` of a handler installed before ours
+struct Previous {
+    handler: AtomicUsize,
+    flags: AtomicI32,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_PREVIOUS: Previous = Previous {
+    handler: AtomicUsize::new(libc::SIG_DFL),
+    flags: AtomicI32::new(0),
+};
+
+/// In the order of `SIGNALS`
+static PREVIOUS: [Previous; 3] = [NO_PREVIOUS; 3];
+
+static INSTALL: Once = Once::new();
+/// errno of installing the handler, 0 if it went fine
+static INSTALL_ERROR: AtomicI32 = AtomicI32::new(0);
+
+/// Start of the siginfo of a fault
+#[repr(C)]
+struct FaultInfo {
+    signo: libc::c_int,
+    errno: libc::c_int,
+    code: libc::c_int,
+    /// The address accessed, of the instruction for SIGFPE
+    addr: u64,
+}
+
+/// Registration of the code of a program, removed on drop
+#[derive(Debug)]
+pub struct TrapRegion {
+    slot: usize,
+}
+
+impl TrapRegion {
+    /// Have faults in `code` end the current run of `runtime`, the program owning both
+    pub fn new(code: Range<u64>, runtime: *const RuntimeContext) -> io::Result<TrapRegion> {
+        INSTALL.call_once(|| unsafe { install() });
+        match INSTALL_ERROR.load(Ordering::Relaxed) {
+            0 => {}
+            errno => return Err(io::Error::from_raw_os_error(errno)),
+        }
+        for (slot, region) in REGIONS.iter().enumerate() {
+            // Claimed through the runtime, published through the start
+            let claimed = region.runtime
+                .compare_exchange(0, runtime as usize, Ordering::AcqRel, Ordering::Relaxed);
+            if claimed.is_ok() {
+                region.end.store(code.end as usize, Ordering::Relaxed);
+                region.start.store(code.start as usize, Ordering::Release);
+                return Ok(TrapRegion { slot });
+            }
+        }
+        Err(io::Error::other(format!("more than {} programs with fault handling", MAX_REGIONS)))
+    }
+}
+
+impl Drop for TrapRegion {
+    fn drop(&mut self) {
+        let region = &REGIONS[self.slot];
+        region.start.store(0, Ordering::Release);
+        region.end.store(0, Ordering::Relaxed);
+        region.runtime.store(0, Ordering::Release);
+    }
+}
+
+unsafe fn install() {
+    for (&signal, previous) in SIGNALS.iter().zip(PREVIOUS.iter()) {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handle_fault as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+        libc::sigemptyset(&mut action.sa_mask);
+        // The previous handler is saved before ours can run
+        let mut old: libc::sigaction = mem::zeroed();
+        if libc::sigaction(signal, ptr::null(), &mut old) != 0 {
+            INSTALL_ERROR.store(last_errno(), Ordering::Relaxed);
+            return;
+        }
+        previous.handler.store(old.sa_sigaction, Ordering::Relaxed);
+        previous.flags.store(old.sa_flags, Ordering::Relaxed);
+        if libc::sigaction(signal, &action, ptr::null_mut()) != 0 {
+            INSTALL_ERROR.store(last_errno(), Ordering::Relaxed);
+            return;
+        }
+    }
+}
+
+fn last_errno() -> i32 {
+    io::Error::last_os_error().raw_os_error().unwrap_or(libc::EINVAL)
+}
+
+/// Context of the program the code at `address` belongs to
+fn find_runtime(address: u64) -> Option<*const RuntimeContext> {
+    let address = address as usize;
+    REGIONS.iter()
+        .find(|region| {
+            let start = region.start.load(Ordering::Acquire);
+            start != 0 && start <= address && address < region.end.load(Ordering::Relaxed)
+        })
+        .map(|region| region.runtime.load(Ordering::Acquire) as *const RuntimeContext)
+}
+
+extern "C" fn handle_fault(
+    signal: libc::c_int, info: *mut libc::siginfo_t, context: *mut libc::c_void
+) {
+    unsafe {
+        let gregs = &mut (*(context as *mut libc::ucontext_t)).uc_mcontext.gregs;
+        let address = gregs[libc::REG_RIP as usize] as u64;
+        let runtime = match find_runtime(address) {
+            Some(runtime) if !runtime.is_null() => &*runtime,
+            _ => return pass_on(signal, info, context),
+        };
+        runtime.status.set(STATUS_FAULT);
+        runtime.fault_signal.set(signal);
+        runtime.fault_address.set(address);
+        runtime.fault_access.set((*(info as *const FaultInfo)).addr);
+        // Return to the entry stub, the return value is 0
+        let entry_rsp = runtime.entry_rsp.get();
+        gregs[libc::REG_RIP as usize] = *(entry_rsp as *const u64) as i64;
+        gregs[libc::REG_RSP as usize] = (entry_rsp + 8) as i64;
+        gregs[libc::REG_RAX as usize] = 0;
+    }
+}
+
+/// Hand a fault outside of the generated code to the handler installed before ours
+unsafe fn pass_on(signal: libc::c_int, info: *mut libc::siginfo_t, context: *mut libc::c_void) {
+    let previous = match SIGNALS.iter().position(|&s| s == signal) {
+        Some(index) => &PREVIOUS[index],
+        None => return,
+    };
+    match previous.handler.load(Ordering::Relaxed) {
+        // Faulting again with the default action kills the process like it would have
+        libc::SIG_DFL | libc::SIG_IGN => {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = libc::SIG_DFL;
+            libc::sigaction(signal, &action, ptr::null_mut());
+        }
+        handler if previous.flags.load(Ordering::Relaxed) & libc::SA_SIGINFO != 0 => {
+            let handler: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) =
+                mem::transmute(handler);
+            handler(signal, info, context);
+        }
+        handler => {
+            let handler: extern "C" fn(libc::c_int) = mem::transmute(handler);
+            handler(signal);
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use crate::jit::ast::Type;
+use crate::jit::decompile;
+use msc::{Cmd, MscsbFile};
+use std::fmt;
+use super::RunError;
+
+/// A 32 bit MSC value passed to or returned from a script
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Int(u32),
+    Float(f32),
+}
+
+impl Value {
+    /// The value of type `t` with this bit pattern
+    pub fn from_bits(bits: u32, t: Type) -> Value {
+        match t {
+            Type::Int => Value::Int(bits),
+            Type::Float => Value::Float(f32::from_bits(bits)),
+        }
+    }
+
+    /// Bit pattern of the value, the way scripts see it
+    pub fn bits(self) -> u32 {
+        match self {
+            Value::Int(val) => val,
+            Value::Float(val) => val.to_bits(),
+        }
+    }
+
+    /// The bits as an int, whatever the type
+    pub fn as_int(self) -> u32 {
+        self.bits()
+    }
+
+    /// The bits as a float, whatever the type
+    pub fn as_float(self) -> f32 {
+        f32::from_bits(self.bits())
+    }
+}
+
+impl From<u32> for Value {
+    fn from(val: u32) -> Value {
+        Value::Int(val)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(val: i32) -> Value {
+        Value::Int(val as u32)
+    }
+}
+
+impl From<f32> for Value {
+    fn from(val: f32) -> Value {
+        Value::Float(val)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Int(val) => write!(f, "0x{:X}", val),
+            Value::Float(val) => write!(f, "{:?}", val),
+        }
+    }
+}
+
+/// How a run ended
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExitStatus {
+    /// The script returned, scripts that never return a value give `Value::Int(0)`
+    Returned(Value),
+    /// A script ran Exit, ending every script call of the run
+    Exited,
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExitStatus::Returned(val) => write!(f, "Return value - {}", val),
+            ExitStatus::Exited => write!(f, "Exited"),
+        }
+    }
+}
+
+/// What a script takes and returns, as far as can be told from its commands
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Signature {
+    pub arg_count: u16,
+    /// None for scripts that never return a value
+    pub return_type: Option<Type>,
+}
+
+impl Signature {
+    pub fn of(file: &MscsbFile, script_index: usize) -> Signature {
+        let arg_count = match file.scripts[script_index].commands.first().map(|cmd| cmd.cmd) {
+            Some(Cmd::Begin { arg_count, .. }) => arg_count,
+            _ => 0,
+        };
+        Signature { arg_count, return_type: decompile::return_type(file, script_index) }
+    }
+
+    /// Check `args` can be passed to the script
+    pub fn check_args(&self, script_index: usize, args: &[Value]) -> Result<(), RunError> {
+        if args.len() != usize::from(self.arg_count) {
+            return Err(RunError::ArgumentCount {
+                script_index, expected: self.arg_count, passed: args.len()
+            });
+        }
+        Ok(())
+    }
+
+    /// The value returned, from the bits the script left in its return register
+    pub fn return_value(&self, bits: u32) -> Value {
+        self.return_type.map_or(Value::Int(0), |t| Value::from_bits(bits, t))
+    }
+}
@@ -4,26 +4,356 @@ extern crate x86asm;
 mod jit;
 
 use jit::x86::*;
+use jit::ast::AsAst;
+use jit::decompile::{decompile, decompile_script};
+use jit::interp::Interpreter;
+use jit::x86::host::HostState;
+use msc::MscsbFile;
 use std::io::prelude::*;
-//use std::io;
+use std::process;
+use std::rc::Rc;
+use std::io;
 
-fn gdb(_address: u64) {
+const USAGE: &str = "\
+Usage: msc-jit <run|disasm|ast|decompile|compile> [options] <file.mscsb>
+
+Options:
+    -e, --entrypoint <index>  Script index to run (or decompile) instead of the file's entrypoint
+    -a, --arg <value>         Argument to pass to the script (int, 0x-hex or float), repeatable
+    -i, --interp              Run with the reference interpreter instead of the JIT
+        --dual-map            Map the code twice (RW and RX) instead of using mprotect
+    -b, --backend <name>      Code generator to compile with, 'commands' (default) or 'ast'
+        --fuel <n>            Stop the run after n script entries and backward jumps
+        --max-depth <n>       Stop the run when script calls nest deeper than n
+        --script-stack <n>    Run scripts on a stack of their own of n bytes, with a guard page
+    -v, --verbose             Print more information, repeat to also dump the emitted asm
+        --gdb                 Print a gdb command for attaching before running
+    -h, --help                Print this message";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Subcommand {
+    Run,
+    Disasm,
+    Ast,
+    Decompile,
+    Compile,
+}
+
+#[derive(Debug)]
+struct Args {
+    subcommand: Subcommand,
+    path: String,
+    entrypoint: Option<usize>,
+    script_args: Vec<Value>,
+    verbosity: usize,
+    gdb: bool,
+    interp: bool,
+    dual_mapping: bool,
+    backend: Backend,
+    fuel: Option<u64>,
+    max_call_depth: Option<u32>,
+    script_stack_size: Option<usize>,
+}
+
+fn parse_int(s: &str) -> Option<u32> {
+    if s.starts_with("0x") || s.starts_with("0X") {
+        u32::from_str_radix(&s[2..], 16).ok()
+    } else if s.starts_with('-') {
+        s.parse::<i32>().ok().map(|i| i as u32)
+    } else {
+        s.parse::<u32>().ok()
+    }
+}
+
+fn parse_script_arg(s: &str) -> Option<Value> {
+    if !s.starts_with("0x") && (s.contains('.') || s.contains('e')) {
+        s.parse::<f32>().ok().map(Value::Float)
+    } else {
+        parse_int(s).map(Value::Int)
+    }
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Args, String> {
+    let subcommand = match args.next().as_deref() {
+        Some("run") => Subcommand::Run,
+        Some("disasm") => Subcommand::Disasm,
+        Some("ast") => Subcommand::Ast,
+        Some("decompile") => Subcommand::Decompile,
+        Some("compile") => Subcommand::Compile,
+        Some(other) => return Err(format!("Unknown subcommand '{}'", other)),
+        None => return Err(String::from("No subcommand given")),
+    };
+
+    let mut path = None;
+    let mut entrypoint = None;
+    let mut script_args = vec![];
+    let mut verbosity = 0;
+    let mut gdb = false;
+    let mut interp = false;
+    let mut dual_mapping = false;
+    let mut backend = Backend::default();
+    let mut fuel = None;
+    let mut max_call_depth = None;
+    let mut script_stack_size = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-e" | "--entrypoint" => {
+                let val = args.next().ok_or("--entrypoint requires a script index")?;
+                entrypoint = Some(
+                    parse_int(&val)
+                        .ok_or_else(|| format!("Invalid script index '{}'", val))? as usize
+                );
+            }
+            "-a" | "--arg" => {
+                let val = args.next().ok_or("--arg requires a value")?;
+                script_args.push(
+                    parse_script_arg(&val)
+                        .ok_or_else(|| format!("Invalid argument '{}'", val))?
+                );
+            }
+            "--verbose" => verbosity += 1,
+            // -v, -vv, -vvv...
+            _ if arg.len() > 1 && arg.starts_with('-') && arg[1..].bytes().all(|b| b == b'v') => {
+                verbosity += arg.len() - 1;
+            }
+            "--gdb" => gdb = true,
+            "-i" | "--interp" => interp = true,
+            "--dual-map" => dual_mapping = true,
+            "-b" | "--backend" => {
+                backend = match args.next().as_deref() {
+                    Some("commands") => Backend::Commands,
+                    Some("ast") => Backend::Ast,
+                    Some(other) => return Err(format!("Unknown backend '{}'", other)),
+                    None => return Err(String::from("--backend requires a name")),
+                };
+            }
+            "--fuel" => {
+                let val = args.next().ok_or("--fuel requires an amount")?;
+                fuel = Some(val.parse::<u64>().map_err(|_| format!("Invalid fuel '{}'", val))?);
+            }
+            "--max-depth" => {
+                let val = args.next().ok_or("--max-depth requires a depth")?;
+                max_call_depth = Some(
+                    val.parse::<u32>().map_err(|_| format!("Invalid depth '{}'", val))?
+                );
+            }
+            "--script-stack" => {
+                let val = args.next().ok_or("--script-stack requires a size")?;
+                script_stack_size = Some(
+                    val.parse::<usize>().map_err(|_| format!("Invalid stack size '{}'", val))?
+                );
+            }
+            _ if arg.starts_with('-') => return Err(format!("Unknown option '{}'", arg)),
+            _ => {
+                if path.is_some() {
+                    return Err(format!("Unexpected argument '{}'", arg));
+                }
+                path = Some(arg);
+            }
+        }
+    }
+
+    Ok(Args {
+        subcommand,
+        path: path.ok_or("No .mscsb file given")?,
+        entrypoint,
+        script_args,
+        verbosity,
+        gdb,
+        interp,
+        dual_mapping,
+        backend,
+        fuel,
+        max_call_depth,
+        script_stack_size,
+    })
+}
+
+/// Print a gdb command breaking at the script at `address`, and wait for Enter once it's attached
+fn gdb(address: u64) {
     std::fs::File::create("/tmp/msc-jit-temp.txt").unwrap()
-        .write_all("layout regs\nb src/jit/mod.rs:50\ncommands\nsi\nend\n".as_bytes()).unwrap();
+        .write_all(format!("layout regs\nb *0x{:X}\ncontinue\n", address).as_bytes()).unwrap();
     println!("sudo gdb -p {} -x /tmp/msc-jit-temp.txt", std::process::id());
-    //let stdin = io::stdin();
-    //stdin.lock().lines().next().unwrap().ok();
+    println!("Press Enter once gdb is attached");
+    let stdin = io::stdin();
+    stdin.lock().lines().next();
     std::fs::remove_file("/tmp/msc-jit-temp.txt").ok();
 }
 
+fn disasm(file: &MscsbFile) {
+    for (i, script) in file.scripts.iter().enumerate() {
+        let marker = if file.get_script_from_loc(file.entrypoint) == Some(i) {
+            " (entrypoint)"
+        } else {
+            ""
+        };
+        println!("script_{} [0x{:X}..0x{:X}]{}:", i, script.bounds.0, script.bounds.1, marker);
+        for cmd in script.iter() {
+            println!(
+                "    0x{:04X}: {:?}{}",
+                cmd.position,
+                cmd.cmd,
+                if cmd.push_bit { " (push)" } else { "" }
+            );
+        }
+        println!();
+    }
+}
+
+/// Options for compiling `file`, with the host syscalls registered
+fn compile_options(file: &MscsbFile, args: &Args) -> CompileOptions {
+    let (syscalls, _) = HostState::registry(file);
+    CompileOptions {
+        dump_asm: args.verbosity >= 2,
+        dual_mapping: args.dual_mapping,
+        backend: args.backend,
+        syscalls: Rc::new(syscalls),
+        fuel: args.fuel.is_some(),
+        max_call_depth: args.max_call_depth,
+        script_stack_size: args.script_stack_size,
+    }
+}
+
+fn compile(file: &MscsbFile, args: &Args) -> CompiledProgram {
+    let options = compile_options(file, args);
+    match file.compile_with(&options) {
+        Ok(program) => program,
+        Err(err) => {
+            eprintln!("Error: failed to compile '{}': {}", args.path, err);
+            process::exit(1);
+        }
+    }
+}
+
+fn interpret(file: &MscsbFile, args: &Args) {
+    let syscalls = compile_options(file, args).syscalls;
+    let mut interpreter = match Interpreter::with_syscalls(file, syscalls) {
+        Ok(interpreter) => interpreter,
+        Err(err) => {
+            eprintln!("Error: failed to load '{}': {}", args.path, err);
+            process::exit(1);
+        }
+    };
+    let script_index = args.entrypoint.unwrap_or(interpreter.entrypoint_index);
+    if script_index >= file.scripts.len() {
+        eprintln!("Error: script index {} out of bounds (< {})", script_index, file.scripts.len());
+        process::exit(1);
+    }
+    if args.verbosity >= 1 {
+        println!("Interpreting script_{} with args {:?}", script_index, args.script_args);
+    }
+    let status = match args.fuel {
+        Some(fuel) => interpreter.run_script_with_fuel(script_index, &args.script_args, fuel),
+        None => interpreter.run_script_status(script_index, &args.script_args),
+    };
+    match status {
+        Ok(status) => println!("\n{}", status),
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            process::exit(1);
+        }
+    }
+}
+
+fn run(file: &MscsbFile, args: &Args) {
+    let mut program = compile(file, args);
+    let script_index = args.entrypoint.unwrap_or(program.entrypoint_index);
+    if script_index >= program.script_count() {
+        eprintln!("Error: script index {} out of bounds (< {})",
+                  script_index, program.script_count());
+        process::exit(1);
+    }
+    if let Err(err) = program.lock_all() {
+        eprintln!("Error: failed to make code executable: {}", err);
+        process::exit(1);
+    }
+    if args.gdb {
+        gdb(program.get_script_address(script_index));
+    }
+    if args.verbosity >= 1 {
+        println!("Running script_{} with args {:?}", script_index, args.script_args);
+    }
+    let status = match args.fuel {
+        Some(fuel) => program.run_script_with_fuel(script_index, &args.script_args, fuel),
+        None => program.run_script_status(script_index, &args.script_args),
+    };
+    match status {
+        Ok(status) => println!("\n{}", status),
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            process::exit(1);
+        }
+    }
+}
+
 fn main() {
-    let test = msc::MscsbFile::open("/home/jam/dev/msc/msc-jit/printf.mscsb")
-                    .unwrap();
-    
-    //println!("{:#?}", test.scripts[0].as_ast());
-    let mut test_compiled = test.compile().expect("Failed to compile");
-    test_compiled.lock_all();
-    let address = test_compiled.get_entrypoint_address();
-    gdb(address);
-    test_compiled.run();
+    if std::env::args().skip(1).any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("Error: {}\n\n{}", err, USAGE);
+            process::exit(1);
+        }
+    };
+
+    let file = match MscsbFile::open(&args.path) {
+        Ok(file) => file,
+        Err(err) => {
+            eprintln!("Error: failed to open '{}': {}", args.path, err);
+            process::exit(1);
+        }
+    };
+
+    match args.subcommand {
+        Subcommand::Run if args.interp => interpret(&file, &args),
+        Subcommand::Run => run(&file, &args),
+        Subcommand::Disasm => disasm(&file),
+        Subcommand::Ast => {
+            for (i, script) in file.scripts.iter().enumerate() {
+                println!("script_{}: {:#?}", i, script.as_ast());
+            }
+        }
+        Subcommand::Decompile => {
+            match args.entrypoint {
+                Some(script_index) if script_index < file.scripts.len() => {
+                    print!("{}", decompile_script(&file, script_index));
+                }
+                Some(script_index) => {
+                    eprintln!("Error: script index {} out of bounds (< {})",
+                              script_index, file.scripts.len());
+                    process::exit(1);
+                }
+                None => print!("{}", decompile(&file)),
+            }
+        }
+        Subcommand::Compile => {
+            let errors = file.check_with(&compile_options(&file, &args));
+            if !errors.is_empty() {
+                for err in errors.iter() {
+                    eprintln!("Error: {}", err);
+                }
+                eprintln!("{} errors compiling '{}'", errors.len(), args.path);
+                process::exit(1);
+            }
+            let program = compile(&file, &args);
+            println!(
+                "Compiled {} scripts, entrypoint is script_{}",
+                program.script_count(),
+                program.entrypoint_index
+            );
+            if args.backend == Backend::Ast {
+                let ast_count = program.backends.iter()
+                    .filter(|&&backend| backend == Backend::Ast)
+                    .count();
+                println!(
+                    "{} scripts compiled from their AST, {} fell back to the command backend",
+                    ast_count,
+                    program.backends.len() - ast_count
+                );
+            }
+        }
+    }
 }